@@ -17,6 +17,6 @@ async fn main() -> Result<(), Error> {
 
     let addr = env::args().nth(1).unwrap_or("127.0.0.1:1883".to_string());
 
-    let _state = Server::new().serve(addr, pending::<()>()).await;
+    let _state = Server::default().serve(addr, pending::<()>()).await;
     Ok(())
 }
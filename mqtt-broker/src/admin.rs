@@ -0,0 +1,266 @@
+use std::time::Instant;
+
+use failure::ResultExt;
+use tokio::sync::oneshot;
+
+use crate::broker::BrokerHandle;
+use crate::session::Session;
+use crate::subscription::Subscription;
+use crate::{ClientId, Error, ErrorKind, Message, Publication, SystemEvent};
+
+/// The state of a session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionStatus {
+    Transient,
+    Persistent,
+    Offline,
+    Disconnecting,
+}
+
+/// A session as seen by an administrator.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    client_id: ClientId,
+    status: SessionStatus,
+    subscriptions: Vec<Subscription>,
+    queued: usize,
+    queued_bytes: usize,
+    dropped: u64,
+    inflight: usize,
+    last_active: Option<Instant>,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(session: &Session) -> Self {
+        let status = match session {
+            Session::Transient(_) => SessionStatus::Transient,
+            Session::Persistent(_) => SessionStatus::Persistent,
+            Session::Offline(_) => SessionStatus::Offline,
+            Session::Disconnecting(_) => SessionStatus::Disconnecting,
+        };
+
+        let mut info = Self {
+            client_id: session.client_id().clone(),
+            status,
+            subscriptions: vec![],
+            queued: 0,
+            queued_bytes: 0,
+            dropped: 0,
+            inflight: 0,
+            last_active: None,
+        };
+        if let Some(state) = session.state() {
+            info.subscriptions = state.subscriptions().cloned().collect();
+            info.queued = state.queued_count();
+            info.queued_bytes = state.queued_bytes();
+            info.dropped = state.dropped_count();
+            info.inflight = state.inflight_count();
+            info.last_active = Some(state.last_active().into_std());
+        }
+        info
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
+    pub fn status(&self) -> SessionStatus {
+        self.status
+    }
+
+    pub fn subscriptions(&self) -> &[Subscription] {
+        &self.subscriptions
+    }
+
+    /// The number of messages waiting to be sent to the client.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    /// The total payload size of the queued messages.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// The number of messages dropped since the broker started because the
+    /// queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The number of messages sent to the client and waiting to be
+    /// acknowledged.
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    /// When a message was last sent to the client, or when the client went
+    /// offline. Sessions being disconnected have no state, so this is
+    /// `None` for them.
+    pub fn last_active(&self) -> Option<Instant> {
+        self.last_active
+    }
+}
+
+/// Inspects and manages the sessions and retained messages of a running
+/// broker.
+///
+/// Each request is a `SystemEvent` processed by the broker in order with
+/// the messages of its clients.
+#[derive(Clone, Debug)]
+pub struct AdminHandle(BrokerHandle);
+
+impl AdminHandle {
+    pub fn new(broker_handle: BrokerHandle) -> Self {
+        AdminHandle(broker_handle)
+    }
+
+    pub async fn sessions(&mut self) -> Result<Vec<SessionInfo>, Error> {
+        self.request(SystemEvent::ListSessions).await
+    }
+
+    /// Drops the connection of a connected client. Its will is published
+    /// and a persistent session goes offline.
+    pub async fn disconnect(&mut self, client_id: ClientId) -> Result<(), Error> {
+        self.request(|reply| SystemEvent::DisconnectClient(client_id, reply))
+            .await?
+    }
+
+    /// Removes an offline persistent session along with its subscriptions
+    /// and queued messages.
+    pub async fn delete_session(&mut self, client_id: ClientId) -> Result<(), Error> {
+        self.request(|reply| SystemEvent::DeleteSession(client_id, reply))
+            .await?
+    }
+
+    pub async fn retained(&mut self) -> Result<Vec<Publication>, Error> {
+        self.request(SystemEvent::ListRetained).await
+    }
+
+    /// Removes the retained message of a topic, returning whether there
+    /// was one.
+    pub async fn delete_retained(&mut self, topic_name: String) -> Result<bool, Error> {
+        self.request(|reply| SystemEvent::DeleteRetained(topic_name, reply))
+            .await?
+    }
+
+    /// Publishes a message as the broker, without a client to authorize it.
+    pub async fn publish(&mut self, publication: Publication) -> Result<(), Error> {
+        self.request(|reply| SystemEvent::Publish(publication, reply))
+            .await?
+    }
+
+    async fn request<T, F>(&mut self, event: F) -> Result<T, Error>
+    where
+        F: FnOnce(oneshot::Sender<T>) -> SystemEvent,
+    {
+        let (reply, response) = oneshot::channel();
+        self.0.send(Message::System(event(reply))).await?;
+        let response = response.await.context(ErrorKind::ReceiveBrokerReply)?;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use mqtt::proto;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        Broker, ClientEvent, ConnReq, ConnectionHandle, DisconnectReason, PublicationProperties,
+    };
+
+    fn connect(id: &str, clean_session: bool) -> proto::Connect {
+        let client_id = if clean_session {
+            proto::ClientId::IdWithCleanSession(id.to_string())
+        } else {
+            proto::ClientId::IdWithExistingSession(id.to_string())
+        };
+        proto::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id,
+            keep_alive: Default::default(),
+            protocol_name: "MQTT".to_string(),
+            protocol_level: 0x4,
+        }
+    }
+
+    fn publication(topic_name: &str, retain: bool) -> Publication {
+        Publication {
+            topic_name: topic_name.to_string(),
+            qos: proto::QoS::AtMostOnce,
+            retain,
+            payload: Bytes::from("payload"),
+            properties: PublicationProperties::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let broker = Broker::default();
+        let mut broker_handle = broker.handle();
+        let mut admin = AdminHandle::new(broker.handle());
+        tokio::spawn(broker.run());
+
+        let client_id = ClientId::from("client".to_string());
+        let (tx, mut rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            connect("client", false),
+            ConnectionHandle::from_sender(tx),
+        );
+        let event = ClientEvent::ConnReq(req);
+        broker_handle
+            .send(Message::Client(client_id.clone(), event))
+            .await
+            .unwrap();
+
+        let sessions = admin.sessions().await.unwrap();
+        assert_eq!(1, sessions.len());
+        assert_eq!(&client_id, sessions[0].client_id());
+        assert_eq!(SessionStatus::Persistent, sessions[0].status());
+        assert!(sessions[0].last_active().is_some());
+
+        // A connected session can't be deleted
+        let error = admin.delete_session(client_id.clone()).await.unwrap_err();
+        assert_eq!(&ErrorKind::SessionConnected, error.kind());
+
+        admin.disconnect(client_id.clone()).await.unwrap();
+        let sessions = admin.sessions().await.unwrap();
+        assert_eq!(SessionStatus::Offline, sessions[0].status());
+        let error = admin.disconnect(client_id.clone()).await.unwrap_err();
+        assert_eq!(&ErrorKind::SessionOffline, error.kind());
+
+        admin.delete_session(client_id.clone()).await.unwrap();
+        assert!(admin.sessions().await.unwrap().is_empty());
+        let error = admin.delete_session(client_id).await.unwrap_err();
+        assert_eq!(&ErrorKind::NoSession, error.kind());
+
+        // The client got a CONNACK and was then dropped
+        assert!(matches!(
+            rx.recv().await,
+            Some(Message::Client(_, ClientEvent::ConnAck(_)))
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Message::Client(
+                _,
+                ClientEvent::ServerDisconnect(DisconnectReason::AdministrativeAction)
+            ))
+        ));
+
+        admin.publish(publication("a/b", true)).await.unwrap();
+        admin.publish(publication("a/c", false)).await.unwrap();
+        let retained = admin.retained().await.unwrap();
+        assert_eq!(vec![publication("a/b", true)], retained);
+
+        assert!(admin.delete_retained("a/b".to_string()).await.unwrap());
+        assert!(!admin.delete_retained("a/b".to_string()).await.unwrap());
+        assert!(admin.retained().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{CertificateIdentity, ClientId, Error, PeerAddr};
+
+/// Identity of an authenticated client.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub struct AuthId(Arc<String>);
+
+impl AuthId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AuthId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl From<String> for AuthId {
+    fn from(s: String) -> AuthId {
+        AuthId(Arc::new(s))
+    }
+}
+
+/// Outcome of authenticating a client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Auth {
+    /// The client is authenticated as the given identity.
+    Identity(AuthId),
+
+    /// The client is allowed to connect without an identity.
+    Anonymous,
+
+    /// The username or password is wrong. The client is refused with
+    /// `BadUserNameOrPassword`.
+    BadCredentials,
+
+    /// The client is not allowed to connect. The client is refused with
+    /// `NotAuthorized`.
+    NotAuthorized,
+}
+
+impl Auth {
+    /// The identity of an authenticated client.
+    pub fn auth_id(&self) -> Option<&AuthId> {
+        match self {
+            Auth::Identity(auth_id) => Some(auth_id),
+            Auth::Anonymous | Auth::BadCredentials | Auth::NotAuthorized => None,
+        }
+    }
+}
+
+/// What a client presented when it connected.
+#[derive(Clone, Debug)]
+pub struct AuthenticationContext {
+    client_id: ClientId,
+    username: Option<String>,
+    password: Option<String>,
+    certificate: Option<CertificateIdentity>,
+    remote_addr: PeerAddr,
+}
+
+impl AuthenticationContext {
+    pub fn new(
+        client_id: ClientId,
+        username: Option<String>,
+        password: Option<String>,
+        certificate: Option<CertificateIdentity>,
+        remote_addr: PeerAddr,
+    ) -> Self {
+        Self {
+            client_id,
+            username,
+            password,
+            certificate,
+            remote_addr,
+        }
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    pub fn certificate(&self) -> Option<&CertificateIdentity> {
+        self.certificate.as_ref()
+    }
+
+    pub fn remote_addr(&self) -> &PeerAddr {
+        &self.remote_addr
+    }
+}
+
+/// Decides whether a client is allowed to connect.
+///
+/// Clients are authenticated on their connection's task after the CONNECT
+/// packet is received, so a slow authenticator doesn't hold up the broker.
+/// The broker refuses the connection when authentication fails.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// An error means the client couldn't be authenticated, for example
+    /// because a credential store is unavailable. Such clients are refused
+    /// with `NotAuthorized`.
+    async fn authenticate(&self, context: AuthenticationContext) -> Result<Auth, Error>;
+}
+
+/// Allows every client to connect anonymously.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+#[async_trait]
+impl Authenticator for AllowAll {
+    async fn authenticate(&self, _context: AuthenticationContext) -> Result<Auth, Error> {
+        Ok(Auth::Anonymous)
+    }
+}
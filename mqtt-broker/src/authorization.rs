@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use failure::ResultExt;
+use tracing::{debug, info};
+
+use crate::auth::{AllowAll, AuthId};
+use crate::subscription::TopicFilter;
+use crate::{ClientId, Error, ErrorKind};
+
+static CLIENT_ID_PLACEHOLDER: &str = "%c";
+static USERNAME_PLACEHOLDER: &str = "%u";
+
+/// An operation a client needs to be authorized for.
+#[derive(Clone, Copy, Debug)]
+pub enum Operation<'a> {
+    /// Publishing to a topic name
+    Publish(&'a str),
+
+    /// Subscribing to a topic filter
+    Subscribe(&'a TopicFilter),
+}
+
+/// Decides which topics a client is allowed to publish and subscribe to.
+///
+/// The broker consults the authorizer for every publication it receives
+/// from a client and every topic filter a client subscribes to. Denied
+/// subscriptions fail in the SUBACK and denied publications are dropped.
+pub trait Authorizer: Send + Sync {
+    /// `auth_id` is the identity the client authenticated as, if any.
+    fn authorize(
+        &self,
+        client_id: &ClientId,
+        auth_id: Option<&AuthId>,
+        operation: Operation<'_>,
+    ) -> bool;
+}
+
+impl Authorizer for AllowAll {
+    fn authorize(
+        &self,
+        _client_id: &ClientId,
+        _auth_id: Option<&AuthId>,
+        _operation: Operation<'_>,
+    ) -> bool {
+        true
+    }
+}
+
+/// What an ACL rule grants.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// Subscribing
+    Read,
+
+    /// Publishing
+    Write,
+
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(self, operation: Operation<'_>) -> bool {
+        match (self, operation) {
+            (Access::ReadWrite, _) => true,
+            (Access::Read, Operation::Subscribe(_)) => true,
+            (Access::Write, Operation::Publish(_)) => true,
+            (_, _) => false,
+        }
+    }
+}
+
+/// A rule with its topic filter. The filter of a pattern keeps its
+/// placeholders, which are replaced for each client.
+#[derive(Clone, Debug)]
+struct AclRule {
+    access: Access,
+    filter: TopicFilter,
+}
+
+impl AclRule {
+    fn allows(&self, operation: Operation<'_>) -> bool {
+        self.access.allows(operation) && filter_allows(&self.filter, operation)
+    }
+
+    /// Like `allows`, for a pattern applied to a client.
+    fn allows_pattern(
+        &self,
+        client_id: &ClientId,
+        auth_id: Option<&AuthId>,
+        operation: Operation<'_>,
+    ) -> bool {
+        self.access.allows(operation)
+            && substitute(&self.filter, client_id, auth_id)
+                .map_or(false, |filter| filter_allows(&filter, operation))
+    }
+}
+
+/// Authorizes clients with access control lists of topic filters.
+///
+/// Rules grant `read` (subscribe), `write` (publish) or `readwrite` access
+/// to the topics matched by a topic filter. A subscription is allowed if a
+/// rule's filter matches every topic the subscription could match. Anything
+/// not granted by a rule is denied.
+///
+/// Rules can grant access to clients authenticated as a user, to anonymous
+/// clients, or through patterns to every client. In patterns, `%c` is
+/// replaced by the client id and `%u` by the username. A pattern doesn't
+/// apply to a client whose id or username contains `/`, `+` or `#`, or that
+/// has no username when the pattern needs one.
+#[derive(Clone, Debug, Default)]
+pub struct AclAuthorizer {
+    anonymous: Vec<AclRule>,
+    users: HashMap<String, Vec<AclRule>>,
+    patterns: Vec<AclRule>,
+}
+
+impl AclAuthorizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grants anonymous clients access to the topics matched by `filter`.
+    pub fn allow_anonymous(mut self, access: Access, filter: TopicFilter) -> Self {
+        self.anonymous.push(AclRule { access, filter });
+        self
+    }
+
+    /// Grants clients authenticated as `username` access to the topics
+    /// matched by `filter`.
+    pub fn allow_user<U: Into<String>>(
+        mut self,
+        username: U,
+        access: Access,
+        filter: TopicFilter,
+    ) -> Self {
+        self.users
+            .entry(username.into())
+            .or_default()
+            .push(AclRule { access, filter });
+        self
+    }
+
+    /// Grants every client access to the topics matched by `pattern`, once
+    /// its placeholders are replaced for the client.
+    pub fn allow_pattern(mut self, access: Access, pattern: TopicFilter) -> Self {
+        self.patterns.push(AclRule {
+            access,
+            filter: pattern,
+        });
+        self
+    }
+
+    /// Reads rules from a mosquitto style ACL file.
+    ///
+    /// `user <username>` starts the rules for a user. `topic [read|write|readwrite]
+    /// <filter>` grants access to the current user, or to anonymous clients
+    /// before the first `user` line. `pattern [read|write|readwrite] <filter>`
+    /// grants access to every client. The access defaults to `readwrite`.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).context(ErrorKind::LoadAclFile)?;
+        let acl = Self::parse(&contents)?;
+        info!("loaded ACL file {}", path.display());
+        Ok(acl)
+    }
+
+    fn parse(contents: &str) -> Result<Self, Error> {
+        let mut acl = Self::new();
+        let mut user = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || Error::from(ErrorKind::InvalidAclFile(i + 1));
+            let mut parts = line.splitn(2, ' ');
+            let keyword = parts.next().ok_or_else(invalid)?;
+            let rest = parts.next().map(str::trim).ok_or_else(invalid)?;
+
+            if keyword == "user" {
+                user = Some(rest.to_string());
+                continue;
+            }
+
+            let (access, topic) = parse_rule(rest).ok_or_else(invalid)?;
+            let filter = topic.parse().map_err(|_| invalid())?;
+            acl = match (keyword, &user) {
+                ("topic", Some(user)) => acl.allow_user(user.clone(), access, filter),
+                ("topic", None) => acl.allow_anonymous(access, filter),
+                ("pattern", _) => acl.allow_pattern(access, filter),
+                _ => return Err(invalid()),
+            };
+        }
+        Ok(acl)
+    }
+}
+
+impl Authorizer for AclAuthorizer {
+    fn authorize(
+        &self,
+        client_id: &ClientId,
+        auth_id: Option<&AuthId>,
+        operation: Operation<'_>,
+    ) -> bool {
+        let rules = match auth_id {
+            Some(auth_id) => self.users.get(auth_id.as_str()),
+            None => Some(&self.anonymous),
+        };
+
+        let allowed = rules
+            .into_iter()
+            .flatten()
+            .any(|rule| rule.allows(operation))
+            || self
+                .patterns
+                .iter()
+                .any(|rule| rule.allows_pattern(client_id, auth_id, operation));
+
+        if !allowed {
+            debug!(
+                "{:?} denied for client {} (user {:?})",
+                operation, client_id, auth_id
+            );
+        }
+        allowed
+    }
+}
+
+fn parse_rule(rule: &str) -> Option<(Access, &str)> {
+    let mut parts = rule.splitn(2, ' ');
+    let first = parts.next()?;
+    let access = match first {
+        "read" => Some(Access::Read),
+        "write" => Some(Access::Write),
+        "readwrite" => Some(Access::ReadWrite),
+        _ => None,
+    };
+
+    match (access, parts.next()) {
+        (Some(access), Some(topic)) => Some((access, topic.trim())),
+        (Some(_), None) => None,
+        (None, _) => Some((Access::ReadWrite, rule)),
+    }
+}
+
+fn filter_allows(filter: &TopicFilter, operation: Operation<'_>) -> bool {
+    match operation {
+        Operation::Publish(topic_name) => filter.matches(topic_name),
+        Operation::Subscribe(subscription) => filter.covers(subscription),
+    }
+}
+
+/// Replaces the placeholders in the levels of a pattern, returning `None`
+/// if the pattern doesn't apply to the client.
+fn substitute(
+    pattern: &TopicFilter,
+    client_id: &ClientId,
+    auth_id: Option<&AuthId>,
+) -> Option<TopicFilter> {
+    pattern.map_levels(|level| {
+        let mut level = level.to_string();
+        if level.contains(CLIENT_ID_PLACEHOLDER) {
+            level = level.replace(CLIENT_ID_PLACEHOLDER, valid_level(client_id.as_str())?);
+        }
+        if level.contains(USERNAME_PLACEHOLDER) {
+            let username = valid_level(auth_id?.as_str())?;
+            level = level.replace(USERNAME_PLACEHOLDER, username);
+        }
+        Some(level)
+    })
+}
+
+fn valid_level(level: &str) -> Option<&str> {
+    if level.is_empty() || level.contains(&['/', '+', '#'][..]) {
+        None
+    } else {
+        Some(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_id(id: &str) -> ClientId {
+        ClientId::from(id.to_string())
+    }
+
+    fn auth_id(id: &str) -> AuthId {
+        AuthId::from(id.to_string())
+    }
+
+    fn filter(filter: &str) -> TopicFilter {
+        filter.parse().unwrap()
+    }
+
+    fn subscribe(acl: &AclAuthorizer, id: &str, user: Option<&str>, filter: &str) -> bool {
+        let filter = filter.parse().unwrap();
+        let auth_id = user.map(auth_id);
+        acl.authorize(
+            &client_id(id),
+            auth_id.as_ref(),
+            Operation::Subscribe(&filter),
+        )
+    }
+
+    fn publish(acl: &AclAuthorizer, id: &str, user: Option<&str>, topic: &str) -> bool {
+        let auth_id = user.map(auth_id);
+        acl.authorize(&client_id(id), auth_id.as_ref(), Operation::Publish(topic))
+    }
+
+    #[test]
+    fn test_user_rules() {
+        let acl = AclAuthorizer::new()
+            .allow_user("alice", Access::ReadWrite, filter("alice/#"))
+            .allow_user("alice", Access::Read, filter("shared/+"))
+            .allow_anonymous(Access::Read, filter("public/#"));
+
+        assert!(publish(&acl, "c1", Some("alice"), "alice/data"));
+        assert!(subscribe(&acl, "c1", Some("alice"), "alice/#"));
+        assert!(subscribe(&acl, "c1", Some("alice"), "shared/news"));
+        assert!(!subscribe(&acl, "c1", Some("alice"), "shared/#"));
+        assert!(!publish(&acl, "c1", Some("alice"), "shared/news"));
+        assert!(!publish(&acl, "c1", Some("bob"), "alice/data"));
+        assert!(!subscribe(&acl, "c1", Some("alice"), "public/#"));
+
+        assert!(subscribe(&acl, "c1", None, "public/#"));
+        assert!(!publish(&acl, "c1", None, "public/news"));
+        assert!(!subscribe(&acl, "c1", None, "#"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let acl = AclAuthorizer::new()
+            .allow_pattern(Access::ReadWrite, filter("devices/%c/#"))
+            .allow_pattern(Access::Write, filter("users/%u/%c"));
+
+        assert!(publish(&acl, "dev1", None, "devices/dev1/telemetry"));
+        assert!(subscribe(&acl, "dev1", None, "devices/dev1/commands/+"));
+        assert!(!publish(&acl, "dev1", None, "devices/dev2/telemetry"));
+        assert!(!subscribe(&acl, "dev1", None, "devices/+/telemetry"));
+
+        assert!(publish(&acl, "dev1", Some("alice"), "users/alice/dev1"));
+        assert!(!publish(&acl, "dev1", None, "users/alice/dev1"));
+        assert!(!subscribe(&acl, "dev1", Some("alice"), "users/alice/dev1"));
+
+        // Wildcards in a client id don't widen a pattern
+        assert!(!publish(&acl, "+", None, "devices/dev2/telemetry"));
+        assert!(!subscribe(&acl, "#", None, "devices/#"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let acl = AclAuthorizer::parse(
+            "# anonymous clients\n\
+             topic read public/#\n\
+             \n\
+             pattern devices/%c/#\n\
+             user alice\n\
+             topic write alice/#\n",
+        )
+        .unwrap();
+
+        assert!(subscribe(&acl, "c1", None, "public/news"));
+        assert!(publish(&acl, "dev1", None, "devices/dev1/telemetry"));
+        assert!(subscribe(&acl, "dev1", None, "devices/dev1/telemetry"));
+        assert!(publish(&acl, "c1", Some("alice"), "alice/data"));
+        assert!(!subscribe(&acl, "c1", Some("alice"), "alice/data"));
+        assert!(!subscribe(&acl, "c1", Some("alice"), "public/news"));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let invalid = [
+            "topic",
+            "user alice\ntopic read",
+            "allow #",
+            "topic read a/#/b",
+            "user alice\ntopic write a+",
+            "pattern readwrite devices/%c#",
+        ];
+        for contents in &invalid {
+            let err = AclAuthorizer::parse(contents).unwrap_err();
+            assert_eq!(
+                ErrorKind::InvalidAclFile(contents.lines().count()),
+                *err.kind()
+            );
+        }
+    }
+}
@@ -1,16 +1,37 @@
+use std::cmp;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use failure::ResultExt;
+use metrics::{counter, decrement_gauge, histogram, increment_counter, increment_gauge};
 use mqtt::proto;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
 use tracing::{debug, info, span, warn, Level};
 use tracing_futures::Instrument;
 
-use crate::session::{ConnectedSession, Session, SessionState};
-use crate::{ClientEvent, ClientId, ConnReq, Error, ErrorKind, Message, SystemEvent};
+use crate::admin::SessionInfo;
+use crate::auth::{AllowAll, Auth};
+use crate::authorization::{Authorizer, Operation};
+use crate::config::{BrokerConfig, ConfigUpdate, ExpiryRules};
+use crate::expiry;
+use crate::journal::{Journal, JournalEntry};
+use crate::retained::RetainedStore;
+use crate::serialize;
+use crate::session::{ConnectedSession, Session, SessionState, Will};
+use crate::subscription::{Subscription, SubscriptionIndex};
+use crate::sys::{self, BrokerStats, Gauges};
+use crate::telemetry;
+use crate::{
+    ClientEvent, ClientId, ConnReq, Disconnect, DisconnectReason, Error, ErrorKind, Message,
+    ProtocolVersion, Publication, PublicationId, PublicationProperties, SystemEvent,
+};
 
 static EXPECTED_PROTOCOL_NAME: &str = "MQTT";
-const EXPECTED_PROTOCOL_LEVEL: u8 = 0x4;
+static MQTT31_PROTOCOL_NAME: &str = "MQIsdp";
+const MQTT31_MAX_CLIENT_ID_LEN: usize = 23;
 
 macro_rules! try_send {
     ($session:expr, $msg:expr) => {{
@@ -20,33 +41,214 @@ macro_rules! try_send {
     }};
 }
 
-// TODO: this is a stubbed broker state, implement for real
-pub struct BrokerState;
+/// State that outlives the broker task.
+///
+/// Holds the state of every persistent session along with the retained
+/// messages, which is everything needed to restore a broker with
+/// `Broker::from_state`. Transient sessions are not part of the state.
+///
+/// When the broker has a journal, the state also records the last journal
+/// segment it covers, and the last id given to a publication so the ids
+/// in newer segments don't collide with the ones of queued messages.
+///
+/// Retained and queued messages keep their expiry deadlines as wall clock
+/// time, so they expire when they would have without the restart.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BrokerState {
+    retained: HashMap<String, Publication>,
+    #[serde(with = "serialize::deadline_map")]
+    retained_deadlines: HashMap<String, Instant>,
+    sessions: Vec<SessionState>,
+    journal_segment: u64,
+    last_publication_id: PublicationId,
+    // QoS 2 messages migrated from an older state, see `SessionStateV3`
+    #[serde(skip)]
+    unreleased: Vec<Publication>,
+}
+
+impl BrokerState {
+    pub fn new(retained: HashMap<String, Publication>, sessions: Vec<SessionState>) -> Self {
+        Self {
+            retained,
+            retained_deadlines: HashMap::new(),
+            sessions,
+            journal_segment: 0,
+            last_publication_id: PublicationId::default(),
+            unreleased: Vec::new(),
+        }
+    }
+
+    /// Records the last journal segment the state covers.
+    pub(crate) fn with_journal_segment(mut self, journal_segment: u64) -> Self {
+        self.journal_segment = journal_segment;
+        self
+    }
+
+    pub(crate) fn with_retained_deadlines(
+        mut self,
+        retained_deadlines: HashMap<String, Instant>,
+    ) -> Self {
+        self.retained_deadlines = retained_deadlines;
+        self
+    }
+
+    pub(crate) fn with_last_publication_id(mut self, last_publication_id: PublicationId) -> Self {
+        self.last_publication_id = last_publication_id;
+        self
+    }
+
+    /// Adds exactly once messages that clients sent but hadn't released
+    /// when an older state was saved. They are delivered as the state is restored.
+    pub(crate) fn with_unreleased(mut self, unreleased: Vec<Publication>) -> Self {
+        self.unreleased = unreleased;
+        self
+    }
+
+    pub fn journal_segment(&self) -> u64 {
+        self.journal_segment
+    }
+
+    pub fn into_parts(self) -> (HashMap<String, Publication>, Vec<SessionState>) {
+        (self.retained, self.sessions)
+    }
+}
 
 pub struct Broker {
     sender: Sender<Message>,
     messages: Receiver<Message>,
     sessions: HashMap<ClientId, Session>,
-    retained: HashMap<String, proto::Publication>,
+    retained: RetainedStore,
+    journal: Option<Journal>,
+    journal_segment: u64,
+    last_publication_id: PublicationId,
+    authorizer: Arc<dyn Authorizer>,
+    subscriptions: SubscriptionIndex,
+    allow_mqtt31: bool,
+    config: BrokerConfig,
+    config_sender: watch::Sender<BrokerConfig>,
+    config_receiver: watch::Receiver<BrokerConfig>,
+    expiry_rules: ExpiryRules,
+    // Wills held back by MQTT 5 clients, until their deadline. They are
+    // lost when the broker restarts.
+    delayed_wills: HashMap<ClientId, (Will, Instant)>,
+    stats: BrokerStats,
 }
 
 impl Broker {
-    pub fn new() -> Self {
-        let (sender, messages) = mpsc::channel(1024);
-        Self {
+    pub fn new(config: BrokerConfig) -> Self {
+        Self::from_state(config, BrokerState::default())
+    }
+
+    /// Creates a broker from previously saved state.
+    ///
+    /// All restored sessions start offline. Their queued and inflight
+    /// messages are delivered when the client reconnects with
+    /// clean session set to false.
+    pub fn from_state(config: BrokerConfig, state: BrokerState) -> Self {
+        let BrokerState {
+            retained: retained_map,
+            retained_deadlines,
+            sessions,
+            journal_segment,
+            last_publication_id,
+            unreleased,
+        } = state;
+        let expiry_rules = config.expiry_rules();
+        let mut retained = RetainedStore::new();
+        for (topic_name, publication) in retained_map {
+            retained.insert_until(publication, retained_deadlines.get(&topic_name).copied());
+        }
+
+        let (sender, messages) = mpsc::channel(config.message_queue_size());
+        let (config_sender, config_receiver) = watch::channel(config.clone());
+        let mut broker = Self {
             sender,
             messages,
             sessions: HashMap::new(),
-            retained: HashMap::new(),
+            retained,
+            journal: None,
+            journal_segment,
+            last_publication_id,
+            authorizer: Arc::new(AllowAll),
+            subscriptions: SubscriptionIndex::new(),
+            allow_mqtt31: false,
+            config,
+            config_sender,
+            config_receiver,
+            expiry_rules,
+            delayed_wills: HashMap::new(),
+            stats: BrokerStats::new(),
+        };
+        for state in sessions {
+            broker.restore_session(state);
+        }
+        for publication in unreleased {
+            let (id, publication, deadline) = broker.route(publication);
+            broker.replay_publish(id, publication, deadline);
+        }
+        broker
+    }
+
+    /// Authorizes the topics clients publish and subscribe to. By default
+    /// everything is allowed.
+    pub fn with_authorizer<A>(mut self, authorizer: A) -> Self
+    where
+        A: Authorizer + 'static,
+    {
+        self.authorizer = Arc::new(authorizer);
+        self
+    }
+
+    /// Accepts clients connecting with MQTT 3.1 (protocol name `MQIsdp`),
+    /// which are refused by default. Their client ids are limited to 23
+    /// characters.
+    pub fn with_mqtt31(mut self, allow: bool) -> Self {
+        self.allow_mqtt31 = allow;
+        self
+    }
+
+    /// Replays the journal on top of the broker's state and records
+    /// further changes in it.
+    ///
+    /// The journal should be opened with the `journal_segment` of the state
+    /// the broker was created from.
+    pub fn with_journal(mut self, journal: Journal) -> Result<Self, Error> {
+        let entries = journal.entries()?;
+        info!("replaying {} journal entries...", entries.len());
+        for entry in entries {
+            self.replay(entry);
         }
+
+        self.journal = Some(journal);
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &BrokerConfig {
+        &self.config
+    }
+
+    /// Follows the config connections are handled with, which changes with
+    /// config updates.
+    pub(crate) fn config_receiver(&self) -> watch::Receiver<BrokerConfig> {
+        self.config_receiver.clone()
     }
 
     pub fn handle(&self) -> BrokerHandle {
         BrokerHandle(self.sender.clone())
     }
 
+    /// Runs the broker until it is shut down.
+    ///
+    /// The broker publishes its statistics as retained messages under
+    /// `$SYS/broker` every `sys_interval` of its config, and removes
+    /// offline sessions older than its `session_expiry` and retained
+    /// messages older than their `message_expiry`.
     pub async fn run(mut self) -> BrokerState {
+        tokio::spawn(sys::sys_timer(self.config_receiver(), self.handle()));
+        tokio::spawn(expiry::expiry_timer(self.config_receiver(), self.handle()));
+
         while let Some(message) = self.messages.recv().await {
+            decrement_gauge!("mqtt_broker_mailbox_messages", 1.0);
             match message {
                 Message::Client(client_id, event) => {
                     let span = span!(Level::INFO, "broker", client_id=%client_id);
@@ -66,11 +268,481 @@ impl Broker {
                     }
                     break;
                 }
+                Message::System(SystemEvent::StateSnapshot(mut snapshot_handle)) => {
+                    debug!("sending state snapshot to the snapshotter...");
+                    let result = self
+                        .snapshot()
+                        .and_then(|state| snapshot_handle.try_send(state));
+                    if let Err(e) = result {
+                        warn!(message = "an error occurred taking a state snapshot", error=%e);
+                    }
+                }
+                Message::System(SystemEvent::ConfigUpdate(update)) => {
+                    self.update_config(update);
+                }
+                Message::System(SystemEvent::PublishStats) => {
+                    let gauges = self.gauges();
+                    telemetry::record_gauges(&gauges);
+                    if let Err(e) = self.publish_stats(&gauges).await {
+                        warn!(message = "an error occurred publishing $SYS topics", error=%e);
+                    }
+                }
+                Message::System(SystemEvent::Expire) => {
+                    self.expire_sessions();
+                    self.expire_retained();
+                    if let Err(e) = self.publish_delayed_wills().await {
+                        warn!(message = "an error occurred publishing delayed wills", error=%e);
+                    }
+                }
+                Message::System(SystemEvent::ListSessions(reply)) => {
+                    let sessions = self.sessions.values().map(SessionInfo::new).collect();
+                    let _ = reply.send(sessions);
+                }
+                Message::System(SystemEvent::DisconnectClient(client_id, reply)) => {
+                    let _ = reply.send(self.disconnect_client(client_id).await);
+                }
+                Message::System(SystemEvent::DeleteSession(client_id, reply)) => {
+                    let _ = reply.send(self.delete_session(client_id));
+                }
+                Message::System(SystemEvent::ListRetained(reply)) => {
+                    let retained = self.retained.to_map().into_iter().map(|(_, p)| p);
+                    let _ = reply.send(retained.collect());
+                }
+                Message::System(SystemEvent::DeleteRetained(topic_name, reply)) => {
+                    let _ = reply.send(self.delete_retained(&topic_name));
+                }
+                Message::System(SystemEvent::Publish(publication, reply)) => {
+                    let _ = reply.send(self.publish_as_broker(publication).await);
+                }
             }
         }
 
         info!("broker is shutdown.");
-        BrokerState
+        self.into_state()
+    }
+
+    fn update_config(&mut self, update: ConfigUpdate) {
+        let (config, authorizer, allow_mqtt31) = update.into_parts();
+
+        if config.message_queue_size() != self.config.message_queue_size() {
+            warn!(
+                "the message queue size can't be changed while the broker is running. keeping {}",
+                self.config.message_queue_size()
+            );
+        }
+        let config = config.with_message_queue_size(self.config.message_queue_size());
+
+        if config.max_inflight_messages() != self.config.max_inflight_messages()
+            || config.queue_limits() != self.config.queue_limits()
+        {
+            for session in self.sessions.values_mut() {
+                if let Some(state) = session.state_mut() {
+                    state.set_max_inflight_messages(config.max_inflight_messages());
+                    state.set_queue_limits(config.queue_limits());
+                }
+            }
+        }
+        // Messages published from now on expire by the new rules
+        self.expiry_rules = config.expiry_rules();
+
+        // Connections accepted from now on use the new config. The broker
+        // holds a receiver itself, so this doesn't fail.
+        let _ = self.config_sender.broadcast(config.clone());
+        self.config = config;
+
+        if let Some(authorizer) = authorizer {
+            self.authorizer = authorizer;
+        }
+        if let Some(allow_mqtt31) = allow_mqtt31 {
+            self.allow_mqtt31 = allow_mqtt31;
+        }
+        info!("broker config updated");
+    }
+
+    async fn disconnect_client(&mut self, client_id: ClientId) -> Result<(), Error> {
+        let session = self.sessions.get(&client_id).ok_or(ErrorKind::NoSession)?;
+        if !session.is_connected() {
+            return Err(ErrorKind::SessionOffline.into());
+        }
+
+        info!("disconnecting client {}", client_id);
+        let reason = DisconnectReason::AdministrativeAction;
+        self.drop_connection(client_id, ClientEvent::ServerDisconnect(reason))
+            .await
+    }
+
+    /// Only offline sessions can be deleted, a connected client has to be
+    /// disconnected first.
+    fn delete_session(&mut self, client_id: ClientId) -> Result<(), Error> {
+        match self.sessions.remove(&client_id) {
+            Some(Session::Offline(offline)) => {
+                self.remove_subscriptions(offline.state());
+                self.journal_session_removal(&client_id);
+                info!("deleted session for {}", client_id);
+                Ok(())
+            }
+            Some(session) => {
+                self.sessions.insert(client_id, session);
+                Err(ErrorKind::SessionConnected.into())
+            }
+            None => Err(ErrorKind::NoSession.into()),
+        }
+    }
+
+    /// Removes the offline sessions whose client has been inactive for
+    /// longer than its session expiry interval, or `session_expiry`.
+    fn expire_sessions(&mut self) {
+        let config = &self.config;
+        let expired = self
+            .sessions
+            .iter()
+            .filter_map(|(client_id, session)| match session {
+                Session::Offline(offline) => {
+                    let state = offline.state();
+                    let expiry = config.session_expiry_interval(state.expiry_interval());
+                    let expired =
+                        expiry != Duration::from_secs(0) && state.last_active().elapsed() >= expiry;
+                    if expired {
+                        Some(client_id.clone())
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for client_id in expired {
+            if let Some(Session::Offline(offline)) = self.sessions.remove(&client_id) {
+                self.remove_subscriptions(offline.state());
+                self.journal_session_removal(&client_id);
+                info!(
+                    "expired session for {} with {} queued messages",
+                    client_id,
+                    offline.state().queued_count()
+                );
+                increment_counter!("mqtt_expired_sessions_total");
+            }
+        }
+    }
+
+    /// Removes the retained messages older than their `message_expiry`.
+    /// Subscribers don't get them once they expire, this frees them.
+    fn expire_retained(&mut self) {
+        let expired = self.retained.remove_expired();
+        if expired > 0 {
+            debug!("removed {} expired retained messages", expired);
+            counter!("mqtt_expired_messages_total", expired as u64);
+        }
+    }
+
+    /// Removes the retained message of a topic, returning whether there
+    /// was one. The removal is journaled first, so a crash doesn't bring
+    /// the message back.
+    fn delete_retained(&mut self, topic_name: &str) -> Result<bool, Error> {
+        self.journal(&JournalEntry::RemoveRetained(topic_name.to_string()))?;
+
+        let removed = self.retained.remove(topic_name).is_some();
+        if removed {
+            info!("removed retained message for topic \"{}\"", topic_name);
+        }
+        Ok(removed)
+    }
+
+    /// Publishes a message that doesn't come from a client. It is journaled
+    /// like a client publication.
+    async fn publish_as_broker(&mut self, publication: Publication) -> Result<(), Error> {
+        let (id, publication, deadline) = self.route_journaled(publication, None)?;
+        self.publish_all(id, publication, deadline).await
+    }
+
+    fn snapshot(&mut self) -> Result<BrokerState, Error> {
+        // Changes made after this point go to a new journal segment,
+        // which is replayed on top of this snapshot.
+        if let Some(journal) = &mut self.journal {
+            self.journal_segment = journal.rotate()?;
+        }
+
+        let sessions = self
+            .sessions
+            .values()
+            .filter_map(|session| match session {
+                Session::Persistent(connected) => Some(connected.state().clone()),
+                Session::Offline(offline) => Some(offline.state().clone()),
+                Session::Transient(_) | Session::Disconnecting(_) => None,
+            })
+            .collect();
+
+        let mut state = BrokerState::new(retained_map(&self.retained), sessions);
+        state.retained_deadlines = retained_deadlines(&self.retained);
+        state.journal_segment = self.journal_segment;
+        state.last_publication_id = self.last_publication_id;
+        Ok(state)
+    }
+
+    fn into_state(self) -> BrokerState {
+        let Broker {
+            sessions,
+            retained,
+            journal,
+            mut journal_segment,
+            last_publication_id,
+            ..
+        } = self;
+
+        // Nothing is journaled after the broker stops, so the final state
+        // covers the current segment.
+        if let Some(mut journal) = journal {
+            match journal.sync() {
+                Ok(()) => journal_segment = journal.segment(),
+                Err(e) => warn!(message = "an error occurred syncing the journal", error=%e),
+            }
+        }
+
+        let sessions = sessions
+            .into_iter()
+            .filter_map(|(_, session)| match session {
+                Session::Persistent(connected) => {
+                    let (state, _will, _handle) = connected.into_parts();
+                    Some(state)
+                }
+                Session::Offline(offline) => Some(offline.into_state()),
+                Session::Transient(_) | Session::Disconnecting(_) => None,
+            })
+            .collect();
+
+        let mut state = BrokerState::new(retained_map(&retained), sessions);
+        state.retained_deadlines = retained_deadlines(&retained);
+        state.journal_segment = journal_segment;
+        state.last_publication_id = last_publication_id;
+        state
+    }
+
+    /// Adds a restored persistent session, which starts offline, in place
+    /// of any session the client had.
+    fn restore_session(&mut self, state: SessionState) {
+        let client_id = state.client_id().clone();
+        if let Some(previous) = self.sessions.remove(&client_id) {
+            if let Some(previous) = previous.state() {
+                self.remove_subscriptions(previous);
+            }
+        }
+
+        for subscription in state.subscriptions() {
+            self.subscriptions.insert(client_id.clone(), subscription);
+        }
+        let state = state
+            .with_max_inflight_messages(self.config.max_inflight_messages())
+            .with_queue_limits(self.config.queue_limits());
+        self.sessions.insert(client_id, Session::new_offline(state));
+    }
+
+    fn replay(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Publish(id, publication, deadline) => {
+                self.replay_publish(id, publication, deadline);
+            }
+            JournalEntry::PublishFrom(client_id, packet_identifier, id, publication, deadline) => {
+                if let Some(Session::Offline(offline)) = self.sessions.get_mut(&client_id) {
+                    offline.replay_publish_from(packet_identifier);
+                }
+                self.replay_publish(id, publication, deadline);
+            }
+            JournalEntry::PubAck(client_id, packet_identifier, id) => {
+                if let Some(Session::Offline(offline)) = self.sessions.get_mut(&client_id) {
+                    offline.replay_puback(packet_identifier, id);
+                }
+            }
+            JournalEntry::PubRec(client_id, packet_identifier, id) => {
+                if let Some(Session::Offline(offline)) = self.sessions.get_mut(&client_id) {
+                    offline.replay_pubrec(packet_identifier, id);
+                }
+            }
+            JournalEntry::PubComp(client_id, packet_identifier) => {
+                if let Some(Session::Offline(offline)) = self.sessions.get_mut(&client_id) {
+                    offline.replay_pubcomp(packet_identifier);
+                }
+            }
+            JournalEntry::PubRel(client_id, packet_identifier) => {
+                if let Some(Session::Offline(offline)) = self.sessions.get_mut(&client_id) {
+                    offline.replay_pubrel(packet_identifier);
+                }
+            }
+            JournalEntry::OpenSession(state) => self.restore_session(state),
+            JournalEntry::RemoveSession(client_id) => {
+                if let Some(session) = self.sessions.remove(&client_id) {
+                    if let Some(state) = session.state() {
+                        self.remove_subscriptions(state);
+                    }
+                }
+            }
+            JournalEntry::SessionExpiry(client_id, expiry_interval) => {
+                if let Some(state) = self
+                    .sessions
+                    .get_mut(&client_id)
+                    .and_then(Session::state_mut)
+                {
+                    state.set_expiry_interval(expiry_interval);
+                }
+            }
+            JournalEntry::Subscribe(client_id, subscriptions) => {
+                if let Some(state) = self
+                    .sessions
+                    .get_mut(&client_id)
+                    .and_then(Session::state_mut)
+                {
+                    for subscription in subscriptions {
+                        self.subscriptions.insert(client_id.clone(), &subscription);
+                        state.update_subscription(subscription.filter().to_string(), subscription);
+                    }
+                }
+            }
+            JournalEntry::RemoveRetained(topic_name) => {
+                self.retained.remove(&topic_name);
+            }
+            JournalEntry::Unsubscribe(client_id, filters) => {
+                if let Some(state) = self
+                    .sessions
+                    .get_mut(&client_id)
+                    .and_then(Session::state_mut)
+                {
+                    for filter in filters {
+                        if let Ok(filter) = filter.parse() {
+                            self.subscriptions.remove(&client_id, &filter);
+                        }
+                        state.remove_subscription(&filter);
+                    }
+                }
+            }
+        }
+    }
+
+    fn replay_publish(
+        &mut self,
+        id: PublicationId,
+        mut publication: Publication,
+        deadline: Option<Instant>,
+    ) {
+        self.last_publication_id = cmp::max(self.last_publication_id, id);
+        self.update_retained(&publication, deadline);
+        publication.retain = false;
+
+        // Restored sessions are offline, so this only queues. A shared
+        // subscription may pick another member than it did before the
+        // restart.
+        let matches = self.subscriptions.matches(&publication.topic_name);
+        for (client_id, max_qos) in matches.clients() {
+            if let Some(session) = self.sessions.get_mut(client_id) {
+                if let Err(e) = session.publish_to(id, &publication, *max_qos, deadline) {
+                    warn!(message = "error replaying a publication", error=%e);
+                }
+            }
+        }
+        for group in matches.groups() {
+            let member = self.next_member(group);
+            if let Some(session) = member.and_then(|id| self.sessions.get_mut(&id)) {
+                if let Err(e) = session.publish_shared(id, &publication, deadline, group) {
+                    warn!(message = "error replaying a publication", error=%e);
+                }
+            }
+        }
+    }
+
+    /// Returns the id for the next publication routed by the broker.
+    fn publication_id(&mut self) -> PublicationId {
+        self.last_publication_id = self.last_publication_id.next();
+        self.last_publication_id
+    }
+
+    /// Gives a publication its id and the deadline its retained and queued
+    /// copies expire at, the sooner of the expiry rules of the broker and
+    /// the message expiry an MQTT 5 publisher set.
+    fn route(&mut self, publication: Publication) -> (PublicationId, Publication, Option<Instant>) {
+        let id = self.publication_id();
+        let deadline = match (
+            self.expiry_rules.deadline(&publication.topic_name),
+            publication.properties.message_expiry,
+        ) {
+            (Some(rule), Some(expiry)) => Some(cmp::min(rule, expiry)),
+            (rule, expiry) => rule.or(expiry),
+        };
+        (id, publication, deadline)
+    }
+
+    /// Routes a publication and journals it, unless it's a QoS 0 message
+    /// that doesn't change a retained message. A `publisher` is the session
+    /// and packet identifier of a QoS 2 publication from a client.
+    fn route_journaled(
+        &mut self,
+        publication: Publication,
+        publisher: Option<(&ClientId, proto::PacketIdentifier)>,
+    ) -> Result<(PublicationId, Publication, Option<Instant>), Error> {
+        let (id, publication, deadline) = self.route(publication);
+
+        let entry = match publisher {
+            Some((client_id, packet_identifier)) => Some(JournalEntry::PublishFrom(
+                client_id.clone(),
+                packet_identifier,
+                id,
+                publication.clone(),
+                deadline,
+            )),
+            None if publication.qos != proto::QoS::AtMostOnce || publication.retain => {
+                Some(JournalEntry::Publish(id, publication.clone(), deadline))
+            }
+            None => None,
+        };
+        if let Some(entry) = entry {
+            self.journal(&entry)?;
+        }
+        Ok((id, publication, deadline))
+    }
+
+    fn journal(&mut self, entry: &JournalEntry) -> Result<(), Error> {
+        if let Some(journal) = &mut self.journal {
+            journal.append(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Journals the acknowledgement of a message sent to a persistent session.
+    ///
+    /// A failure only means the message may be sent again after a crash,
+    /// so it is logged rather than failing the acknowledgement.
+    fn journal_delivery<F>(
+        &mut self,
+        client_id: &ClientId,
+        packet_identifier: proto::PacketIdentifier,
+        entry: F,
+    ) where
+        F: FnOnce(ClientId, proto::PacketIdentifier, PublicationId) -> JournalEntry,
+    {
+        let inflight = match self.sessions.get(client_id) {
+            Some(Session::Persistent(connected)) => connected.state().inflight(packet_identifier),
+            _ => None,
+        };
+
+        if let Some(id) = inflight {
+            let entry = entry(client_id.clone(), packet_identifier, id);
+            if let Err(e) = self.journal(&entry) {
+                warn!(message = "an error occurred journaling an acknowledgement", error=%e);
+            }
+        }
+    }
+
+    /// Journals a change to the sessions or subscriptions that replaying
+    /// publications depends on.
+    ///
+    /// The change has already been made, so a failure is logged. The
+    /// snapshot taken next includes the change.
+    fn journal_session_change(&mut self, entry: &JournalEntry) {
+        if let Err(e) = self.journal(entry) {
+            warn!(message = "an error occurred journaling a session change", error=%e);
+        }
+    }
+
+    fn journal_session_removal(&mut self, client_id: &ClientId) {
+        self.journal_session_change(&JournalEntry::RemoveSession(client_id.clone()));
     }
 
     async fn process_message(
@@ -79,14 +751,30 @@ impl Broker {
         event: ClientEvent,
     ) -> Result<(), Error> {
         debug!("incoming: {:?}", event);
+        if let ClientEvent::PublishFrom(publish, _) = &event {
+            self.stats.received(publish);
+        }
+
+        let started = Instant::now();
+        let label = telemetry::event_label(&event);
+
         let result = match event {
             ClientEvent::ConnReq(connreq) => self.process_connect(client_id, connreq).await,
             ClientEvent::ConnAck(_) => {
                 info!("broker received CONNACK, ignoring");
                 Ok(())
             }
-            ClientEvent::Disconnect(_) => self.process_disconnect(client_id).await,
-            ClientEvent::DropConnection => self.process_drop_connection(client_id).await,
+            ClientEvent::Disconnect(disconnect) => {
+                self.process_disconnect(client_id, disconnect).await
+            }
+            ClientEvent::DropConnection => {
+                self.drop_connection(client_id, ClientEvent::DropConnection)
+                    .await
+            }
+            ClientEvent::ServerDisconnect(reason) => {
+                self.drop_connection(client_id, ClientEvent::ServerDisconnect(reason))
+                    .await
+            }
             ClientEvent::CloseSession => self.process_close_session(client_id).await,
             ClientEvent::PingReq(ping) => self.process_ping_req(client_id, ping).await,
             ClientEvent::PingResp(_) => {
@@ -105,12 +793,18 @@ impl Broker {
                 info!("broker received UNSUBACK, ignoring");
                 Ok(())
             }
-            ClientEvent::PublishFrom(publish) => self.process_publish(client_id, publish).await,
+            ClientEvent::PublishFrom(publish, properties) => {
+                self.process_publish(client_id, publish, properties).await
+            }
             ClientEvent::PublishTo(_publish) => {
                 info!("broker received a PublishTo, ignoring");
                 Ok(())
             }
             ClientEvent::PubAck0(id) => self.process_puback0(client_id, id).await,
+            ClientEvent::PublishNotAuthorized(..) => {
+                info!("broker received a PublishNotAuthorized, ignoring");
+                Ok(())
+            }
             ClientEvent::PubAck(puback) => self.process_puback(client_id, puback).await,
             ClientEvent::PubRec(pubrec) => self.process_pubrec(client_id, pubrec).await,
             ClientEvent::PubRel(pubrel) => self.process_pubrel(client_id, pubrel).await,
@@ -121,6 +815,7 @@ impl Broker {
             warn!(message = "error processing message", %e);
         }
 
+        histogram!("mqtt_broker_event_duration_seconds", started.elapsed(), "event" => label);
         Ok(())
     }
 
@@ -135,7 +830,8 @@ impl Broker {
         }
 
         for mut session in sessions {
-            if let Err(e) = session.send(ClientEvent::DropConnection).await {
+            let event = ClientEvent::ServerDisconnect(DisconnectReason::ServerShuttingDown);
+            if let Err(e) = session.send(event).await {
                 warn!(error=%e, message = "an error occurred closing the session", client_id = %session.client_id());
             }
         }
@@ -156,7 +852,8 @@ impl Broker {
         // CONNECT packet in line with this specification.
         //
         // We will simply disconnect the client and return.
-        if connreq.connect().protocol_name != EXPECTED_PROTOCOL_NAME {
+        let protocol_name = &connreq.connect().protocol_name;
+        if protocol_name != EXPECTED_PROTOCOL_NAME && protocol_name != MQTT31_PROTOCOL_NAME {
             warn!(
                 "invalid protocol name received from client: {}",
                 connreq.connect().protocol_name
@@ -167,48 +864,55 @@ impl Broker {
             return Ok(());
         }
 
-        // [MQTT-3.1.2-2] - The Server MUST respond to the CONNECT Packet
-        // with a CONNACK return code 0x01 (unacceptable protocol level)
-        // and then disconnect the Client if the Protocol Level is not supported
-        // by the Server.
-        if connreq.connect().protocol_level != EXPECTED_PROTOCOL_LEVEL {
-            warn!(
-                "invalid protocol level received from client: {}",
-                connreq.connect().protocol_level
-            );
-            let ack = proto::ConnAck {
-                session_present: false,
-                return_code: proto::ConnectReturnCode::Refused(
-                    proto::ConnectionRefusedReason::UnacceptableProtocolVersion,
-                ),
-            };
-
-            debug!("sending connack...");
-            let event = ClientEvent::ConnAck(ack);
-            let message = Message::Client(client_id.clone(), event);
-            try_send!(connreq.handle_mut(), message);
+        let protocol_version = match self.accept(&client_id, &connreq) {
+            Ok(protocol_version) => protocol_version,
+            Err(reason) => {
+                refuse_connection(client_id, connreq, reason).await;
+                return Ok(());
+            }
+        };
 
-            debug!("dropping connection due to invalid protocol level");
-            let message = Message::Client(client_id, ClientEvent::DropConnection);
-            try_send!(connreq.handle_mut(), message);
-            return Ok(());
+        // A client coming back before its delayed will is due cancels it,
+        // unless it starts a new session, which ends the one the will
+        // belongs to.
+        if let Some((will, _)) = self.delayed_wills.remove(&client_id) {
+            if !resumes_session(&connreq) {
+                let (id, will, deadline) = self.route(will.into_publication());
+                self.publish_all(id, will, deadline).await?;
+            }
         }
 
+        // MQTT 3.1 has no session present flag in the CONNACK
+        let mqtt31 = protocol_version == ProtocolVersion::V31;
+
         // Process the CONNECT packet after it has been validated
         // TODO - fix ConnAck return_code != accepted to not add session to sessions map
-        match self.open_session(connreq) {
-            Ok((ack, events)) => {
+        match self.open_session(connreq, protocol_version) {
+            Ok((mut ack, events)) => {
+                ack.session_present &= !mqtt31;
+
                 // Send ConnAck on new session
-                let session = self.get_session_mut(&client_id)?;
+                let session = self
+                    .sessions
+                    .get_mut(&client_id)
+                    .ok_or_else(|| Error::new(ErrorKind::NoSession.into()))?;
+                if let Some(version) = session.protocol_version() {
+                    debug!("{} connected with {}", client_id, version);
+                }
                 session.send(ClientEvent::ConnAck(ack)).await?;
 
                 for event in events {
-                    session.send(event).await?;
+                    send(&mut self.stats, session, event).await?;
                 }
             }
-            Err(SessionError::DuplicateSession(mut old_session, ack)) => {
+            Err(SessionError::DuplicateSession(mut old_session, mut ack)) => {
+                ack.session_present &= !mqtt31;
+
                 // Drop the old connection
-                old_session.send(ClientEvent::DropConnection).await?;
+                let reason = DisconnectReason::SessionTakenOver;
+                old_session
+                    .send(ClientEvent::ServerDisconnect(reason))
+                    .await?;
 
                 // Send ConnAck on new connection
                 let should_drop = ack.return_code != proto::ConnectReturnCode::Accepted;
@@ -220,7 +924,10 @@ impl Broker {
                 }
             }
             Err(SessionError::ProtocolViolation(mut old_session)) => {
-                old_session.send(ClientEvent::DropConnection).await?
+                let reason = DisconnectReason::ProtocolError;
+                old_session
+                    .send(ClientEvent::ServerDisconnect(reason))
+                    .await?
             }
             Err(SessionError::PacketIdentifiersExhausted) => {
                 panic!("Session identifiers exhausted, this can only be caused by a bug.");
@@ -231,12 +938,116 @@ impl Broker {
         Ok(())
     }
 
-    async fn process_disconnect(&mut self, client_id: ClientId) -> Result<(), Error> {
+    /// Checks a connection before a session is opened for it. Returns the
+    /// protocol version the connection is accepted with, or the reason it is
+    /// refused.
+    fn accept(
+        &self,
+        client_id: &ClientId,
+        connreq: &ConnReq,
+    ) -> Result<ProtocolVersion, proto::ConnectionRefusedReason> {
+        // [MQTT-3.1.2-2] - The Server MUST respond to the CONNECT Packet
+        // with a CONNACK return code 0x01 (unacceptable protocol level)
+        // and then disconnect the Client if the Protocol Level is not supported
+        // by the Server.
+        let protocol_version = match connreq.protocol_version() {
+            Some(ProtocolVersion::V311) => ProtocolVersion::V311,
+            Some(ProtocolVersion::V5) => ProtocolVersion::V5,
+            Some(ProtocolVersion::V31) if self.allow_mqtt31 => ProtocolVersion::V31,
+            _ => {
+                warn!(
+                    "unsupported protocol received from client: {} level {}",
+                    connreq.connect().protocol_name,
+                    connreq.connect().protocol_level
+                );
+                return Err(proto::ConnectionRefusedReason::UnacceptableProtocolVersion);
+            }
+        };
+
+        // MQTT 3.1 client ids are between 1 and 23 characters long
+        if protocol_version == ProtocolVersion::V31 {
+            let valid = match &connreq.connect().client_id {
+                proto::ClientId::IdWithCleanSession(id)
+                | proto::ClientId::IdWithExistingSession(id) => {
+                    !id.is_empty() && id.chars().count() <= MQTT31_MAX_CLIENT_ID_LEN
+                }
+                proto::ClientId::ServerGenerated => false,
+            };
+            if !valid {
+                warn!("invalid MQTT 3.1 client id {}", client_id);
+                return Err(proto::ConnectionRefusedReason::IdentifierRejected);
+            }
+        }
+
+        // Clients are authenticated by their connection before the connect
+        // request reaches the broker.
+        match connreq.auth() {
+            Auth::Identity(_) | Auth::Anonymous => (),
+            Auth::BadCredentials => {
+                warn!("client {} failed authentication", client_id);
+                return Err(proto::ConnectionRefusedReason::BadUserNameOrPassword);
+            }
+            Auth::NotAuthorized => {
+                warn!("client {} failed authentication", client_id);
+                return Err(proto::ConnectionRefusedReason::NotAuthorized);
+            }
+        }
+
+        // A will is published on the client's behalf, so the client has to
+        // be allowed to publish it.
+        if let Some(will) = &connreq.connect().will {
+            let operation = Operation::Publish(&will.topic_name);
+            if !self
+                .authorizer
+                .authorize(client_id, connreq.auth().auth_id(), operation)
+            {
+                warn!(
+                    "client {} is not authorized to publish its will to {}",
+                    client_id, will.topic_name
+                );
+                return Err(proto::ConnectionRefusedReason::NotAuthorized);
+            }
+        }
+
+        // A listener can require clients to connect with the identity of
+        // their certificate as the client id.
+        if connreq.match_client_id() {
+            let matches = connreq
+                .certificate()
+                .map_or(false, |cert| cert.matches(client_id.as_str()));
+            if !matches {
+                warn!(
+                    "client id {} does not match the client certificate {:?}",
+                    client_id,
+                    connreq.certificate()
+                );
+                return Err(proto::ConnectionRefusedReason::NotAuthorized);
+            }
+        }
+
+        Ok(protocol_version)
+    }
+
+    async fn process_disconnect(
+        &mut self,
+        client_id: ClientId,
+        disconnect: Disconnect,
+    ) -> Result<(), Error> {
         debug!("handling disconnect...");
+        if let Some(expiry_interval) = disconnect.session_expiry_interval {
+            self.set_session_expiry(&client_id, expiry_interval);
+        }
         if let Some(mut session) = self.close_session(&client_id) {
             session
-                .send(ClientEvent::Disconnect(proto::Disconnect))
+                .send(ClientEvent::Disconnect(Disconnect::default()))
                 .await?;
+
+            // An MQTT 5 client can disconnect with its will
+            if disconnect.publish_will {
+                if let Some(will) = session.into_will() {
+                    self.publish_will(&client_id, will).await?;
+                }
+            }
         } else {
             debug!("no session for {}", client_id);
         }
@@ -244,14 +1055,46 @@ impl Broker {
         Ok(())
     }
 
-    async fn process_drop_connection(&mut self, client_id: ClientId) -> Result<(), Error> {
-        debug!("handling drop connection...");
+    /// Changes the session expiry interval of a connected MQTT 5 client as
+    /// it disconnects. An interval of zero ends a persistent session with
+    /// the connection.
+    fn set_session_expiry(&mut self, client_id: &ClientId, expiry_interval: Duration) {
+        match self.sessions.remove(client_id) {
+            Some(Session::Persistent(connected)) if expiry_interval == Duration::from_secs(0) => {
+                self.journal_session_removal(client_id);
+                self.sessions
+                    .insert(client_id.clone(), Session::Transient(connected));
+            }
+            Some(Session::Persistent(mut connected)) => {
+                connected
+                    .state_mut()
+                    .set_expiry_interval(Some(expiry_interval));
+                let entry = JournalEntry::SessionExpiry(client_id.clone(), Some(expiry_interval));
+                self.journal_session_change(&entry);
+                self.sessions
+                    .insert(client_id.clone(), Session::Persistent(connected));
+            }
+            Some(session) => {
+                self.sessions.insert(client_id.clone(), session);
+            }
+            None => (),
+        }
+    }
+
+    /// Closes the connection of a client that was lost or has to go,
+    /// sending it `event`, and publishes the client's will.
+    async fn drop_connection(
+        &mut self,
+        client_id: ClientId,
+        event: ClientEvent,
+    ) -> Result<(), Error> {
+        debug!("handling drop connection...");
         if let Some(mut session) = self.close_session(&client_id) {
-            session.send(ClientEvent::DropConnection).await?;
+            session.send(event).await?;
 
             // Ungraceful disconnect - send the will
             if let Some(will) = session.into_will() {
-                self.publish_all(will).await?;
+                self.publish_will(&client_id, will).await?;
             }
         } else {
             debug!("no session for {}", client_id);
@@ -267,7 +1110,7 @@ impl Broker {
 
             // Ungraceful disconnect - send the will
             if let Some(will) = session.into_will() {
-                self.publish_all(will).await?;
+                self.publish_will(&client_id, will).await?;
             }
         } else {
             debug!("no session for {}", client_id);
@@ -276,6 +1119,51 @@ impl Broker {
         Ok(())
     }
 
+    /// Publishes the will of a client whose connection was lost. A will
+    /// delay holds it back while the session lives on, until the client
+    /// comes back or the delay is over.
+    async fn publish_will(&mut self, client_id: &ClientId, will: Will) -> Result<(), Error> {
+        let offline = matches!(self.sessions.get(client_id), Some(Session::Offline(_)));
+        if offline && will.delay() > Duration::from_secs(0) {
+            let deadline = Instant::now() + will.delay();
+            debug!(
+                "holding back the will of {} for {:?}",
+                client_id,
+                will.delay()
+            );
+            self.delayed_wills
+                .insert(client_id.clone(), (will, deadline));
+            return Ok(());
+        }
+
+        let (id, will, deadline) = self.route(will.into_publication());
+        self.publish_all(id, will, deadline).await
+    }
+
+    /// Publishes the delayed wills that are due, along with the ones whose
+    /// session ended before they were.
+    async fn publish_delayed_wills(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let sessions = &self.sessions;
+        let due = self
+            .delayed_wills
+            .iter()
+            .filter(|(client_id, (_, deadline))| {
+                *deadline <= now || !sessions.contains_key(client_id)
+            })
+            .map(|(client_id, _)| client_id.clone())
+            .collect::<Vec<_>>();
+
+        for client_id in due {
+            if let Some((will, _)) = self.delayed_wills.remove(&client_id) {
+                debug!("publishing the delayed will of {}", client_id);
+                let (id, will, deadline) = self.route(will.into_publication());
+                self.publish_all(id, will, deadline).await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn process_ping_req(
         &mut self,
         client_id: ClientId,
@@ -292,14 +1180,34 @@ impl Broker {
         }
     }
 
+    fn subscribe(
+        &mut self,
+        client_id: &ClientId,
+        subscribe: proto::Subscribe,
+    ) -> Result<(proto::SubAck, Vec<Subscription>), Error> {
+        let authorizer = self.authorizer.clone();
+        let session = self.get_session_mut(client_id)?;
+        let (suback, subscriptions) = session.subscribe(subscribe, &*authorizer)?;
+        let persistent = matches!(session, Session::Persistent(_));
+
+        for subscription in &subscriptions {
+            self.subscriptions.insert(client_id.clone(), subscription);
+        }
+        if persistent && !subscriptions.is_empty() {
+            let entry = JournalEntry::Subscribe(client_id.clone(), subscriptions.clone());
+            self.journal_session_change(&entry);
+        }
+        Ok((suback, subscriptions))
+    }
+
     async fn process_subscribe(
         &mut self,
         client_id: ClientId,
         subscribe: proto::Subscribe,
     ) -> Result<(), Error> {
-        let subscriptions = match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                let (suback, subscriptions) = session.subscribe(subscribe)?;
+        let subscriptions = match self.subscribe(&client_id, subscribe) {
+            Ok((suback, subscriptions)) => {
+                let session = self.get_session_mut(&client_id)?;
                 session.send(ClientEvent::SubAck(suback)).await?;
                 subscriptions
             }
@@ -311,31 +1219,49 @@ impl Broker {
         };
 
         // Handle retained messages
-        let publications = self
-            .retained
-            .values()
-            .filter(|p| {
-                subscriptions
-                    .iter()
-                    .any(|sub| sub.filter().matches(&p.topic_name))
-            })
-            .cloned()
-            .collect::<Vec<proto::Publication>>();
-
-        match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                for mut publication in publications {
-                    publication.retain = true;
-                    publish_to(session, &publication).await?;
+        // A message matched by several of the filters is only sent once,
+        // with the largest QoS they grant
+        let mut topics = HashMap::new();
+        let mut publications = vec![];
+        // Retained messages are not sent for shared subscriptions
+        for subscription in subscriptions.iter().filter(|sub| !sub.filter().is_shared()) {
+            let max_qos = *subscription.max_qos();
+            for (publication, deadline) in self.retained.matches(subscription.filter()) {
+                if let Some(&i) = topics.get(&publication.topic_name) {
+                    let (_, _, qos) = &mut publications[i];
+                    *qos = cmp::max(*qos, max_qos);
+                } else {
+                    topics.insert(&publication.topic_name, publications.len());
+                    publications.push((publication.clone(), deadline, max_qos));
                 }
-                Ok(())
             }
-            Err(e) if *e.kind() == ErrorKind::NoSession => {
-                debug!("no session for {}", client_id);
-                Ok(())
+        }
+
+        // Each copy of a retained message is a publication of its own, which
+        // expires along with the retained message
+        let publications = publications
+            .into_iter()
+            .map(|(publication, deadline, max_qos)| {
+                ((self.publication_id(), publication, deadline), max_qos)
+            })
+            .collect::<Vec<_>>();
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            for ((id, mut publication, deadline), max_qos) in publications {
+                publication.retain = true;
+                publish_to(
+                    &mut self.stats,
+                    session,
+                    id,
+                    &publication,
+                    max_qos,
+                    deadline,
+                )
+                .await?;
             }
-            Err(e) => Err(e),
+        } else {
+            debug!("no session for {}", client_id);
         }
+        Ok(())
     }
 
     async fn process_unsubscribe(
@@ -343,6 +1269,17 @@ impl Broker {
         client_id: ClientId,
         unsubscribe: proto::Unsubscribe,
     ) -> Result<(), Error> {
+        for filter in &unsubscribe.unsubscribe_from {
+            if let Ok(filter) = filter.parse() {
+                self.subscriptions.remove(&client_id, &filter);
+            }
+        }
+
+        if let Some(Session::Persistent(_)) = self.sessions.get(&client_id) {
+            let filters = unsubscribe.unsubscribe_from.clone();
+            self.journal_session_change(&JournalEntry::Unsubscribe(client_id.clone(), filters));
+        }
+
         match self.get_session_mut(&client_id) {
             Ok(session) => {
                 let unsuback = session.unsubscribe(&unsubscribe)?;
@@ -360,15 +1297,24 @@ impl Broker {
         &mut self,
         client_id: ClientId,
         publish: proto::Publish,
+        properties: PublicationProperties,
     ) -> Result<(), Error> {
-        let maybe_publication = match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                let (maybe_publication, maybe_event) = session.handle_publish(publish)?;
-                if let Some(event) = maybe_event {
-                    session.send(event).await?;
-                }
-                maybe_publication
+        let acknowledged = match publish.packet_identifier_dup_qos {
+            proto::PacketIdentifierDupQoS::AtMostOnce => None,
+            proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) => {
+                Some((packet_identifier, proto::QoS::AtLeastOnce))
             }
+            proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => {
+                Some((packet_identifier, proto::QoS::ExactlyOnce))
+            }
+        };
+        let exactly_once = match acknowledged {
+            Some((packet_identifier, proto::QoS::ExactlyOnce)) => Some(packet_identifier),
+            _ => None,
+        };
+
+        let (maybe_publication, mut maybe_event) = match self.get_session_mut(&client_id) {
+            Ok(session) => session.handle_publish(publish, properties)?,
             Err(e) if *e.kind() == ErrorKind::NoSession => {
                 debug!("no session for {}", client_id);
                 return Ok(());
@@ -376,8 +1322,53 @@ impl Broker {
             Err(e) => return Err(e),
         };
 
-        if let Some(publication) = maybe_publication {
-            self.publish_all(publication).await?
+        // Unauthorized publications are dropped. They are still
+        // acknowledged, which tells MQTT 5 clients they weren't authorized,
+        // and an exactly once one isn't held until its PUBREL.
+        let maybe_publication = match maybe_publication {
+            Some(publication) if !self.authorize_publish(&client_id, &publication) => {
+                if let Some(packet_identifier) = exactly_once {
+                    let pubrel = proto::PubRel { packet_identifier };
+                    self.get_session_mut(&client_id)?.handle_pubrel(&pubrel)?;
+                }
+                maybe_event = acknowledged.map(|(packet_identifier, qos)| {
+                    ClientEvent::PublishNotAuthorized(packet_identifier, qos)
+                });
+                None
+            }
+            maybe_publication => maybe_publication,
+        };
+
+        // Publications are journaled before they are acknowledged. If this
+        // fails the client doesn't get a PUBACK or PUBREC, and sends the
+        // publication again.
+        let maybe_publication = match maybe_publication {
+            Some(publication) => {
+                let publisher =
+                    exactly_once.map(|packet_identifier| (&client_id, packet_identifier));
+                match self.route_journaled(publication, publisher) {
+                    Ok(routed) => Some(routed),
+                    Err(e) => {
+                        // The session forgets the packet identifier, so the
+                        // publication isn't taken for a duplicate when it
+                        // comes again
+                        if let Some(packet_identifier) = exactly_once {
+                            let pubrel = proto::PubRel { packet_identifier };
+                            self.get_session_mut(&client_id)?.handle_pubrel(&pubrel)?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(event) = maybe_event {
+            self.get_session_mut(&client_id)?.send(event).await?;
+        }
+
+        if let Some((id, publication, deadline)) = maybe_publication {
+            self.publish_all(id, publication, deadline).await?
         }
         Ok(())
     }
@@ -387,19 +1378,16 @@ impl Broker {
         client_id: ClientId,
         puback: proto::PubAck,
     ) -> Result<(), Error> {
-        match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                if let Some(event) = session.handle_puback(&puback)? {
-                    session.send(event).await?
-                }
-                Ok(())
-            }
-            Err(e) if *e.kind() == ErrorKind::NoSession => {
-                debug!("no session for {}", client_id);
-                Ok(())
+        self.journal_delivery(&client_id, puback.packet_identifier, JournalEntry::PubAck);
+
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            if let Some(event) = session.handle_puback(&puback)? {
+                send(&mut self.stats, session, event).await?;
             }
-            Err(e) => Err(e),
+        } else {
+            debug!("no session for {}", client_id);
         }
+        Ok(())
     }
 
     async fn process_puback0(
@@ -407,19 +1395,14 @@ impl Broker {
         client_id: ClientId,
         id: proto::PacketIdentifier,
     ) -> Result<(), Error> {
-        match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                if let Some(event) = session.handle_puback0(id)? {
-                    session.send(event).await?
-                }
-                Ok(())
-            }
-            Err(e) if *e.kind() == ErrorKind::NoSession => {
-                debug!("no session for {}", client_id);
-                Ok(())
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            if let Some(event) = session.handle_puback0(id)? {
+                send(&mut self.stats, session, event).await?;
             }
-            Err(e) => Err(e),
+        } else {
+            debug!("no session for {}", client_id);
         }
+        Ok(())
     }
 
     async fn process_pubrec(
@@ -427,19 +1410,16 @@ impl Broker {
         client_id: ClientId,
         pubrec: proto::PubRec,
     ) -> Result<(), Error> {
-        match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                if let Some(event) = session.handle_pubrec(&pubrec)? {
-                    session.send(event).await?
-                }
-                Ok(())
-            }
-            Err(e) if *e.kind() == ErrorKind::NoSession => {
-                debug!("no session for {}", client_id);
-                Ok(())
+        self.journal_delivery(&client_id, pubrec.packet_identifier, JournalEntry::PubRec);
+
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            if let Some(event) = session.handle_pubrec(&pubrec)? {
+                send(&mut self.stats, session, event).await?;
             }
-            Err(e) => Err(e),
+        } else {
+            debug!("no session for {}", client_id);
         }
+        Ok(())
     }
 
     async fn process_pubrel(
@@ -447,24 +1427,21 @@ impl Broker {
         client_id: ClientId,
         pubrel: proto::PubRel,
     ) -> Result<(), Error> {
-        let maybe_publication = match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                let packet_identifier = pubrel.packet_identifier;
-                let maybe_publication = session.handle_pubrel(&pubrel)?;
+        // The publication was delivered when it arrived, so this only
+        // releases its packet identifier. The release is journaled first,
+        // otherwise a restored session takes the next publication reusing
+        // the packet identifier for a duplicate.
+        if let Some(Session::Persistent(_)) = self.sessions.get(&client_id) {
+            let entry = JournalEntry::PubRel(client_id.clone(), pubrel.packet_identifier);
+            self.journal(&entry)?;
+        }
 
-                let pubcomp = proto::PubComp { packet_identifier };
-                session.send(ClientEvent::PubComp(pubcomp)).await?;
-                maybe_publication
-            }
-            Err(e) if *e.kind() == ErrorKind::NoSession => {
-                debug!("no session for {}", client_id);
-                return Ok(());
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            if let Some(event) = session.handle_pubrel(&pubrel)? {
+                send(&mut self.stats, session, event).await?;
             }
-            Err(e) => return Err(e),
-        };
-
-        if let Some(publication) = maybe_publication {
-            self.publish_all(publication).await?
+        } else {
+            debug!("no session for {}", client_id);
         }
         Ok(())
     }
@@ -474,19 +1451,42 @@ impl Broker {
         client_id: ClientId,
         pubcomp: proto::PubComp,
     ) -> Result<(), Error> {
-        match self.get_session_mut(&client_id) {
-            Ok(session) => {
-                if let Some(event) = session.handle_pubcomp(&pubcomp)? {
-                    session.send(event).await?
-                }
-                Ok(())
+        if let Some(Session::Persistent(_)) = self.sessions.get(&client_id) {
+            let entry = JournalEntry::PubComp(client_id.clone(), pubcomp.packet_identifier);
+            if let Err(e) = self.journal(&entry) {
+                warn!(message = "an error occurred journaling an acknowledgement", error=%e);
             }
-            Err(e) if *e.kind() == ErrorKind::NoSession => {
-                debug!("no session for {}", client_id);
-                Ok(())
+        }
+
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            if let Some(event) = session.handle_pubcomp(&pubcomp)? {
+                send(&mut self.stats, session, event).await?;
             }
-            Err(e) => Err(e),
+        } else {
+            debug!("no session for {}", client_id);
+        }
+        Ok(())
+    }
+
+    fn authorize_publish(&self, client_id: &ClientId, publication: &Publication) -> bool {
+        if sys::is_sys_topic(&publication.topic_name) {
+            warn!(
+                "client {} can't publish to {}. dropping publication",
+                client_id, publication.topic_name
+            );
+            return false;
         }
+
+        let auth_id = self.sessions.get(client_id).and_then(Session::auth_id);
+        let operation = Operation::Publish(&publication.topic_name);
+        let allowed = self.authorizer.authorize(client_id, auth_id, operation);
+        if !allowed {
+            warn!(
+                "client {} is not authorized to publish to {}. dropping publication",
+                client_id, publication.topic_name
+            );
+        }
+        allowed
     }
 
     fn get_session_mut(&mut self, client_id: &ClientId) -> Result<&mut Session, Error> {
@@ -498,33 +1498,36 @@ impl Broker {
     fn open_session(
         &mut self,
         connreq: ConnReq,
+        protocol_version: ProtocolVersion,
     ) -> Result<(proto::ConnAck, Vec<ClientEvent>), SessionError> {
         let client_id = connreq.client_id().clone();
 
         match self.sessions.remove(&client_id) {
             Some(Session::Transient(current_connected)) => {
-                self.open_session_connected(connreq, current_connected)
+                self.open_session_connected(connreq, protocol_version, current_connected, false)
             }
             Some(Session::Persistent(current_connected)) => {
-                self.open_session_connected(connreq, current_connected)
+                self.open_session_connected(connreq, protocol_version, current_connected, true)
             }
-            Some(Session::Offline(offline)) => {
+            Some(Session::Offline(mut offline)) => {
                 debug!("found an offline session for {}", client_id);
 
-                let (new_session, events, session_present) =
-                    if let proto::ClientId::IdWithExistingSession(_) = connreq.connect().client_id {
-                        debug!("moving offline session to online for {}", client_id);
-                        let (state, events) = offline
-                            .into_online()
-                            .map_err(|_e| SessionError::PacketIdentifiersExhausted)?;
-                        let new_session = Session::new_persistent(connreq, state);
-                        (new_session, events, true)
-                    } else {
-                        info!("cleaning offline session for {}", client_id);
-                        let new_session = Session::new_transient(connreq);
-                        (new_session, vec![], false)
-                    };
+                let (state, events) = if resumes_session(&connreq) {
+                    debug!("moving offline session to online for {}", client_id);
+                    self.reconnect_state(offline.state_mut(), &connreq, true);
+                    let (state, events) = offline
+                        .into_online()
+                        .map_err(|_e| SessionError::PacketIdentifiersExhausted)?;
+                    (Some(state), events)
+                } else {
+                    info!("cleaning offline session for {}", client_id);
+                    self.remove_subscriptions(offline.state());
+                    self.journal_session_removal(&client_id);
+                    (None, vec![])
+                };
 
+                let session_present = state.is_some();
+                let new_session = self.connect_session(connreq, protocol_version, state, true);
                 self.sessions.insert(client_id, new_session);
 
                 let ack = proto::ConnAck {
@@ -539,17 +1542,7 @@ impl Broker {
             )),
             None => {
                 // No session present - create a new one.
-                let new_session = if let proto::ClientId::IdWithExistingSession(_) =
-                    connreq.connect().client_id
-                {
-                    info!("creating new persistent session for {}", client_id);
-                    let state = SessionState::new(client_id.clone(), &connreq);
-                    Session::new_persistent(connreq, state)
-                } else {
-                    info!("creating new transient session for {}", client_id);
-                    Session::new_transient(connreq)
-                };
-
+                let new_session = self.connect_session(connreq, protocol_version, None, false);
                 self.sessions.insert(client_id.clone(), new_session);
 
                 let ack = proto::ConnAck {
@@ -563,10 +1556,76 @@ impl Broker {
         }
     }
 
+    fn new_state(&self, connreq: &ConnReq) -> SessionState {
+        SessionState::new(connreq.client_id().clone(), connreq)
+            .with_max_inflight_messages(self.config.max_inflight_messages())
+            .with_queue_limits(self.config.queue_limits())
+    }
+
+    /// Creates the session of a new connection, with the `state` of the
+    /// session it resumes if any. A persistent session is journaled unless
+    /// its state was `journaled` already, and a resumed one that is no
+    /// longer persistent is removed from the journal.
+    fn connect_session(
+        &mut self,
+        connreq: ConnReq,
+        protocol_version: ProtocolVersion,
+        state: Option<SessionState>,
+        journaled: bool,
+    ) -> Session {
+        let client_id = connreq.client_id().clone();
+        match (state, keeps_session(&connreq)) {
+            (Some(state), true) => {
+                if !journaled {
+                    self.journal_session_change(&JournalEntry::OpenSession(state.clone()));
+                }
+                Session::new_persistent(connreq, state, protocol_version)
+            }
+            (Some(state), false) => {
+                if journaled {
+                    self.journal_session_removal(&client_id);
+                }
+                Session::new_transient(connreq, state, protocol_version)
+            }
+            (None, true) => {
+                info!("creating new persistent session for {}", client_id);
+                let state = self.new_state(&connreq);
+                self.journal_session_change(&JournalEntry::OpenSession(state.clone()));
+                Session::new_persistent(connreq, state, protocol_version)
+            }
+            (None, false) => {
+                info!("creating new transient session for {}", client_id);
+                let state = self.new_state(&connreq);
+                Session::new_transient(connreq, state, protocol_version)
+            }
+        }
+    }
+
+    /// Updates a resumed session with what an MQTT 5 client asked for on
+    /// its new connection. A changed expiry interval of a persistent
+    /// session is journaled if its state was `journaled` already.
+    fn reconnect_state(&mut self, state: &mut SessionState, connreq: &ConnReq, journaled: bool) {
+        let expiry_interval = connreq
+            .properties()
+            .map(|properties| properties.session_expiry_interval);
+        if journaled && keeps_session(connreq) && state.expiry_interval() != expiry_interval {
+            let entry = JournalEntry::SessionExpiry(state.client_id().clone(), expiry_interval);
+            self.journal_session_change(&entry);
+        }
+        state.set_expiry_interval(expiry_interval);
+        state.set_receive_maximum(
+            connreq
+                .properties()
+                .and_then(|properties| properties.receive_maximum),
+        );
+    }
+
     fn open_session_connected(
         &mut self,
         connreq: ConnReq,
+        protocol_version: ProtocolVersion,
         current_connected: ConnectedSession,
+        journaled: bool,
     ) -> Result<(proto::ConnAck, Vec<ClientEvent>), SessionError> {
         if current_connected.handle() == connreq.handle() {
             // [MQTT-3.1.0-2] - The Server MUST process a second CONNECT Packet
@@ -591,22 +1650,23 @@ impl Broker {
             );
 
             let client_id = connreq.client_id().clone();
-            let (state, _will, handle) = current_connected.into_parts();
+            let (mut state, _will, handle) = current_connected.into_parts();
             let old_session = Session::new_disconnecting(client_id.clone(), None, handle);
-            let (new_session, session_present) =
-                if let proto::ClientId::IdWithExistingSession(_) = connreq.connect().client_id {
-                    debug!(
-                        "moving persistent session to this connection for {}",
-                        client_id
-                    );
-                    let new_session = Session::new_persistent(connreq, state);
-                    (new_session, true)
-                } else {
-                    info!("cleaning session for {}", client_id);
-                    let new_session = Session::new_transient(connreq);
-                    (new_session, false)
-                };
+            let (state, journaled) = if resumes_session(&connreq) {
+                debug!("moving session to this connection for {}", client_id);
+                self.reconnect_state(&mut state, &connreq, journaled);
+                (Some(state), journaled)
+            } else {
+                info!("cleaning session for {}", client_id);
+                self.remove_subscriptions(&state);
+                if journaled {
+                    self.journal_session_removal(&client_id);
+                }
+                (None, false)
+            };
 
+            let session_present = state.is_some();
+            let new_session = self.connect_session(connreq, protocol_version, state, journaled);
             self.sessions.insert(client_id, new_session);
             let ack = proto::ConnAck {
                 session_present,
@@ -621,7 +1681,8 @@ impl Broker {
         match self.sessions.remove(client_id) {
             Some(Session::Transient(connected)) => {
                 info!("closing transient session for {}", client_id);
-                let (_state, will, handle) = connected.into_parts();
+                let (state, will, handle) = connected.into_parts();
+                self.remove_subscriptions(&state);
                 Some(Session::new_disconnecting(client_id.clone(), will, handle))
             }
             Some(Session::Persistent(connected)) => {
@@ -630,7 +1691,9 @@ impl Broker {
                 // to be sent on the connection
 
                 info!("moving persistent session to offline for {}", client_id);
-                let (state, will, handle) = connected.into_parts();
+                let (mut state, will, handle) = connected.into_parts();
+                // Expiry counts from when the client went offline
+                state.mark_active();
                 let new_session = Session::new_offline(state);
                 self.sessions.insert(client_id.clone(), new_session);
                 Some(Session::new_disconnecting(client_id.clone(), will, handle))
@@ -645,7 +1708,123 @@ impl Broker {
         }
     }
 
-    async fn publish_all(&mut self, mut publication: proto::Publication) -> Result<(), Error> {
+    fn remove_subscriptions(&mut self, state: &SessionState) {
+        for subscription in state.subscriptions() {
+            self.subscriptions
+                .remove(state.client_id(), subscription.filter());
+        }
+    }
+
+    async fn publish_all(
+        &mut self,
+        id: PublicationId,
+        mut publication: Publication,
+        deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        self.update_retained(&publication, deadline);
+
+        // Set the retain to false. This should only be set true
+        // when sending due to a new subscription.
+        //
+        // This will not happen here.
+        publication.retain = false;
+
+        // Only the sessions with a matching subscription are visited, with
+        // the largest QoS their matching subscriptions grant
+        let matches = self.subscriptions.matches(&publication.topic_name);
+        for (client_id, max_qos) in matches.clients() {
+            if let Some(session) = self.sessions.get_mut(client_id) {
+                if let Err(e) = publish_to(
+                    &mut self.stats,
+                    session,
+                    id,
+                    &publication,
+                    *max_qos,
+                    deadline,
+                )
+                .await
+                {
+                    warn!(message = "error processing message", error=%e);
+                }
+            }
+        }
+
+        // A shared subscription delivers to one of its members
+        for group in matches.groups() {
+            let member = self.next_member(group);
+            let sessions = &mut self.sessions;
+            if let Some(session) = member.and_then(|id| sessions.get_mut(&id)) {
+                if let Err(e) =
+                    publish_shared(&mut self.stats, session, id, &publication, deadline, group)
+                        .await
+                {
+                    warn!(message = "error processing message", error=%e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn gauges(&self) -> Gauges {
+        let mut gauges = Gauges::default();
+        for session in self.sessions.values() {
+            match session {
+                Session::Transient(_) => gauges.sessions_transient += 1,
+                Session::Persistent(_) => gauges.sessions_persistent += 1,
+                Session::Offline(_) => gauges.sessions_offline += 1,
+                Session::Disconnecting(_) => gauges.sessions_disconnecting += 1,
+            }
+            if let Some(state) = session.state() {
+                gauges.subscriptions += state.subscriptions().count();
+                gauges.queued += state.queued_count();
+                gauges.inflight += state.inflight_count();
+            }
+        }
+        gauges.clients_connected = gauges.sessions_transient + gauges.sessions_persistent;
+        gauges.clients_total = gauges.clients_connected + gauges.sessions_offline;
+        gauges.retained = self.retained.count();
+        gauges
+    }
+
+    async fn publish_stats(&mut self, gauges: &Gauges) -> Result<(), Error> {
+        for publication in self.stats.publications(gauges) {
+            let (id, publication, deadline) = self.route(publication);
+            self.publish_all(id, publication, deadline).await?;
+        }
+        Ok(())
+    }
+
+    /// Picks the member of a shared subscription to deliver the next
+    /// publication to.
+    ///
+    /// Members take turns. Connected members that are at their limit of
+    /// inflight messages are passed over while another connected member
+    /// isn't, and offline members only get a turn when no member is
+    /// connected.
+    fn next_member(&mut self, group: &str) -> Option<ClientId> {
+        let sessions = &self.sessions;
+        let shared = self.subscriptions.group_mut(group)?;
+        let connected = |client_id: &&ClientId| {
+            sessions.get(*client_id).and_then(|session| match session {
+                Session::Transient(connected) | Session::Persistent(connected) => {
+                    Some(connected.state())
+                }
+                _ => None,
+            })
+        };
+
+        let member = shared
+            .members()
+            .find(|id| connected(id).map_or(false, SessionState::allowed_to_send))
+            .or_else(|| shared.members().find(|id| connected(id).is_some()))
+            .or_else(|| shared.members().next())?
+            .clone();
+        shared.advance(&member);
+        Some(member)
+    }
+
+    fn update_retained(&mut self, publication: &Publication, deadline: Option<Instant>) {
         if publication.retain {
             // [MQTT-3.3.1-6]. If the Server receives a QoS 0 message with the
             // RETAIN flag set to 1 it MUST discard any message previously
@@ -662,9 +1841,7 @@ impl Broker {
                 );
                 self.retained.remove(&publication.topic_name);
             } else {
-                let maybe_retained = self
-                    .retained
-                    .insert(publication.topic_name.to_owned(), publication.clone());
+                let maybe_retained = self.retained.insert_until(publication.clone(), deadline);
                 if maybe_retained.is_none() {
                     info!(
                         "new retained message for topic \"{}\"",
@@ -673,45 +1850,121 @@ impl Broker {
                 }
             }
         }
+    }
+}
 
-        // Set the retain to false. This should only be set true
-        // when sending due to a new subscription.
-        //
-        // This will not happen here.
-        publication.retain = false;
-
-        for session in self.sessions.values_mut() {
-            if let Err(e) = publish_to(session, &publication).await {
-                warn!(message = "error processing message", error=%e);
-            }
-        }
+/// Sends an event to the client of a session. The events that carry a
+/// PUBLISH are counted as sent, so every send of one has to go through here.
+async fn send(
+    stats: &mut BrokerStats,
+    session: &mut Session,
+    event: ClientEvent,
+) -> Result<(), Error> {
+    if let ClientEvent::PublishTo(publish) = &event {
+        stats.sent(publish);
+    }
+    session.send(event).await
+}
 
-        Ok(())
+async fn publish_to(
+    stats: &mut BrokerStats,
+    session: &mut Session,
+    id: PublicationId,
+    publication: &Publication,
+    max_qos: proto::QoS,
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    if let Some(event) = session.publish_to(id, &publication, max_qos, deadline)? {
+        send(stats, session, event).await?;
     }
+    Ok(())
 }
 
-async fn publish_to(session: &mut Session, publication: &proto::Publication) -> Result<(), Error> {
-    if let Some(event) = session.publish_to(&publication)? {
-        session.send(event).await?
+async fn publish_shared(
+    stats: &mut BrokerStats,
+    session: &mut Session,
+    id: PublicationId,
+    publication: &Publication,
+    deadline: Option<Instant>,
+    group: &str,
+) -> Result<(), Error> {
+    if let Some(event) = session.publish_shared(id, publication, deadline, group)? {
+        send(stats, session, event).await?;
     }
     Ok(())
 }
 
+/// The retained messages to keep in the broker's state. `$SYS` topics are
+/// published again once the broker runs.
+fn retained_map(retained: &RetainedStore) -> HashMap<String, Publication> {
+    let mut map = retained.to_map();
+    map.retain(|topic_name, _| !sys::is_sys_topic(topic_name));
+    map
+}
+
+fn retained_deadlines(retained: &RetainedStore) -> HashMap<String, Instant> {
+    let mut deadlines = retained.deadlines();
+    deadlines.retain(|topic_name, _| !sys::is_sys_topic(topic_name));
+    deadlines
+}
+
 impl Default for Broker {
     fn default() -> Self {
-        Broker::new()
+        Broker::new(BrokerConfig::default())
     }
 }
 
+/// Sends a CONNACK refusing the connection and then drops the connection.
+/// Whether a client resumes the session it had rather than starting a
+/// clean one.
+fn resumes_session(connreq: &ConnReq) -> bool {
+    matches!(
+        connreq.connect().client_id,
+        proto::ClientId::IdWithExistingSession(_)
+    )
+}
+
+/// Whether the session of a client outlives its connection. MQTT 5 clients
+/// ask for a session expiry interval, older clients keep the sessions they
+/// resume.
+fn keeps_session(connreq: &ConnReq) -> bool {
+    connreq.properties().map_or_else(
+        || resumes_session(connreq),
+        |properties| properties.session_expiry_interval > Duration::from_secs(0),
+    )
+}
+
+async fn refuse_connection(
+    client_id: ClientId,
+    mut connreq: ConnReq,
+    reason: proto::ConnectionRefusedReason,
+) {
+    let ack = proto::ConnAck {
+        session_present: false,
+        return_code: proto::ConnectReturnCode::Refused(reason),
+    };
+
+    debug!("sending connack...");
+    let event = ClientEvent::ConnAck(ack);
+    let message = Message::Client(client_id.clone(), event);
+    try_send!(connreq.handle_mut(), message);
+
+    debug!("dropping refused connection");
+    let message = Message::Client(client_id, ClientEvent::DropConnection);
+    try_send!(connreq.handle_mut(), message);
+}
+
 #[derive(Clone, Debug)]
 pub struct BrokerHandle(Sender<Message>);
 
 impl BrokerHandle {
     pub async fn send(&mut self, message: Message) -> Result<(), Error> {
-        self.0
-            .send(message)
-            .await
-            .context(ErrorKind::SendBrokerMessage)?;
+        increment_gauge!("mqtt_broker_mailbox_messages", 1.0);
+        let result = self.0.send(message).await;
+        if result.is_err() {
+            decrement_gauge!("mqtt_broker_mailbox_messages", 1.0);
+        }
+        result.context(ErrorKind::SendBrokerMessage)?;
         Ok(())
     }
 }
@@ -727,11 +1980,17 @@ pub enum SessionError {
 mod tests {
     use super::*;
 
+    use std::time::Duration;
+
+    use bytes::Bytes;
     use futures_util::future::FutureExt;
     use matches::assert_matches;
+    use tempfile::TempDir;
     use uuid::Uuid;
 
-    use crate::ConnectionHandle;
+    use crate::{
+        Access, AclAuthorizer, CertificateIdentity, ConnectProperties, ConnectionHandle, Publish,
+    };
 
     fn connection_handle() -> ConnectionHandle {
         let id = Uuid::new_v4();
@@ -763,6 +2022,20 @@ mod tests {
         }
     }
 
+    /// Connects with MQTT 5 and a clean start, keeping the session for
+    /// `expiry`.
+    fn v5_connreq(id: &str, expiry: Duration, handle: ConnectionHandle) -> ConnReq {
+        let connect = proto::Connect {
+            protocol_level: 0x5,
+            ..transient_connect(id.to_string())
+        };
+        let properties = ConnectProperties {
+            session_expiry_interval: expiry,
+            ..ConnectProperties::default()
+        };
+        ConnReq::new(ClientId::from(id.to_string()), connect, handle).with_properties(properties)
+    }
+
     #[tokio::test]
     #[should_panic]
     async fn test_double_connect_protocol_violation() {
@@ -877,7 +2150,10 @@ mod tests {
         );
         assert_matches!(
             rx1.recv().await.unwrap(),
-            Message::Client(_, ClientEvent::DropConnection)
+            Message::Client(
+                _,
+                ClientEvent::ServerDisconnect(DisconnectReason::SessionTakenOver)
+            )
         );
         assert!(rx1.recv().await.is_none());
 
@@ -967,7 +2243,224 @@ mod tests {
         assert!(rx1.recv().await.is_none());
     }
 
-    #[test]
+    #[tokio::test]
+    async fn test_connect_auth_failure() {
+        let broker = Broker::default();
+        let mut broker_handle = broker.handle();
+        tokio::spawn(broker.run().map(drop));
+
+        let cases = vec![
+            (
+                Auth::BadCredentials,
+                proto::ConnectionRefusedReason::BadUserNameOrPassword,
+            ),
+            (
+                Auth::NotAuthorized,
+                proto::ConnectionRefusedReason::NotAuthorized,
+            ),
+        ];
+        for (auth, reason) in cases {
+            let (tx1, mut rx1) = mpsc::channel(128);
+            let conn1 = ConnectionHandle::from_sender(tx1);
+            let client_id = ClientId::from("blah".to_string());
+            let req1 = ConnReq::new(
+                client_id.clone(),
+                transient_connect("blah".to_string()),
+                conn1,
+            )
+            .with_auth(auth);
+
+            broker_handle
+                .send(Message::Client(client_id, ClientEvent::ConnReq(req1)))
+                .await
+                .unwrap();
+
+            match rx1.recv().await.unwrap() {
+                Message::Client(_, ClientEvent::ConnAck(ack)) => {
+                    assert_eq!(proto::ConnectReturnCode::Refused(reason), ack.return_code);
+                }
+                message => panic!("unexpected message {:?}", message),
+            }
+            assert_matches!(
+                rx1.recv().await.unwrap(),
+                Message::Client(_, ClientEvent::DropConnection)
+            );
+            assert!(rx1.recv().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mqtt31() {
+        async fn connect(broker: &mut Broker, id: &str) -> proto::ConnAck {
+            let connect = proto::Connect {
+                protocol_name: "MQIsdp".to_string(),
+                protocol_level: 0x3,
+                ..persistent_connect(id.to_string())
+            };
+            let (tx, mut rx) = mpsc::channel(128);
+            let client_id = ClientId::from(id.to_string());
+            let req = ConnReq::new(
+                client_id.clone(),
+                connect,
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.process_connect(client_id, req).await.unwrap();
+
+            match rx.recv().await.unwrap() {
+                Message::Client(_, ClientEvent::ConnAck(ack)) => ack,
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+
+        let refused = |reason| proto::ConnectReturnCode::Refused(reason);
+
+        let mut broker = Broker::default();
+        let ack = connect(&mut broker, "legacy").await;
+        assert_eq!(
+            refused(proto::ConnectionRefusedReason::UnacceptableProtocolVersion),
+            ack.return_code
+        );
+
+        let mut broker = Broker::default().with_mqtt31(true);
+        let ack = connect(&mut broker, "legacy-device-with-a-long-id").await;
+        assert_eq!(
+            refused(proto::ConnectionRefusedReason::IdentifierRejected),
+            ack.return_code
+        );
+
+        let ack = connect(&mut broker, "legacy").await;
+        assert_eq!(proto::ConnectReturnCode::Accepted, ack.return_code);
+
+        // The persistent session is resumed, but MQTT 3.1 doesn't report it
+        broker.close_session(&ClientId::from("legacy".to_string()));
+        let ack = connect(&mut broker, "legacy").await;
+        assert_eq!(proto::ConnectReturnCode::Accepted, ack.return_code);
+        assert!(!ack.session_present);
+        let session = &broker.sessions[&ClientId::from("legacy".to_string())];
+        assert_matches!(session, Session::Persistent(_));
+        assert_eq!(Some(ProtocolVersion::V31), session.protocol_version());
+    }
+
+    #[tokio::test]
+    async fn test_config_update() {
+        let mut broker = Broker::default();
+        let connections = broker.config_receiver();
+
+        let (tx, _rx) = mpsc::channel(128);
+        let client_id = ClientId::from("client".to_string());
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect("client".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker
+            .process_connect(client_id.clone(), req)
+            .await
+            .unwrap();
+        let session = broker.sessions.get_mut(&client_id).unwrap();
+        assert!(session.state_mut().unwrap().allowed_to_send());
+
+        let config = BrokerConfig::new()
+            .with_max_inflight_messages(0)
+            .with_message_queue_size(1)
+            .with_connection_timeout(Duration::from_secs(30));
+        let update = ConfigUpdate::new(config)
+            .with_authorizer(AclAuthorizer::new())
+            .with_mqtt31(true);
+        broker.update_config(update);
+
+        // The session is kept with the new limit
+        let session = broker.sessions.get_mut(&client_id).unwrap();
+        assert!(!session.state_mut().unwrap().allowed_to_send());
+
+        // The message queue can't be resized
+        assert_eq!(1024, broker.config().message_queue_size());
+        assert_eq!(
+            Duration::from_secs(30),
+            connections.borrow().connection_timeout()
+        );
+
+        assert!(broker.allow_mqtt31);
+        let publication = Publication {
+            topic_name: "topic".to_string(),
+            qos: proto::QoS::AtMostOnce,
+            retain: false,
+            payload: Bytes::from("payload"),
+            properties: PublicationProperties::default(),
+        };
+        assert!(!broker.authorize_publish(&client_id, &publication));
+    }
+
+    #[tokio::test]
+    async fn test_client_id_certificate_mismatch() {
+        let broker = Broker::default();
+        let mut broker_handle = broker.handle();
+        tokio::spawn(broker.run().map(drop));
+
+        let cert = rcgen::generate_simple_self_signed(vec!["device-1".to_string()]).unwrap();
+        let identity = CertificateIdentity::from_der(&cert.serialize_der().unwrap()).unwrap();
+
+        let (tx1, mut rx1) = mpsc::channel(128);
+        let conn1 = ConnectionHandle::from_sender(tx1);
+        let client_id = ClientId::from("device-2".to_string());
+        let req1 = ConnReq::new(
+            client_id.clone(),
+            transient_connect("device-2".to_string()),
+            conn1,
+        )
+        .with_certificate(Some(identity.clone()), true);
+
+        broker_handle
+            .send(Message::Client(client_id, ClientEvent::ConnReq(req1)))
+            .await
+            .unwrap();
+
+        assert_matches!(
+            rx1.recv().await.unwrap(),
+            Message::Client(
+                _,
+                ClientEvent::ConnAck(proto::ConnAck {
+                    return_code: proto::ConnectReturnCode::Refused(
+                        proto::ConnectionRefusedReason::NotAuthorized,
+                    ),
+                    ..
+                })
+            )
+        );
+        assert_matches!(
+            rx1.recv().await.unwrap(),
+            Message::Client(_, ClientEvent::DropConnection)
+        );
+        assert!(rx1.recv().await.is_none());
+
+        let (tx2, mut rx2) = mpsc::channel(128);
+        let conn2 = ConnectionHandle::from_sender(tx2);
+        let client_id = ClientId::from("device-1".to_string());
+        let req2 = ConnReq::new(
+            client_id.clone(),
+            transient_connect("device-1".to_string()),
+            conn2,
+        )
+        .with_certificate(Some(identity), true);
+
+        broker_handle
+            .send(Message::Client(client_id, ClientEvent::ConnReq(req2)))
+            .await
+            .unwrap();
+
+        assert_matches!(
+            rx2.recv().await.unwrap(),
+            Message::Client(
+                _,
+                ClientEvent::ConnAck(proto::ConnAck {
+                    return_code: proto::ConnectReturnCode::Accepted,
+                    ..
+                })
+            )
+        );
+    }
+
+    #[test]
     fn test_add_session_empty_transient() {
         let id = "id1".to_string();
         let mut broker = Broker::default();
@@ -976,7 +2469,7 @@ mod tests {
         let handle = connection_handle();
         let req = ConnReq::new(client_id.clone(), connect, handle);
 
-        broker.open_session(req).unwrap();
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
 
         // check new session
         assert_eq!(1, broker.sessions.len());
@@ -997,7 +2490,7 @@ mod tests {
         let handle = connection_handle();
         let req = ConnReq::new(client_id.clone(), connect, handle);
 
-        broker.open_session(req).unwrap();
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
 
         assert_eq!(1, broker.sessions.len());
         assert_matches!(broker.sessions[&client_id], Session::Persistent(_));
@@ -1025,10 +2518,10 @@ mod tests {
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
         assert_eq!(1, broker.sessions.len());
 
-        let result = broker.open_session(req2);
+        let result = broker.open_session(req2, ProtocolVersion::V311);
         assert_matches!(result, Err(SessionError::ProtocolViolation(_)));
         assert_eq!(0, broker.sessions.len());
     }
@@ -1049,10 +2542,10 @@ mod tests {
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
         assert_eq!(1, broker.sessions.len());
 
-        let result = broker.open_session(req2);
+        let result = broker.open_session(req2, ProtocolVersion::V311);
         assert_matches!(result, Err(SessionError::ProtocolViolation(_)));
         assert_eq!(0, broker.sessions.len());
     }
@@ -1068,11 +2561,11 @@ mod tests {
         let handle2 = connection_handle();
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
         assert_eq!(1, broker.sessions.len());
 
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
-        let result = broker.open_session(req2);
+        let result = broker.open_session(req2, ProtocolVersion::V311);
         assert_matches!(result, Err(SessionError::DuplicateSession(_, _)));
         assert_matches!(broker.sessions[&client_id], Session::Transient(_));
         assert_eq!(1, broker.sessions.len());
@@ -1089,11 +2582,11 @@ mod tests {
         let handle2 = connection_handle();
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
         assert_eq!(1, broker.sessions.len());
 
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
-        let result = broker.open_session(req2);
+        let result = broker.open_session(req2, ProtocolVersion::V311);
         assert_matches!(result, Err(SessionError::DuplicateSession(_, _)));
         assert_matches!(broker.sessions[&client_id], Session::Persistent(_));
         assert_eq!(1, broker.sessions.len());
@@ -1110,11 +2603,11 @@ mod tests {
         let handle2 = connection_handle();
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
         assert_eq!(1, broker.sessions.len());
 
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
-        let result = broker.open_session(req2);
+        let result = broker.open_session(req2, ProtocolVersion::V311);
         assert_matches!(result, Err(SessionError::DuplicateSession(_, _)));
         assert_matches!(broker.sessions[&client_id], Session::Transient(_));
         assert_eq!(1, broker.sessions.len());
@@ -1132,10 +2625,10 @@ mod tests {
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
         assert_eq!(1, broker.sessions.len());
 
-        let result = broker.open_session(req2);
+        let result = broker.open_session(req2, ProtocolVersion::V311);
         assert_matches!(result, Err(SessionError::DuplicateSession(_, _)));
         assert_matches!(broker.sessions[&client_id], Session::Persistent(_));
         assert_eq!(1, broker.sessions.len());
@@ -1153,7 +2646,7 @@ mod tests {
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
 
         assert_eq!(1, broker.sessions.len());
         assert_matches!(broker.sessions[&client_id], Session::Persistent(_));
@@ -1165,7 +2658,7 @@ mod tests {
         assert_matches!(broker.sessions[&client_id], Session::Offline(_));
 
         // Reopen session
-        broker.open_session(req2).unwrap();
+        broker.open_session(req2, ProtocolVersion::V311).unwrap();
 
         assert_eq!(1, broker.sessions.len());
         assert_matches!(broker.sessions[&client_id], Session::Persistent(_));
@@ -1181,7 +2674,7 @@ mod tests {
         let handle2 = connection_handle();
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
 
-        broker.open_session(req1).unwrap();
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
 
         assert_eq!(1, broker.sessions.len());
         assert_matches!(broker.sessions[&client_id], Session::Persistent(_));
@@ -1195,9 +2688,1386 @@ mod tests {
         // Reopen session
         let connect2 = transient_connect(id.clone());
         let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
-        broker.open_session(req2).unwrap();
+        broker.open_session(req2, ProtocolVersion::V311).unwrap();
 
         assert_eq!(1, broker.sessions.len());
         assert_matches!(broker.sessions[&client_id], Session::Transient(_));
     }
+
+    #[tokio::test]
+    async fn test_state_roundtrip() {
+        let id = "id1".to_string();
+        let mut broker = Broker::default();
+        let client_id = ClientId::from(id.clone());
+        let connect1 = persistent_connect(id.clone());
+        let handle1 = connection_handle();
+        let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
+
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
+
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic/new".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+            }],
+        };
+        broker.subscribe(&client_id, subscribe).unwrap();
+        broker.close_session(&client_id);
+
+        let publication = Publication {
+            topic_name: "topic/new".to_string(),
+            qos: proto::QoS::AtLeastOnce,
+            retain: true,
+            payload: "payload".into(),
+            properties: PublicationProperties::default(),
+        };
+        broker
+            .publish_all(PublicationId::default(), publication.clone(), None)
+            .await
+            .unwrap();
+
+        let state = broker.into_state();
+        let serialized = bincode::serialize(&state).unwrap();
+        let state: BrokerState = bincode::deserialize(&serialized).unwrap();
+        let mut broker = Broker::from_state(BrokerConfig::default(), state);
+
+        assert_eq!(1, broker.sessions.len());
+        assert_matches!(broker.sessions[&client_id], Session::Offline(_));
+        assert_eq!(
+            Some(&publication),
+            broker.retained.to_map().get("topic/new")
+        );
+
+        // Reopen session and check the queued message is delivered
+        let connect2 = persistent_connect(id);
+        let handle2 = connection_handle();
+        let req2 = ConnReq::new(client_id.clone(), connect2, handle2);
+        let (ack, events) = broker.open_session(req2, ProtocolVersion::V311).unwrap();
+
+        assert!(ack.session_present);
+        assert_eq!(1, events.len());
+        assert_matches!(events[0], ClientEvent::PublishTo(Publish::QoS12(_, _, _)));
+    }
+
+    #[tokio::test]
+    async fn test_state_keeps_deadlines() {
+        let config = BrokerConfig::new().with_message_expiry(Duration::from_millis(500));
+        let mut broker = Broker::new(config.clone());
+        let id = "id1".to_string();
+        let client_id = ClientId::from(id.clone());
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect(id.clone()),
+            connection_handle(),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+            }],
+        };
+        broker.subscribe(&client_id, subscribe).unwrap();
+        broker.close_session(&client_id);
+
+        let publication = Publication {
+            topic_name: "topic".to_string(),
+            qos: proto::QoS::AtLeastOnce,
+            retain: true,
+            payload: "payload".into(),
+            properties: PublicationProperties::default(),
+        };
+        broker.publish_as_broker(publication).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+
+        let state = broker.into_state();
+        let serialized = bincode::serialize(&state).unwrap();
+        let state: BrokerState = bincode::deserialize(&serialized).unwrap();
+        let mut broker = Broker::from_state(config, state);
+        assert_eq!(1, broker.retained.count());
+
+        // The messages expire when they would have without the restart
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(1, broker.retained.remove_expired());
+
+        let req = ConnReq::new(client_id, persistent_connect(id), connection_handle());
+        let (ack, events) = broker.open_session(req, ProtocolVersion::V311).unwrap();
+        assert!(ack.session_present);
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retained_copies_keep_deadline() {
+        let expiry = Duration::from_millis(400);
+        let config = BrokerConfig::new()
+            .with_message_expiry(expiry)
+            .with_max_inflight_messages(0);
+        let mut broker = Broker::new(config);
+        let publication = Publication {
+            topic_name: "topic".to_string(),
+            qos: proto::QoS::AtLeastOnce,
+            retain: true,
+            payload: "payload".into(),
+            properties: PublicationProperties::default(),
+        };
+        broker.publish_as_broker(publication).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(250)).await;
+
+        // The copy is queued, as nothing can be inflight
+        let client_id = ClientId::from("c1".to_string());
+        let (tx, _rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect("c1".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+            }],
+        };
+        broker
+            .process_subscribe(client_id.clone(), subscribe)
+            .await
+            .unwrap();
+        broker.close_session(&client_id);
+        broker.update_config(ConfigUpdate::new(
+            BrokerConfig::new().with_message_expiry(expiry),
+        ));
+
+        // The copy expires with the retained message
+        tokio::time::delay_for(Duration::from_millis(250)).await;
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect("c1".to_string()),
+            connection_handle(),
+        );
+        let (ack, events) = broker.open_session(req, ProtocolVersion::V311).unwrap();
+        assert!(ack.session_present);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_persistent_sessions() {
+        let mut broker = Broker::default();
+
+        let client_id1 = ClientId::from("id1".to_string());
+        let req1 = ConnReq::new(
+            client_id1.clone(),
+            persistent_connect("id1".to_string()),
+            connection_handle(),
+        );
+        broker.open_session(req1, ProtocolVersion::V311).unwrap();
+
+        let client_id2 = ClientId::from("id2".to_string());
+        let req2 = ConnReq::new(
+            client_id2,
+            transient_connect("id2".to_string()),
+            connection_handle(),
+        );
+        broker.open_session(req2, ProtocolVersion::V311).unwrap();
+
+        let (_retained, sessions) = broker.snapshot().unwrap().into_parts();
+
+        assert_eq!(1, sessions.len());
+        assert_eq!(&client_id1, sessions[0].client_id());
+        assert_eq!(2, broker.sessions.len());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_index() {
+        let mut broker = Broker::default();
+        let mut receivers = vec![];
+        for (id, filter) in &[("c1", "a/+"), ("c2", "b/#")] {
+            let client_id = ClientId::from((*id).to_string());
+            let (tx, rx) = mpsc::channel(128);
+            let req = ConnReq::new(
+                client_id.clone(),
+                transient_connect((*id).to_string()),
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+            let subscribe = proto::Subscribe {
+                packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![proto::SubscribeTo {
+                    topic_filter: (*filter).to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                }],
+            };
+            broker.subscribe(&client_id, subscribe).unwrap();
+            receivers.push(rx);
+        }
+
+        let c1 = ClientId::from("c1".to_string());
+        let c2 = ClientId::from("c2".to_string());
+        assert!(broker
+            .subscriptions
+            .matches("a/x")
+            .clients()
+            .contains_key(&c1));
+        assert!(broker
+            .subscriptions
+            .matches("b/x/y")
+            .clients()
+            .contains_key(&c2));
+
+        let publication = Publication {
+            topic_name: "a/x".to_string(),
+            qos: proto::QoS::AtMostOnce,
+            retain: false,
+            payload: "payload".into(),
+            properties: PublicationProperties::default(),
+        };
+        broker
+            .publish_all(PublicationId::default(), publication, None)
+            .await
+            .unwrap();
+        assert_matches!(
+            receivers[0].recv().await,
+            Some(Message::Client(_, ClientEvent::PublishTo(_)))
+        );
+        assert!(receivers[1].try_recv().is_err());
+
+        let unsubscribe = proto::Unsubscribe {
+            packet_identifier: proto::PacketIdentifier::new(2).unwrap(),
+            unsubscribe_from: vec!["a/+".to_string()],
+        };
+        broker
+            .process_unsubscribe(c1.clone(), unsubscribe)
+            .await
+            .unwrap();
+        assert!(broker.subscriptions.matches("a/x").clients().is_empty());
+
+        // Closing a transient session drops its subscriptions
+        broker.close_session(&c2);
+        assert!(broker.subscriptions.matches("b/x/y").clients().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_subscriptions() {
+        let mut broker = Broker::default();
+        let retained = Publication {
+            topic_name: "a/b".to_string(),
+            qos: proto::QoS::ExactlyOnce,
+            retain: true,
+            payload: "retained".into(),
+            properties: PublicationProperties::default(),
+        };
+        broker
+            .publish_all(PublicationId::default(), retained, None)
+            .await
+            .unwrap();
+
+        let client_id = ClientId::from("c1".to_string());
+        let (tx, mut rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            transient_connect("c1".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![
+                proto::SubscribeTo {
+                    topic_filter: "a/#".to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                },
+                proto::SubscribeTo {
+                    topic_filter: "a/b".to_string(),
+                    qos: proto::QoS::AtLeastOnce,
+                },
+            ],
+        };
+        broker
+            .process_subscribe(client_id.clone(), subscribe)
+            .await
+            .unwrap();
+        assert_matches!(
+            rx.recv().await,
+            Some(Message::Client(_, ClientEvent::SubAck(_)))
+        );
+
+        // The retained message is sent once, with the largest QoS granted
+        let qos = |message| match message {
+            Some(Message::Client(_, ClientEvent::PublishTo(publish))) => {
+                let publish = match publish {
+                    Publish::QoS0(_, publish, _) | Publish::QoS12(_, publish, _) => publish,
+                };
+                match publish.packet_identifier_dup_qos {
+                    proto::PacketIdentifierDupQoS::AtMostOnce => proto::QoS::AtMostOnce,
+                    proto::PacketIdentifierDupQoS::AtLeastOnce(_, _) => proto::QoS::AtLeastOnce,
+                    proto::PacketIdentifierDupQoS::ExactlyOnce(_, _) => proto::QoS::ExactlyOnce,
+                }
+            }
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(proto::QoS::AtLeastOnce, qos(rx.recv().await));
+        assert!(rx.try_recv().is_err());
+
+        for (topic_name, expected) in &[
+            ("a/b", proto::QoS::AtLeastOnce),
+            ("a/c", proto::QoS::AtMostOnce),
+        ] {
+            let publication = Publication {
+                topic_name: (*topic_name).to_string(),
+                qos: proto::QoS::ExactlyOnce,
+                retain: false,
+                payload: "payload".into(),
+                properties: PublicationProperties::default(),
+            };
+            broker
+                .publish_all(PublicationId::default(), publication, None)
+                .await
+                .unwrap();
+            assert_eq!(*expected, qos(rx.recv().await));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sys_topics() {
+        let mut broker = Broker::default();
+        let mut receivers = vec![];
+        for (id, filter) in &[("c1", "$SYS/broker/messages/received"), ("c2", "#")] {
+            let client_id = ClientId::from((*id).to_string());
+            let (tx, rx) = mpsc::channel(128);
+            let req = ConnReq::new(
+                client_id.clone(),
+                transient_connect((*id).to_string()),
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+            let subscribe = proto::Subscribe {
+                packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![proto::SubscribeTo {
+                    topic_filter: (*filter).to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                }],
+            };
+            broker.subscribe(&client_id, subscribe).unwrap();
+            receivers.push(rx);
+        }
+
+        // Clients can't publish to $SYS
+        let publish = proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: true,
+            topic_name: "$SYS/broker/messages/received".to_string(),
+            payload: "100".into(),
+        };
+        let c2 = ClientId::from("c2".to_string());
+        broker
+            .process_message(
+                c2,
+                ClientEvent::PublishFrom(publish, PublicationProperties::default()),
+            )
+            .await
+            .unwrap();
+        assert!(receivers[0].try_recv().is_err());
+
+        let gauges = broker.gauges();
+        assert_eq!(2, gauges.clients_connected);
+        assert_eq!(2, gauges.subscriptions);
+        broker.publish_stats(&gauges).await.unwrap();
+        let message = receivers[0].recv().await;
+        if let Some(Message::Client(_, ClientEvent::PublishTo(Publish::QoS0(_, publish, _)))) =
+            message
+        {
+            assert_eq!("$SYS/broker/messages/received", publish.topic_name);
+            assert_eq!(Bytes::from("1"), publish.payload);
+        } else {
+            panic!("unexpected message {:?}", message);
+        }
+
+        // $SYS topics don't match wildcards at the first level
+        assert!(receivers[1].try_recv().is_err());
+
+        // $SYS topics are retained while the broker runs, but not saved
+        let filter = "$SYS/#".parse().unwrap();
+        assert!(!broker.retained.matches(&filter).is_empty());
+        let (retained, _sessions) = broker.snapshot().unwrap().into_parts();
+        assert!(retained.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sent_stats() {
+        let config = BrokerConfig::new().with_max_inflight_messages(1);
+        let mut broker = Broker::new(config);
+        let sent = |broker: &Broker| {
+            let publications = broker.stats.publications(&Gauges::default());
+            publications
+                .into_iter()
+                .find(|publication| publication.topic_name == "$SYS/broker/messages/sent")
+                .map(|publication| publication.payload)
+                .unwrap()
+        };
+
+        let client_id = ClientId::from("c1".to_string());
+        let (tx, _rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect("c1".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker
+            .process_connect(client_id.clone(), req)
+            .await
+            .unwrap();
+
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+            }],
+        };
+        broker.subscribe(&client_id, subscribe).unwrap();
+
+        for payload in &["1", "2"] {
+            let publication = Publication {
+                topic_name: "topic".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+                retain: false,
+                payload: (*payload).into(),
+                properties: PublicationProperties::default(),
+            };
+            broker
+                .publish_all(PublicationId::default(), publication, None)
+                .await
+                .unwrap();
+        }
+
+        // The second message waits for the first to be acknowledged
+        assert_eq!(Bytes::from("1"), sent(&broker));
+        let puback = proto::PubAck {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+        };
+        broker
+            .process_puback(client_id.clone(), puback)
+            .await
+            .unwrap();
+        assert_eq!(Bytes::from("2"), sent(&broker));
+
+        // The unacknowledged message is sent again on reconnect
+        broker
+            .process_close_session(client_id.clone())
+            .await
+            .unwrap();
+        let (tx, _rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect("c1".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker
+            .process_connect(client_id.clone(), req)
+            .await
+            .unwrap();
+        assert_eq!(Bytes::from("3"), sent(&broker));
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscription() {
+        let mut broker = Broker::default();
+        let mut receivers = vec![];
+        for id in &["c1", "c2", "c3"] {
+            let client_id = ClientId::from((*id).to_string());
+            let (tx, rx) = mpsc::channel(128);
+            let req = ConnReq::new(
+                client_id.clone(),
+                persistent_connect((*id).to_string()),
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+            let subscribe = proto::Subscribe {
+                packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![proto::SubscribeTo {
+                    topic_filter: "$share/workers/jobs/+".to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                }],
+            };
+            broker.subscribe(&client_id, subscribe).unwrap();
+            receivers.push(rx);
+        }
+
+        // Offline members are skipped while others are connected
+        broker.close_session(&ClientId::from("c3".to_string()));
+
+        for _ in 0..4 {
+            let publication = Publication {
+                topic_name: "jobs/job1".to_string(),
+                qos: proto::QoS::AtMostOnce,
+                retain: false,
+                payload: "payload".into(),
+                properties: PublicationProperties::default(),
+            };
+            broker
+                .publish_all(PublicationId::default(), publication, None)
+                .await
+                .unwrap();
+        }
+
+        for rx in &mut receivers[..2] {
+            for _ in 0..2 {
+                assert_matches!(
+                    rx.recv().await,
+                    Some(Message::Client(_, ClientEvent::PublishTo(_)))
+                );
+            }
+            assert!(rx.try_recv().is_err());
+        }
+
+        // Nothing was queued for the offline member
+        let req = ConnReq::new(
+            ClientId::from("c3".to_string()),
+            persistent_connect("c3".to_string()),
+            connection_handle(),
+        );
+        let (ack, events) = broker.open_session(req, ProtocolVersion::V311).unwrap();
+        assert!(ack.session_present);
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authorizer() {
+        let acl =
+            AclAuthorizer::new().allow_anonymous(Access::ReadWrite, "allowed/#".parse().unwrap());
+        let mut broker = Broker::default().with_authorizer(acl);
+
+        let client_id = ClientId::from("client".to_string());
+        let (tx, mut rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            transient_connect("client".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![
+                proto::SubscribeTo {
+                    topic_filter: "allowed/#".to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                },
+                proto::SubscribeTo {
+                    topic_filter: "#".to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                },
+            ],
+        };
+        broker
+            .process_subscribe(client_id.clone(), subscribe)
+            .await
+            .unwrap();
+
+        match rx.recv().await.unwrap() {
+            Message::Client(_, ClientEvent::SubAck(suback)) => assert_eq!(
+                vec![
+                    proto::SubAckQos::Success(proto::QoS::AtMostOnce),
+                    proto::SubAckQos::Failure
+                ],
+                suback.qos
+            ),
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        for topic in &["denied/topic", "allowed/topic"] {
+            let publish = proto::Publish {
+                packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: (*topic).to_string(),
+                payload: "payload".into(),
+            };
+            broker
+                .process_publish(client_id.clone(), publish, PublicationProperties::default())
+                .await
+                .unwrap();
+        }
+
+        match rx.recv().await.unwrap() {
+            Message::Client(_, ClientEvent::PublishTo(Publish::QoS0(_, publish, _))) => {
+                assert_eq!("allowed/topic", publish.topic_name);
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay() {
+        let dir = TempDir::new().unwrap();
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let mut broker = Broker::default().with_journal(journal).unwrap();
+
+        let sub_id = ClientId::from("sub".to_string());
+        let (sub_tx, _sub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            sub_id.clone(),
+            persistent_connect("sub".to_string()),
+            ConnectionHandle::from_sender(sub_tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic/new".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+            }],
+        };
+        broker
+            .process_subscribe(sub_id.clone(), subscribe)
+            .await
+            .unwrap();
+
+        let pub_id = ClientId::from("pub".to_string());
+        let (pub_tx, _pub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            pub_id.clone(),
+            transient_connect("pub".to_string()),
+            ConnectionHandle::from_sender(pub_tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let state = broker.snapshot().unwrap();
+
+        for (id, payload) in &[(1, "1"), (2, "2")] {
+            let publish = proto::Publish {
+                packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtLeastOnce(
+                    proto::PacketIdentifier::new(*id).unwrap(),
+                    false,
+                ),
+                retain: false,
+                topic_name: "topic/new".to_string(),
+                payload: (*payload).into(),
+            };
+            broker
+                .process_publish(pub_id.clone(), publish, PublicationProperties::default())
+                .await
+                .unwrap();
+        }
+
+        // The first message is acknowledged, then the broker crashes
+        let puback = proto::PubAck {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+        };
+        broker.process_puback(sub_id.clone(), puback).await.unwrap();
+        drop(broker);
+
+        let journal = Journal::open(dir.path(), state.journal_segment()).unwrap();
+        let mut broker = Broker::from_state(BrokerConfig::default(), state)
+            .with_journal(journal)
+            .unwrap();
+
+        let req = ConnReq::new(
+            sub_id.clone(),
+            persistent_connect("sub".to_string()),
+            connection_handle(),
+        );
+        let (ack, events) = broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        assert!(ack.session_present);
+        assert_eq!(1, events.len());
+        match &events[0] {
+            ClientEvent::PublishTo(Publish::QoS12(_, publish, _)) => {
+                assert_eq!(b"2", publish.payload.as_ref())
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay_after_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let mut broker = Broker::default().with_journal(journal).unwrap();
+
+        let pub_id = ClientId::from("pub".to_string());
+        let (pub_tx, _pub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            pub_id.clone(),
+            transient_connect("pub".to_string()),
+            ConnectionHandle::from_sender(pub_tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let state = broker.snapshot().unwrap();
+
+        // Both sessions and their subscriptions only exist in the journal
+        let mut receivers = vec![];
+        for id in &["sub", "late"] {
+            let client_id = ClientId::from((*id).to_string());
+            let (tx, rx) = mpsc::channel(128);
+            let req = ConnReq::new(
+                client_id.clone(),
+                persistent_connect((*id).to_string()),
+                ConnectionHandle::from_sender(tx),
+            );
+            receivers.push(rx);
+            broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+            let subscribe = proto::Subscribe {
+                packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![proto::SubscribeTo {
+                    topic_filter: "topic/#".to_string(),
+                    qos: proto::QoS::ExactlyOnce,
+                }],
+            };
+            broker
+                .process_subscribe(client_id, subscribe)
+                .await
+                .unwrap();
+        }
+
+        // The same payload is published with QoS 1 and then with QoS 2
+        let packet_identifier = proto::PacketIdentifier::new(1).unwrap();
+        let publish = proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtLeastOnce(
+                packet_identifier,
+                false,
+            ),
+            retain: false,
+            topic_name: "topic/same".to_string(),
+            payload: "same".into(),
+        };
+        broker
+            .process_publish(pub_id.clone(), publish, PublicationProperties::default())
+            .await
+            .unwrap();
+        let packet_identifier = proto::PacketIdentifier::new(2).unwrap();
+        let publish = proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::ExactlyOnce(
+                packet_identifier,
+                false,
+            ),
+            retain: false,
+            topic_name: "topic/same".to_string(),
+            payload: "same".into(),
+        };
+        broker
+            .process_publish(pub_id.clone(), publish, PublicationProperties::default())
+            .await
+            .unwrap();
+        broker
+            .process_pubrel(pub_id.clone(), proto::PubRel { packet_identifier })
+            .await
+            .unwrap();
+
+        // Only the QoS 2 message is received by sub before the broker crashes
+        let sub_id = ClientId::from("sub".to_string());
+        let pubrec = proto::PubRec { packet_identifier };
+        broker.process_pubrec(sub_id.clone(), pubrec).await.unwrap();
+        drop(broker);
+
+        let journal = Journal::open(dir.path(), state.journal_segment()).unwrap();
+        let mut broker = Broker::from_state(BrokerConfig::default(), state)
+            .with_journal(journal)
+            .unwrap();
+        assert_eq!(
+            2,
+            broker.subscriptions.matches("topic/same").clients().len()
+        );
+
+        let req = ConnReq::new(
+            sub_id.clone(),
+            persistent_connect("sub".to_string()),
+            connection_handle(),
+        );
+        let (ack, events) = broker.open_session(req, ProtocolVersion::V311).unwrap();
+        assert!(ack.session_present);
+        assert_eq!(2, events.len());
+        match &events[0] {
+            ClientEvent::PubRel(pubrel) => assert_eq!(packet_identifier, pubrel.packet_identifier),
+            event => panic!("unexpected event {:?}", event),
+        }
+        match &events[1] {
+            ClientEvent::PublishTo(Publish::QoS12(_, publish, _)) => assert_matches!(
+                publish.packet_identifier_dup_qos,
+                proto::PacketIdentifierDupQoS::AtLeastOnce(_, _)
+            ),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let req = ConnReq::new(
+            ClientId::from("late".to_string()),
+            persistent_connect("late".to_string()),
+            connection_handle(),
+        );
+        let (ack, events) = broker.open_session(req, ProtocolVersion::V311).unwrap();
+        assert!(ack.session_present);
+        assert_eq!(2, events.len());
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay_clean_session() {
+        let dir = TempDir::new().unwrap();
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let mut broker = Broker::default().with_journal(journal).unwrap();
+
+        let client_id = ClientId::from("client".to_string());
+        let (tx, _rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            persistent_connect("client".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+            }],
+        };
+        broker
+            .process_subscribe(client_id.clone(), subscribe)
+            .await
+            .unwrap();
+        broker
+            .drop_connection(client_id.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+
+        let state = broker.snapshot().unwrap();
+
+        // The client comes back with a clean session and then disconnects
+        let (tx, _rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            transient_connect("client".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+        broker
+            .drop_connection(client_id.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+        drop(broker);
+
+        let journal = Journal::open(dir.path(), state.journal_segment()).unwrap();
+        let broker = Broker::from_state(BrokerConfig::default(), state)
+            .with_journal(journal)
+            .unwrap();
+        assert!(broker.sessions.is_empty());
+        assert!(broker.subscriptions.matches("topic").clients().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay_qos2_before_pubrel() {
+        let dir = TempDir::new().unwrap();
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let mut broker = Broker::default().with_journal(journal).unwrap();
+
+        let sub_id = ClientId::from("sub".to_string());
+        let (sub_tx, _sub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            sub_id.clone(),
+            persistent_connect("sub".to_string()),
+            ConnectionHandle::from_sender(sub_tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "topic".to_string(),
+                qos: proto::QoS::ExactlyOnce,
+            }],
+        };
+        broker
+            .process_subscribe(sub_id.clone(), subscribe)
+            .await
+            .unwrap();
+        broker
+            .drop_connection(sub_id.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+
+        let pub_id = ClientId::from("pub".to_string());
+        let (pub_tx, _pub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            pub_id.clone(),
+            persistent_connect("pub".to_string()),
+            ConnectionHandle::from_sender(pub_tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        let state = broker.snapshot().unwrap();
+
+        // The broker crashes after the PUBREC and before the PUBREL
+        let packet_identifier = proto::PacketIdentifier::new(1).unwrap();
+        let publish = |dup| proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::ExactlyOnce(
+                packet_identifier,
+                dup,
+            ),
+            retain: false,
+            topic_name: "topic".to_string(),
+            payload: "payload".into(),
+        };
+        broker
+            .process_publish(
+                pub_id.clone(),
+                publish(false),
+                PublicationProperties::default(),
+            )
+            .await
+            .unwrap();
+        drop(broker);
+
+        let journal = Journal::open(dir.path(), state.journal_segment()).unwrap();
+        let mut broker = Broker::from_state(BrokerConfig::default(), state)
+            .with_journal(journal)
+            .unwrap();
+        let queued = |broker: &Broker| broker.sessions[&sub_id].state().unwrap().queued_count();
+        assert_eq!(1, queued(&broker));
+
+        // The publisher doesn't know if the PUBLISH arrived and sends it again
+        let (pub_tx, _pub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            pub_id.clone(),
+            persistent_connect("pub".to_string()),
+            ConnectionHandle::from_sender(pub_tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+        broker
+            .process_publish(
+                pub_id.clone(),
+                publish(true),
+                PublicationProperties::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, queued(&broker));
+
+        // Once released, the packet identifier can be used again
+        broker
+            .process_pubrel(pub_id.clone(), proto::PubRel { packet_identifier })
+            .await
+            .unwrap();
+        broker
+            .process_publish(
+                pub_id.clone(),
+                publish(false),
+                PublicationProperties::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, queued(&broker));
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay_retained() {
+        let dir = TempDir::new().unwrap();
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let mut broker = Broker::default().with_journal(journal).unwrap();
+
+        let state = broker.snapshot().unwrap();
+
+        // A QoS 0 message is journaled when it's retained
+        for (topic_name, qos) in &[
+            ("topic/kept", proto::QoS::AtMostOnce),
+            ("topic/deleted", proto::QoS::AtLeastOnce),
+        ] {
+            let publication = Publication {
+                topic_name: (*topic_name).to_string(),
+                qos: *qos,
+                retain: true,
+                payload: "payload".into(),
+                properties: PublicationProperties::default(),
+            };
+            broker.publish_as_broker(publication).await.unwrap();
+        }
+        assert!(broker.delete_retained("topic/deleted").unwrap());
+        drop(broker);
+
+        let journal = Journal::open(dir.path(), state.journal_segment()).unwrap();
+        let broker = Broker::from_state(BrokerConfig::default(), state)
+            .with_journal(journal)
+            .unwrap();
+
+        let retained = broker.retained.to_map();
+        assert_eq!(1, retained.len());
+        assert!(retained.contains_key("topic/kept"));
+    }
+
+    #[tokio::test]
+    async fn test_expire_sessions() {
+        let config = BrokerConfig::new().with_session_expiry(Duration::from_millis(50));
+        let mut broker = Broker::new(config);
+
+        let mut receivers = vec![];
+        for id in &["offline", "online"] {
+            let client_id = ClientId::from((*id).to_string());
+            let (tx, rx) = mpsc::channel(128);
+            let req = ConnReq::new(
+                client_id.clone(),
+                persistent_connect((*id).to_string()),
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.open_session(req, ProtocolVersion::V311).unwrap();
+            receivers.push(rx);
+
+            let subscribe = proto::Subscribe {
+                packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![proto::SubscribeTo {
+                    topic_filter: "topic".to_string(),
+                    qos: proto::QoS::AtLeastOnce,
+                }],
+            };
+            broker
+                .process_subscribe(client_id, subscribe)
+                .await
+                .unwrap();
+        }
+
+        let offline = ClientId::from("offline".to_string());
+        broker
+            .drop_connection(offline.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+        assert_matches!(broker.sessions[&offline], Session::Offline(_));
+
+        // Kept until the session has been offline for the expiry
+        broker.expire_sessions();
+        assert_eq!(2, broker.sessions.len());
+
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        broker.expire_sessions();
+        assert!(!broker.sessions.contains_key(&offline));
+        assert_matches!(
+            broker.sessions[&ClientId::from("online".to_string())],
+            Session::Persistent(_)
+        );
+        assert_eq!(1, broker.subscriptions.matches("topic").clients().len());
+    }
+
+    #[tokio::test]
+    async fn test_v5_session_expiry() {
+        let mut broker = Broker::default();
+
+        let mut receivers = vec![];
+        for (id, expiry) in &[("short", 50), ("long", 3_600_000), ("transient", 0)] {
+            let (tx, rx) = mpsc::channel(128);
+            let req = v5_connreq(
+                id,
+                Duration::from_millis(*expiry),
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.open_session(req, ProtocolVersion::V5).unwrap();
+            receivers.push(rx);
+        }
+        let short = ClientId::from("short".to_string());
+        let long = ClientId::from("long".to_string());
+        let transient = ClientId::from("transient".to_string());
+        assert_matches!(broker.sessions[&short], Session::Persistent(_));
+        assert_matches!(broker.sessions[&transient], Session::Transient(_));
+
+        for client_id in &[&short, &long, &transient] {
+            broker
+                .drop_connection((*client_id).clone(), ClientEvent::DropConnection)
+                .await
+                .unwrap();
+        }
+        assert!(!broker.sessions.contains_key(&transient));
+
+        // Each session expires after its own interval, even though the
+        // broker's config keeps sessions forever
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        broker.expire_sessions();
+        assert!(!broker.sessions.contains_key(&short));
+        assert_matches!(broker.sessions[&long], Session::Offline(_));
+    }
+
+    #[tokio::test]
+    async fn test_v5_session_expiry_capped() {
+        let config = BrokerConfig::new().with_session_expiry(Duration::from_millis(50));
+        let mut broker = Broker::new(config);
+
+        let (tx, _rx) = mpsc::channel(128);
+        let req = v5_connreq(
+            "client",
+            Duration::from_secs(3600),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V5).unwrap();
+        let client_id = ClientId::from("client".to_string());
+        broker
+            .drop_connection(client_id.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        broker.expire_sessions();
+        assert!(!broker.sessions.contains_key(&client_id));
+    }
+
+    #[tokio::test]
+    async fn test_v5_disconnect_ends_session() {
+        let dir = TempDir::new().unwrap();
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let mut broker = Broker::default().with_journal(journal).unwrap();
+
+        let mut receivers = vec![];
+        for id in &["ended", "kept"] {
+            let (tx, rx) = mpsc::channel(128);
+            let req = v5_connreq(
+                id,
+                Duration::from_secs(3600),
+                ConnectionHandle::from_sender(tx),
+            );
+            broker.open_session(req, ProtocolVersion::V5).unwrap();
+            receivers.push(rx);
+        }
+
+        // One client ends its session as it disconnects, the other keeps
+        // it for longer
+        let ended = ClientId::from("ended".to_string());
+        let kept = ClientId::from("kept".to_string());
+        for (client_id, expiry) in &[(&ended, 0), (&kept, 7200)] {
+            let disconnect = Disconnect {
+                session_expiry_interval: Some(Duration::from_secs(*expiry)),
+                ..Disconnect::default()
+            };
+            broker
+                .process_disconnect((*client_id).clone(), disconnect)
+                .await
+                .unwrap();
+        }
+        assert!(!broker.sessions.contains_key(&ended));
+        assert_matches!(broker.sessions[&kept], Session::Offline(_));
+        drop(broker);
+
+        // Both changes are journaled
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let broker = Broker::default().with_journal(journal).unwrap();
+        assert!(!broker.sessions.contains_key(&ended));
+        assert_eq!(
+            Some(Duration::from_secs(7200)),
+            broker.sessions[&kept].state().unwrap().expiry_interval()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_will_delay() {
+        let mut broker = Broker::default();
+
+        let sub_id = ClientId::from("sub".to_string());
+        let (tx, mut sub_rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            sub_id.clone(),
+            transient_connect("sub".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+        let subscribe = proto::Subscribe {
+            packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![proto::SubscribeTo {
+                topic_filter: "will".to_string(),
+                qos: proto::QoS::AtMostOnce,
+            }],
+        };
+        broker.process_subscribe(sub_id, subscribe).await.unwrap();
+        assert_matches!(
+            sub_rx.recv().await,
+            Some(Message::Client(_, ClientEvent::SubAck(_)))
+        );
+
+        let client_id = ClientId::from("client".to_string());
+        let connect = |clean_start: bool, tx| {
+            let id = "client".to_string();
+            let client_id = if clean_start {
+                proto::ClientId::IdWithCleanSession(id)
+            } else {
+                proto::ClientId::IdWithExistingSession(id)
+            };
+            let connect = proto::Connect {
+                will: Some(proto::Publication {
+                    topic_name: "will".to_string(),
+                    qos: proto::QoS::AtMostOnce,
+                    retain: false,
+                    payload: "gone".into(),
+                }),
+                client_id,
+                protocol_level: 0x5,
+                ..transient_connect(String::new())
+            };
+            let properties = ConnectProperties {
+                session_expiry_interval: Duration::from_secs(3600),
+                will_delay_interval: Duration::from_millis(50),
+                will_message_expiry_interval: Some(Duration::from_secs(60)),
+                ..ConnectProperties::default()
+            };
+            ConnReq::new(
+                ClientId::from("client".to_string()),
+                connect,
+                ConnectionHandle::from_sender(tx),
+            )
+            .with_properties(properties)
+        };
+
+        // The will is held back until the delay is over
+        let (tx, _rx) = mpsc::channel(128);
+        broker
+            .process_connect(client_id.clone(), connect(true, tx))
+            .await
+            .unwrap();
+        broker
+            .drop_connection(client_id.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+        broker.publish_delayed_wills().await.unwrap();
+        assert!(sub_rx.try_recv().is_err());
+
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        broker.publish_delayed_wills().await.unwrap();
+        match sub_rx.recv().await.unwrap() {
+            Message::Client(_, ClientEvent::PublishTo(Publish::QoS0(_, publish, properties))) => {
+                assert_eq!("will", publish.topic_name);
+                // The will expires from when it is published
+                let expiry = properties.message_expiry.unwrap();
+                assert!(expiry > std::time::Instant::now() + Duration::from_secs(59));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        // Resuming the session cancels the will
+        let (tx, _rx) = mpsc::channel(128);
+        broker
+            .process_connect(client_id.clone(), connect(false, tx))
+            .await
+            .unwrap();
+        broker
+            .drop_connection(client_id.clone(), ClientEvent::DropConnection)
+            .await
+            .unwrap();
+        let (tx, _rx) = mpsc::channel(128);
+        broker
+            .process_connect(client_id.clone(), connect(false, tx))
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        broker.publish_delayed_wills().await.unwrap();
+        assert!(sub_rx.try_recv().is_err());
+
+        // A graceful disconnect discards it
+        broker
+            .process_disconnect(client_id.clone(), Disconnect::default())
+            .await
+            .unwrap();
+        assert!(broker.delayed_wills.is_empty());
+
+        // Unless the client disconnects with its will
+        let (tx, _rx) = mpsc::channel(128);
+        broker
+            .process_connect(client_id.clone(), connect(false, tx))
+            .await
+            .unwrap();
+        let disconnect = Disconnect {
+            publish_will: true,
+            ..Disconnect::default()
+        };
+        broker
+            .process_disconnect(client_id.clone(), disconnect)
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        broker.publish_delayed_wills().await.unwrap();
+        assert_matches!(
+            sub_rx.recv().await,
+            Some(Message::Client(_, ClientEvent::PublishTo(_)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_not_authorized() {
+        let acl = AclAuthorizer::new();
+        let mut broker = Broker::default().with_authorizer(acl);
+
+        let client_id = ClientId::from("client".to_string());
+        let (tx, mut rx) = mpsc::channel(128);
+        let req = ConnReq::new(
+            client_id.clone(),
+            transient_connect("client".to_string()),
+            ConnectionHandle::from_sender(tx),
+        );
+        broker.open_session(req, ProtocolVersion::V311).unwrap();
+
+        // The exactly once publication isn't held for its PUBREL, so it
+        // isn't taken for a duplicate when it comes again
+        let packet_identifier = proto::PacketIdentifier::new(1).unwrap();
+        for (packet_identifier_dup_qos, qos) in &[
+            (
+                proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false),
+                proto::QoS::AtLeastOnce,
+            ),
+            (
+                proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, false),
+                proto::QoS::ExactlyOnce,
+            ),
+            (
+                proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, true),
+                proto::QoS::ExactlyOnce,
+            ),
+        ] {
+            let publish = proto::Publish {
+                packet_identifier_dup_qos: *packet_identifier_dup_qos,
+                retain: false,
+                topic_name: "topic".to_string(),
+                payload: "payload".into(),
+            };
+            broker
+                .process_publish(client_id.clone(), publish, PublicationProperties::default())
+                .await
+                .unwrap();
+
+            match rx.recv().await.unwrap() {
+                Message::Client(_, ClientEvent::PublishNotAuthorized(id, acked_qos)) => {
+                    assert_eq!(packet_identifier, id);
+                    assert_eq!(*qos, acked_qos);
+                }
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_message_expiry() {
+        let config = BrokerConfig::new().with_message_expiry(Duration::from_secs(60));
+        let mut broker = Broker::new(config);
+
+        let publication = |expiry: Option<Duration>| Publication {
+            topic_name: "topic".to_string(),
+            qos: proto::QoS::AtMostOnce,
+            retain: false,
+            payload: "payload".into(),
+            properties: PublicationProperties {
+                message_expiry: expiry.map(|expiry| Instant::now() + expiry),
+                ..PublicationProperties::default()
+            },
+        };
+
+        // The sooner expiry applies, whether it's the broker's or the
+        // publisher's
+        let (_, _, deadline) = broker.route(publication(Some(Duration::from_secs(10))));
+        assert!(deadline.unwrap() < Instant::now() + Duration::from_secs(30));
+        let (_, _, deadline) = broker.route(publication(Some(Duration::from_secs(3600))));
+        assert!(deadline.unwrap() < Instant::now() + Duration::from_secs(90));
+        let (_, _, deadline) = broker.route(publication(None));
+        assert!(deadline.is_some());
+
+        broker.update_config(ConfigUpdate::new(BrokerConfig::new()));
+        let (_, _, deadline) = broker.route(publication(None));
+        assert!(deadline.is_none());
+    }
 }
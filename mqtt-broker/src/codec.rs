@@ -0,0 +1,1653 @@
+//! Encoding and decoding of MQTT packets.
+//!
+//! The version of the protocol is picked from the CONNECT packet a
+//! connection starts with. MQTT 3.1 and 3.1.1 packets are handled by the
+//! codec of the `mqtt` crate, MQTT 5 packets by this module, which maps
+//! them onto the same types along with the properties the broker knows.
+//!
+//! Of MQTT 5, the broker doesn't support
+//! - subscription identifiers, which end the connection,
+//! - the No Local, Retain As Published and Retain Handling options of
+//!   subscriptions, which are ignored,
+//! - enhanced authentication, which refuses the client,
+//! - sending topic aliases to clients, though aliases from clients are
+//!   resolved, and
+//! - user properties and reason strings of packets other than PUBLISH,
+//!   which are dropped.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use failure::Fail;
+use mqtt::proto::{self, Packet, PacketCodec};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{ConnectProperties, Disconnect, DisconnectReason, PublicationProperties};
+
+/// The most topic aliases a client can set on a connection.
+const TOPIC_ALIAS_MAXIMUM: u16 = 16;
+
+/// The largest remaining length of a packet.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// The reason code of a CONNACK refusing a client that asked for
+/// enhanced authentication.
+pub(crate) const BAD_AUTHENTICATION_METHOD: u8 = 0x8C;
+
+/// The reason code of a DISCONNECT from a client asking for its will to
+/// be published.
+const DISCONNECT_WITH_WILL: u8 = 0x04;
+
+mod packet_type {
+    pub const CONNECT: u8 = 1;
+    pub const CONNACK: u8 = 2;
+    pub const PUBLISH: u8 = 3;
+    pub const PUBACK: u8 = 4;
+    pub const PUBREC: u8 = 5;
+    pub const PUBREL: u8 = 6;
+    pub const PUBCOMP: u8 = 7;
+    pub const SUBSCRIBE: u8 = 8;
+    pub const SUBACK: u8 = 9;
+    pub const UNSUBSCRIBE: u8 = 10;
+    pub const UNSUBACK: u8 = 11;
+    pub const PINGREQ: u8 = 12;
+    pub const PINGRESP: u8 = 13;
+    pub const DISCONNECT: u8 = 14;
+    pub const AUTH: u8 = 15;
+}
+
+mod property {
+    pub const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+    pub const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    pub const CONTENT_TYPE: u8 = 0x03;
+    pub const RESPONSE_TOPIC: u8 = 0x08;
+    pub const CORRELATION_DATA: u8 = 0x09;
+    pub const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+    pub const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+    pub const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+    pub const AUTHENTICATION_METHOD: u8 = 0x15;
+    pub const AUTHENTICATION_DATA: u8 = 0x16;
+    pub const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+    pub const WILL_DELAY_INTERVAL: u8 = 0x18;
+    pub const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+    pub const REASON_STRING: u8 = 0x1F;
+    pub const RECEIVE_MAXIMUM: u8 = 0x21;
+    pub const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+    pub const TOPIC_ALIAS: u8 = 0x23;
+    pub const USER_PROPERTY: u8 = 0x26;
+    pub const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+    pub const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+
+    pub const CONNECT: &[u8] = &[
+        SESSION_EXPIRY_INTERVAL,
+        AUTHENTICATION_METHOD,
+        AUTHENTICATION_DATA,
+        REQUEST_PROBLEM_INFORMATION,
+        REQUEST_RESPONSE_INFORMATION,
+        RECEIVE_MAXIMUM,
+        TOPIC_ALIAS_MAXIMUM,
+        USER_PROPERTY,
+        MAXIMUM_PACKET_SIZE,
+    ];
+    pub const WILL: &[u8] = &[
+        PAYLOAD_FORMAT_INDICATOR,
+        MESSAGE_EXPIRY_INTERVAL,
+        CONTENT_TYPE,
+        RESPONSE_TOPIC,
+        CORRELATION_DATA,
+        WILL_DELAY_INTERVAL,
+        USER_PROPERTY,
+    ];
+    pub const PUBLISH: &[u8] = &[
+        PAYLOAD_FORMAT_INDICATOR,
+        MESSAGE_EXPIRY_INTERVAL,
+        CONTENT_TYPE,
+        RESPONSE_TOPIC,
+        CORRELATION_DATA,
+        SUBSCRIPTION_IDENTIFIER,
+        TOPIC_ALIAS,
+        USER_PROPERTY,
+    ];
+    pub const ACK: &[u8] = &[REASON_STRING, USER_PROPERTY];
+    pub const SUBSCRIBE: &[u8] = &[SUBSCRIPTION_IDENTIFIER, USER_PROPERTY];
+    pub const UNSUBSCRIBE: &[u8] = &[USER_PROPERTY];
+    pub const DISCONNECT: &[u8] = &[SESSION_EXPIRY_INTERVAL, REASON_STRING, USER_PROPERTY];
+}
+
+/// A packet sent by a client.
+#[derive(Debug)]
+pub(crate) enum ClientPacket {
+    Connect {
+        connect: proto::Connect,
+
+        /// The properties of an MQTT 5 CONNECT packet
+        properties: Option<ConnectProperties>,
+
+        /// The method of enhanced authentication the client asked for
+        authentication_method: Option<String>,
+    },
+    Disconnect(Disconnect),
+    PingReq(proto::PingReq),
+    Publish(proto::Publish, PublicationProperties),
+    PubAck(proto::PubAck),
+    PubRec(proto::PubRec),
+    PubRel(proto::PubRel),
+    PubComp(proto::PubComp),
+    Subscribe(proto::Subscribe),
+    Unsubscribe(proto::Unsubscribe),
+}
+
+/// A packet sent to a client.
+#[derive(Debug)]
+pub(crate) enum ServerPacket {
+    ConnAck(proto::ConnAck, ConnAckProperties),
+
+    /// Only MQTT 5 clients are sent a DISCONNECT packet
+    Disconnect(DisconnectReason),
+    PingResp(proto::PingResp),
+    Publish(proto::Publish, PublicationProperties),
+    PubAck(proto::PubAck),
+    PubRec(proto::PubRec),
+    PubRel(proto::PubRel),
+    PubComp(proto::PubComp),
+    SubAck(proto::SubAck),
+    UnsubAck(proto::UnsubAck),
+
+    /// The PUBACK or PUBREC of a publication the client isn't authorized
+    /// to make, which MQTT 3.1.1 has no way to tell the client about
+    PublishNotAuthorized(proto::PacketIdentifier, proto::QoS),
+}
+
+/// What the broker tells an MQTT 5 client in CONNACK.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ConnAckProperties {
+    /// The session expiry interval the broker uses, if it isn't the one
+    /// the client asked for
+    pub session_expiry_interval: Option<Duration>,
+
+    /// The client id the broker assigned to a client that connected
+    /// without one
+    pub assigned_client_identifier: Option<String>,
+}
+
+#[derive(Debug, Fail)]
+pub(crate) enum DecodeError {
+    #[fail(display = "An I/O error occurred: {}", _0)]
+    Io(#[fail(cause)] io::Error),
+
+    #[fail(display = "Invalid packet: {}", _0)]
+    V3(#[fail(cause)] proto::DecodeError),
+
+    #[fail(display = "Malformed packet: {}", _0)]
+    Malformed(&'static str),
+
+    #[fail(display = "MQTT protocol violation: {}", _0)]
+    ProtocolError(&'static str),
+
+    #[fail(display = "Topic alias {} is invalid.", _0)]
+    TopicAliasInvalid(u16),
+
+    #[fail(display = "Subscription identifiers aren't supported.")]
+    SubscriptionIdentifier,
+}
+
+impl DecodeError {
+    /// Why the broker closes the connection after the error, or `None` if
+    /// the connection is gone.
+    pub(crate) fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        match self {
+            DecodeError::Io(e) if e.kind() == io::ErrorKind::TimedOut => {
+                Some(DisconnectReason::KeepAliveTimeout)
+            }
+            DecodeError::Io(_) => None,
+            DecodeError::V3(_) | DecodeError::Malformed(_) => {
+                Some(DisconnectReason::MalformedPacket)
+            }
+            DecodeError::ProtocolError(_) => Some(DisconnectReason::ProtocolError),
+            DecodeError::TopicAliasInvalid(_) => Some(DisconnectReason::TopicAliasInvalid),
+            DecodeError::SubscriptionIdentifier => {
+                Some(DisconnectReason::SubscriptionIdentifiersNotSupported)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+#[derive(Debug, Fail)]
+pub(crate) enum EncodeError {
+    #[fail(display = "An I/O error occurred: {}", _0)]
+    Io(#[fail(cause)] io::Error),
+
+    #[fail(display = "Packet can't be encoded: {}", _0)]
+    V3(#[fail(cause)] proto::EncodeError),
+
+    #[fail(display = "Packet of {} bytes is larger than the client accepts.", _0)]
+    PacketTooLarge(usize),
+
+    #[fail(display = "String of {} bytes is too long.", _0)]
+    StringTooLong(usize),
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(e: io::Error) -> Self {
+        EncodeError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Version {
+    V3,
+    V5,
+}
+
+/// Encodes and decodes the packets of a connection, with the version of
+/// the protocol the client connected with.
+#[derive(Default)]
+pub(crate) struct Codec {
+    version: Option<Version>,
+    v3: PacketCodec,
+
+    /// The topics clients set aliases to
+    topic_aliases: HashMap<u16, String>,
+
+    /// The size of the largest packet the client accepts
+    maximum_packet_size: Option<u32>,
+
+    /// Whether the session ends with the connection by the CONNECT packet,
+    /// which doesn't allow DISCONNECT to change it
+    session_ends_with_connection: bool,
+
+    /// How many topic filters each UNSUBSCRIBE waiting for its UNSUBACK
+    /// has, which gets a reason code for each of them
+    unsubscribe_filters: HashMap<proto::PacketIdentifier, usize>,
+}
+
+impl Decoder for Codec {
+    type Item = ClientPacket;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let version = match self.version {
+            Some(version) => version,
+            None => match version_of(src)? {
+                Some(version) => {
+                    self.version = Some(version);
+                    version
+                }
+                None => return Ok(None),
+            },
+        };
+
+        match version {
+            Version::V3 => match self.v3.decode(src).map_err(DecodeError::V3)? {
+                Some(packet) => from_v3(packet).map(Some),
+                None => Ok(None),
+            },
+            Version::V5 => match split_packet(src)? {
+                Some((first_byte, mut src)) => {
+                    let packet = self.decode_v5(first_byte, &mut src)?;
+                    if src.has_remaining() {
+                        return Err(DecodeError::Malformed("packet is too long"));
+                    }
+                    Ok(Some(packet))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = ServerPacket;
+    type Error = EncodeError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if self.version == Some(Version::V5) {
+            return self.encode_v5(item, dst);
+        }
+        match into_v3(item) {
+            Some(packet) => self.v3.encode(packet, dst).map_err(EncodeError::V3),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Codec {
+    fn decode_v5(&mut self, first_byte: u8, src: &mut Bytes) -> Result<ClientPacket, DecodeError> {
+        let flags = first_byte & 0x0F;
+        let expected_flags = match first_byte >> 4 {
+            packet_type::PUBLISH => flags,
+            packet_type::PUBREL | packet_type::SUBSCRIBE | packet_type::UNSUBSCRIBE => 0b0010,
+            _ => 0,
+        };
+        if flags != expected_flags {
+            return Err(DecodeError::Malformed("packet has invalid flags"));
+        }
+
+        match first_byte >> 4 {
+            packet_type::CONNECT => self.decode_connect(src),
+            packet_type::PUBLISH => self.decode_publish(flags, src),
+            packet_type::PUBACK => {
+                let (packet_identifier, _) = read_ack(src)?;
+                Ok(ClientPacket::PubAck(proto::PubAck { packet_identifier }))
+            }
+            packet_type::PUBREC => match read_ack(src)? {
+                // A PUBREC with an error reason code ends the exchange,
+                // like a PUBACK does
+                (packet_identifier, reason_code) if reason_code >= 0x80 => {
+                    Ok(ClientPacket::PubAck(proto::PubAck { packet_identifier }))
+                }
+                (packet_identifier, _) => {
+                    Ok(ClientPacket::PubRec(proto::PubRec { packet_identifier }))
+                }
+            },
+            packet_type::PUBREL => {
+                let (packet_identifier, _) = read_ack(src)?;
+                Ok(ClientPacket::PubRel(proto::PubRel { packet_identifier }))
+            }
+            packet_type::PUBCOMP => {
+                let (packet_identifier, _) = read_ack(src)?;
+                Ok(ClientPacket::PubComp(proto::PubComp { packet_identifier }))
+            }
+            packet_type::SUBSCRIBE => decode_subscribe(src),
+            packet_type::UNSUBSCRIBE => self.decode_unsubscribe(src),
+            packet_type::PINGREQ => Ok(ClientPacket::PingReq(proto::PingReq)),
+            packet_type::DISCONNECT => self.decode_disconnect(src),
+            packet_type::AUTH => Err(DecodeError::ProtocolError(
+                "enhanced authentication isn't supported",
+            )),
+            packet_type::CONNACK
+            | packet_type::SUBACK
+            | packet_type::UNSUBACK
+            | packet_type::PINGRESP => {
+                Err(DecodeError::ProtocolError("packet is only sent by servers"))
+            }
+            _ => Err(DecodeError::Malformed("packet type is reserved")),
+        }
+    }
+
+    fn decode_connect(&mut self, src: &mut Bytes) -> Result<ClientPacket, DecodeError> {
+        let protocol_name = read_string(src)?;
+        let protocol_level = read_u8(src)?;
+        let flags = read_u8(src)?;
+        if flags & 0x01 != 0 {
+            return Err(DecodeError::Malformed("reserved connect flag is set"));
+        }
+        let keep_alive = Duration::from_secs(u64::from(read_u16(src)?));
+        let properties = read_properties(src, property::CONNECT)?;
+
+        let id = read_string(src)?;
+        let client_id = if id.is_empty() {
+            proto::ClientId::ServerGenerated
+        } else if flags & 0x02 != 0 {
+            proto::ClientId::IdWithCleanSession(id)
+        } else {
+            proto::ClientId::IdWithExistingSession(id)
+        };
+
+        let (will, will_properties) = if flags & 0x04 != 0 {
+            let will_properties = read_properties(src, property::WILL)?;
+            let will = proto::Publication {
+                topic_name: read_string(src)?,
+                qos: qos((flags >> 3) & 0x03)?,
+                retain: flags & 0x20 != 0,
+                payload: read_binary(src)?,
+            };
+            (Some(will), will_properties)
+        } else if flags & 0x38 != 0 {
+            return Err(DecodeError::Malformed("will flags are set without a will"));
+        } else {
+            (None, Properties::default())
+        };
+
+        let username = if flags & 0x80 != 0 {
+            Some(read_string(src)?)
+        } else {
+            None
+        };
+        let password = if flags & 0x40 != 0 {
+            let password = read_binary(src)?.to_vec();
+            let password = String::from_utf8(password)
+                .map_err(|_| DecodeError::Malformed("password isn't UTF-8"))?;
+            Some(password)
+        } else {
+            None
+        };
+
+        if properties.receive_maximum == Some(0) {
+            return Err(DecodeError::ProtocolError("receive maximum is zero"));
+        }
+        if properties.maximum_packet_size == Some(0) {
+            return Err(DecodeError::ProtocolError("maximum packet size is zero"));
+        }
+        self.maximum_packet_size = properties.maximum_packet_size;
+
+        let session_expiry_interval = properties
+            .session_expiry_interval
+            .map_or_else(Duration::default, seconds);
+        self.session_ends_with_connection = session_expiry_interval == Duration::from_secs(0);
+
+        let connect = proto::Connect {
+            username,
+            password,
+            will,
+            client_id,
+            keep_alive,
+            protocol_name,
+            protocol_level,
+        };
+        let will_delay_interval = will_properties
+            .will_delay_interval
+            .map_or_else(Duration::default, seconds);
+        let will_message_expiry_interval = will_properties.message_expiry_interval.map(seconds);
+        let connect_properties = ConnectProperties {
+            session_expiry_interval,
+            receive_maximum: properties.receive_maximum,
+            will_properties: will_properties.into_publication_properties()?,
+            will_message_expiry_interval,
+            will_delay_interval,
+        };
+        Ok(ClientPacket::Connect {
+            connect,
+            properties: Some(connect_properties),
+            authentication_method: properties.authentication_method,
+        })
+    }
+
+    fn decode_publish(&mut self, flags: u8, src: &mut Bytes) -> Result<ClientPacket, DecodeError> {
+        let dup = flags & 0x08 != 0;
+        let retain = flags & 0x01 != 0;
+        let topic_name = read_string(src)?;
+        let packet_identifier_dup_qos = match qos((flags >> 1) & 0x03)? {
+            proto::QoS::AtMostOnce if dup => {
+                return Err(DecodeError::Malformed("QoS 0 publication has DUP set"));
+            }
+            proto::QoS::AtMostOnce => proto::PacketIdentifierDupQoS::AtMostOnce,
+            proto::QoS::AtLeastOnce => {
+                proto::PacketIdentifierDupQoS::AtLeastOnce(read_packet_identifier(src)?, dup)
+            }
+            proto::QoS::ExactlyOnce => {
+                proto::PacketIdentifierDupQoS::ExactlyOnce(read_packet_identifier(src)?, dup)
+            }
+        };
+
+        let properties = read_properties(src, property::PUBLISH)?;
+        if properties.subscription_identifier {
+            return Err(DecodeError::ProtocolError(
+                "publication from a client has a subscription identifier",
+            ));
+        }
+        let topic_name = self.resolve_topic_alias(topic_name, properties.topic_alias)?;
+        let message_expiry = properties
+            .message_expiry_interval
+            .map(|interval| Instant::now() + seconds(interval));
+        let properties = PublicationProperties {
+            message_expiry,
+            ..properties.into_publication_properties()?
+        };
+
+        let publish = proto::Publish {
+            packet_identifier_dup_qos,
+            retain,
+            topic_name,
+            payload: src.split_to(src.len()),
+        };
+        Ok(ClientPacket::Publish(publish, properties))
+    }
+
+    /// Resolves a topic alias to the topic it was last set to. A topic name
+    /// along with the alias sets it.
+    fn resolve_topic_alias(
+        &mut self,
+        topic_name: String,
+        topic_alias: Option<u16>,
+    ) -> Result<String, DecodeError> {
+        match topic_alias {
+            None if topic_name.is_empty() => Err(DecodeError::ProtocolError(
+                "publication has neither topic name nor alias",
+            )),
+            None => Ok(topic_name),
+            Some(alias) if alias == 0 || alias > TOPIC_ALIAS_MAXIMUM => {
+                Err(DecodeError::TopicAliasInvalid(alias))
+            }
+            Some(alias) if topic_name.is_empty() => self
+                .topic_aliases
+                .get(&alias)
+                .cloned()
+                .ok_or(DecodeError::TopicAliasInvalid(alias)),
+            Some(alias) => {
+                self.topic_aliases.insert(alias, topic_name.clone());
+                Ok(topic_name)
+            }
+        }
+    }
+
+    fn decode_unsubscribe(&mut self, src: &mut Bytes) -> Result<ClientPacket, DecodeError> {
+        let packet_identifier = read_packet_identifier(src)?;
+        read_properties(src, property::UNSUBSCRIBE)?;
+
+        let mut unsubscribe_from = Vec::new();
+        while src.has_remaining() {
+            unsubscribe_from.push(read_string(src)?);
+        }
+        if unsubscribe_from.is_empty() {
+            return Err(DecodeError::ProtocolError(
+                "UNSUBSCRIBE has no topic filters",
+            ));
+        }
+
+        self.unsubscribe_filters
+            .insert(packet_identifier, unsubscribe_from.len());
+        Ok(ClientPacket::Unsubscribe(proto::Unsubscribe {
+            packet_identifier,
+            unsubscribe_from,
+        }))
+    }
+
+    fn decode_disconnect(&mut self, src: &mut Bytes) -> Result<ClientPacket, DecodeError> {
+        let reason_code = if src.has_remaining() {
+            read_u8(src)?
+        } else {
+            0
+        };
+        let properties = if src.has_remaining() {
+            read_properties(src, property::DISCONNECT)?
+        } else {
+            Properties::default()
+        };
+
+        let session_expiry_interval = properties.session_expiry_interval.map(seconds);
+        if self.session_ends_with_connection
+            && session_expiry_interval.map_or(false, |interval| interval > Duration::from_secs(0))
+        {
+            return Err(DecodeError::ProtocolError(
+                "session expiry interval set on DISCONNECT after it was zero on CONNECT",
+            ));
+        }
+
+        Ok(ClientPacket::Disconnect(Disconnect {
+            session_expiry_interval,
+            publish_will: reason_code == DISCONNECT_WITH_WILL,
+        }))
+    }
+
+    fn encode_v5(&mut self, item: ServerPacket, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let mut body = BytesMut::new();
+        let first_byte = match item {
+            ServerPacket::ConnAck(connack, properties) => {
+                encode_connack(&connack, properties, &mut body)?;
+                packet_type::CONNACK << 4
+            }
+            ServerPacket::Disconnect(reason) => {
+                body.put_u8(disconnect_reason_code(reason));
+                put_properties(&mut body, &[]);
+                packet_type::DISCONNECT << 4
+            }
+            ServerPacket::PingResp(_) => packet_type::PINGRESP << 4,
+            ServerPacket::Publish(publish, properties) => {
+                encode_publish(&publish, &properties, &mut body)?
+            }
+            ServerPacket::PubAck(puback) => {
+                body.put_u16(puback.packet_identifier.get());
+                packet_type::PUBACK << 4
+            }
+            ServerPacket::PubRec(pubrec) => {
+                body.put_u16(pubrec.packet_identifier.get());
+                packet_type::PUBREC << 4
+            }
+            ServerPacket::PubRel(pubrel) => {
+                body.put_u16(pubrel.packet_identifier.get());
+                packet_type::PUBREL << 4 | 0b0010
+            }
+            ServerPacket::PubComp(pubcomp) => {
+                body.put_u16(pubcomp.packet_identifier.get());
+                packet_type::PUBCOMP << 4
+            }
+            ServerPacket::SubAck(suback) => {
+                body.put_u16(suback.packet_identifier.get());
+                put_properties(&mut body, &[]);
+                for qos in suback.qos {
+                    body.put_u8(match qos {
+                        proto::SubAckQos::Success(qos) => qos_code(qos),
+                        proto::SubAckQos::Failure => 0x80,
+                    });
+                }
+                packet_type::SUBACK << 4
+            }
+            ServerPacket::UnsubAck(unsuback) => {
+                let filters = self
+                    .unsubscribe_filters
+                    .remove(&unsuback.packet_identifier)
+                    .unwrap_or(1);
+                body.put_u16(unsuback.packet_identifier.get());
+                put_properties(&mut body, &[]);
+                for _ in 0..filters {
+                    body.put_u8(0x00);
+                }
+                packet_type::UNSUBACK << 4
+            }
+            ServerPacket::PublishNotAuthorized(packet_identifier, qos) => {
+                body.put_u16(packet_identifier.get());
+                body.put_u8(0x87);
+                match qos {
+                    proto::QoS::ExactlyOnce => packet_type::PUBREC << 4,
+                    _ => packet_type::PUBACK << 4,
+                }
+            }
+        };
+
+        let size = 1 + variable_byte_integer_len(body.len()) + body.len();
+        let too_large = self.maximum_packet_size.map_or(false, |maximum| {
+            u32::try_from(size).map_or(true, |size| size > maximum)
+        });
+        if body.len() > MAX_REMAINING_LENGTH || too_large {
+            return Err(EncodeError::PacketTooLarge(size));
+        }
+
+        dst.reserve(size);
+        dst.put_u8(first_byte);
+        put_variable_byte_integer(dst, body.len());
+        dst.put_slice(&body);
+        Ok(())
+    }
+}
+
+/// Picks the version of the protocol from the start of the first packet,
+/// once enough of it has been read. Anything but an MQTT 5 CONNECT packet
+/// is left to the MQTT 3.1.1 codec.
+fn version_of(src: &[u8]) -> Result<Option<Version>, DecodeError> {
+    let (first_byte, header_len, remaining_length) = match fixed_header(src)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if first_byte >> 4 != packet_type::CONNECT {
+        return Ok(Some(Version::V3));
+    }
+
+    // The protocol name is followed by the protocol level
+    if src.len() < header_len + 2 {
+        return Ok(None);
+    }
+    let name_len = usize::from(u16::from_be_bytes([src[header_len], src[header_len + 1]]));
+    let level = header_len + 2 + name_len;
+    if level >= header_len + remaining_length {
+        return Ok(Some(Version::V3));
+    }
+    if src.len() <= level {
+        return Ok(None);
+    }
+
+    if &src[header_len + 2..level] == b"MQTT" && src[level] == 0x5 {
+        Ok(Some(Version::V5))
+    } else {
+        Ok(Some(Version::V3))
+    }
+}
+
+/// Reads the fixed header of a packet, returning its first byte, its
+/// length and the remaining length of the packet once it's complete.
+fn fixed_header(src: &[u8]) -> Result<Option<(u8, usize, usize)>, DecodeError> {
+    let mut remaining_length = 0;
+    for (i, byte) in src.iter().skip(1).take(4).enumerate() {
+        remaining_length |= usize::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((src[0], i + 2, remaining_length)));
+        }
+    }
+
+    if src.len() > 4 {
+        Err(DecodeError::Malformed("remaining length is too long"))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Splits the next packet off `src` once it's complete, returning its
+/// first byte along with the rest of it after the fixed header.
+fn split_packet(src: &mut BytesMut) -> Result<Option<(u8, Bytes)>, DecodeError> {
+    let (first_byte, header_len, remaining_length) = match fixed_header(src)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let len = header_len + remaining_length;
+    if src.len() < len {
+        src.reserve(len - src.len());
+        return Ok(None);
+    }
+
+    let mut packet = src.split_to(len).freeze();
+    packet.advance(header_len);
+    Ok(Some((first_byte, packet)))
+}
+
+fn from_v3(packet: Packet) -> Result<ClientPacket, DecodeError> {
+    match packet {
+        Packet::Connect(connect) => Ok(ClientPacket::Connect {
+            connect,
+            properties: None,
+            authentication_method: None,
+        }),
+        Packet::Disconnect(_) => Ok(ClientPacket::Disconnect(Disconnect::default())),
+        Packet::PingReq(ping) => Ok(ClientPacket::PingReq(ping)),
+        Packet::Publish(publish) => Ok(ClientPacket::Publish(
+            publish,
+            PublicationProperties::default(),
+        )),
+        Packet::PubAck(puback) => Ok(ClientPacket::PubAck(puback)),
+        Packet::PubRec(pubrec) => Ok(ClientPacket::PubRec(pubrec)),
+        Packet::PubRel(pubrel) => Ok(ClientPacket::PubRel(pubrel)),
+        Packet::PubComp(pubcomp) => Ok(ClientPacket::PubComp(pubcomp)),
+        Packet::Subscribe(subscribe) => Ok(ClientPacket::Subscribe(subscribe)),
+        Packet::Unsubscribe(unsubscribe) => Ok(ClientPacket::Unsubscribe(unsubscribe)),
+        Packet::ConnAck(_) | Packet::PingResp(_) | Packet::SubAck(_) | Packet::UnsubAck(_) => {
+            Err(DecodeError::ProtocolError("packet is only sent by servers"))
+        }
+    }
+}
+
+fn into_v3(packet: ServerPacket) -> Option<Packet> {
+    match packet {
+        ServerPacket::ConnAck(connack, _) => Some(Packet::ConnAck(connack)),
+        ServerPacket::Disconnect(_) => None,
+        ServerPacket::PingResp(pingresp) => Some(Packet::PingResp(pingresp)),
+        ServerPacket::Publish(publish, _) => Some(Packet::Publish(publish)),
+        ServerPacket::PubAck(puback) => Some(Packet::PubAck(puback)),
+        ServerPacket::PubRec(pubrec) => Some(Packet::PubRec(pubrec)),
+        ServerPacket::PubRel(pubrel) => Some(Packet::PubRel(pubrel)),
+        ServerPacket::PubComp(pubcomp) => Some(Packet::PubComp(pubcomp)),
+        ServerPacket::SubAck(suback) => Some(Packet::SubAck(suback)),
+        ServerPacket::UnsubAck(unsuback) => Some(Packet::UnsubAck(unsuback)),
+        ServerPacket::PublishNotAuthorized(packet_identifier, proto::QoS::ExactlyOnce) => {
+            Some(Packet::PubRec(proto::PubRec { packet_identifier }))
+        }
+        ServerPacket::PublishNotAuthorized(packet_identifier, _) => {
+            Some(Packet::PubAck(proto::PubAck { packet_identifier }))
+        }
+    }
+}
+
+fn decode_subscribe(src: &mut Bytes) -> Result<ClientPacket, DecodeError> {
+    let packet_identifier = read_packet_identifier(src)?;
+    let properties = read_properties(src, property::SUBSCRIBE)?;
+    if properties.subscription_identifier {
+        return Err(DecodeError::SubscriptionIdentifier);
+    }
+
+    let mut subscribe_to = Vec::new();
+    while src.has_remaining() {
+        let topic_filter = read_string(src)?;
+        let options = read_u8(src)?;
+        if options & 0xC0 != 0 {
+            return Err(DecodeError::Malformed(
+                "reserved subscription option is set",
+            ));
+        }
+        if options & 0x30 == 0x30 {
+            return Err(DecodeError::ProtocolError("retain handling is invalid"));
+        }
+        subscribe_to.push(proto::SubscribeTo {
+            topic_filter,
+            qos: qos(options & 0x03)?,
+        });
+    }
+    if subscribe_to.is_empty() {
+        return Err(DecodeError::ProtocolError("SUBSCRIBE has no topic filters"));
+    }
+
+    Ok(ClientPacket::Subscribe(proto::Subscribe {
+        packet_identifier,
+        subscribe_to,
+    }))
+}
+
+fn encode_connack(
+    connack: &proto::ConnAck,
+    properties: ConnAckProperties,
+    dst: &mut BytesMut,
+) -> Result<(), EncodeError> {
+    dst.put_u8(u8::from(connack.session_present));
+    let reason = match connack.return_code {
+        proto::ConnectReturnCode::Accepted => None,
+        proto::ConnectReturnCode::Refused(reason) => Some(reason),
+    };
+    dst.put_u8(reason.map_or(0x00, connack_reason_code));
+
+    let mut buf = BytesMut::new();
+    if reason.is_none() {
+        if let Some(interval) = properties.session_expiry_interval {
+            buf.put_u8(property::SESSION_EXPIRY_INTERVAL);
+            buf.put_u32(u32::try_from(interval.as_secs()).unwrap_or(u32::max_value()));
+        }
+        if let Some(client_id) = properties.assigned_client_identifier {
+            buf.put_u8(property::ASSIGNED_CLIENT_IDENTIFIER);
+            put_string(&mut buf, &client_id)?;
+        }
+        buf.put_u8(property::TOPIC_ALIAS_MAXIMUM);
+        buf.put_u16(TOPIC_ALIAS_MAXIMUM);
+        buf.put_u8(property::SUBSCRIPTION_IDENTIFIER_AVAILABLE);
+        buf.put_u8(0);
+    }
+    put_properties(dst, &buf);
+    Ok(())
+}
+
+/// Encodes the variable header and payload of a PUBLISH packet, returning
+/// its first byte.
+fn encode_publish(
+    publish: &proto::Publish,
+    properties: &PublicationProperties,
+    dst: &mut BytesMut,
+) -> Result<u8, EncodeError> {
+    let (qos, packet_identifier, dup) = match publish.packet_identifier_dup_qos {
+        proto::PacketIdentifierDupQoS::AtMostOnce => (0, None, false),
+        proto::PacketIdentifierDupQoS::AtLeastOnce(id, dup) => (1, Some(id), dup),
+        proto::PacketIdentifierDupQoS::ExactlyOnce(id, dup) => (2, Some(id), dup),
+    };
+    let first_byte =
+        packet_type::PUBLISH << 4 | u8::from(dup) << 3 | qos << 1 | u8::from(publish.retain);
+
+    put_string(dst, &publish.topic_name)?;
+    if let Some(packet_identifier) = packet_identifier {
+        dst.put_u16(packet_identifier.get());
+    }
+
+    let mut buf = BytesMut::new();
+    if properties.payload_format_indicator {
+        buf.put_u8(property::PAYLOAD_FORMAT_INDICATOR);
+        buf.put_u8(1);
+    }
+    if let Some(expiry) = properties.message_expiry {
+        // The interval left, rounded up so it doesn't look expired early
+        let left = expiry.saturating_duration_since(Instant::now());
+        let seconds = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        buf.put_u8(property::MESSAGE_EXPIRY_INTERVAL);
+        buf.put_u32(u32::try_from(seconds).unwrap_or(u32::max_value()));
+    }
+    if let Some(content_type) = &properties.content_type {
+        buf.put_u8(property::CONTENT_TYPE);
+        put_string(&mut buf, content_type)?;
+    }
+    if let Some(response_topic) = &properties.response_topic {
+        buf.put_u8(property::RESPONSE_TOPIC);
+        put_string(&mut buf, response_topic)?;
+    }
+    if let Some(correlation_data) = &properties.correlation_data {
+        buf.put_u8(property::CORRELATION_DATA);
+        put_binary(&mut buf, correlation_data)?;
+    }
+    for (name, value) in &properties.user_properties {
+        buf.put_u8(property::USER_PROPERTY);
+        put_string(&mut buf, name)?;
+        put_string(&mut buf, value)?;
+    }
+    put_properties(dst, &buf);
+
+    dst.put_slice(&publish.payload);
+    Ok(first_byte)
+}
+
+fn connack_reason_code(reason: proto::ConnectionRefusedReason) -> u8 {
+    match reason {
+        proto::ConnectionRefusedReason::UnacceptableProtocolVersion => 0x84,
+        proto::ConnectionRefusedReason::IdentifierRejected => 0x85,
+        proto::ConnectionRefusedReason::BadUserNameOrPassword => 0x86,
+        proto::ConnectionRefusedReason::NotAuthorized => 0x87,
+        proto::ConnectionRefusedReason::ServerUnavailable => 0x88,
+        proto::ConnectionRefusedReason::Other(code) => code,
+    }
+}
+
+fn disconnect_reason_code(reason: DisconnectReason) -> u8 {
+    match reason {
+        DisconnectReason::MalformedPacket => 0x81,
+        DisconnectReason::ProtocolError => 0x82,
+        DisconnectReason::ServerShuttingDown => 0x8B,
+        DisconnectReason::KeepAliveTimeout => 0x8D,
+        DisconnectReason::SessionTakenOver => 0x8E,
+        DisconnectReason::TopicAliasInvalid => 0x94,
+        DisconnectReason::AdministrativeAction => 0x98,
+        DisconnectReason::SubscriptionIdentifiersNotSupported => 0xA1,
+    }
+}
+
+/// The properties of an MQTT 5 packet the broker reads.
+#[derive(Debug, Default)]
+struct Properties {
+    payload_format_indicator: Option<u8>,
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    correlation_data: Option<Bytes>,
+    subscription_identifier: bool,
+    session_expiry_interval: Option<u32>,
+    authentication_method: Option<String>,
+    will_delay_interval: Option<u32>,
+    receive_maximum: Option<u16>,
+    topic_alias: Option<u16>,
+    user_properties: Vec<(String, String)>,
+    maximum_packet_size: Option<u32>,
+}
+
+impl Properties {
+    /// The properties of a publication, without its message expiry, which
+    /// counts from when the message is published.
+    fn into_publication_properties(self) -> Result<PublicationProperties, DecodeError> {
+        let payload_format_indicator = match self.payload_format_indicator {
+            None | Some(0) => false,
+            Some(1) => true,
+            Some(_) => {
+                return Err(DecodeError::ProtocolError(
+                    "payload format indicator is invalid",
+                ))
+            }
+        };
+        if let Some(response_topic) = &self.response_topic {
+            if response_topic.contains(&['+', '#'][..]) {
+                return Err(DecodeError::ProtocolError("response topic has wildcards"));
+            }
+        }
+
+        Ok(PublicationProperties {
+            payload_format_indicator,
+            message_expiry: None,
+            content_type: self.content_type,
+            response_topic: self.response_topic,
+            correlation_data: self.correlation_data,
+            user_properties: self.user_properties,
+        })
+    }
+}
+
+/// Reads the properties of a packet, which may only have the `allowed`
+/// ones. Properties the broker doesn't use are skipped.
+fn read_properties(src: &mut Bytes, allowed: &[u8]) -> Result<Properties, DecodeError> {
+    let len = read_variable_byte_integer(src)?;
+    if src.len() < len {
+        return Err(DecodeError::Malformed("packet is too short"));
+    }
+    let mut src = src.split_to(len);
+
+    let mut properties = Properties::default();
+    while src.has_remaining() {
+        let identifier = read_u8(&mut src)?;
+        if !allowed.contains(&identifier) {
+            return Err(DecodeError::Malformed("property isn't allowed in packet"));
+        }
+
+        match identifier {
+            property::PAYLOAD_FORMAT_INDICATOR => {
+                set(&mut properties.payload_format_indicator, read_u8(&mut src)?)?;
+            }
+            property::MESSAGE_EXPIRY_INTERVAL => {
+                set(&mut properties.message_expiry_interval, read_u32(&mut src)?)?;
+            }
+            property::CONTENT_TYPE => set(&mut properties.content_type, read_string(&mut src)?)?,
+            property::RESPONSE_TOPIC => {
+                set(&mut properties.response_topic, read_string(&mut src)?)?;
+            }
+            property::CORRELATION_DATA => {
+                set(&mut properties.correlation_data, read_binary(&mut src)?)?;
+            }
+            property::SUBSCRIPTION_IDENTIFIER => {
+                read_variable_byte_integer(&mut src)?;
+                properties.subscription_identifier = true;
+            }
+            property::SESSION_EXPIRY_INTERVAL => {
+                set(&mut properties.session_expiry_interval, read_u32(&mut src)?)?;
+            }
+            property::AUTHENTICATION_METHOD => set(
+                &mut properties.authentication_method,
+                read_string(&mut src)?,
+            )?,
+            property::WILL_DELAY_INTERVAL => {
+                set(&mut properties.will_delay_interval, read_u32(&mut src)?)?;
+            }
+            property::RECEIVE_MAXIMUM => set(&mut properties.receive_maximum, read_u16(&mut src)?)?,
+            property::TOPIC_ALIAS => set(&mut properties.topic_alias, read_u16(&mut src)?)?,
+            property::MAXIMUM_PACKET_SIZE => {
+                set(&mut properties.maximum_packet_size, read_u32(&mut src)?)?;
+            }
+            property::USER_PROPERTY => {
+                let name = read_string(&mut src)?;
+                let value = read_string(&mut src)?;
+                properties.user_properties.push((name, value));
+            }
+            property::AUTHENTICATION_DATA => {
+                read_binary(&mut src)?;
+            }
+            property::REQUEST_PROBLEM_INFORMATION | property::REQUEST_RESPONSE_INFORMATION => {
+                read_u8(&mut src)?;
+            }
+            property::REASON_STRING => {
+                read_string(&mut src)?;
+            }
+            property::TOPIC_ALIAS_MAXIMUM => {
+                read_u16(&mut src)?;
+            }
+            _ => return Err(DecodeError::Malformed("unknown property")),
+        }
+    }
+    Ok(properties)
+}
+
+/// Sets a property, which may only be included once.
+fn set<T>(property: &mut Option<T>, value: T) -> Result<(), DecodeError> {
+    if property.replace(value).is_some() {
+        Err(DecodeError::ProtocolError(
+            "property is included more than once",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads a PUBACK, PUBREC, PUBREL or PUBCOMP packet, which leaves out its
+/// reason code if it's success, and its properties if there are none.
+fn read_ack(src: &mut Bytes) -> Result<(proto::PacketIdentifier, u8), DecodeError> {
+    let packet_identifier = read_packet_identifier(src)?;
+    let reason_code = if src.has_remaining() {
+        read_u8(src)?
+    } else {
+        0
+    };
+    if src.has_remaining() {
+        read_properties(src, property::ACK)?;
+    }
+    Ok((packet_identifier, reason_code))
+}
+
+fn read_packet_identifier(src: &mut Bytes) -> Result<proto::PacketIdentifier, DecodeError> {
+    proto::PacketIdentifier::new(read_u16(src)?)
+        .ok_or(DecodeError::Malformed("packet identifier is zero"))
+}
+
+fn qos(qos: u8) -> Result<proto::QoS, DecodeError> {
+    match qos {
+        0 => Ok(proto::QoS::AtMostOnce),
+        1 => Ok(proto::QoS::AtLeastOnce),
+        2 => Ok(proto::QoS::ExactlyOnce),
+        _ => Err(DecodeError::Malformed("QoS is invalid")),
+    }
+}
+
+fn qos_code(qos: proto::QoS) -> u8 {
+    match qos {
+        proto::QoS::AtMostOnce => 0,
+        proto::QoS::AtLeastOnce => 1,
+        proto::QoS::ExactlyOnce => 2,
+    }
+}
+
+fn seconds(seconds: u32) -> Duration {
+    Duration::from_secs(u64::from(seconds))
+}
+
+fn read_u8(src: &mut Bytes) -> Result<u8, DecodeError> {
+    if src.remaining() < 1 {
+        return Err(DecodeError::Malformed("packet is too short"));
+    }
+    Ok(src.get_u8())
+}
+
+fn read_u16(src: &mut Bytes) -> Result<u16, DecodeError> {
+    if src.remaining() < 2 {
+        return Err(DecodeError::Malformed("packet is too short"));
+    }
+    Ok(src.get_u16())
+}
+
+fn read_u32(src: &mut Bytes) -> Result<u32, DecodeError> {
+    if src.remaining() < 4 {
+        return Err(DecodeError::Malformed("packet is too short"));
+    }
+    Ok(src.get_u32())
+}
+
+fn read_variable_byte_integer(src: &mut Bytes) -> Result<usize, DecodeError> {
+    let mut value = 0;
+    for i in 0..4 {
+        let byte = read_u8(src)?;
+        value |= usize::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DecodeError::Malformed("variable byte integer is too long"))
+}
+
+fn read_binary(src: &mut Bytes) -> Result<Bytes, DecodeError> {
+    let len = usize::from(read_u16(src)?);
+    if src.remaining() < len {
+        return Err(DecodeError::Malformed("packet is too short"));
+    }
+    Ok(src.split_to(len))
+}
+
+fn read_string(src: &mut Bytes) -> Result<String, DecodeError> {
+    let bytes = read_binary(src)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Malformed("string isn't UTF-8"))
+}
+
+fn variable_byte_integer_len(value: usize) -> usize {
+    match value {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+fn put_variable_byte_integer(dst: &mut BytesMut, mut value: usize) {
+    loop {
+        #[allow(clippy::cast_possible_truncation)]
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            dst.put_u8(byte);
+            return;
+        }
+        dst.put_u8(byte | 0x80);
+    }
+}
+
+fn put_properties(dst: &mut BytesMut, properties: &[u8]) {
+    put_variable_byte_integer(dst, properties.len());
+    dst.put_slice(properties);
+}
+
+fn put_binary(dst: &mut BytesMut, value: &[u8]) -> Result<(), EncodeError> {
+    let len = u16::try_from(value.len()).map_err(|_| EncodeError::StringTooLong(value.len()))?;
+    dst.put_u16(len);
+    dst.put_slice(value);
+    Ok(())
+}
+
+fn put_string(dst: &mut BytesMut, value: &str) -> Result<(), EncodeError> {
+    put_binary(dst, value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use matches::assert_matches;
+
+    fn packet(first_byte: u8, body: &[u8]) -> BytesMut {
+        let mut dst = BytesMut::new();
+        dst.put_u8(first_byte);
+        put_variable_byte_integer(&mut dst, body.len());
+        dst.put_slice(body);
+        dst
+    }
+
+    fn connect(flags: u8, properties: &[u8], payload: &[u8]) -> BytesMut {
+        let mut body = BytesMut::new();
+        put_string(&mut body, "MQTT").unwrap();
+        body.put_u8(0x5);
+        body.put_u8(flags);
+        body.put_u16(60);
+        put_properties(&mut body, properties);
+        body.put_slice(payload);
+        packet(packet_type::CONNECT << 4, &body)
+    }
+
+    fn publish(topic_name: &str, properties: &[u8]) -> BytesMut {
+        let mut body = BytesMut::new();
+        put_string(&mut body, topic_name).unwrap();
+        put_properties(&mut body, properties);
+        body.put_slice(b"payload");
+        packet(packet_type::PUBLISH << 4, &body)
+    }
+
+    /// A codec that decoded an MQTT 5 CONNECT packet with `properties`.
+    fn connected(properties: &[u8]) -> Codec {
+        let mut payload = BytesMut::new();
+        put_string(&mut payload, "client").unwrap();
+
+        let mut codec = Codec::default();
+        let mut src = connect(0x00, properties, &payload);
+        assert_matches!(
+            codec.decode(&mut src),
+            Ok(Some(ClientPacket::Connect { .. }))
+        );
+        codec
+    }
+
+    #[test]
+    fn test_decode_connect() {
+        let mut properties = BytesMut::new();
+        properties.put_u8(property::SESSION_EXPIRY_INTERVAL);
+        properties.put_u32(3600);
+        properties.put_u8(property::RECEIVE_MAXIMUM);
+        properties.put_u16(10);
+        properties.put_u8(property::USER_PROPERTY);
+        put_string(&mut properties, "name").unwrap();
+        put_string(&mut properties, "value").unwrap();
+
+        let mut will_properties = BytesMut::new();
+        will_properties.put_u8(property::WILL_DELAY_INTERVAL);
+        will_properties.put_u32(30);
+        will_properties.put_u8(property::MESSAGE_EXPIRY_INTERVAL);
+        will_properties.put_u32(60);
+        will_properties.put_u8(property::CONTENT_TYPE);
+        put_string(&mut will_properties, "text/plain").unwrap();
+
+        let mut payload = BytesMut::new();
+        put_string(&mut payload, "client").unwrap();
+        put_properties(&mut payload, &will_properties);
+        put_string(&mut payload, "will").unwrap();
+        put_binary(&mut payload, b"gone").unwrap();
+        put_string(&mut payload, "user").unwrap();
+        put_binary(&mut payload, b"secret").unwrap();
+
+        // username, password, will QoS 1, will, clean start
+        let mut src = connect(0xCE, &properties, &payload);
+        let mut codec = Codec::default();
+        match codec.decode(&mut src).unwrap() {
+            Some(ClientPacket::Connect {
+                connect,
+                properties: Some(properties),
+                authentication_method: None,
+            }) => {
+                assert_eq!(
+                    proto::ClientId::IdWithCleanSession("client".to_string()),
+                    connect.client_id
+                );
+                assert_eq!(Some("user".to_string()), connect.username);
+                assert_eq!(Some("secret".to_string()), connect.password);
+                assert_eq!(Duration::from_secs(60), connect.keep_alive);
+
+                let will = connect.will.unwrap();
+                assert_eq!("will", will.topic_name);
+                assert_eq!(proto::QoS::AtLeastOnce, will.qos);
+                assert!(!will.retain);
+
+                assert_eq!(
+                    Duration::from_secs(3600),
+                    properties.session_expiry_interval
+                );
+                assert_eq!(Some(10), properties.receive_maximum);
+                assert_eq!(Duration::from_secs(30), properties.will_delay_interval);
+                assert_eq!(
+                    Some(Duration::from_secs(60)),
+                    properties.will_message_expiry_interval
+                );
+                assert_eq!(
+                    Some("text/plain".to_string()),
+                    properties.will_properties.content_type
+                );
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let mut payload = BytesMut::new();
+        put_string(&mut payload, "client").unwrap();
+        let src = connect(0x00, &[], &payload);
+
+        let mut codec = Codec::default();
+        for len in 0..src.len() {
+            let mut partial = BytesMut::from(&src[..len]);
+            assert_matches!(codec.decode(&mut partial), Ok(None));
+        }
+        let mut src = src;
+        assert_matches!(
+            codec.decode(&mut src),
+            Ok(Some(ClientPacket::Connect { .. }))
+        );
+    }
+
+    #[test]
+    fn test_version_of() {
+        let mut payload = BytesMut::new();
+        put_string(&mut payload, "client").unwrap();
+        let src = connect(0x00, &[], &payload);
+        assert_eq!(Some(Version::V5), version_of(&src).unwrap());
+
+        let mut body = BytesMut::new();
+        put_string(&mut body, "MQTT").unwrap();
+        body.put_u8(0x4);
+        body.put_u8(0x02);
+        body.put_u16(60);
+        put_string(&mut body, "client").unwrap();
+        let src = packet(packet_type::CONNECT << 4, &body);
+        assert_eq!(Some(Version::V3), version_of(&src).unwrap());
+
+        let src = packet(packet_type::PINGREQ << 4, &[]);
+        assert_eq!(Some(Version::V3), version_of(&src).unwrap());
+    }
+
+    #[test]
+    fn test_topic_alias() {
+        let mut codec = connected(&[]);
+
+        let alias = |alias: u16| {
+            let mut properties = BytesMut::new();
+            properties.put_u8(property::TOPIC_ALIAS);
+            properties.put_u16(alias);
+            properties
+        };
+
+        // A topic name with an alias sets it
+        let mut src = publish("topic", &alias(1));
+        assert_matches!(
+            codec.decode(&mut src),
+            Ok(Some(ClientPacket::Publish(proto::Publish { ref topic_name, .. }, _))) if topic_name == "topic"
+        );
+
+        // The alias alone is resolved
+        let mut src = publish("", &alias(1));
+        assert_matches!(
+            codec.decode(&mut src),
+            Ok(Some(ClientPacket::Publish(proto::Publish { ref topic_name, .. }, _))) if topic_name == "topic"
+        );
+
+        let mut src = publish("", &alias(2));
+        let error = codec.decode(&mut src).unwrap_err();
+        assert_eq!(
+            Some(DisconnectReason::TopicAliasInvalid),
+            error.disconnect_reason()
+        );
+
+        let mut src = publish("topic", &alias(TOPIC_ALIAS_MAXIMUM + 1));
+        assert_matches!(
+            codec.decode(&mut src),
+            Err(DecodeError::TopicAliasInvalid(_))
+        );
+    }
+
+    #[test]
+    fn test_publish_properties() {
+        let mut codec = connected(&[]);
+
+        let properties = PublicationProperties {
+            payload_format_indicator: true,
+            message_expiry: Some(Instant::now() + Duration::from_secs(30)),
+            content_type: Some("text/plain".to_string()),
+            response_topic: Some("response".to_string()),
+            correlation_data: Some("correlation".into()),
+            user_properties: vec![("name".to_string(), "value".to_string())],
+        };
+        let publish = proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtLeastOnce(
+                proto::PacketIdentifier::new(1).unwrap(),
+                false,
+            ),
+            retain: true,
+            topic_name: "topic".to_string(),
+            payload: "payload".into(),
+        };
+        let mut dst = BytesMut::new();
+        codec
+            .encode(
+                ServerPacket::Publish(publish.clone(), properties.clone()),
+                &mut dst,
+            )
+            .unwrap();
+
+        // Decoding it back gives the same publication
+        match codec.decode(&mut dst).unwrap() {
+            Some(ClientPacket::Publish(decoded, decoded_properties)) => {
+                assert_eq!(publish, decoded);
+                let message_expiry = decoded_properties.message_expiry.unwrap();
+                assert!(message_expiry >= properties.message_expiry.unwrap());
+                assert_eq!(
+                    PublicationProperties {
+                        message_expiry: properties.message_expiry,
+                        ..decoded_properties
+                    },
+                    properties
+                );
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_packet_too_large() {
+        let mut properties = BytesMut::new();
+        properties.put_u8(property::MAXIMUM_PACKET_SIZE);
+        properties.put_u32(32);
+        let mut codec = connected(&properties);
+
+        let publish = proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "topic".to_string(),
+            payload: vec![0; 32].into(),
+        };
+        let mut dst = BytesMut::new();
+        let packet = ServerPacket::Publish(publish, PublicationProperties::default());
+        assert_matches!(
+            codec.encode(packet, &mut dst),
+            Err(EncodeError::PacketTooLarge(_))
+        );
+        assert!(dst.is_empty());
+
+        codec
+            .encode(ServerPacket::PingResp(proto::PingResp), &mut dst)
+            .unwrap();
+        assert_eq!(&[packet_type::PINGRESP << 4, 0][..], &dst[..]);
+    }
+
+    #[test]
+    fn test_encode_connack() {
+        let mut codec = connected(&[]);
+
+        let connack = proto::ConnAck {
+            session_present: false,
+            return_code: proto::ConnectReturnCode::Accepted,
+        };
+        let properties = ConnAckProperties {
+            session_expiry_interval: Some(Duration::from_secs(60)),
+            assigned_client_identifier: Some("id".to_string()),
+        };
+        let mut dst = BytesMut::new();
+        codec
+            .encode(ServerPacket::ConnAck(connack, properties), &mut dst)
+            .unwrap();
+        let expected = [
+            packet_type::CONNACK << 4,
+            18,
+            0x00,
+            0x00,
+            15,
+            property::SESSION_EXPIRY_INTERVAL,
+            0,
+            0,
+            0,
+            60,
+            property::ASSIGNED_CLIENT_IDENTIFIER,
+            0,
+            2,
+            b'i',
+            b'd',
+            property::TOPIC_ALIAS_MAXIMUM,
+            0,
+            16,
+            property::SUBSCRIPTION_IDENTIFIER_AVAILABLE,
+            0,
+        ];
+        assert_eq!(&expected[..], &dst[..]);
+
+        // A refused client is sent the reason code alone
+        let connack = proto::ConnAck {
+            session_present: false,
+            return_code: proto::ConnectReturnCode::Refused(
+                proto::ConnectionRefusedReason::NotAuthorized,
+            ),
+        };
+        let mut dst = BytesMut::new();
+        codec
+            .encode(
+                ServerPacket::ConnAck(connack, ConnAckProperties::default()),
+                &mut dst,
+            )
+            .unwrap();
+        assert_eq!(&[packet_type::CONNACK << 4, 3, 0x00, 0x87, 0][..], &dst[..]);
+    }
+
+    #[test]
+    fn test_encode_acks() {
+        let mut codec = connected(&[]);
+        let packet_identifier = proto::PacketIdentifier::new(1).unwrap();
+
+        let mut dst = BytesMut::new();
+        let not_authorized =
+            ServerPacket::PublishNotAuthorized(packet_identifier, proto::QoS::ExactlyOnce);
+        codec.encode(not_authorized, &mut dst).unwrap();
+        assert_eq!(&[packet_type::PUBREC << 4, 3, 0, 1, 0x87][..], &dst[..]);
+
+        // UNSUBACK has a reason code for each topic filter
+        let mut body = BytesMut::new();
+        body.put_u16(1);
+        put_properties(&mut body, &[]);
+        put_string(&mut body, "a").unwrap();
+        put_string(&mut body, "b").unwrap();
+        let mut src = packet(packet_type::UNSUBSCRIBE << 4 | 0b0010, &body);
+        assert_matches!(
+            codec.decode(&mut src),
+            Ok(Some(ClientPacket::Unsubscribe(_)))
+        );
+        let mut dst = BytesMut::new();
+        let unsuback = proto::UnsubAck { packet_identifier };
+        codec
+            .encode(ServerPacket::UnsubAck(unsuback), &mut dst)
+            .unwrap();
+        assert_eq!(
+            &[packet_type::UNSUBACK << 4, 5, 0, 1, 0, 0x00, 0x00][..],
+            &dst[..]
+        );
+
+        let mut dst = BytesMut::new();
+        let reason = DisconnectReason::ServerShuttingDown;
+        codec
+            .encode(ServerPacket::Disconnect(reason), &mut dst)
+            .unwrap();
+        assert_eq!(&[packet_type::DISCONNECT << 4, 2, 0x8B, 0][..], &dst[..]);
+    }
+
+    #[test]
+    fn test_decode_disconnect() {
+        let mut properties = BytesMut::new();
+        properties.put_u8(property::SESSION_EXPIRY_INTERVAL);
+        properties.put_u32(60);
+        let mut body = BytesMut::new();
+        body.put_u8(DISCONNECT_WITH_WILL);
+        put_properties(&mut body, &properties);
+
+        let mut connect_properties = BytesMut::new();
+        connect_properties.put_u8(property::SESSION_EXPIRY_INTERVAL);
+        connect_properties.put_u32(3600);
+        let mut codec = connected(&connect_properties);
+        let mut src = packet(packet_type::DISCONNECT << 4, &body);
+        match codec.decode(&mut src).unwrap() {
+            Some(ClientPacket::Disconnect(disconnect)) => assert_eq!(
+                Disconnect {
+                    session_expiry_interval: Some(Duration::from_secs(60)),
+                    publish_will: true,
+                },
+                disconnect
+            ),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+
+        // A session that ends with the connection can't be kept
+        let mut codec = connected(&[]);
+        let mut src = packet(packet_type::DISCONNECT << 4, &body);
+        assert_matches!(codec.decode(&mut src), Err(DecodeError::ProtocolError(_)));
+
+        let mut codec = connected(&[]);
+        let mut src = packet(packet_type::DISCONNECT << 4, &[]);
+        assert_matches!(
+            codec.decode(&mut src),
+            Ok(Some(ClientPacket::Disconnect(Disconnect {
+                session_expiry_interval: None,
+                publish_will: false,
+            })))
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        // Subscription identifiers aren't supported
+        let mut properties = BytesMut::new();
+        properties.put_u8(property::SUBSCRIPTION_IDENTIFIER);
+        properties.put_u8(1);
+        let mut body = BytesMut::new();
+        body.put_u16(1);
+        put_properties(&mut body, &properties);
+        put_string(&mut body, "topic").unwrap();
+        body.put_u8(0x01);
+        let mut codec = connected(&[]);
+        let mut src = packet(packet_type::SUBSCRIBE << 4 | 0b0010, &body);
+        let error = codec.decode(&mut src).unwrap_err();
+        assert_eq!(
+            Some(DisconnectReason::SubscriptionIdentifiersNotSupported),
+            error.disconnect_reason()
+        );
+
+        // A property that doesn't belong in the packet
+        let mut properties = BytesMut::new();
+        properties.put_u8(property::SESSION_EXPIRY_INTERVAL);
+        properties.put_u32(60);
+        let mut src = publish("topic", &properties);
+        let error = connected(&[]).decode(&mut src).unwrap_err();
+        assert_eq!(
+            Some(DisconnectReason::MalformedPacket),
+            error.disconnect_reason()
+        );
+
+        // A property included twice
+        let mut properties = BytesMut::new();
+        properties.put_u8(property::CONTENT_TYPE);
+        put_string(&mut properties, "a").unwrap();
+        properties.put_u8(property::CONTENT_TYPE);
+        put_string(&mut properties, "b").unwrap();
+        let mut src = publish("topic", &properties);
+        let error = connected(&[]).decode(&mut src).unwrap_err();
+        assert_eq!(
+            Some(DisconnectReason::ProtocolError),
+            error.disconnect_reason()
+        );
+
+        // Enhanced authentication
+        let mut src = packet(packet_type::AUTH << 4, &[]);
+        assert_matches!(
+            connected(&[]).decode(&mut src),
+            Err(DecodeError::ProtocolError(_))
+        );
+
+        let error = DecodeError::Io(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(
+            Some(DisconnectReason::KeepAliveTimeout),
+            error.disconnect_reason()
+        );
+        let error = DecodeError::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(None, error.disconnect_reason());
+    }
+
+    #[test]
+    fn test_refused_pubrec() {
+        let mut codec = connected(&[]);
+
+        // A PUBREC with an error reason code ends the exchange
+        let mut src = packet(packet_type::PUBREC << 4, &[0, 1, 0x80]);
+        assert_matches!(codec.decode(&mut src), Ok(Some(ClientPacket::PubAck(_))));
+
+        let mut src = packet(packet_type::PUBREC << 4, &[0, 1]);
+        assert_matches!(codec.decode(&mut src), Ok(Some(ClientPacket::PubRec(_))));
+    }
+}
@@ -0,0 +1,382 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{cmp, fmt};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::authorization::Authorizer;
+use crate::subscription::TopicFilter;
+
+const DEFAULT_MAX_INFLIGHT_MESSAGES: usize = 16;
+const DEFAULT_MESSAGE_QUEUE_SIZE: usize = 1024;
+const DEFAULT_CONNECTION_QUEUE_SIZE: usize = 128;
+const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_KEEP_ALIVE_MULTIPLIER: f32 = 1.5;
+const DEFAULT_SYS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits and timeouts of a broker and its connections.
+///
+/// A config can be deserialized, e.g. from a section of a config file.
+/// Missing fields keep their defaults and timeouts are given in seconds.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    max_inflight_messages: usize,
+    message_queue_size: usize,
+    connection_queue_size: usize,
+    #[serde(deserialize_with = "seconds")]
+    connection_timeout: Duration,
+    keep_alive_multiplier: f32,
+    #[serde(deserialize_with = "seconds")]
+    sys_interval: Duration,
+    max_queued_messages: usize,
+    max_queued_bytes: usize,
+    queue_overflow: OverflowPolicy,
+    #[serde(deserialize_with = "seconds")]
+    session_expiry: Duration,
+    #[serde(deserialize_with = "seconds")]
+    message_expiry: Duration,
+    topic_message_expiry: Vec<TopicExpiry>,
+}
+
+/// What a session does with a new message when its queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum OverflowPolicy {
+    /// Removes the oldest queued messages to make room.
+    #[serde(rename = "drop_oldest")]
+    DropOldest,
+    /// Drops the new message.
+    #[serde(rename = "drop_newest")]
+    DropNewest,
+    /// Removes the oldest queued QoS 0 messages to make room, including
+    /// the new one. QoS 1 and QoS 2 messages are always queued, even when
+    /// the queue is over its limits.
+    #[serde(rename = "drop_qos0")]
+    DropQoS0,
+}
+
+/// A message expiry for the topics matching a filter, overriding the
+/// `message_expiry` of a `BrokerConfig`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicExpiry {
+    filter: TopicFilter,
+    #[serde(deserialize_with = "seconds")]
+    expiry: Duration,
+}
+
+impl TopicExpiry {
+    /// Zero keeps the messages of the matching topics until they are
+    /// delivered or replaced.
+    pub fn new(filter: TopicFilter, expiry: Duration) -> Self {
+        Self { filter, expiry }
+    }
+
+    pub fn filter(&self) -> &TopicFilter {
+        &self.filter
+    }
+
+    pub fn expiry(&self) -> Duration {
+        self.expiry
+    }
+}
+
+/// The message expiry of a `BrokerConfig`, shared by the broker and its
+/// sessions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ExpiryRules {
+    default: Duration,
+    topics: Arc<Vec<TopicExpiry>>,
+}
+
+impl ExpiryRules {
+    /// When a message published to `topic_name` now expires, if it does.
+    pub(crate) fn deadline(&self, topic_name: &str) -> Option<Instant> {
+        let expiry = self
+            .topics
+            .iter()
+            .find(|topic| topic.filter.matches(topic_name))
+            .map_or(self.default, |topic| topic.expiry);
+        if expiry == Duration::from_secs(0) {
+            None
+        } else {
+            Some(Instant::now() + expiry)
+        }
+    }
+}
+
+/// The limits of the messages queued for a session, taken from a
+/// `BrokerConfig`. Zero means no limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct QueueLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl BrokerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of messages a session sends before waiting for them to
+    /// be acknowledged. Further messages are queued.
+    pub fn max_inflight_messages(&self) -> usize {
+        self.max_inflight_messages
+    }
+
+    pub fn with_max_inflight_messages(mut self, max_inflight_messages: usize) -> Self {
+        self.max_inflight_messages = max_inflight_messages;
+        self
+    }
+
+    /// The number of messages waiting to be processed by the broker before
+    /// connections have to wait to hand over packets.
+    pub fn message_queue_size(&self) -> usize {
+        self.message_queue_size
+    }
+
+    pub fn with_message_queue_size(mut self, message_queue_size: usize) -> Self {
+        self.message_queue_size = message_queue_size;
+        self
+    }
+
+    /// The number of packets waiting to be sent on a connection before the
+    /// broker has to wait to hand over more.
+    pub fn connection_queue_size(&self) -> usize {
+        self.connection_queue_size
+    }
+
+    pub fn with_connection_queue_size(mut self, connection_queue_size: usize) -> Self {
+        self.connection_queue_size = connection_queue_size;
+        self
+    }
+
+    /// How long a new connection has to send its CONNECT packet, and how
+    /// long writing to a connection may block.
+    pub fn connection_timeout(&self) -> Duration {
+        self.connection_timeout
+    }
+
+    pub fn with_connection_timeout(mut self, connection_timeout: Duration) -> Self {
+        self.connection_timeout = connection_timeout;
+        self
+    }
+
+    /// A connection is dropped when nothing is received from the client for
+    /// its keep alive times this multiplier.
+    pub fn keep_alive_multiplier(&self) -> f32 {
+        self.keep_alive_multiplier
+    }
+
+    pub fn with_keep_alive_multiplier(mut self, keep_alive_multiplier: f32) -> Self {
+        self.keep_alive_multiplier = keep_alive_multiplier;
+        self
+    }
+
+    /// How often the broker publishes its statistics under `$SYS/broker`
+    /// and updates the gauges of its metrics. Zero turns them off.
+    pub fn sys_interval(&self) -> Duration {
+        self.sys_interval
+    }
+
+    pub fn with_sys_interval(mut self, sys_interval: Duration) -> Self {
+        self.sys_interval = sys_interval;
+        self
+    }
+
+    /// The number of messages a session queues while its client is offline
+    /// or has `max_inflight_messages` unacknowledged messages. Zero, the
+    /// default, means no limit.
+    pub fn max_queued_messages(&self) -> usize {
+        self.max_queued_messages
+    }
+
+    pub fn with_max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+        self.max_queued_messages = max_queued_messages;
+        self
+    }
+
+    /// The total payload size of the messages a session queues. Zero, the
+    /// default, means no limit.
+    pub fn max_queued_bytes(&self) -> usize {
+        self.max_queued_bytes
+    }
+
+    pub fn with_max_queued_bytes(mut self, max_queued_bytes: usize) -> Self {
+        self.max_queued_bytes = max_queued_bytes;
+        self
+    }
+
+    /// What a session does with a new message when its queue is full.
+    pub fn queue_overflow(&self) -> OverflowPolicy {
+        self.queue_overflow
+    }
+
+    pub fn with_queue_overflow(mut self, queue_overflow: OverflowPolicy) -> Self {
+        self.queue_overflow = queue_overflow;
+        self
+    }
+
+    /// How long a persistent session is kept once its client is offline
+    /// and no longer active. Expired sessions are removed along with their
+    /// subscriptions and queued messages. Zero keeps them until the client
+    /// comes back.
+    pub fn session_expiry(&self) -> Duration {
+        self.session_expiry
+    }
+
+    pub fn with_session_expiry(mut self, session_expiry: Duration) -> Self {
+        self.session_expiry = session_expiry;
+        self
+    }
+
+    /// How long a session is kept when its client asked for `requested`
+    /// with MQTT 5. A non-zero `session_expiry` caps what clients ask for,
+    /// and applies to clients that can't ask.
+    pub fn session_expiry_interval(&self, requested: Option<Duration>) -> Duration {
+        match requested {
+            Some(requested) if self.session_expiry == Duration::from_secs(0) => requested,
+            Some(requested) => cmp::min(requested, self.session_expiry),
+            None => self.session_expiry,
+        }
+    }
+
+    /// How long a message is kept in the queues of sessions and as a
+    /// retained message before it is dropped. Zero keeps messages until
+    /// they are delivered or replaced.
+    ///
+    /// A message expires by the rules in place when it was published.
+    /// Restored messages keep their deadlines, so the time the broker was
+    /// stopped counts toward their expiry.
+    pub fn message_expiry(&self) -> Duration {
+        self.message_expiry
+    }
+
+    pub fn with_message_expiry(mut self, message_expiry: Duration) -> Self {
+        self.message_expiry = message_expiry;
+        self
+    }
+
+    /// Message expiries of topics that differ from `message_expiry`. The
+    /// first filter matching a topic applies.
+    pub fn topic_message_expiry(&self) -> &[TopicExpiry] {
+        &self.topic_message_expiry
+    }
+
+    pub fn with_topic_message_expiry(mut self, topic_expiry: TopicExpiry) -> Self {
+        self.topic_message_expiry.push(topic_expiry);
+        self
+    }
+
+    pub(crate) fn expiry_rules(&self) -> ExpiryRules {
+        ExpiryRules {
+            default: self.message_expiry,
+            topics: Arc::new(self.topic_message_expiry.clone()),
+        }
+    }
+
+    pub(crate) fn queue_limits(&self) -> QueueLimits {
+        QueueLimits {
+            max_messages: self.max_queued_messages,
+            max_bytes: self.max_queued_bytes,
+            overflow: self.queue_overflow,
+        }
+    }
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            max_inflight_messages: DEFAULT_MAX_INFLIGHT_MESSAGES,
+            message_queue_size: DEFAULT_MESSAGE_QUEUE_SIZE,
+            connection_queue_size: DEFAULT_CONNECTION_QUEUE_SIZE,
+            connection_timeout: DEFAULT_CONNECTION_TIMEOUT,
+            keep_alive_multiplier: DEFAULT_KEEP_ALIVE_MULTIPLIER,
+            sys_interval: DEFAULT_SYS_INTERVAL,
+            max_queued_messages: 0,
+            max_queued_bytes: 0,
+            queue_overflow: OverflowPolicy::DropNewest,
+            session_expiry: Duration::from_secs(0),
+            message_expiry: Duration::from_secs(0),
+            topic_message_expiry: vec![],
+        }
+    }
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        BrokerConfig::default().queue_limits()
+    }
+}
+
+/// Settings to change on a running broker, sent with
+/// `SystemEvent::ConfigUpdate`.
+///
+/// Sessions and connections are kept. The new limits of inflight and queued
+/// messages apply to every session right away, though messages queued over
+/// a lowered limit are only dropped once another one is queued. The
+/// connection settings apply to connections accepted from now on. The
+/// message queue size can't be changed while the broker is running, so a
+/// change to it is only logged.
+/// A new `$SYS` interval applies after the next publication, and a new
+/// session expiry after the next sweep. A new message expiry applies to
+/// messages published from now on.
+///
+/// A new authorizer applies to publications and subscriptions from now
+/// on. Existing subscriptions are kept.
+pub struct ConfigUpdate {
+    config: BrokerConfig,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    allow_mqtt31: Option<bool>,
+}
+
+impl ConfigUpdate {
+    pub fn new(config: BrokerConfig) -> Self {
+        Self {
+            config,
+            authorizer: None,
+            allow_mqtt31: None,
+        }
+    }
+
+    /// Replaces the broker's authorizer.
+    pub fn with_authorizer<A>(mut self, authorizer: A) -> Self
+    where
+        A: Authorizer + 'static,
+    {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Changes whether MQTT 3.1 clients are accepted.
+    pub fn with_mqtt31(mut self, allow: bool) -> Self {
+        self.allow_mqtt31 = Some(allow);
+        self
+    }
+
+    pub(crate) fn into_parts(self) -> (BrokerConfig, Option<Arc<dyn Authorizer>>, Option<bool>) {
+        (self.config, self.authorizer, self.allow_mqtt31)
+    }
+}
+
+impl fmt::Debug for ConfigUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigUpdate")
+            .field("config", &self.config)
+            .field("authorizer", &self.authorizer.is_some())
+            .field("allow_mqtt31", &self.allow_mqtt31)
+            .finish()
+    }
+}
+
+pub(crate) fn default_max_inflight_messages() -> usize {
+    DEFAULT_MAX_INFLIGHT_MESSAGES
+}
+
+fn seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
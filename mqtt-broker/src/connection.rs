@@ -1,5 +1,4 @@
 use std::fmt;
-use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -8,8 +7,8 @@ use futures_util::future::{select, Either};
 use futures_util::pin_mut;
 use futures_util::sink::{Sink, SinkExt};
 use futures_util::stream::{Stream, StreamExt};
-use lazy_static::lazy_static;
-use mqtt::proto::{self, DecodeError, EncodeError, Packet, PacketCodec};
+use metrics::increment_counter;
+use mqtt::proto;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio_io_timeout::TimeoutStream;
@@ -18,14 +17,17 @@ use tracing::{debug, info, span, trace, warn, Level};
 use tracing_futures::Instrument;
 use uuid::Uuid;
 
+use crate::auth::{Auth, AuthenticationContext, Authenticator};
 use crate::broker::BrokerHandle;
-use crate::{ClientEvent, ClientId, ConnReq, Error, ErrorKind, Message, Publish};
-
-lazy_static! {
-    static ref DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
-}
-
-const KEEPALIVE_MULT: f32 = 1.5;
+use crate::codec::{
+    self, ClientPacket, Codec, ConnAckProperties, DecodeError, EncodeError, ServerPacket,
+};
+use crate::config::BrokerConfig;
+use crate::telemetry;
+use crate::{
+    CertificateIdentity, ClientEvent, ClientId, ConnReq, ConnectProperties, DisconnectReason,
+    Error, ErrorKind, Message, PeerAddr, Publish,
+};
 
 /// Allows sending events to a connection.
 ///
@@ -72,19 +74,33 @@ impl fmt::Display for ConnectionHandle {
 ///
 /// Receives a source of packets and a handle to the Broker.
 /// Starts two tasks (sending and receiving)
+///
+/// `certificate` is the identity of the client certificate presented on a
+/// TLS connection. It is passed on to the broker with the connect request,
+/// along with whether the client id has to match it.
+///
+/// The client is authenticated before the connect request is sent to the
+/// broker.
+///
+/// Timeouts and the size of the connection's queue come from `config`.
+#[allow(clippy::too_many_lines)]
 pub async fn process<I>(
     io: I,
-    remote_addr: SocketAddr,
+    remote_addr: PeerAddr,
+    certificate: Option<CertificateIdentity>,
+    match_client_id: bool,
+    authenticator: Arc<dyn Authenticator>,
     mut broker_handle: BrokerHandle,
+    config: &BrokerConfig,
 ) -> Result<(), Error>
 where
     I: AsyncRead + AsyncWrite + Unpin,
 {
     let mut timeout = TimeoutStream::new(io);
-    timeout.set_read_timeout(Some(*DEFAULT_TIMEOUT));
-    timeout.set_write_timeout(Some(*DEFAULT_TIMEOUT));
+    timeout.set_read_timeout(Some(config.connection_timeout()));
+    timeout.set_write_timeout(Some(config.connection_timeout()));
 
-    let mut codec = Framed::new(timeout, PacketCodec::default());
+    let mut codec = Framed::new(timeout, Codec::default());
 
     // [MQTT-3.1.0-1] - After a Network Connection is established by a Client to a Server,
     // the first Packet sent from the Client to the Server MUST be a CONNECT Packet.
@@ -95,9 +111,13 @@ where
     // to keep the state machine correct.
 
     match codec.next().await {
-        Some(Ok(Packet::Connect(connect))) => {
+        Some(Ok(ClientPacket::Connect {
+            connect,
+            properties,
+            authentication_method,
+        })) => {
             let client_id = client_id(&connect.client_id);
-            let (sender, events) = mpsc::channel(128);
+            let (sender, events) = mpsc::channel(config.connection_queue_size());
             let connection_handle = ConnectionHandle::from_sender(sender);
             let span = span!(Level::INFO, "connection", client_id=%client_id, remote_addr=%remote_addr, connection=%connection_handle);
 
@@ -106,12 +126,20 @@ where
                 info!("new client connection");
                 debug!("received CONNECT: {:?}", connect);
 
+                // Enhanced authentication isn't supported
+                if let Some(method) = authentication_method {
+                    info!("refusing client asking for authentication method {}", method);
+                    return refuse_authentication(&mut codec).await;
+                }
+
                 // [MQTT-3.1.2-24] - If the Keep Alive value is non-zero and
                 // the Server does not receive a Control Packet from the
                 // Client within one and a half times the Keep Alive time
                 // period, it MUST disconnect the Network Connection to the
                 // Client as if the network had failed.
-                let keep_alive = connect.keep_alive.mul_f32(KEEPALIVE_MULT);
+                let keep_alive = connect
+                    .keep_alive
+                    .mul_f32(config.keep_alive_multiplier());
                 if keep_alive == Duration::from_secs(0) {
                     debug!("received 0 length keepalive from client. disabling keepalive timeout");
                     codec.get_mut().set_read_timeout(None);
@@ -122,7 +150,24 @@ where
 
                 let (outgoing, incoming) = codec.split();
 
-                let req = ConnReq::new(client_id.clone(), connect, connection_handle);
+                let context = AuthenticationContext::new(
+                    client_id.clone(),
+                    connect.username.clone(),
+                    connect.password.clone(),
+                    certificate.clone(),
+                    remote_addr,
+                );
+                let auth = authenticate(&*authenticator, context).await;
+                debug!("authenticated client: {:?}", auth);
+
+                let connack_properties =
+                    connack_properties(config, &client_id, &connect, properties.as_ref());
+                let mut req = ConnReq::new(client_id.clone(), connect, connection_handle)
+                    .with_certificate(certificate, match_client_id)
+                    .with_auth(auth);
+                if let Some(properties) = properties {
+                    req = req.with_properties(properties);
+                }
                 let event = ClientEvent::ConnReq(req);
                 let message = Message::Client(client_id.clone(), event);
                 broker_handle.send(message).await?;
@@ -130,7 +175,7 @@ where
                 // Start up the processing tasks
                 let incoming_task =
                     incoming_task(client_id.clone(), incoming, broker_handle.clone());
-                let outgoing_task = outgoing_task(client_id.clone(), events, outgoing, broker_handle.clone());
+                let outgoing_task = outgoing_task(client_id.clone(), events, outgoing, broker_handle.clone(), connack_properties);
                 pin_mut!(incoming_task);
                 pin_mut!(outgoing_task);
 
@@ -195,51 +240,108 @@ where
                 .instrument(span)
                 .await
         }
-        Some(Ok(packet)) => Err(ErrorKind::NoConnect(packet).into()),
+        Some(Ok(packet)) => Err(ErrorKind::NoConnect(format!("{:?}", packet)).into()),
         Some(Err(e)) => Err(e.context(ErrorKind::DecodePacket).into()),
         None => Err(ErrorKind::NoPackets.into()),
     }
 }
 
+/// Refuses a client asking for enhanced authentication, which the broker
+/// doesn't support.
+async fn refuse_authentication<S>(outgoing: &mut S) -> Result<(), Error>
+where
+    S: Sink<ServerPacket, Error = EncodeError> + Unpin,
+{
+    let reason = proto::ConnectionRefusedReason::Other(codec::BAD_AUTHENTICATION_METHOD);
+    let connack = proto::ConnAck {
+        session_present: false,
+        return_code: proto::ConnectReturnCode::Refused(reason),
+    };
+    let packet = ServerPacket::ConnAck(connack, ConnAckProperties::default());
+    outgoing
+        .send(packet)
+        .await
+        .context(ErrorKind::EncodePacket)?;
+    Ok(())
+}
+
+/// What an MQTT 5 client is told about its connection in CONNACK: the
+/// session expiry interval the broker caps the requested one to, and the
+/// client id it assigned.
+fn connack_properties(
+    config: &BrokerConfig,
+    client_id: &ClientId,
+    connect: &proto::Connect,
+    properties: Option<&ConnectProperties>,
+) -> ConnAckProperties {
+    let mut connack_properties = ConnAckProperties::default();
+    if let Some(properties) = properties {
+        let requested = properties.session_expiry_interval;
+        let session_expiry_interval = config.session_expiry_interval(Some(requested));
+        if session_expiry_interval != requested {
+            connack_properties.session_expiry_interval = Some(session_expiry_interval);
+        }
+        if connect.client_id == proto::ClientId::ServerGenerated {
+            connack_properties.assigned_client_identifier = Some(client_id.to_string());
+        }
+    }
+    connack_properties
+}
+
+/// Authentication errors refuse the client rather than failing the
+/// connection.
+async fn authenticate(authenticator: &dyn Authenticator, context: AuthenticationContext) -> Auth {
+    match authenticator.authenticate(context).await {
+        Ok(auth) => auth,
+        Err(e) => {
+            warn!(message = "an error occurred authenticating client", error=%e);
+            Auth::NotAuthorized
+        }
+    }
+}
+
 async fn incoming_task<S>(
     client_id: ClientId,
     mut incoming: S,
     mut broker: BrokerHandle,
 ) -> Result<(), Error>
 where
-    S: Stream<Item = Result<Packet, DecodeError>> + Unpin,
+    S: Stream<Item = Result<ClientPacket, DecodeError>> + Unpin,
 {
     debug!("incoming_task start");
     while let Some(maybe_packet) = incoming.next().await {
         match maybe_packet {
             Ok(packet) => {
                 let event = match packet {
-                    Packet::Connect(_) => {
+                    ClientPacket::Connect { .. } => {
                         // [MQTT-3.1.0-2] - The Server MUST process a second CONNECT Packet
                         // sent from a Client as a protocol violation and disconnect the Client.
 
                         warn!("CONNECT packet received on an already established connection, dropping connection due to protocol violation");
-                        return Err(Error::from(ErrorKind::ProtocolViolation));
+                        let event = ClientEvent::ServerDisconnect(DisconnectReason::ProtocolError);
+                        let message = Message::Client(client_id.clone(), event);
+                        broker.send(message).await?;
+                        return Ok(());
                     }
-                    Packet::ConnAck(connack) => ClientEvent::ConnAck(connack),
-                    Packet::Disconnect(disconnect) => {
+                    ClientPacket::Disconnect(disconnect) => {
                         let event = ClientEvent::Disconnect(disconnect);
                         let message = Message::Client(client_id.clone(), event);
                         broker.send(message).await?;
                         debug!("disconnect received. shutting down receive side of connection");
                         return Ok(());
                     }
-                    Packet::PingReq(ping) => ClientEvent::PingReq(ping),
-                    Packet::PingResp(pingresp) => ClientEvent::PingResp(pingresp),
-                    Packet::PubAck(puback) => ClientEvent::PubAck(puback),
-                    Packet::PubComp(pubcomp) => ClientEvent::PubComp(pubcomp),
-                    Packet::Publish(publish) => ClientEvent::PublishFrom(publish),
-                    Packet::PubRec(pubrec) => ClientEvent::PubRec(pubrec),
-                    Packet::PubRel(pubrel) => ClientEvent::PubRel(pubrel),
-                    Packet::Subscribe(subscribe) => ClientEvent::Subscribe(subscribe),
-                    Packet::SubAck(suback) => ClientEvent::SubAck(suback),
-                    Packet::Unsubscribe(unsubscribe) => ClientEvent::Unsubscribe(unsubscribe),
-                    Packet::UnsubAck(unsuback) => ClientEvent::UnsubAck(unsuback),
+                    ClientPacket::PingReq(ping) => ClientEvent::PingReq(ping),
+                    ClientPacket::PubAck(puback) => ClientEvent::PubAck(puback),
+                    ClientPacket::PubComp(pubcomp) => ClientEvent::PubComp(pubcomp),
+                    ClientPacket::Publish(publish, properties) => {
+                        let qos = telemetry::qos_label(publish.packet_identifier_dup_qos);
+                        increment_counter!("mqtt_messages_received_total", "qos" => qos);
+                        ClientEvent::PublishFrom(publish, properties)
+                    }
+                    ClientPacket::PubRec(pubrec) => ClientEvent::PubRec(pubrec),
+                    ClientPacket::PubRel(pubrel) => ClientEvent::PubRel(pubrel),
+                    ClientPacket::Subscribe(subscribe) => ClientEvent::Subscribe(subscribe),
+                    ClientPacket::Unsubscribe(unsubscribe) => ClientEvent::Unsubscribe(unsubscribe),
                 };
 
                 let message = Message::Client(client_id.clone(), event);
@@ -247,6 +349,14 @@ where
             }
             Err(e) => {
                 warn!(message="error occurred while reading from connection", error=%e);
+
+                // The broker closes the connection, telling MQTT 5 clients why
+                if let Some(reason) = e.disconnect_reason() {
+                    let event = ClientEvent::ServerDisconnect(reason);
+                    let message = Message::Client(client_id.clone(), event);
+                    broker.send(message).await?;
+                    return Ok(());
+                }
                 return Err(e.context(ErrorKind::DecodePacket).into());
             }
         }
@@ -264,9 +374,10 @@ async fn outgoing_task<S>(
     mut messages: Receiver<Message>,
     mut outgoing: S,
     mut broker: BrokerHandle,
+    connack_properties: ConnAckProperties,
 ) -> Result<(), (Receiver<Message>, Error)>
 where
-    S: Sink<Packet, Error = EncodeError> + Unpin,
+    S: Sink<ServerPacket, Error = EncodeError> + Unpin,
 {
     debug!("outgoing_task start");
     while let Some(message) = messages.recv().await {
@@ -274,7 +385,9 @@ where
         let maybe_packet = match message {
             Message::Client(_client_id, event) => match event {
                 ClientEvent::ConnReq(_) => None,
-                ClientEvent::ConnAck(connack) => Some(Packet::ConnAck(connack)),
+                ClientEvent::ConnAck(connack) => {
+                    Some(ServerPacket::ConnAck(connack, connack_properties.clone()))
+                }
                 ClientEvent::Disconnect(_) => {
                     debug!("asked to disconnect. outgoing_task completing...");
                     return Ok(());
@@ -283,26 +396,57 @@ where
                     debug!("asked to drop connection. outgoing_task completing...");
                     return Ok(());
                 }
-                ClientEvent::PingReq(req) => Some(Packet::PingReq(req)),
-                ClientEvent::PingResp(response) => Some(Packet::PingResp(response)),
-                ClientEvent::Subscribe(sub) => Some(Packet::Subscribe(sub)),
-                ClientEvent::SubAck(suback) => Some(Packet::SubAck(suback)),
-                ClientEvent::Unsubscribe(unsub) => Some(Packet::Unsubscribe(unsub)),
-                ClientEvent::UnsubAck(unsuback) => Some(Packet::UnsubAck(unsuback)),
-                ClientEvent::PublishTo(Publish::QoS12(_id, publish)) => {
-                    Some(Packet::Publish(publish))
+                ClientEvent::ServerDisconnect(reason) => {
+                    debug!(
+                        "disconnected by the broker: {:?}. outgoing_task completing...",
+                        reason
+                    );
+                    if let Err(e) = outgoing.send(ServerPacket::Disconnect(reason)).await {
+                        debug!(message = "error occurred while sending DISCONNECT", error=%e);
+                    }
+                    return Ok(());
                 }
-                ClientEvent::PublishTo(Publish::QoS0(id, publish)) => {
-                    let result = outgoing
-                        .send(Packet::Publish(publish))
+                ClientEvent::PingResp(response) => Some(ServerPacket::PingResp(response)),
+                ClientEvent::SubAck(suback) => Some(ServerPacket::SubAck(suback)),
+                ClientEvent::UnsubAck(unsuback) => Some(ServerPacket::UnsubAck(unsuback)),
+                ClientEvent::PublishTo(publish) => {
+                    // QoS 0 publications are acknowledged to the broker once written
+                    let (ack, publish, properties) = match publish {
+                        Publish::QoS0(id, publish, properties) => {
+                            (Some(ClientEvent::PubAck0(id)), publish, properties)
+                        }
+                        Publish::QoS12(_id, publish, properties) => (None, publish, properties),
+                    };
+                    let qos = telemetry::qos_label(publish.packet_identifier_dup_qos);
+                    increment_counter!("mqtt_messages_sent_total", "qos" => qos);
+
+                    // [MQTT-3.1.2-25] - A publication larger than the client accepts is
+                    // discarded as if it had been delivered.
+                    let discarded_ack = match publish.packet_identifier_dup_qos {
+                        proto::PacketIdentifierDupQoS::AtMostOnce => None,
+                        proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _)
+                        | proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => {
+                            Some(ClientEvent::PubAck(proto::PubAck { packet_identifier }))
+                        }
+                    };
+
+                    let ack = match outgoing
+                        .send(ServerPacket::Publish(publish, properties))
                         .await
-                        .context(ErrorKind::EncodePacket);
+                    {
+                        Ok(()) => ack,
+                        Err(EncodeError::PacketTooLarge(size)) => {
+                            debug!("discarding publication of {} bytes, which is larger than the client accepts", size);
+                            ack.or(discarded_ack)
+                        }
+                        Err(e) => {
+                            warn!(message = "error occurred while writing to connection", error=%e);
+                            return Err((messages, e.context(ErrorKind::EncodePacket).into()));
+                        }
+                    };
 
-                    if let Err(e) = result {
-                        warn!(message = "error occurred while writing to connection", error=%e);
-                        return Err((messages, e.into()));
-                    } else {
-                        let message = Message::Client(client_id.clone(), ClientEvent::PubAck0(id));
+                    if let Some(ack) = ack {
+                        let message = Message::Client(client_id.clone(), ack);
                         if let Err(e) = broker.send(message).await {
                             warn!(message = "error occurred while sending QoS ack to broker", error=%e);
                             return Err((messages, e));
@@ -310,10 +454,13 @@ where
                     }
                     None
                 }
-                ClientEvent::PubAck(puback) => Some(Packet::PubAck(puback)),
-                ClientEvent::PubRec(pubrec) => Some(Packet::PubRec(pubrec)),
-                ClientEvent::PubRel(pubrel) => Some(Packet::PubRel(pubrel)),
-                ClientEvent::PubComp(pubcomp) => Some(Packet::PubComp(pubcomp)),
+                ClientEvent::PubAck(puback) => Some(ServerPacket::PubAck(puback)),
+                ClientEvent::PubRec(pubrec) => Some(ServerPacket::PubRec(pubrec)),
+                ClientEvent::PublishNotAuthorized(packet_identifier, qos) => {
+                    Some(ServerPacket::PublishNotAuthorized(packet_identifier, qos))
+                }
+                ClientEvent::PubRel(pubrel) => Some(ServerPacket::PubRel(pubrel)),
+                ClientEvent::PubComp(pubcomp) => Some(ServerPacket::PubComp(pubcomp)),
                 event => {
                     warn!("ignoring event for outgoing_task: {:?}", event);
                     None
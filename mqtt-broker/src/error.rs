@@ -1,7 +1,6 @@
 use std::fmt;
 
 use failure::{Backtrace, Context, Fail};
-use mqtt::proto::Packet;
 
 #[derive(Debug)]
 pub struct Error {
@@ -16,12 +15,18 @@ pub enum ErrorKind {
     #[fail(display = "An error occurred sending a message to the broker.")]
     SendBrokerMessage,
 
+    #[fail(display = "The broker didn't reply to a request.")]
+    ReceiveBrokerReply,
+
     #[fail(display = "An error occurred sending a message to a connection.")]
     SendConnectionMessage,
 
     #[fail(display = "An error occurred binding the server's listening socket.")]
     BindServer,
 
+    #[fail(display = "An error occurred setting the permissions of a Unix socket.")]
+    SocketPermissions,
+
     #[fail(display = "An error occurred getting a connection's peer address.")]
     ConnectionPeerAddress,
 
@@ -34,8 +39,8 @@ pub enum ErrorKind {
     #[fail(display = "An error occurred encoding a packet.")]
     EncodePacket,
 
-    #[fail(display = "Expected CONNECT packet as first packet, received {:?}", _0)]
-    NoConnect(Packet),
+    #[fail(display = "Expected CONNECT packet as first packet, received {}", _0)]
+    NoConnect(String),
 
     #[fail(display = "Connection closed before any packets received.")]
     NoPackets,
@@ -46,6 +51,9 @@ pub enum ErrorKind {
     #[fail(display = "Session is offline.")]
     SessionOffline,
 
+    #[fail(display = "Session is connected.")]
+    SessionConnected,
+
     #[fail(display = "MQTT protocol violation occurred.")]
     ProtocolViolation,
 
@@ -57,6 +65,81 @@ pub enum ErrorKind {
 
     #[fail(display = "An error occurred joining the broker task.")]
     BrokerJoin,
+
+    #[fail(display = "An error occurred creating the state directory {}.", _0)]
+    CreateStateDir(String),
+
+    #[fail(display = "An error occurred loading broker state.")]
+    LoadState,
+
+    #[fail(display = "An error occurred storing broker state.")]
+    StoreState,
+
+    #[fail(display = "Broker state is corrupt.")]
+    CorruptState,
+
+    #[fail(display = "Unsupported broker state format version {}.", _0)]
+    UnsupportedStateVersion(u32),
+
+    #[fail(display = "An error occurred accessing the journal.")]
+    Journal,
+
+    #[fail(display = "Journal is corrupt.")]
+    CorruptJournal,
+
+    #[fail(display = "An error occurred loading a TLS certificate.")]
+    LoadTlsCertificate,
+
+    #[fail(display = "An error occurred loading a TLS private key.")]
+    LoadTlsPrivateKey,
+
+    #[fail(display = "An error occurred configuring TLS.")]
+    TlsConfiguration,
+
+    #[fail(display = "An error occurred during a TLS handshake.")]
+    TlsHandshake,
+
+    #[fail(display = "An error occurred during a WebSocket handshake.")]
+    WebSocketHandshake,
+
+    #[fail(display = "An error occurred loading the TLS client CA certificates.")]
+    LoadTlsClientCa,
+
+    #[fail(display = "An error occurred reading a client certificate.")]
+    ClientCertificate,
+
+    #[fail(display = "An error occurred loading the password file.")]
+    LoadPasswordFile,
+
+    #[fail(display = "Password file is invalid at line {}.", _0)]
+    InvalidPasswordFile(usize),
+
+    #[fail(display = "An error occurred hashing a password.")]
+    HashPassword,
+
+    #[fail(display = "An error occurred loading the ACL file.")]
+    LoadAclFile,
+
+    #[fail(display = "ACL file is invalid at line {}.", _0)]
+    InvalidAclFile(usize),
+
+    #[fail(display = "An error occurred loading the config file.")]
+    LoadConfig,
+
+    #[fail(display = "Config file is invalid.")]
+    InvalidConfig,
+
+    #[fail(display = "Invalid command line arguments.")]
+    InvalidArguments,
+
+    #[fail(display = "An error occurred installing the metrics recorder.")]
+    InstallMetricsRecorder,
+
+    #[fail(display = "An error occurred sending a message to the snapshotter.")]
+    SendSnapshotMessage,
+
+    #[fail(display = "An error occurred joining the snapshotter task.")]
+    SnapshotJoin,
 }
 
 impl Fail for Error {
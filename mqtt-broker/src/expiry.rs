@@ -0,0 +1,42 @@
+use std::cmp;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time;
+use tracing::debug;
+
+use crate::{BrokerConfig, BrokerHandle, Message, SystemEvent};
+
+/// Long expiry times are still checked this often, so sessions and
+/// retained messages don't outlive their expiry by much.
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Asks the broker to remove expired offline sessions and retained
+/// messages, following the `session_expiry` and `message_expiry` of its
+/// config.
+///
+/// MQTT 5 clients pick the expiry of their sessions and messages, and may
+/// have their will held back, so the broker is asked to sweep even when
+/// its config expires nothing.
+///
+/// Runs until the broker stops accepting messages.
+pub(crate) async fn expiry_timer(
+    config: watch::Receiver<BrokerConfig>,
+    mut broker_handle: BrokerHandle,
+) {
+    loop {
+        let session_expiry = config.borrow().session_expiry();
+        let period = if session_expiry == Duration::from_secs(0) {
+            MAX_SWEEP_INTERVAL
+        } else {
+            cmp::min(session_expiry, MAX_SWEEP_INTERVAL)
+        };
+        time::delay_for(period).await;
+
+        let message = Message::System(SystemEvent::Expire);
+        if let Err(e) = broker_handle.send(message).await {
+            debug!(message = "stopping expiry timer", error=%e);
+            break;
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use failure::ResultExt;
+use mqtt::proto;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::serialize;
+use crate::session::SessionState;
+use crate::subscription::Subscription;
+use crate::{ClientId, Error, ErrorKind, Publication, PublicationId};
+
+static SEGMENT_PREFIX: &str = "journal-";
+static SEGMENT_SUFFIX: &str = ".log";
+
+const SEGMENT_MAGIC: &[u8; 4] = b"MQTJ";
+const SEGMENT_VERSION: u32 = 3;
+
+/// magic (4) + format version (4)
+const SEGMENT_HEADER_LEN: usize = 8;
+
+/// length (4) + checksum (4)
+const RECORD_HEADER_LEN: usize = 8;
+
+/// A change to broker state made between snapshots.
+///
+/// Replaying the entries in order on top of a snapshot rebuilds the
+/// persistent sessions, their subscriptions and the messages queued for
+/// them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum JournalEntry {
+    /// A publication made by the broker or accepted from a client, unless
+    /// it's a `PublishFrom`, with the deadline its retained and queued
+    /// copies expire at
+    Publish(
+        PublicationId,
+        Publication,
+        #[serde(with = "serialize::deadline")] Option<Instant>,
+    ),
+
+    /// A publication accepted from a client to be delivered exactly once.
+    /// The client's session keeps the packet identifier until the client
+    /// releases it
+    PublishFrom(
+        ClientId,
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        PublicationId,
+        Publication,
+        #[serde(with = "serialize::deadline")] Option<Instant>,
+    ),
+
+    /// A QoS 1 publish acknowledged by a persistent session
+    PubAck(
+        ClientId,
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        PublicationId,
+    ),
+
+    /// A QoS 2 publish received by a persistent session
+    PubRec(
+        ClientId,
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        PublicationId,
+    ),
+
+    /// A QoS 2 publish completed by a persistent session
+    PubComp(
+        ClientId,
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+    ),
+
+    /// A publication released by the persistent session it came from
+    PubRel(
+        ClientId,
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+    ),
+
+    /// A persistent session created for a client, with the state it
+    /// started with
+    OpenSession(SessionState),
+
+    /// A persistent session removed by its client connecting with a clean
+    /// session, by expiry or by an administrator
+    RemoveSession(ClientId),
+
+    /// The expiry interval an MQTT 5 client set for its persistent session
+    /// when it reconnected or disconnected
+    SessionExpiry(ClientId, Option<Duration>),
+
+    /// Subscriptions made by a persistent session
+    Subscribe(ClientId, Vec<Subscription>),
+
+    /// Topic filters a persistent session unsubscribed from
+    Unsubscribe(ClientId, Vec<String>),
+
+    /// The retained message of a topic removed by an administrator
+    RemoveRetained(String),
+}
+
+/// Append-only log of the changes to broker state made since the last
+/// snapshot.
+///
+/// The journal is split into numbered segments. Taking a snapshot rotates
+/// the journal to a new segment and records the last segment it covers,
+/// so on startup only the newer segments are replayed on top of it.
+/// Segments covered by a persisted snapshot are removed by a `Compactor`.
+///
+/// Entries are written to the OS as they are appended, so they survive the
+/// process crashing. Segments are synced to disk when they are rotated.
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    after: u64,
+    segment: u64,
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal in `dir`, ignoring segments up to and including
+    /// `after` as they are already covered by a snapshot.
+    ///
+    /// Appends always go to a new segment.
+    pub fn open<P: Into<PathBuf>>(dir: P, after: u64) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context(ErrorKind::Journal)?;
+
+        let last = segments(&dir)?.last().copied().unwrap_or_default();
+        let segment = last.max(after) + 1;
+        let file = create_segment(&dir, segment)?;
+
+        Ok(Self {
+            dir,
+            after,
+            segment,
+            file,
+        })
+    }
+
+    /// The segment entries are currently appended to.
+    pub fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    pub fn compactor(&self) -> Compactor {
+        Compactor {
+            dir: self.dir.clone(),
+        }
+    }
+
+    /// Reads the entries that are not covered by a snapshot, in the order
+    /// they were appended.
+    ///
+    /// A record torn by a crash ends its segment.
+    pub fn entries(&self) -> Result<Vec<JournalEntry>, Error> {
+        let mut entries = Vec::new();
+        for segment in segments(&self.dir)? {
+            if segment <= self.after || segment >= self.segment {
+                continue;
+            }
+
+            let path = segment_path(&self.dir, segment);
+            let mut buf = Vec::new();
+            File::open(&path)
+                .and_then(|mut file| file.read_to_end(&mut buf))
+                .context(ErrorKind::Journal)?;
+
+            if buf.len() < SEGMENT_HEADER_LEN
+                || &buf[0..4] != SEGMENT_MAGIC
+                || buf[4..8] != SEGMENT_VERSION.to_le_bytes()
+            {
+                warn!(message = "ignoring a journal segment written in another format", path = %path.display());
+                continue;
+            }
+
+            let count = entries.len();
+            if let Err(e) = read_records(&buf[SEGMENT_HEADER_LEN..], &mut entries) {
+                warn!(message = "ignoring the rest of a journal segment", path = %path.display(), error = %e);
+            }
+            debug!(
+                "read {} entries from {}",
+                entries.len() - count,
+                path.display()
+            );
+        }
+        Ok(entries)
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<(), Error> {
+        let payload = bincode::serialize(entry).context(ErrorKind::Journal)?;
+        let len: u32 = payload
+            .len()
+            .try_into()
+            .map_err(|_| Error::from(ErrorKind::Journal))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        self.file.write_all(&record).context(ErrorKind::Journal)?;
+        Ok(())
+    }
+
+    /// Syncs the current segment and starts a new one.
+    ///
+    /// Returns the last segment holding entries appended before the call.
+    pub fn rotate(&mut self) -> Result<u64, Error> {
+        self.sync()?;
+
+        let previous = self.segment;
+        self.file = create_segment(&self.dir, previous + 1)?;
+        self.segment = previous + 1;
+
+        debug!("rotated journal to segment {}", self.segment);
+        Ok(previous)
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_all().context(ErrorKind::Journal)?;
+        Ok(())
+    }
+}
+
+/// Removes journal segments once a snapshot covering them is persisted.
+#[derive(Clone, Debug)]
+pub struct Compactor {
+    dir: PathBuf,
+}
+
+impl Compactor {
+    /// Removes every segment up to and including `through`.
+    pub fn compact(&self, through: u64) -> Result<(), Error> {
+        for segment in segments(&self.dir)? {
+            if segment > through {
+                break;
+            }
+            fs::remove_file(segment_path(&self.dir, segment)).context(ErrorKind::Journal)?;
+        }
+        info!("compacted journal through segment {}", through);
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{:020}{}",
+        SEGMENT_PREFIX, segment, SEGMENT_SUFFIX
+    ))
+}
+
+/// Segments start with a header holding the format version of their
+/// entries.
+fn create_segment(dir: &Path, segment: u64) -> Result<File, Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, segment))
+        .context(ErrorKind::Journal)?;
+
+    let mut header = Vec::with_capacity(SEGMENT_HEADER_LEN);
+    header.extend_from_slice(SEGMENT_MAGIC);
+    header.extend_from_slice(&SEGMENT_VERSION.to_le_bytes());
+    file.write_all(&header).context(ErrorKind::Journal)?;
+    Ok(file)
+}
+
+/// Returns the existing segment numbers in ascending order.
+fn segments(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir).context(ErrorKind::Journal)? {
+        let entry = entry.context(ErrorKind::Journal)?;
+        let name = entry.file_name();
+        let segment = name
+            .to_str()
+            .filter(|name| name.starts_with(SEGMENT_PREFIX) && name.ends_with(SEGMENT_SUFFIX))
+            .and_then(|name| {
+                name[SEGMENT_PREFIX.len()..name.len() - SEGMENT_SUFFIX.len()]
+                    .parse()
+                    .ok()
+            });
+        if let Some(segment) = segment {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+fn read_records(mut buf: &[u8], entries: &mut Vec<JournalEntry>) -> Result<(), Error> {
+    while !buf.is_empty() {
+        if buf.len() < RECORD_HEADER_LEN {
+            return Err(Error::from(ErrorKind::CorruptJournal));
+        }
+
+        let len = u32::from_le_bytes(buf[0..4].try_into().expect("slice is 4 bytes")) as usize;
+        let checksum = u32::from_le_bytes(buf[4..8].try_into().expect("slice is 4 bytes"));
+        let rest = &buf[RECORD_HEADER_LEN..];
+        if rest.len() < len || crc32fast::hash(&rest[..len]) != checksum {
+            return Err(Error::from(ErrorKind::CorruptJournal));
+        }
+
+        let entry = bincode::deserialize(&rest[..len]).context(ErrorKind::CorruptJournal)?;
+        entries.push(entry);
+        buf = &rest[len..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    use crate::PublicationProperties;
+
+    fn publish(payload: &'static str) -> JournalEntry {
+        let publication = Publication {
+            topic_name: "topic/journal".to_string(),
+            qos: proto::QoS::AtLeastOnce,
+            retain: false,
+            payload: payload.into(),
+            properties: PublicationProperties::default(),
+        };
+        JournalEntry::Publish(PublicationId::default(), publication, None)
+    }
+
+    fn payloads(entries: &[JournalEntry]) -> Vec<&[u8]> {
+        entries
+            .iter()
+            .map(|entry| match entry {
+                JournalEntry::Publish(_, publication, _) => publication.payload.as_ref(),
+                _ => panic!("unexpected entry {:?}", entry),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entries_after_snapshot() {
+        let dir = TempDir::new().unwrap();
+
+        let mut journal = Journal::open(dir.path(), 0).unwrap();
+        journal.append(&publish("1")).unwrap();
+        let snapshot = journal.rotate().unwrap();
+        journal.append(&publish("2")).unwrap();
+        journal.append(&publish("3")).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(vec![b"1", b"2", b"3"], payloads(&entries));
+
+        let journal = Journal::open(dir.path(), snapshot).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(vec![b"2", b"3"], payloads(&entries));
+    }
+
+    #[test]
+    fn test_torn_record_ends_segment() {
+        let dir = TempDir::new().unwrap();
+
+        let mut journal = Journal::open(dir.path(), 0).unwrap();
+        journal.append(&publish("1")).unwrap();
+        journal.append(&publish("2")).unwrap();
+        let segment = journal.segment();
+        drop(journal);
+
+        let path = segment_path(dir.path(), segment);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(vec![b"1"], payloads(&entries));
+    }
+
+    #[test]
+    fn test_other_format_is_ignored() {
+        let dir = TempDir::new().unwrap();
+
+        let mut journal = Journal::open(dir.path(), 0).unwrap();
+        journal.append(&publish("1")).unwrap();
+        let old = journal.rotate().unwrap();
+        journal.append(&publish("2")).unwrap();
+        drop(journal);
+
+        let path = segment_path(dir.path(), old);
+        let mut buf = fs::read(&path).unwrap();
+        buf[4..8].copy_from_slice(&(SEGMENT_VERSION + 1).to_le_bytes());
+        fs::write(&path, buf).unwrap();
+
+        let journal = Journal::open(dir.path(), 0).unwrap();
+        assert_eq!(vec![b"2"], payloads(&journal.entries().unwrap()));
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = TempDir::new().unwrap();
+
+        let mut journal = Journal::open(dir.path(), 0).unwrap();
+        journal.append(&publish("1")).unwrap();
+        let snapshot = journal.rotate().unwrap();
+        journal.append(&publish("2")).unwrap();
+
+        journal.compactor().compact(snapshot).unwrap();
+        assert_eq!(vec![journal.segment()], segments(dir.path()).unwrap());
+
+        // Segments numbers keep increasing after compaction
+        drop(journal);
+        let journal = Journal::open(dir.path(), snapshot).unwrap();
+        assert_eq!(snapshot + 2, journal.segment());
+        assert_eq!(vec![b"2"], payloads(&journal.entries().unwrap()));
+    }
+}
@@ -10,23 +10,56 @@
 )]
 
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use mqtt::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 
+mod admin;
+mod auth;
+mod authorization;
 mod broker;
+mod codec;
+mod config;
 mod connection;
 mod error;
+mod expiry;
+mod journal;
+mod password;
+mod persist;
+mod queue;
+mod retained;
+mod serialize;
 mod server;
 mod session;
+mod snapshot;
 mod subscription;
-
-pub use crate::broker::BrokerState;
+mod sys;
+mod telemetry;
+mod tls;
+mod websocket;
+
+pub use crate::admin::{AdminHandle, SessionInfo, SessionStatus};
+pub use crate::auth::{AllowAll, Auth, AuthId, AuthenticationContext, Authenticator};
+pub use crate::authorization::{Access, AclAuthorizer, Authorizer, Operation};
+pub use crate::broker::{Broker, BrokerHandle, BrokerState};
+pub use crate::config::{BrokerConfig, ConfigUpdate, OverflowPolicy, TopicExpiry};
 pub use crate::connection::ConnectionHandle;
 pub use crate::error::{Error, ErrorKind};
-pub use crate::server::Server;
-
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub use crate::journal::{Compactor, Journal, JournalEntry};
+pub use crate::password::{PasswordFileAuthenticator, PasswordHash};
+pub use crate::persist::{FilePersistor, Persist};
+pub use crate::server::{Listener, Server};
+pub use crate::snapshot::{snapshot_timer, Snapshotter, StateSnapshotHandle};
+pub use crate::subscription::{Subscription, TopicFilter};
+pub use crate::telemetry::register_metrics;
+pub use crate::tls::{CertificateIdentity, ClientAuth, TlsConfig};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
 pub struct ClientId(Arc<String>);
 
 impl ClientId {
@@ -47,11 +80,185 @@ impl From<String> for ClientId {
     }
 }
 
+/// Identifies a publication routed by the broker.
+///
+/// The copies queued or sent to each session keep the id of the publication,
+/// so acknowledgements replayed from the journal remove the right message.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize,
+)]
+pub struct PublicationId(u64);
+
+impl PublicationId {
+    /// The id after this one.
+    pub fn next(self) -> Self {
+        PublicationId(self.0 + 1)
+    }
+}
+
+/// A message published to a topic, by a client or by the broker itself.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Publication {
+    pub topic_name: String,
+    #[serde(with = "serialize::QoSDef")]
+    pub qos: proto::QoS,
+    pub retain: bool,
+    pub payload: Bytes,
+    pub properties: PublicationProperties,
+}
+
+impl From<proto::Publication> for Publication {
+    fn from(publication: proto::Publication) -> Self {
+        Self {
+            topic_name: publication.topic_name,
+            qos: publication.qos,
+            retain: publication.retain,
+            payload: publication.payload,
+            properties: PublicationProperties::default(),
+        }
+    }
+}
+
+/// The properties an MQTT 5 client can publish a message with. They are
+/// passed on to subscribers connected with MQTT 5, and are empty for
+/// messages published with an older version.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PublicationProperties {
+    /// Whether the payload is UTF-8 encoded character data
+    pub payload_format_indicator: bool,
+
+    /// When the message expires, from the message expiry interval it was
+    /// published with
+    #[serde(with = "serialize::deadline")]
+    pub message_expiry: Option<Instant>,
+
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Bytes>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+/// A version of the MQTT protocol a client can connect with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1, protocol name `MQIsdp` and level 3
+    V31,
+
+    /// MQTT 3.1.1, protocol name `MQTT` and level 4
+    V311,
+
+    /// MQTT 5, protocol name `MQTT` and level 5
+    V5,
+}
+
+impl ProtocolVersion {
+    /// Returns the version for the protocol name and level of a CONNECT
+    /// packet, if the broker knows it.
+    pub fn from_connect(protocol_name: &str, protocol_level: u8) -> Option<Self> {
+        match (protocol_name, protocol_level) {
+            ("MQIsdp", 0x3) => Some(ProtocolVersion::V31),
+            ("MQTT", 0x4) => Some(ProtocolVersion::V311),
+            ("MQTT", 0x5) => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolVersion::V31 => write!(f, "MQTT 3.1"),
+            ProtocolVersion::V311 => write!(f, "MQTT 3.1.1"),
+            ProtocolVersion::V5 => write!(f, "MQTT 5"),
+        }
+    }
+}
+
+/// The address of the client on the other end of a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerAddr {
+    /// A TCP connection, which includes TLS and WebSocket connections
+    Tcp(SocketAddr),
+
+    /// A Unix domain socket connection, with the credentials of the peer
+    /// process when they could be read
+    Unix(Option<PeerCredentials>),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(Some(credentials)) => write!(
+                f,
+                "unix:uid={},gid={}",
+                credentials.uid(),
+                credentials.gid()
+            ),
+            PeerAddr::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
+    }
+}
+
+/// The user and group of the process on the other end of a Unix socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCredentials {
+    uid: u32,
+    gid: u32,
+}
+
+impl PeerCredentials {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self { uid, gid }
+    }
+
+    pub fn uid(self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(self) -> u32 {
+        self.gid
+    }
+}
+
+/// What an MQTT 5 client connects with on top of the CONNECT packet of
+/// older versions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectProperties {
+    /// How long the session is kept after the client disconnects. Zero
+    /// ends the session along with the connection.
+    pub session_expiry_interval: Duration,
+
+    /// How many at least once and exactly once messages the client
+    /// handles at a time
+    pub receive_maximum: Option<u16>,
+
+    /// The properties of the will
+    pub will_properties: PublicationProperties,
+
+    /// How long the will is kept once it is published, if it expires
+    pub will_message_expiry_interval: Option<Duration>,
+
+    /// How long the will is held back after the connection is lost. The
+    /// will isn't published if the client reconnects in the meantime.
+    pub will_delay_interval: Duration,
+}
+
 #[derive(Debug)]
 pub struct ConnReq {
     client_id: ClientId,
     connect: proto::Connect,
+    properties: Option<ConnectProperties>,
     handle: ConnectionHandle,
+    certificate: Option<CertificateIdentity>,
+    match_client_id: bool,
+    auth: Auth,
 }
 
 impl ConnReq {
@@ -59,10 +266,41 @@ impl ConnReq {
         Self {
             client_id,
             connect,
+            properties: None,
             handle,
+            certificate: None,
+            match_client_id: false,
+            auth: Auth::Anonymous,
         }
     }
 
+    /// Adds the properties of an MQTT 5 CONNECT packet.
+    pub fn with_properties(mut self, properties: ConnectProperties) -> Self {
+        self.properties = Some(properties);
+        self
+    }
+
+    /// Sets the outcome of authenticating the client.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Attaches the identity of the client certificate presented when the
+    /// connection was established.
+    ///
+    /// With `match_client_id` the client id has to match the certificate
+    /// for the connection to be accepted.
+    pub fn with_certificate(
+        mut self,
+        certificate: Option<CertificateIdentity>,
+        match_client_id: bool,
+    ) -> Self {
+        self.certificate = certificate;
+        self.match_client_id = match_client_id;
+        self
+    }
+
     pub fn client_id(&self) -> &ClientId {
         &self.client_id
     }
@@ -71,6 +309,39 @@ impl ConnReq {
         &self.connect
     }
 
+    /// The properties the client connected with, if it connected with
+    /// MQTT 5.
+    pub fn properties(&self) -> Option<&ConnectProperties> {
+        self.properties.as_ref()
+    }
+
+    /// The will of the client along with its properties.
+    pub fn will(&self) -> Option<Publication> {
+        let mut will = Publication::from(self.connect.will.clone()?);
+        if let Some(properties) = &self.properties {
+            will.properties = properties.will_properties.clone();
+        }
+        Some(will)
+    }
+
+    /// The protocol version the client asked for, or `None` if the broker
+    /// doesn't know it.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::from_connect(&self.connect.protocol_name, self.connect.protocol_level)
+    }
+
+    pub fn certificate(&self) -> Option<&CertificateIdentity> {
+        self.certificate.as_ref()
+    }
+
+    pub fn match_client_id(&self) -> bool {
+        self.match_client_id
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
     pub fn handle(&self) -> &ConnectionHandle {
         &self.handle
     }
@@ -88,10 +359,57 @@ impl ConnReq {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Publish {
-    QoS0(proto::PacketIdentifier, proto::Publish),
-    QoS12(proto::PacketIdentifier, proto::Publish),
+    QoS0(
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        #[serde(with = "serialize::PublishDef")] proto::Publish,
+        PublicationProperties,
+    ),
+    QoS12(
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        #[serde(with = "serialize::PublishDef")] proto::Publish,
+        PublicationProperties,
+    ),
+}
+
+/// A DISCONNECT packet from a client. MQTT 5 clients can change the
+/// expiry interval of their session as they disconnect, and can ask for
+/// their will to be published anyway.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Disconnect {
+    pub session_expiry_interval: Option<Duration>,
+    pub publish_will: bool,
+}
+
+/// Why the broker closes a connection. MQTT 5 clients are sent a
+/// DISCONNECT packet with the reason before the connection is closed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client sent a packet that couldn't be decoded
+    MalformedPacket,
+
+    /// The client sent a packet that isn't allowed
+    ProtocolError,
+
+    /// Nothing was received from the client for longer than its keep alive
+    KeepAliveTimeout,
+
+    /// The client published with a topic alias it hadn't set, or that is
+    /// out of range
+    TopicAliasInvalid,
+
+    /// The client subscribed with a subscription identifier
+    SubscriptionIdentifiersNotSupported,
+
+    /// Another connection took over the session of the client
+    SessionTakenOver,
+
+    /// The broker is shutting down
+    ServerShuttingDown,
+
+    /// An administrator disconnected the client
+    AdministrativeAction,
 }
 
 #[derive(Debug)]
@@ -103,11 +421,15 @@ pub enum ClientEvent {
     ConnAck(proto::ConnAck),
 
     /// Graceful disconnect request
-    Disconnect(proto::Disconnect),
+    Disconnect(Disconnect),
 
     /// Non-graceful disconnect request,
     DropConnection,
 
+    /// Disconnect by the broker - the connection is closed like with
+    /// DropConnection, after telling MQTT 5 clients why
+    ServerDisconnect(DisconnectReason),
+
     /// Close session - connection is already closed but session needs clean up
     CloseSession,
 
@@ -130,7 +452,7 @@ pub enum ClientEvent {
     UnsubAck(proto::UnsubAck),
 
     /// PublishFrom - publish packet from a client
-    PublishFrom(proto::Publish),
+    PublishFrom(proto::Publish, PublicationProperties),
 
     /// PublishTo - publish packet to a client
     PublishTo(Publish),
@@ -138,6 +460,11 @@ pub enum ClientEvent {
     /// Publish acknowledgement (QoS 0)
     PubAck0(proto::PacketIdentifier),
 
+    /// Acknowledgement of a QoS 1 or QoS 2 publication the client isn't
+    /// authorized to make, which is dropped. MQTT 5 clients are told
+    /// with the reason code of the PUBACK or PUBREC.
+    PublishNotAuthorized(proto::PacketIdentifier, proto::QoS),
+
     /// Publish acknowledgement (QoS 1)
     PubAck(proto::PubAck),
 
@@ -154,8 +481,18 @@ pub enum ClientEvent {
 #[derive(Debug)]
 pub enum SystemEvent {
     Shutdown,
-    // StateSnapshot,
-    // ConfigUpdate,
+    StateSnapshot(StateSnapshotHandle),
+    ConfigUpdate(ConfigUpdate),
+    PublishStats,
+    Expire,
+
+    // Requests of an `AdminHandle`, answered on the sender
+    ListSessions(oneshot::Sender<Vec<SessionInfo>>),
+    DisconnectClient(ClientId, oneshot::Sender<Result<(), Error>>),
+    DeleteSession(ClientId, oneshot::Sender<Result<(), Error>>),
+    ListRetained(oneshot::Sender<Vec<Publication>>),
+    DeleteRetained(String, oneshot::Sender<Result<bool, Error>>),
+    Publish(Publication, oneshot::Sender<Result<(), Error>>),
 }
 
 #[derive(Debug)]
@@ -166,8 +503,39 @@ pub enum Message {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_protocol_version() {
+        assert_eq!(
+            Some(ProtocolVersion::V311),
+            ProtocolVersion::from_connect("MQTT", 0x4)
+        );
+        assert_eq!(
+            Some(ProtocolVersion::V31),
+            ProtocolVersion::from_connect("MQIsdp", 0x3)
+        );
+
+        assert_eq!(
+            Some(ProtocolVersion::V5),
+            ProtocolVersion::from_connect("MQTT", 0x5)
+        );
+        assert_eq!(None, ProtocolVersion::from_connect("MQTT", 0x3));
+        assert_eq!(None, ProtocolVersion::from_connect("MQIsdp", 0x4));
+    }
+
+    #[test]
+    fn test_peer_addr() {
+        let addr = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1883)));
+        assert_eq!("127.0.0.1:1883", addr.to_string());
+
+        let addr = PeerAddr::Unix(Some(PeerCredentials::new(1000, 100)));
+        assert_eq!("unix:uid=1000,gid=100", addr.to_string());
+        assert_eq!("unix", PeerAddr::Unix(None).to_string());
+    }
 }
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use failure::ResultExt;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::task;
+use tracing::{debug, info};
+
+use crate::auth::{Auth, AuthId, AuthenticationContext, Authenticator};
+use crate::{Error, ErrorKind};
+
+/// Prefix of a PBKDF2-SHA512 hash, as written by `mosquitto_passwd`.
+static PBKDF2_SHA512_PREFIX: &str = "$7$";
+
+const DEFAULT_ITERATIONS: u32 = 10_000;
+const SALT_LEN: usize = 12;
+const HASH_LEN: usize = 64;
+
+/// A salted PBKDF2-SHA512 password hash.
+///
+/// The text form is `$7$<iterations>$<base64 salt>$<base64 hash>`, the same
+/// as the hashes in a mosquitto password file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hashes `password` with a random salt.
+    pub fn generate(password: &str) -> Result<Self, Error> {
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| Error::from(ErrorKind::HashPassword))?;
+
+        let iterations = NonZeroU32::new(DEFAULT_ITERATIONS).expect("iterations is not zero");
+        let mut hash = vec![0; HASH_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA512,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+
+        Ok(Self {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    /// Parses the text form of a hash, returning `None` if it is malformed
+    /// or uses another hash function.
+    pub fn parse(s: &str) -> Option<Self> {
+        if !s.starts_with(PBKDF2_SHA512_PREFIX) {
+            return None;
+        }
+
+        let mut parts = s[PBKDF2_SHA512_PREFIX.len()..].split('$');
+        let iterations = parts.next()?.parse().ok().and_then(NonZeroU32::new)?;
+        let salt = base64::decode(parts.next()?).ok()?;
+        let hash = base64::decode(parts.next()?).ok()?;
+        if parts.next().is_some() || salt.is_empty() || hash.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    /// A hash no password matches, checked for unknown users so they take
+    /// as long to refuse as a wrong password.
+    fn dummy() -> Self {
+        Self {
+            iterations: NonZeroU32::new(DEFAULT_ITERATIONS).expect("iterations is not zero"),
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; HASH_LEN],
+        }
+    }
+
+    /// Checks `password` against the hash in constant time.
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA512,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}${}${}",
+            PBKDF2_SHA512_PREFIX,
+            self.iterations,
+            base64::encode(&self.salt),
+            base64::encode(&self.hash)
+        )
+    }
+}
+
+/// Authenticates clients by username and password against a password file.
+///
+/// Each line of the file is `username:hash`, where the hash is in the form
+/// written by `PasswordHash`. Blank lines and lines starting with `#` are
+/// ignored. This is compatible with password files created by
+/// `mosquitto_passwd` from mosquitto 2.0.
+///
+/// Clients connecting without a username are not authorized. Clones share
+/// the credentials, so a clone can be kept to `reload` the file while the
+/// server is running.
+#[derive(Clone, Debug)]
+pub struct PasswordFileAuthenticator {
+    path: PathBuf,
+    credentials: Arc<RwLock<HashMap<String, PasswordHash>>>,
+}
+
+impl PasswordFileAuthenticator {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let path = path.into();
+        let credentials = read_password_file(&path)?;
+        info!(
+            "loaded {} users from password file {}",
+            credentials.len(),
+            path.display()
+        );
+
+        Ok(Self {
+            path,
+            credentials: Arc::new(RwLock::new(credentials)),
+        })
+    }
+
+    /// Re-reads the password file.
+    ///
+    /// Connected clients stay connected. If the file can't be read, the
+    /// previous credentials are kept.
+    pub fn reload(&self) -> Result<(), Error> {
+        let credentials = read_password_file(&self.path)?;
+        info!(
+            "reloaded {} users from password file {}",
+            credentials.len(),
+            self.path.display()
+        );
+
+        *self
+            .credentials
+            .write()
+            .expect("password file lock is poisoned") = credentials;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Authenticator for PasswordFileAuthenticator {
+    async fn authenticate(&self, context: AuthenticationContext) -> Result<Auth, Error> {
+        let username = if let Some(username) = context.username() {
+            username.to_string()
+        } else {
+            debug!("client connected without a username");
+            return Ok(Auth::NotAuthorized);
+        };
+
+        let hash = self
+            .credentials
+            .read()
+            .expect("password file lock is poisoned")
+            .get(&username)
+            .cloned();
+        // Unknown users are checked against a dummy hash, so the time it
+        // takes to refuse them doesn't tell which users exist
+        let known = hash.is_some();
+        let hash = hash.unwrap_or_else(PasswordHash::dummy);
+
+        // Hashing is deliberately slow, so it is kept off the async runtime
+        let password = context.password().unwrap_or_default().to_string();
+        let verified = task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .context(ErrorKind::HashPassword)?;
+
+        if known && verified {
+            Ok(Auth::Identity(AuthId::from(username)))
+        } else if known {
+            debug!("wrong password for user {}", username);
+            Ok(Auth::BadCredentials)
+        } else {
+            debug!("unknown user {}", username);
+            Ok(Auth::BadCredentials)
+        }
+    }
+}
+
+fn read_password_file(path: &Path) -> Result<HashMap<String, PasswordHash>, Error> {
+    let contents = fs::read_to_string(path).context(ErrorKind::LoadPasswordFile)?;
+
+    let mut credentials = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let username = parts.next().filter(|username| !username.is_empty());
+        let hash = parts.next().and_then(PasswordHash::parse);
+        match (username, hash) {
+            (Some(username), Some(hash)) => {
+                credentials.insert(username.to_string(), hash);
+            }
+            _ => return Err(Error::from(ErrorKind::InvalidPasswordFile(i + 1))),
+        }
+    }
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tempfile::TempDir;
+
+    use crate::{ClientId, PeerAddr};
+
+    fn context(username: Option<&str>, password: Option<&str>) -> AuthenticationContext {
+        AuthenticationContext::new(
+            ClientId::from("client".to_string()),
+            username.map(str::to_string),
+            password.map(str::to_string),
+            None,
+            PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1883))),
+        )
+    }
+
+    #[test]
+    fn test_hash_roundtrip() {
+        let hash = PasswordHash::generate("secret").unwrap();
+        let parsed = PasswordHash::parse(&hash.to_string()).unwrap();
+
+        assert_eq!(hash, parsed);
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("Secret"));
+    }
+
+    #[test]
+    fn test_dummy_hash() {
+        let dummy = PasswordHash::dummy();
+        assert_eq!(Some(dummy.clone()), PasswordHash::parse(&dummy.to_string()));
+        assert!(!dummy.verify(""));
+        assert!(!dummy.verify("secret"));
+    }
+
+    #[test]
+    fn test_mosquitto_hash() {
+        // The password "password" hashed the way `mosquitto_passwd` does,
+        // with 101 iterations and a 12 byte salt
+        let hash = PasswordHash::parse(
+            "$7$101$AQIDBAUGBwgJCgsM$vi3gtTBBsyA0ndLJ0V/YApuRViDA9ghC166cp1AjnIEUVevnLN/JRt4mV1VV33uV0F7F5qO0R1SqlyHqKHWWdg==",
+        )
+        .unwrap();
+
+        assert!(hash.verify("password"));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let invalid = [
+            "",
+            "password",
+            "$6$salt$hash",
+            "$7$0$c2FsdA==$aGFzaA==",
+            "$7$101$not base64$aGFzaA==",
+            "$7$101$c2FsdA==$aGFzaA==$extra",
+        ];
+        for hash in &invalid {
+            assert_eq!(None, PasswordHash::parse(hash), "{}", hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("passwd");
+        let hash = PasswordHash::generate("secret").unwrap();
+        fs::write(&path, format!("# users\n\nuser1:{}\n", hash)).unwrap();
+
+        let authenticator = PasswordFileAuthenticator::open(&path).unwrap();
+
+        let auth = authenticator
+            .authenticate(context(Some("user1"), Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(Auth::Identity(AuthId::from("user1".to_string())), auth);
+
+        let auth = authenticator
+            .authenticate(context(Some("user1"), Some("wrong")))
+            .await
+            .unwrap();
+        assert_eq!(Auth::BadCredentials, auth);
+
+        let auth = authenticator
+            .authenticate(context(Some("user2"), Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(Auth::BadCredentials, auth);
+
+        let auth = authenticator
+            .authenticate(context(None, None))
+            .await
+            .unwrap();
+        assert_eq!(Auth::NotAuthorized, auth);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("passwd");
+        let hash = PasswordHash::generate("secret").unwrap();
+        fs::write(&path, format!("user1:{}\n", hash)).unwrap();
+
+        let authenticator = PasswordFileAuthenticator::open(&path).unwrap();
+        let clone = authenticator.clone();
+
+        fs::write(&path, format!("user2:{}\n", hash)).unwrap();
+        clone.reload().unwrap();
+
+        let auth = authenticator
+            .authenticate(context(Some("user2"), Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(Auth::Identity(AuthId::from("user2".to_string())), auth);
+
+        // A broken file keeps the previous credentials
+        fs::write(&path, "user3\n").unwrap();
+        let err = clone.reload().unwrap_err();
+        assert_eq!(ErrorKind::InvalidPasswordFile(1), *err.kind());
+
+        let auth = authenticator
+            .authenticate(context(Some("user2"), Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(Auth::Identity(AuthId::from("user2".to_string())), auth);
+    }
+}
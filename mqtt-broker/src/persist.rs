@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use failure::{Fail, ResultExt};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::broker::BrokerState;
+use crate::serialize;
+use crate::session::{SessionState, SessionStateV2, SessionStateV3, SessionStateV4};
+use crate::{Error, ErrorKind, Publication, PublicationId};
+
+static STATE_FILE: &str = "state.dat";
+static BACKUP_FILE: &str = "state.dat.bak";
+static TEMP_FILE: &str = "state.dat.tmp";
+
+const MAGIC: &[u8; 4] = b"MQTS";
+const FORMAT_VERSION: u32 = 5;
+
+/// magic (4) + format version (4) + checksum (4) + payload length (8)
+const HEADER_LEN: usize = 20;
+
+/// Loads and stores broker state so it survives a broker restart.
+pub trait Persist {
+    fn load(&mut self) -> Result<Option<BrokerState>, Error>;
+
+    fn store(&mut self, state: &BrokerState) -> Result<(), Error>;
+}
+
+/// Persists broker state to a file in a state directory.
+///
+/// The state is written to a temporary file which is synced and then renamed
+/// over the previous state, so a crash during a write never leaves a partially
+/// written state file behind. The previous state is kept as a backup and is
+/// used when the current state file is missing or fails its integrity check.
+///
+/// The file starts with a header holding a format version and a checksum of
+/// the serialized state.
+#[derive(Debug)]
+pub struct FilePersistor {
+    dir: PathBuf,
+}
+
+impl FilePersistor {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Persist for FilePersistor {
+    fn load(&mut self) -> Result<Option<BrokerState>, Error> {
+        let path = self.dir.join(STATE_FILE);
+        let error = match read_state(&path) {
+            Ok(Some(state)) => {
+                info!("loaded broker state from {}", path.display());
+                return Ok(Some(state));
+            }
+            Ok(None) => {
+                debug!("no broker state found at {}", path.display());
+                None
+            }
+            Err(e) => {
+                warn!(message = "failed to load broker state. trying backup...", path = %path.display(), error = %e);
+                Some(e)
+            }
+        };
+
+        let backup = self.dir.join(BACKUP_FILE);
+        match read_state(&backup)? {
+            Some(state) => {
+                info!("loaded broker state from backup {}", backup.display());
+                Ok(Some(state))
+            }
+            None => error.map_or(Ok(None), Err),
+        }
+    }
+
+    fn store(&mut self, state: &BrokerState) -> Result<(), Error> {
+        let buf = encode(state)?;
+
+        fs::create_dir_all(&self.dir).context(ErrorKind::StoreState)?;
+
+        let temp = self.dir.join(TEMP_FILE);
+        let path = self.dir.join(STATE_FILE);
+        let backup = self.dir.join(BACKUP_FILE);
+
+        let mut file = File::create(&temp).context(ErrorKind::StoreState)?;
+        file.write_all(&buf).context(ErrorKind::StoreState)?;
+        file.sync_all().context(ErrorKind::StoreState)?;
+        drop(file);
+
+        if path.exists() {
+            fs::rename(&path, &backup).context(ErrorKind::StoreState)?;
+        }
+        fs::rename(&temp, &path).context(ErrorKind::StoreState)?;
+        sync_dir(&self.dir).context(ErrorKind::StoreState)?;
+
+        debug!("stored broker state to {}", path.display());
+        Ok(())
+    }
+}
+
+fn read_state(path: &Path) -> Result<Option<BrokerState>, Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e.context(ErrorKind::LoadState))),
+    };
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).context(ErrorKind::LoadState)?;
+    decode(&buf).map(Some)
+}
+
+fn encode(state: &BrokerState) -> Result<Vec<u8>, Error> {
+    let payload = bincode::serialize(state).context(ErrorKind::StoreState)?;
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+fn decode(buf: &[u8]) -> Result<BrokerState, Error> {
+    if buf.len() < HEADER_LEN || &buf[0..4] != MAGIC {
+        return Err(Error::from(ErrorKind::CorruptState));
+    }
+
+    let version = u32::from_le_bytes(buf[4..8].try_into().expect("slice is 4 bytes"));
+    let checksum = u32::from_le_bytes(buf[8..12].try_into().expect("slice is 4 bytes"));
+    let len = u64::from_le_bytes(buf[12..20].try_into().expect("slice is 8 bytes"));
+
+    let payload = &buf[HEADER_LEN..];
+    if payload.len() as u64 != len || crc32fast::hash(payload) != checksum {
+        return Err(Error::from(ErrorKind::CorruptState));
+    }
+
+    // Older format versions are migrated here as the format evolves.
+    match version {
+        1 => {
+            let state: BrokerStateV1 =
+                bincode::deserialize(payload).context(ErrorKind::CorruptState)?;
+            let (sessions, unreleased) =
+                migrate_sessions(state.sessions.into_iter().map(Into::into));
+            Ok(BrokerState::new(state.retained, sessions).with_unreleased(unreleased))
+        }
+        2 => {
+            let state: BrokerStateV2 =
+                bincode::deserialize(payload).context(ErrorKind::CorruptState)?;
+            let (sessions, unreleased) =
+                migrate_sessions(state.sessions.into_iter().map(Into::into));
+            Ok(BrokerState::new(state.retained, sessions)
+                .with_journal_segment(state.journal_segment)
+                .with_unreleased(unreleased))
+        }
+        3 => {
+            let state: BrokerStateV3 =
+                bincode::deserialize(payload).context(ErrorKind::CorruptState)?;
+            let (sessions, unreleased) = migrate_sessions(state.sessions);
+            Ok(BrokerState::new(state.retained, sessions)
+                .with_retained_deadlines(state.retained_deadlines)
+                .with_journal_segment(state.journal_segment)
+                .with_last_publication_id(state.last_publication_id)
+                .with_unreleased(unreleased))
+        }
+        4 => {
+            let state: BrokerStateV4 =
+                bincode::deserialize(payload).context(ErrorKind::CorruptState)?;
+            let sessions = state.sessions.into_iter().map(Into::into).collect();
+            Ok(BrokerState::new(state.retained, sessions)
+                .with_retained_deadlines(state.retained_deadlines)
+                .with_journal_segment(state.journal_segment)
+                .with_last_publication_id(state.last_publication_id))
+        }
+        FORMAT_VERSION => {
+            let state = bincode::deserialize(payload).context(ErrorKind::CorruptState)?;
+            Ok(state)
+        }
+        version => Err(Error::from(ErrorKind::UnsupportedStateVersion(version))),
+    }
+}
+
+/// Broker state before the journal segment was recorded.
+#[derive(Deserialize)]
+struct BrokerStateV1 {
+    #[serde(deserialize_with = "serialize::publication_map::deserialize")]
+    retained: HashMap<String, Publication>,
+    sessions: Vec<SessionStateV2>,
+}
+
+/// Broker state before messages kept the id of their publication.
+#[derive(Deserialize)]
+struct BrokerStateV2 {
+    #[serde(deserialize_with = "serialize::publication_map::deserialize")]
+    retained: HashMap<String, Publication>,
+    sessions: Vec<SessionStateV2>,
+    journal_segment: u64,
+}
+
+/// Broker state before exactly once messages were delivered when they
+/// arrived.
+#[derive(Deserialize)]
+struct BrokerStateV3 {
+    #[serde(deserialize_with = "serialize::publication_map::deserialize")]
+    retained: HashMap<String, Publication>,
+    #[serde(with = "serialize::deadline_map")]
+    retained_deadlines: HashMap<String, Instant>,
+    sessions: Vec<SessionStateV3>,
+    journal_segment: u64,
+    last_publication_id: PublicationId,
+}
+
+/// Broker state before messages had MQTT 5 properties.
+#[derive(Deserialize)]
+struct BrokerStateV4 {
+    #[serde(deserialize_with = "serialize::publication_map::deserialize")]
+    retained: HashMap<String, Publication>,
+    #[serde(with = "serialize::deadline_map")]
+    retained_deadlines: HashMap<String, Instant>,
+    sessions: Vec<SessionStateV4>,
+    journal_segment: u64,
+    last_publication_id: PublicationId,
+}
+
+fn migrate_sessions<I>(sessions: I) -> (Vec<SessionState>, Vec<Publication>)
+where
+    I: IntoIterator<Item = SessionStateV3>,
+{
+    let mut unreleased = Vec::new();
+    let sessions = sessions
+        .into_iter()
+        .map(|state| {
+            let (state, publications) = state.into_state();
+            unreleased.extend(publications);
+            state.into()
+        })
+        .collect();
+    (sessions, unreleased)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mqtt::proto;
+    use tempfile::TempDir;
+
+    use crate::PublicationProperties;
+
+    fn state() -> BrokerState {
+        let mut retained = HashMap::new();
+        retained.insert(
+            "topic/retained".to_string(),
+            Publication {
+                topic_name: "topic/retained".to_string(),
+                qos: proto::QoS::AtLeastOnce,
+                retain: true,
+                payload: "payload".into(),
+                properties: PublicationProperties::default(),
+            },
+        );
+        BrokerState::new(retained, vec![])
+    }
+
+    #[test]
+    fn test_load_empty() {
+        let dir = TempDir::new().unwrap();
+        let mut persistor = FilePersistor::new(dir.path());
+
+        assert!(persistor.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_store_load() {
+        let dir = TempDir::new().unwrap();
+        let mut persistor = FilePersistor::new(dir.path().join("state"));
+
+        persistor.store(&state()).unwrap();
+        let (retained, sessions) = persistor.load().unwrap().unwrap().into_parts();
+
+        assert_eq!(1, retained.len());
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_falls_back_to_backup() {
+        let dir = TempDir::new().unwrap();
+        let mut persistor = FilePersistor::new(dir.path());
+
+        persistor.store(&state()).unwrap();
+        persistor.store(&BrokerState::default()).unwrap();
+
+        let path = dir.path().join(STATE_FILE);
+        let mut buf = fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        fs::write(&path, buf).unwrap();
+
+        let (retained, _sessions) = persistor.load().unwrap().unwrap().into_parts();
+        assert_eq!(1, retained.len());
+    }
+
+    #[test]
+    fn test_corrupt_without_backup() {
+        let dir = TempDir::new().unwrap();
+        let mut persistor = FilePersistor::new(dir.path());
+
+        fs::write(dir.path().join(STATE_FILE), b"garbage").unwrap();
+
+        let err = persistor.load().unwrap_err();
+        assert_eq!(ErrorKind::CorruptState, *err.kind());
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        // An empty v1 state is an empty retained map followed by an empty
+        // list of sessions
+        let payload =
+            bincode::serialize(&(HashMap::<String, String>::new(), Vec::<String>::new())).unwrap();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&1_u32.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let state = decode(&buf).unwrap();
+        assert_eq!(0, state.journal_segment());
+    }
+
+    #[test]
+    fn test_migrate_v2() {
+        // An empty v2 state is an empty retained map, an empty list of
+        // sessions and the journal segment
+        let payload = bincode::serialize(&(
+            HashMap::<String, String>::new(),
+            Vec::<String>::new(),
+            7_u64,
+        ))
+        .unwrap();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&2_u32.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let state = decode(&buf).unwrap();
+        assert_eq!(7, state.journal_segment());
+    }
+
+    #[test]
+    fn test_migrate_v3() {
+        // An empty v3 state is an empty retained map, an empty map of
+        // retained deadlines, an empty list of sessions, the journal segment
+        // and the last publication id
+        let payload = bincode::serialize(&(
+            HashMap::<String, String>::new(),
+            HashMap::<String, String>::new(),
+            Vec::<String>::new(),
+            7_u64,
+            9_u64,
+        ))
+        .unwrap();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&3_u32.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let state = decode(&buf).unwrap();
+        assert_eq!(7, state.journal_segment());
+    }
+
+    #[test]
+    fn test_migrate_v4() {
+        // A v4 state has the same fields as a v3 state, with a retained
+        // message
+        let mut retained = HashMap::new();
+        retained.insert(
+            "topic/retained",
+            ("topic/retained", 1_u32, true, b"payload".to_vec()),
+        );
+        let payload = bincode::serialize(&(
+            retained,
+            HashMap::<String, String>::new(),
+            Vec::<String>::new(),
+            7_u64,
+            9_u64,
+        ))
+        .unwrap();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&4_u32.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let state = decode(&buf).unwrap();
+        assert_eq!(7, state.journal_segment());
+
+        let (retained, _sessions) = state.into_parts();
+        let publication = &retained["topic/retained"];
+        assert_eq!(proto::QoS::AtLeastOnce, publication.qos);
+        assert_eq!(PublicationProperties::default(), publication.properties);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut buf = encode(&state()).unwrap();
+        buf[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        let err = decode(&buf).unwrap_err();
+        assert_eq!(
+            ErrorKind::UnsupportedStateVersion(FORMAT_VERSION + 1),
+            *err.kind()
+        );
+    }
+}
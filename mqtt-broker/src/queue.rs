@@ -0,0 +1,548 @@
+use std::collections::VecDeque;
+use std::iter::FromIterator;
+use std::time::Instant;
+
+use metrics::counter;
+use mqtt::proto;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::{OverflowPolicy, QueueLimits};
+use crate::serialize;
+use crate::{Publication, PublicationId};
+
+/// The messages waiting to be sent to a client, along with the total size
+/// of their payloads. Each message keeps the id of the publication it is a
+/// copy of.
+///
+/// Expired messages are skipped when the queue is popped, and removed
+/// first when the queue is full.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PublicationQueue {
+    publications: VecDeque<(PublicationId, Publication, Option<Instant>)>,
+    bytes: usize,
+}
+
+impl PublicationQueue {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.publications.len()
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Queues a publication that expires at `deadline`, and drops messages
+    /// as the overflow policy of `limits` says when the queue goes over
+    /// them.
+    ///
+    /// Returns the number of dropped messages, which may include the new
+    /// one. Expired messages aren't counted.
+    pub(crate) fn push(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<Instant>,
+        limits: &QueueLimits,
+    ) -> usize {
+        let size = publication.payload.len();
+        if exceeds(limits, self.len() + 1, self.bytes + size) {
+            self.remove_expired();
+        }
+
+        match limits.overflow {
+            OverflowPolicy::DropNewest => {
+                if exceeds(limits, self.len() + 1, self.bytes + size) {
+                    return 1;
+                }
+                self.push_back(id, publication, deadline);
+                0
+            }
+            OverflowPolicy::DropOldest => {
+                // A message that can't fit on its own isn't worth emptying
+                // the queue for
+                if exceeds(limits, 1, size) {
+                    return 1;
+                }
+                self.push_back(id, publication, deadline);
+
+                let mut dropped = 0;
+                while exceeds(limits, self.len(), self.bytes) {
+                    self.remove(0);
+                    dropped += 1;
+                }
+                dropped
+            }
+            OverflowPolicy::DropQoS0 => {
+                self.push_back(id, publication, deadline);
+
+                let mut dropped = 0;
+                while exceeds(limits, self.len(), self.bytes) {
+                    let position = self
+                        .publications
+                        .iter()
+                        .position(|(_, publication, _)| publication.qos == proto::QoS::AtMostOnce);
+                    if let Some(position) = position {
+                        self.remove(position);
+                        dropped += 1;
+                    } else {
+                        break;
+                    }
+                }
+                dropped
+            }
+        }
+    }
+
+    /// Takes the oldest message that hasn't expired.
+    pub(crate) fn pop_front(&mut self) -> Option<(PublicationId, Publication)> {
+        let now = Instant::now();
+        let mut expired = 0;
+        let mut next = None;
+        while let Some((id, publication, deadline)) = self.publications.pop_front() {
+            self.bytes -= publication.payload.len();
+            if is_expired(deadline, now) {
+                expired += 1;
+            } else {
+                next = Some((id, publication));
+                break;
+            }
+        }
+
+        if expired > 0 {
+            counter!("mqtt_expired_messages_total", expired);
+        }
+        next
+    }
+
+    /// Removes the first message that is a copy of the publication `id`.
+    pub(crate) fn remove_id(&mut self, id: PublicationId) -> Option<Publication> {
+        let index = self
+            .publications
+            .iter()
+            .position(|(queued, _, _)| *queued == id)?;
+        self.remove(index)
+    }
+
+    fn remove(&mut self, index: usize) -> Option<Publication> {
+        let (_, publication, _) = self.publications.remove(index)?;
+        self.bytes -= publication.payload.len();
+        Some(publication)
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        let len = self.len();
+        let mut bytes = self.bytes;
+        self.publications.retain(|(_, publication, deadline)| {
+            let expired = is_expired(*deadline, now);
+            if expired {
+                bytes -= publication.payload.len();
+            }
+            !expired
+        });
+        self.bytes = bytes;
+
+        let expired = len - self.len();
+        if expired > 0 {
+            counter!("mqtt_expired_messages_total", expired as u64);
+        }
+    }
+
+    fn push_back(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<Instant>,
+    ) {
+        self.bytes += publication.payload.len();
+        self.publications.push_back((id, publication, deadline));
+    }
+}
+
+fn is_expired(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.map_or(false, |deadline| deadline <= now)
+}
+
+fn exceeds(limits: &QueueLimits, len: usize, bytes: usize) -> bool {
+    (limits.max_messages != 0 && len > limits.max_messages)
+        || (limits.max_bytes != 0 && bytes > limits.max_bytes)
+}
+
+impl Serialize for PublicationQueue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize::publication_queue::serialize(&self.publications, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicationQueue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let publications = serialize::publication_queue::deserialize(deserializer)?;
+        Ok(publications.into_iter().collect())
+    }
+}
+
+impl PublicationQueue {
+    /// Reads a queue stored by a state format before 5.
+    pub(crate) fn deserialize_v4<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let publications = serialize::publication_queue::deserialize_v4(deserializer)?;
+        Ok(publications.into_iter().collect())
+    }
+}
+
+/// Queues restored messages regardless of the queue limits. Each message
+/// keeps its deadline.
+impl FromIterator<(PublicationId, Publication, Option<Instant>)> for PublicationQueue {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (PublicationId, Publication, Option<Instant>)>,
+    {
+        let mut queue = Self::new();
+        for (id, publication, deadline) in iter {
+            queue.push_back(id, publication, deadline);
+        }
+        queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use mqtt::proto::QoS;
+
+    use crate::PublicationProperties;
+
+    fn publication(payload: &str, qos: QoS) -> Publication {
+        Publication {
+            topic_name: "topic".to_string(),
+            qos,
+            retain: false,
+            payload: payload.to_string().into(),
+            properties: PublicationProperties::default(),
+        }
+    }
+
+    fn limits(max_messages: usize, max_bytes: usize, overflow: OverflowPolicy) -> QueueLimits {
+        QueueLimits {
+            max_messages,
+            max_bytes,
+            overflow,
+        }
+    }
+
+    fn payloads(queue: &PublicationQueue) -> Vec<String> {
+        queue
+            .publications
+            .iter()
+            .map(|(_, publication, _)| String::from_utf8_lossy(&publication.payload).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limits = limits(0, 0, OverflowPolicy::DropNewest);
+        let mut queue = PublicationQueue::new();
+        for _ in 0..100 {
+            assert_eq!(
+                0,
+                queue.push(
+                    PublicationId::default(),
+                    publication("a", QoS::AtMostOnce),
+                    None,
+                    &limits
+                )
+            );
+        }
+        assert_eq!(100, queue.len());
+        assert_eq!(100, queue.bytes());
+    }
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let limits = QueueLimits::default();
+        let mut queue = PublicationQueue::new();
+        for _ in 0..2000 {
+            let publication = publication("a", QoS::AtLeastOnce);
+            assert_eq!(
+                0,
+                queue.push(PublicationId::default(), publication, None, &limits)
+            );
+        }
+        assert_eq!(2000, queue.len());
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let limits = limits(2, 0, OverflowPolicy::DropNewest);
+        let mut queue = PublicationQueue::new();
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("a", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("b", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(
+            1,
+            queue.push(
+                PublicationId::default(),
+                publication("c", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["a", "b"], payloads(&queue));
+
+        queue.pop_front();
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("d", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["b", "d"], payloads(&queue));
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let limits = limits(0, 5, OverflowPolicy::DropOldest);
+        let mut queue = PublicationQueue::new();
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("aa", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("bb", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(
+            2,
+            queue.push(
+                PublicationId::default(),
+                publication("cccc", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["cccc"], payloads(&queue));
+        assert_eq!(4, queue.bytes());
+
+        // Too large for the queue on its own
+        assert_eq!(
+            1,
+            queue.push(
+                PublicationId::default(),
+                publication("dddddd", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["cccc"], payloads(&queue));
+    }
+
+    #[test]
+    fn test_drop_qos0() {
+        let limits = limits(2, 0, OverflowPolicy::DropQoS0);
+        let mut queue = PublicationQueue::new();
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("a", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("b", QoS::AtMostOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(
+            1,
+            queue.push(
+                PublicationId::default(),
+                publication("c", QoS::ExactlyOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["a", "c"], payloads(&queue));
+
+        assert_eq!(
+            1,
+            queue.push(
+                PublicationId::default(),
+                publication("d", QoS::AtMostOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["a", "c"], payloads(&queue));
+
+        // QoS 1 and QoS 2 messages are kept over the limit
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("e", QoS::AtLeastOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["a", "c", "e"], payloads(&queue));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let limits = limits(2, 0, OverflowPolicy::DropNewest);
+        let expired = Some(Instant::now());
+        let mut queue = PublicationQueue::new();
+        queue.push(
+            PublicationId::default(),
+            publication("a", QoS::AtMostOnce),
+            expired,
+            &limits,
+        );
+        queue.push(
+            PublicationId::default(),
+            publication("b", QoS::AtMostOnce),
+            None,
+            &limits,
+        );
+        assert_eq!(2, queue.bytes());
+
+        // Expired messages are skipped
+        assert_eq!("b", queue.pop_front().unwrap().1.payload);
+        assert!(queue.pop_front().is_none());
+        assert_eq!(0, queue.bytes());
+
+        // and make room when the queue is full
+        queue.push(
+            PublicationId::default(),
+            publication("c", QoS::AtMostOnce),
+            expired,
+            &limits,
+        );
+        queue.push(
+            PublicationId::default(),
+            publication("d", QoS::AtMostOnce),
+            expired,
+            &limits,
+        );
+        assert_eq!(
+            0,
+            queue.push(
+                PublicationId::default(),
+                publication("e", QoS::AtMostOnce),
+                None,
+                &limits
+            )
+        );
+        assert_eq!(vec!["e"], payloads(&queue));
+    }
+
+    #[test]
+    fn test_remove_id() {
+        let limits = limits(0, 0, OverflowPolicy::DropNewest);
+        let first = PublicationId::default();
+        let second = first.next();
+        let mut queue = PublicationQueue::new();
+        // Copies of different publications can have the same payload
+        queue.push(first, publication("a", QoS::AtLeastOnce), None, &limits);
+        queue.push(second, publication("a", QoS::AtLeastOnce), None, &limits);
+        queue.push(
+            second.next(),
+            publication("b", QoS::AtLeastOnce),
+            None,
+            &limits,
+        );
+
+        assert!(queue.remove_id(second).is_some());
+        assert!(queue.remove_id(second).is_none());
+        assert_eq!(Some(first), queue.pop_front().map(|(id, _)| id));
+        assert_eq!(vec!["b"], payloads(&queue));
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let limits = limits(0, 0, OverflowPolicy::DropNewest);
+        let first = PublicationId::default().next();
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        let mut queue = PublicationQueue::new();
+        queue.push(first, publication("abc", QoS::AtMostOnce), None, &limits);
+        queue.push(
+            first.next(),
+            publication("de", QoS::ExactlyOnce),
+            Some(deadline),
+            &limits,
+        );
+        queue.push(
+            first.next().next(),
+            publication("f", QoS::AtLeastOnce),
+            Some(Instant::now()),
+            &limits,
+        );
+
+        let bytes = bincode::serialize(&queue).unwrap();
+        let mut queue: PublicationQueue = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(vec!["abc", "de", "f"], payloads(&queue));
+        assert_eq!(6, queue.bytes());
+
+        // Restored messages keep their deadlines
+        let restored = queue.publications[1].2.unwrap();
+        let difference = if restored > deadline {
+            restored - deadline
+        } else {
+            deadline - restored
+        };
+        assert!(difference < Duration::from_secs(1));
+
+        assert_eq!(Some(first), queue.pop_front().map(|(id, _)| id));
+        assert_eq!(Some(first.next()), queue.pop_front().map(|(id, _)| id));
+        assert!(queue.pop_front().is_none());
+    }
+}
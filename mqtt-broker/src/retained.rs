@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::subscription::{Segment, TopicFilter, TOPIC_SEPARATOR};
+use crate::Publication;
+
+/// Retained messages stored in a tree of their topic levels.
+///
+/// Looking up the messages for a topic filter only walks the branches the
+/// filter can match, instead of testing the filter against every retained
+/// topic. Expired messages are left out of lookups until they are removed.
+#[derive(Clone, Debug, Default)]
+pub struct RetainedStore {
+    root: Node,
+    count: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    levels: HashMap<String, Node>,
+    publication: Option<Publication>,
+    deadline: Option<Instant>,
+}
+
+impl RetainedStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retains `publication` for its topic, returning the message it replaces.
+    pub fn insert(&mut self, publication: Publication) -> Option<Publication> {
+        self.insert_until(publication, None)
+    }
+
+    /// Like `insert`, for a message that expires at `deadline`.
+    pub fn insert_until(
+        &mut self,
+        publication: Publication,
+        deadline: Option<Instant>,
+    ) -> Option<Publication> {
+        let mut node = &mut self.root;
+        for level in publication.topic_name.split(TOPIC_SEPARATOR) {
+            node = node.levels.entry(level.to_string()).or_default();
+        }
+
+        node.deadline = deadline;
+        let replaced = node.publication.replace(publication);
+        if replaced.is_none() {
+            self.count += 1;
+        }
+        replaced
+    }
+
+    pub fn remove(&mut self, topic_name: &str) -> Option<Publication> {
+        let levels = topic_name.split(TOPIC_SEPARATOR).collect::<Vec<_>>();
+        let removed = self.root.remove(&levels);
+        if removed.is_some() {
+            self.count -= 1;
+        }
+        removed
+    }
+
+    /// Returns the retained messages with a topic matched by `filter`,
+    /// along with the deadline each message expires at.
+    pub fn matches(&self, filter: &TopicFilter) -> Vec<(&Publication, Option<Instant>)> {
+        let mut publications = vec![];
+        self.root
+            .collect(filter.segments(), true, Instant::now(), &mut publications);
+        publications
+    }
+
+    /// The number of retained messages, including expired messages that
+    /// haven't been removed yet.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn to_map(&self) -> HashMap<String, Publication> {
+        let mut publications = vec![];
+        self.root.collect_all(Instant::now(), &mut publications);
+        publications
+            .into_iter()
+            .map(|(publication, _)| (publication.topic_name.clone(), publication.clone()))
+            .collect()
+    }
+
+    /// Removes the expired messages, returning how many there were.
+    pub fn remove_expired(&mut self) -> usize {
+        let removed = self.root.remove_expired(Instant::now());
+        self.count -= removed;
+        removed
+    }
+
+    /// The deadlines of the retained messages that expire, by topic.
+    pub fn deadlines(&self) -> HashMap<String, Instant> {
+        let mut deadlines = HashMap::new();
+        self.root.collect_deadlines(Instant::now(), &mut deadlines);
+        deadlines
+    }
+}
+
+impl From<HashMap<String, Publication>> for RetainedStore {
+    fn from(retained: HashMap<String, Publication>) -> Self {
+        let mut store = Self::new();
+        for publication in retained.values() {
+            store.insert(publication.clone());
+        }
+        store
+    }
+}
+
+impl Node {
+    fn remove(&mut self, levels: &[&str]) -> Option<Publication> {
+        if let Some((level, rest)) = levels.split_first() {
+            let child = self.levels.get_mut(*level)?;
+            let removed = child.remove(rest);
+            if child.is_empty() {
+                self.levels.remove(*level);
+            }
+            removed
+        } else {
+            self.deadline = None;
+            self.publication.take()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.is_empty() && self.publication.is_none()
+    }
+
+    fn live_publication(&self, now: Instant) -> Option<&Publication> {
+        match self.deadline {
+            Some(deadline) if deadline <= now => None,
+            _ => self.publication.as_ref(),
+        }
+    }
+
+    fn live_entry(&self, now: Instant) -> Option<(&Publication, Option<Instant>)> {
+        self.live_publication(now)
+            .map(|publication| (publication, self.deadline))
+    }
+
+    fn remove_expired(&mut self, now: Instant) -> usize {
+        let mut removed = 0;
+        if self.publication.is_some() && self.live_publication(now).is_none() {
+            self.publication = None;
+            self.deadline = None;
+            removed += 1;
+        }
+
+        for child in self.levels.values_mut() {
+            removed += child.remove_expired(now);
+        }
+        self.levels.retain(|_, child| !child.is_empty());
+        removed
+    }
+
+    fn collect_deadlines(&self, now: Instant, deadlines: &mut HashMap<String, Instant>) {
+        if let (Some(publication), Some(deadline)) = (self.live_publication(now), self.deadline) {
+            deadlines.insert(publication.topic_name.clone(), deadline);
+        }
+        for child in self.levels.values() {
+            child.collect_deadlines(now, deadlines);
+        }
+    }
+
+    /// `first` is true at the root, where wildcards don't match topics
+    /// starting with `$` [MQTT-4.7.2-1].
+    fn collect<'a>(
+        &'a self,
+        segments: &[Segment],
+        first: bool,
+        now: Instant,
+        publications: &mut Vec<(&'a Publication, Option<Instant>)>,
+    ) {
+        match segments.split_first() {
+            None => publications.extend(self.live_entry(now)),
+            Some((Segment::Level(level), rest)) => {
+                if let Some(child) = self.levels.get(level) {
+                    child.collect(rest, false, now, publications);
+                }
+            }
+            Some((Segment::SingleLevelWildcard, rest)) => {
+                for child in self.wildcard_levels(first) {
+                    child.collect(rest, false, now, publications);
+                }
+            }
+            Some((Segment::MultiLevelWildcard, _)) => {
+                // `#` also matches the parent level
+                publications.extend(self.live_entry(now));
+                for child in self.wildcard_levels(first) {
+                    child.collect_all(now, publications);
+                }
+            }
+        }
+    }
+
+    fn collect_all<'a>(
+        &'a self,
+        now: Instant,
+        publications: &mut Vec<(&'a Publication, Option<Instant>)>,
+    ) {
+        publications.extend(self.live_entry(now));
+        for child in self.levels.values() {
+            child.collect_all(now, publications);
+        }
+    }
+
+    fn wildcard_levels(&self, first: bool) -> impl Iterator<Item = &Node> {
+        self.levels
+            .iter()
+            .filter(move |(level, _)| !first || !level.starts_with('$'))
+            .map(|(_, child)| child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use mqtt::proto;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use crate::PublicationProperties;
+
+    fn publication(topic_name: &str) -> Publication {
+        Publication {
+            topic_name: topic_name.to_string(),
+            qos: proto::QoS::AtMostOnce,
+            retain: true,
+            payload: "payload".into(),
+            properties: PublicationProperties::default(),
+        }
+    }
+
+    fn matches(store: &RetainedStore, filter: &str) -> Vec<String> {
+        let mut topics = store
+            .matches(&filter.parse().unwrap())
+            .into_iter()
+            .map(|(publication, _)| publication.topic_name.clone())
+            .collect::<Vec<_>>();
+        topics.sort();
+        topics
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut store = RetainedStore::new();
+        assert!(store.insert(publication("a/b")).is_none());
+        assert!(store.insert(publication("a")).is_none());
+        assert!(store.insert(publication("a/b")).is_some());
+        assert_eq!(2, store.to_map().len());
+        assert_eq!(2, store.count());
+
+        assert_eq!(Some(publication("a/b")), store.remove("a/b"));
+        assert_eq!(None, store.remove("a/b"));
+        assert_eq!(None, store.remove("a/c"));
+        assert_eq!(Some(publication("a")), store.remove("a"));
+        assert!(store.root.is_empty());
+        assert_eq!(0, store.count());
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut store = RetainedStore::new();
+        for topic in &["a", "a/b", "a/b/c", "a/c", "/a", "$SYS/a", "b/$c"] {
+            store.insert(publication(topic));
+        }
+
+        assert_eq!(
+            vec!["/a", "a", "a/b", "a/b/c", "a/c", "b/$c"],
+            matches(&store, "#")
+        );
+        assert_eq!(vec!["a", "a/b", "a/b/c", "a/c"], matches(&store, "a/#"));
+        assert_eq!(vec!["a/b", "a/c"], matches(&store, "a/+"));
+        assert_eq!(vec!["a/b/c"], matches(&store, "+/+/c"));
+        assert_eq!(vec!["/a"], matches(&store, "+/a"));
+        assert_eq!(vec!["b/$c"], matches(&store, "b/+"));
+        assert_eq!(vec!["$SYS/a"], matches(&store, "$SYS/+"));
+        assert!(matches(&store, "+/b/c/d").is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut store = RetainedStore::new();
+        store.insert_until(publication("a/b"), Some(Instant::now()));
+        store.insert(publication("a/c"));
+        store.insert_until(publication("d"), Some(Instant::now()));
+        assert_eq!(vec!["a/c"], matches(&store, "#"));
+        assert_eq!(3, store.count());
+        assert!(store.deadlines().is_empty());
+
+        assert_eq!(2, store.remove_expired());
+        assert_eq!(1, store.count());
+        assert_eq!(0, store.remove_expired());
+        assert_eq!(vec!["a/c"], matches(&store, "#"));
+        assert!(!store.root.levels.contains_key("d"));
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        store.insert_until(publication("e"), Some(deadline));
+        assert_eq!(1, store.deadlines().len());
+        assert_eq!(Some(&deadline), store.deadlines().get("e"));
+        assert_eq!(
+            vec![(&publication("e"), Some(deadline))],
+            store.matches(&"e".parse().unwrap())
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn matches_filters(
+            topics in vec("[$ab]?(/[$ab]?){0,3}", 1..10),
+            filter in "([$ab]|\\+)(/([$ab]?|\\+)){0,3}(/#)?|#",
+        ) {
+            let mut store = RetainedStore::new();
+            for topic in &topics {
+                store.insert(publication(topic));
+            }
+
+            let filter = filter.parse::<TopicFilter>().unwrap();
+            let mut expected = store
+                .to_map()
+                .keys()
+                .filter(|topic| filter.matches(topic))
+                .cloned()
+                .collect::<Vec<_>>();
+            expected.sort();
+
+            let mut matched = store
+                .matches(&filter)
+                .into_iter()
+                .map(|(publication, _)| publication.topic_name.clone())
+                .collect::<Vec<_>>();
+            matched.sort();
+            prop_assert_eq!(expected, matched);
+        }
+    }
+}
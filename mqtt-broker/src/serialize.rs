@@ -0,0 +1,363 @@
+//! Serde support for types held in broker state that don't implement
+//! `Serialize`/`Deserialize` themselves.
+//!
+//! The protocol types come from the `mqtt` crate, so they are handled through
+//! remote definitions and `#[serde(with = "...")]` modules.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Instant as StdInstant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use mqtt::proto;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::PublicationId;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "proto::QoS")]
+pub(crate) enum QoSDef {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "proto::PacketIdentifierDupQoS")]
+pub(crate) enum PacketIdentifierDupQoSDef {
+    AtMostOnce,
+    AtLeastOnce(
+        #[serde(with = "packet_identifier")] proto::PacketIdentifier,
+        bool,
+    ),
+    ExactlyOnce(
+        #[serde(with = "packet_identifier")] proto::PacketIdentifier,
+        bool,
+    ),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "proto::Publication")]
+pub(crate) struct PublicationDef {
+    topic_name: String,
+    #[serde(with = "QoSDef")]
+    qos: proto::QoS,
+    retain: bool,
+    payload: Bytes,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "proto::Publish")]
+pub(crate) struct PublishDef {
+    #[serde(with = "PacketIdentifierDupQoSDef")]
+    packet_identifier_dup_qos: proto::PacketIdentifierDupQoS,
+    retain: bool,
+    topic_name: String,
+    payload: Bytes,
+}
+
+#[derive(Deserialize)]
+struct PublicationOwned(#[serde(with = "PublicationDef")] proto::Publication);
+
+#[derive(Deserialize)]
+struct PublishOwned(#[serde(with = "PublishDef")] proto::Publish);
+
+fn to_packet_identifier<E>(id: u16) -> Result<proto::PacketIdentifier, E>
+where
+    E: de::Error,
+{
+    proto::PacketIdentifier::new(id).ok_or_else(|| E::custom("packet identifier must be non-zero"))
+}
+
+pub(crate) mod packet_identifier {
+    use super::{proto, to_packet_identifier, Deserialize, Deserializer, Serializer};
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub(crate) fn serialize<S>(
+        id: &proto::PacketIdentifier,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u16(id.get())
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<proto::PacketIdentifier, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let id = u16::deserialize(deserializer)?;
+        to_packet_identifier(id)
+    }
+}
+
+pub(crate) mod packet_identifier_set {
+    use super::{proto, to_packet_identifier, Deserialize, Deserializer, HashSet, Serializer};
+
+    pub(crate) fn serialize<S>(
+        ids: &HashSet<proto::PacketIdentifier>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(ids.iter().map(|id| id.get()))
+    }
+
+    pub(crate) fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<HashSet<proto::PacketIdentifier>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<u16>::deserialize(deserializer)?
+            .into_iter()
+            .map(to_packet_identifier)
+            .collect()
+    }
+}
+
+pub(crate) mod packet_identifier_map {
+    use super::{
+        proto, to_packet_identifier, Deserialize, Deserializer, HashMap, Serialize, Serializer,
+    };
+
+    pub(crate) fn serialize<V, S>(
+        map: &HashMap<proto::PacketIdentifier, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_map(map.iter().map(|(id, value)| (id.get(), value)))
+    }
+
+    pub(crate) fn deserialize<'de, V, D>(
+        deserializer: D,
+    ) -> Result<HashMap<proto::PacketIdentifier, V>, D::Error>
+    where
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        HashMap::<u16, V>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(id, value)| Ok((to_packet_identifier(id)?, value)))
+            .collect()
+    }
+}
+
+/// Only older state formats hold the publishes clients hadn't released.
+pub(crate) mod publish_map {
+    use super::{proto, to_packet_identifier, Deserialize, Deserializer, HashMap, PublishOwned};
+
+    pub(crate) fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<HashMap<proto::PacketIdentifier, proto::Publish>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        HashMap::<u16, PublishOwned>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(id, PublishOwned(publish))| Ok((to_packet_identifier(id)?, publish)))
+            .collect()
+    }
+}
+
+pub(crate) mod publication_queue {
+    use super::{
+        deadline, proto, Deserialize, Deserializer, PublicationDef, PublicationId, Serialize,
+        Serializer, StdInstant, VecDeque,
+    };
+    use crate::Publication;
+
+    type Queue = VecDeque<(PublicationId, Publication, Option<StdInstant>)>;
+
+    #[derive(Serialize)]
+    struct QueuedRef<'a>(
+        PublicationId,
+        &'a Publication,
+        #[serde(with = "deadline")] Option<StdInstant>,
+    );
+
+    #[derive(Deserialize)]
+    struct QueuedOwned(
+        PublicationId,
+        Publication,
+        #[serde(with = "deadline")] Option<StdInstant>,
+    );
+
+    #[derive(Deserialize)]
+    struct QueuedV4(
+        PublicationId,
+        #[serde(with = "PublicationDef")] proto::Publication,
+        #[serde(with = "deadline")] Option<StdInstant>,
+    );
+
+    pub(crate) fn serialize<S>(queue: &Queue, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(
+            queue
+                .iter()
+                .map(|(id, publication, deadline)| QueuedRef(*id, publication, *deadline)),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Queue, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let queue = Vec::<QueuedOwned>::deserialize(deserializer)?
+            .into_iter()
+            .map(|QueuedOwned(id, publication, deadline)| (id, publication, deadline))
+            .collect();
+        Ok(queue)
+    }
+
+    /// Reads a queue stored by a state format before 5, whose messages
+    /// had no properties.
+    pub(crate) fn deserialize_v4<'de, D>(deserializer: D) -> Result<Queue, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let queue = Vec::<QueuedV4>::deserialize(deserializer)?
+            .into_iter()
+            .map(|QueuedV4(id, publication, deadline)| (id, publication.into(), deadline))
+            .collect();
+        Ok(queue)
+    }
+}
+
+/// Only state formats before 5 hold retained messages without properties.
+pub(crate) mod publication_map {
+    use super::{Deserialize, Deserializer, HashMap, PublicationOwned};
+    use crate::Publication;
+
+    pub(crate) fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<HashMap<String, Publication>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = HashMap::<String, PublicationOwned>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(topic, PublicationOwned(publication))| (topic, publication.into()))
+            .collect();
+        Ok(map)
+    }
+}
+
+/// Instants are only meaningful within a single process, so they are
+/// stored as wall clock time and mapped back onto the monotonic clock
+/// when loaded.
+pub(crate) mod instant {
+    use super::{
+        from_system_time, to_system_time, Deserialize, Deserializer, Instant, Serialize,
+        Serializer, SystemTime,
+    };
+
+    pub(crate) fn serialize<S>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        to_system_time(instant.into_std()).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Instant, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let time = SystemTime::deserialize(deserializer)?;
+        Ok(Instant::from_std(from_system_time(time)))
+    }
+}
+
+/// When a message expires, stored like `instant`. `None` means that the
+/// message doesn't expire.
+pub(crate) mod deadline {
+    use super::{
+        from_system_time, to_system_time, Deserialize, Deserializer, Serialize, Serializer,
+        StdInstant, SystemTime,
+    };
+
+    pub(crate) fn serialize<S>(
+        deadline: &Option<StdInstant>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        deadline.map(to_system_time).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<StdInstant>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let time = Option::<SystemTime>::deserialize(deserializer)?;
+        Ok(time.map(from_system_time))
+    }
+}
+
+/// Deadlines of retained messages by topic, stored like `deadline`.
+pub(crate) mod deadline_map {
+    use super::{
+        from_system_time, to_system_time, Deserialize, Deserializer, HashMap, Serializer,
+        StdInstant, SystemTime,
+    };
+
+    pub(crate) fn serialize<S>(
+        map: &HashMap<String, StdInstant>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(
+            map.iter()
+                .map(|(topic, deadline)| (topic, to_system_time(*deadline))),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<HashMap<String, StdInstant>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = HashMap::<String, SystemTime>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(topic, time)| (topic, from_system_time(time)))
+            .collect();
+        Ok(map)
+    }
+}
+
+/// Maps an instant, which can be in the past or the future, onto the wall
+/// clock.
+fn to_system_time(instant: StdInstant) -> SystemTime {
+    let now = StdInstant::now();
+    let system_now = SystemTime::now();
+    if instant > now {
+        system_now + (instant - now)
+    } else {
+        system_now.checked_sub(now - instant).unwrap_or(UNIX_EPOCH)
+    }
+}
+
+/// Maps wall clock time back onto the monotonic clock. Times before the
+/// monotonic clock started map to now.
+fn from_system_time(time: SystemTime) -> StdInstant {
+    let now = StdInstant::now();
+    match time.duration_since(SystemTime::now()) {
+        Ok(ahead) => now + ahead,
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now),
+    }
+}
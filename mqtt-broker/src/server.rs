@@ -1,43 +1,210 @@
 use std::fmt::Display;
+#[cfg(unix)]
+use std::fs;
 use std::future::Future;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use failure::ResultExt;
 
 use futures_util::future::{self, Either, FutureExt};
 use futures_util::pin_mut;
-use futures_util::stream::StreamExt;
-use tokio::net::{TcpListener, ToSocketAddrs};
-use tokio::sync::oneshot;
-use tracing::{debug, error, info, span, warn, Level};
+use futures_util::stream::{Stream, StreamExt};
+use metrics::{decrement_gauge, increment_counter, increment_gauge};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{oneshot, watch};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, span, warn, Level, Span};
 use tracing_futures::Instrument;
+#[cfg(unix)]
+use uuid::Uuid;
 
+use crate::auth::{AllowAll, Authenticator};
 use crate::broker::{Broker, BrokerHandle, BrokerState};
-use crate::{connection, Error, ErrorKind, Message, SystemEvent};
+use crate::config::BrokerConfig;
+use crate::tls::{self, TlsConfig};
+#[cfg(unix)]
+use crate::PeerCredentials;
+use crate::{connection, websocket, Error, ErrorKind, Message, PeerAddr, SystemEvent};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const WEBSOCKET_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A socket the server accepts connections on, along with how the accepted
+/// connections are handled.
+#[derive(Clone)]
+pub struct Listener {
+    addr: String,
+    socket: Socket,
+    transport: Transport,
+}
+
+impl Listener {
+    pub fn tcp<A: Display>(addr: A) -> Self {
+        Self {
+            addr: addr.to_string(),
+            socket: Socket::Tcp,
+            transport: Transport::Tcp,
+        }
+    }
+
+    /// A listener serving clients on a Unix domain socket at `path`.
+    ///
+    /// A file left at `path` by a previous run is replaced. With `mode`
+    /// the permissions of the socket file are set, e.g. `0o660` to only
+    /// let the owner and group connect. The file is removed when the
+    /// listener stops.
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(path: P, mode: Option<u32>) -> Self {
+        let path = path.into();
+        Self {
+            addr: path.display().to_string(),
+            socket: Socket::Unix { path, mode },
+            transport: Transport::Tcp,
+        }
+    }
+
+    /// A listener serving clients over TLS.
+    ///
+    /// The certificate and key are loaded when the listener is created.
+    pub fn tls<A: Display>(addr: A, tls: &TlsConfig) -> Result<Self, Error> {
+        let transport = Transport::Tls {
+            acceptor: tls.acceptor()?,
+            match_client_id: tls.match_client_id(),
+        };
+        Ok(Self {
+            addr: addr.to_string(),
+            socket: Socket::Tcp,
+            transport,
+        })
+    }
+
+    /// A listener serving clients over websockets.
+    ///
+    /// Clients upgrade an HTTP request for `path` (e.g. `/mqtt`) with the
+    /// `mqtt` subprotocol, then send MQTT packets in binary frames.
+    pub fn websocket<A: Display, P: Into<String>>(addr: A, path: P) -> Self {
+        Self {
+            addr: addr.to_string(),
+            socket: Socket::Tcp,
+            transport: Transport::WebSocket { path: path.into() },
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+}
 
-#[derive(Default)]
 pub struct Server {
     broker: Broker,
+    listeners: Vec<Listener>,
+    authenticator: Arc<dyn Authenticator>,
 }
 
 impl Server {
-    pub fn new() -> Self {
+    pub fn new(config: BrokerConfig) -> Self {
+        Self::from_broker(Broker::new(config))
+    }
+
+    /// Connections are handled with the limits and timeouts of the
+    /// broker's config, including updates to it.
+    pub fn from_broker(broker: Broker) -> Self {
         Self {
-            broker: Broker::default(),
+            broker,
+            listeners: Vec::new(),
+            authenticator: Arc::new(AllowAll),
         }
     }
 
+    /// Authenticates clients connecting on any listener. By default every
+    /// client is allowed to connect.
+    pub fn with_authenticator<A>(mut self, authenticator: A) -> Self
+    where
+        A: Authenticator + 'static,
+    {
+        self.authenticator = Arc::new(authenticator);
+        self
+    }
+
+    /// Adds a listener. Connections from every listener are handled by the
+    /// same broker.
+    pub fn with_listener(mut self, listener: Listener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
     pub async fn serve<A, F>(self, addr: A, shutdown_signal: F) -> Result<BrokerState, Error>
     where
         A: ToSocketAddrs + Display,
         F: Future<Output = ()> + Unpin,
     {
-        let Server { broker } = self;
+        self.with_listener(Listener::tcp(addr))
+            .run(shutdown_signal)
+            .await
+    }
+
+    /// Serves clients over TLS.
+    ///
+    /// The certificate and key are loaded before the listener is bound.
+    pub async fn serve_tls<A, F>(
+        self,
+        addr: A,
+        tls: &TlsConfig,
+        shutdown_signal: F,
+    ) -> Result<BrokerState, Error>
+    where
+        A: ToSocketAddrs + Display,
+        F: Future<Output = ()> + Unpin,
+    {
+        self.with_listener(Listener::tls(addr, tls)?)
+            .run(shutdown_signal)
+            .await
+    }
+
+    /// Runs the broker and accepts connections on every listener until
+    /// `shutdown_signal` completes.
+    ///
+    /// All accept loops are stopped before the broker is shut down. If a
+    /// listener fails, the other listeners and the broker are shut down
+    /// and the error is returned.
+    pub async fn run<F>(self, shutdown_signal: F) -> Result<BrokerState, Error>
+    where
+        F: Future<Output = ()> + Unpin,
+    {
+        let Server {
+            broker,
+            listeners,
+            authenticator,
+        } = self;
         let mut handle = broker.handle();
+        let config = broker.config_receiver();
 
-        let (itx, irx) = oneshot::channel::<()>();
+        let mut shutdowns = Vec::with_capacity(listeners.len());
+        let mut incoming_tasks = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let (itx, irx) = oneshot::channel::<()>();
+            shutdowns.push(itx);
+            incoming_tasks.push(incoming_task(
+                listener,
+                authenticator.clone(),
+                handle.clone(),
+                config.clone(),
+                irx.map(drop),
+            ));
+        }
 
         let broker_task = tokio::spawn(broker.run());
-        let incoming_task = incoming_task(addr, handle.clone(), irx.map(drop));
+        let incoming_task = future::try_join_all(incoming_tasks);
         pin_mut!(broker_task);
         pin_mut!(incoming_task);
 
@@ -48,9 +215,12 @@ impl Server {
             Either::Left((_, tasks)) => {
                 info!("server received shutdown signal");
 
-                // shutdown the incoming loop
-                info!("shutting down accept loop...");
-                itx.send(()).unwrap();
+                // shutdown the incoming loops
+                info!("shutting down accept loops...");
+                for itx in shutdowns {
+                    // the accept loop may have already exited on its own
+                    let _ = itx.send(());
+                }
                 match tasks.await {
                     Either::Right((_, broker_task)) => {
                         debug!("sending Shutdown message to broker");
@@ -71,6 +241,8 @@ impl Server {
                     broker_task.await.context(ErrorKind::BrokerJoin)?
                 }
                 Either::Right((Err(e), broker_task)) => {
+                    // try_join_all drops the other accept loops on the
+                    // first error, so they are already stopped
                     error!(message = "an error occurred in the accept loop", error=%e);
                     debug!("sending Shutdown message to broker");
                     handle.send(Message::System(SystemEvent::Shutdown)).await?;
@@ -79,6 +251,9 @@ impl Server {
                 }
                 Either::Left((broker_state, incoming_task)) => {
                     warn!("broker exited before accept loop");
+                    for itx in shutdowns {
+                        let _ = itx.send(());
+                    }
                     incoming_task.await?;
                     broker_state.context(ErrorKind::BrokerJoin)?
                 }
@@ -88,44 +263,283 @@ impl Server {
     }
 }
 
-async fn incoming_task<A, F>(
-    addr: A,
+impl Default for Server {
+    fn default() -> Self {
+        Self::new(BrokerConfig::default())
+    }
+}
+
+/// The kind of socket a listener accepts connections on.
+#[derive(Clone)]
+enum Socket {
+    Tcp,
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+}
+
+/// How accepted connections are wrapped before their packets are processed.
+#[derive(Clone)]
+enum Transport {
+    Tcp,
+    Tls {
+        acceptor: TlsAcceptor,
+        match_client_id: bool,
+    },
+    WebSocket {
+        path: String,
+    },
+}
+
+impl Transport {
+    async fn process<I>(
+        self,
+        stream: I,
+        peer: PeerAddr,
+        authenticator: Arc<dyn Authenticator>,
+        broker_handle: BrokerHandle,
+        config: BrokerConfig,
+    ) -> Result<(), Error>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Transport::Tcp => {
+                connection::process(
+                    stream,
+                    peer,
+                    None,
+                    false,
+                    authenticator,
+                    broker_handle,
+                    &config,
+                )
+                .await
+            }
+            Transport::Tls {
+                acceptor,
+                match_client_id,
+            } => {
+                let stream = time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                    .await
+                    .context(ErrorKind::TlsHandshake)?
+                    .context(ErrorKind::TlsHandshake)?;
+                let (_, session) = stream.get_ref();
+                let certificate = tls::peer_identity(session)?;
+                connection::process(
+                    stream,
+                    peer,
+                    certificate,
+                    match_client_id,
+                    authenticator,
+                    broker_handle,
+                    &config,
+                )
+                .await
+            }
+            Transport::WebSocket { path } => {
+                let stream = time::timeout(
+                    WEBSOCKET_HANDSHAKE_TIMEOUT,
+                    websocket::accept(stream, &path),
+                )
+                .await
+                .context(ErrorKind::WebSocketHandshake)?
+                .context(ErrorKind::WebSocketHandshake)?;
+                connection::process(
+                    stream,
+                    peer,
+                    None,
+                    false,
+                    authenticator,
+                    broker_handle,
+                    &config,
+                )
+                .await
+            }
+        }
+    }
+}
+
+async fn incoming_task<F>(
+    listener: Listener,
+    authenticator: Arc<dyn Authenticator>,
     handle: BrokerHandle,
-    mut shutdown_signal: F,
+    config: watch::Receiver<BrokerConfig>,
+    shutdown_signal: F,
 ) -> Result<(), Error>
 where
-    A: ToSocketAddrs + Display,
     F: Future<Output = ()> + Unpin,
 {
+    let Listener {
+        addr,
+        socket,
+        transport,
+    } = listener;
     let span = span!(Level::INFO, "server", listener=%addr);
     let _enter = span.enter();
 
-    let mut listener = TcpListener::bind(&addr)
-        .await
-        .context(ErrorKind::BindServer)?;
-    let mut incoming = listener.incoming();
-
-    info!("Listening on address {}", addr);
+    match socket {
+        Socket::Tcp => {
+            let mut listener = TcpListener::bind(addr.as_str())
+                .await
+                .context(ErrorKind::BindServer)?;
+            info!("Listening on address {}", addr);
 
-    loop {
-        match future::select(&mut shutdown_signal, incoming.next()).await {
-            Either::Right((Some(Ok(stream)), _)) => {
+            let peer_addr = |stream: &TcpStream| {
                 stream
                     .set_nodelay(true)
                     .context(ErrorKind::ConnectionConfiguration)?;
                 let peer = stream
                     .peer_addr()
                     .context(ErrorKind::ConnectionPeerAddress)?;
+                Ok(PeerAddr::Tcp(peer))
+            };
+            let incoming = listener.incoming();
+            accept_loop(
+                &addr,
+                incoming,
+                peer_addr,
+                &transport,
+                &authenticator,
+                &handle,
+                &config,
+                &span,
+                shutdown_signal,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        Socket::Unix { path, mode } => {
+            let mut listener = bind_unix(&path, mode)?;
+            info!("Listening on Unix socket {}", addr);
+
+            let peer_addr = |stream: &UnixStream| {
+                let credentials = stream
+                    .peer_cred()
+                    .map(|cred| PeerCredentials::new(cred.uid, cred.gid))
+                    .ok();
+                Ok(PeerAddr::Unix(credentials))
+            };
+            let incoming = listener.incoming();
+            let result = accept_loop(
+                &addr,
+                incoming,
+                peer_addr,
+                &transport,
+                &authenticator,
+                &handle,
+                &config,
+                &span,
+                shutdown_signal,
+            )
+            .await;
+
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("failed to remove Unix socket {} - {}", addr, e);
+            }
+            result
+        }
+    }
+}
+
+/// Binds a Unix socket, replacing a socket file left by a previous run.
+///
+/// With `mode`, the socket is bound in a new directory only the owner can
+/// enter, given `mode` there and then moved to `path`, so clients the mode
+/// keeps out can't connect in between. The umask isn't used for this, as
+/// it is shared by every thread of the process.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener, Error> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            debug!("removing stale Unix socket {}", path.display());
+            fs::remove_file(path).context(ErrorKind::BindServer)?;
+        }
+    }
+
+    match mode {
+        Some(mode) => bind_unix_with_mode(path, mode),
+        None => Ok(UnixListener::bind(path).context(ErrorKind::BindServer)?),
+    }
+}
 
+#[cfg(unix)]
+fn bind_unix_with_mode(path: &Path, mode: u32) -> Result<UnixListener, Error> {
+    // The directory is next to `path`, so the socket can be renamed into place
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let id = Uuid::new_v4().to_simple().to_string();
+    let dir = parent.join(format!(".mqttd-{}", &id[..8]));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .context(ErrorKind::BindServer)?;
+
+    let result = bind_unix_in(&dir, path, mode);
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!(message = "failed to remove a temporary socket directory", dir=%dir.display(), error=%e);
+    }
+    result
+}
+
+#[cfg(unix)]
+fn bind_unix_in(dir: &Path, path: &Path, mode: u32) -> Result<UnixListener, Error> {
+    let temp = dir.join("sock");
+    let listener = UnixListener::bind(&temp).context(ErrorKind::BindServer)?;
+    fs::set_permissions(&temp, fs::Permissions::from_mode(mode))
+        .context(ErrorKind::SocketPermissions)?;
+    fs::rename(&temp, path).context(ErrorKind::BindServer)?;
+    Ok(listener)
+}
+
+/// Accepts connections until `shutdown_signal` completes, handling each
+/// one on its own task.
+///
+/// `peer_addr` configures an accepted stream and returns the address of its
+/// client.
+#[allow(clippy::too_many_arguments)]
+async fn accept_loop<S, I, P, F>(
+    addr: &str,
+    mut incoming: S,
+    peer_addr: P,
+    transport: &Transport,
+    authenticator: &Arc<dyn Authenticator>,
+    handle: &BrokerHandle,
+    config: &watch::Receiver<BrokerConfig>,
+    span: &Span,
+    mut shutdown_signal: F,
+) -> Result<(), Error>
+where
+    S: Stream<Item = io::Result<I>> + Unpin,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    P: Fn(&I) -> Result<PeerAddr, Error>,
+    F: Future<Output = ()> + Unpin,
+{
+    loop {
+        match future::select(&mut shutdown_signal, incoming.next()).await {
+            Either::Right((Some(Ok(stream)), _)) => {
+                let peer = peer_addr(&stream)?;
+
+                let transport = transport.clone();
+                let authenticator = authenticator.clone();
                 let broker_handle = handle.clone();
+                let config = config.borrow().clone();
                 let span = span.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = connection::process(stream, peer, broker_handle)
+                    increment_counter!("mqtt_connections_total");
+                    increment_gauge!("mqtt_connections", 1.0);
+                    if let Err(e) = transport
+                        .process(stream, peer, authenticator, broker_handle, config)
                         .instrument(span)
                         .await
                     {
                         warn!(message = "failed to process connection", error=%e);
                     }
+                    decrement_gauge!("mqtt_connections", 1.0);
                 });
             }
             Either::Left(_) => {
@@ -147,3 +561,96 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{SocketAddr, TcpListener as StdTcpListener};
+
+    fn free_addr() -> SocketAddr {
+        StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn connect(addr: SocketAddr) {
+        for _ in 0..50 {
+            if TcpStream::connect(addr).await.is_ok() {
+                return;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("unable to connect to {}", addr);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let addr1 = free_addr();
+        let addr2 = free_addr();
+        let server = Server::default()
+            .with_listener(Listener::tcp(addr1))
+            .with_listener(Listener::tcp(addr2));
+
+        let (tx, rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(server.run(rx.map(drop)));
+
+        connect(addr1).await;
+        connect(addr2).await;
+
+        tx.send(()).unwrap();
+        task.await.unwrap().unwrap();
+
+        assert!(TcpStream::connect(addr1).await.is_err());
+        assert!(TcpStream::connect(addr2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_listener_bind_error() {
+        let addr1 = free_addr();
+        let taken = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::default()
+            .with_listener(Listener::tcp(addr1))
+            .with_listener(Listener::tcp(taken.local_addr().unwrap()));
+
+        let err = server.run(future::pending()).await.unwrap_err();
+        assert_eq!(ErrorKind::BindServer, *err.kind());
+        assert!(TcpStream::connect(addr1).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_listener() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("mqttd.sock");
+
+        // A socket file left behind by a previous run is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let server = Server::default().with_listener(Listener::unix(&path, Some(0o600)));
+        let (tx, rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(server.run(rx.map(drop)));
+
+        let mut connected = false;
+        for _ in 0..50 {
+            if UnixStream::connect(&path).await.is_ok() {
+                connected = true;
+                break;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(connected, "unable to connect to {}", path.display());
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        // Only the socket is left once it is bound
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+        tx.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,34 +1,98 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::time::Duration;
 use std::{cmp, fmt, mem};
 
 use failure::ResultExt;
+use metrics::counter;
 use mqtt::proto;
+use serde::de::{Deserializer, Error as _};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 use tracing::{debug, warn};
 
-use crate::subscription::Subscription;
-use crate::{ClientEvent, ClientId, ConnReq, ConnectionHandle, Error, ErrorKind, Message, Publish};
+use crate::auth::AuthId;
+use crate::authorization::{Authorizer, Operation};
+use crate::config::{self, QueueLimits};
+use crate::queue::PublicationQueue;
+use crate::serialize;
+use crate::subscription::{Subscription, TopicFilter};
+use crate::{
+    ClientEvent, ClientId, ConnReq, ConnectionHandle, Error, ErrorKind, Message, ProtocolVersion,
+    Publication, PublicationId, PublicationProperties, Publish,
+};
+
+/// The will of a client, published when its connection is lost. An MQTT 5
+/// client may ask for it to be held back for a while, and for it to expire
+/// some time after it is published.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Will {
+    publication: Publication,
+    delay: Duration,
+    message_expiry_interval: Option<Duration>,
+}
+
+impl Will {
+    pub fn new(publication: Publication, delay: Duration) -> Self {
+        Self {
+            publication,
+            delay,
+            message_expiry_interval: None,
+        }
+    }
+
+    fn of(connreq: &ConnReq) -> Option<Self> {
+        let will = connreq
+            .will()
+            .map(|publication| Self::new(publication, Duration::default()));
+        match (will, connreq.properties()) {
+            (Some(will), Some(properties)) => Some(Self {
+                delay: properties.will_delay_interval,
+                message_expiry_interval: properties.will_message_expiry_interval,
+                ..will
+            }),
+            (will, _) => will,
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
 
-const MAX_INFLIGHT_MESSAGES: usize = 16;
+    /// The publication of the will, which starts to expire now.
+    pub fn into_publication(self) -> Publication {
+        let mut publication = self.publication;
+        if let Some(interval) = self.message_expiry_interval {
+            publication.properties.message_expiry = Some(std::time::Instant::now() + interval);
+        }
+        publication
+    }
+}
 
 #[derive(Debug)]
 pub struct ConnectedSession {
     state: SessionState,
-    will: Option<proto::Publication>,
+    will: Option<Will>,
     handle: ConnectionHandle,
+    auth_id: Option<AuthId>,
+    protocol_version: ProtocolVersion,
 }
 
 impl ConnectedSession {
     fn new(
         state: SessionState,
-        will: Option<proto::Publication>,
+        will: Option<Will>,
         handle: ConnectionHandle,
+        auth_id: Option<AuthId>,
+        protocol_version: ProtocolVersion,
     ) -> Self {
         Self {
             state,
             will,
             handle,
+            auth_id,
+            protocol_version,
         }
     }
 
@@ -36,23 +100,42 @@ impl ConnectedSession {
         &self.state.client_id
     }
 
+    /// The identity the client authenticated as, if any.
+    pub fn auth_id(&self) -> Option<&AuthId> {
+        self.auth_id.as_ref()
+    }
+
+    /// The protocol version negotiated on the client's connection.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
     pub fn handle(&self) -> &ConnectionHandle {
         &self.handle
     }
 
-    pub fn into_will(self) -> Option<proto::Publication> {
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut SessionState {
+        &mut self.state
+    }
+
+    pub fn into_will(self) -> Option<Will> {
         self.will
     }
 
-    pub fn into_parts(self) -> (SessionState, Option<proto::Publication>, ConnectionHandle) {
+    pub fn into_parts(self) -> (SessionState, Option<Will>, ConnectionHandle) {
         (self.state, self.will, self.handle)
     }
 
     pub fn handle_publish(
         &mut self,
         publish: proto::Publish,
-    ) -> Result<(Option<proto::Publication>, Option<ClientEvent>), Error> {
-        self.state.handle_publish(publish)
+        properties: PublicationProperties,
+    ) -> Result<(Option<Publication>, Option<ClientEvent>), Error> {
+        self.state.handle_publish(publish, properties)
     }
 
     pub fn handle_puback(&mut self, puback: &proto::PubAck) -> Result<Option<ClientEvent>, Error> {
@@ -70,10 +153,7 @@ impl ConnectedSession {
         self.state.handle_pubrec(pubrec)
     }
 
-    pub fn handle_pubrel(
-        &mut self,
-        pubrel: &proto::PubRel,
-    ) -> Result<Option<proto::Publication>, Error> {
+    pub fn handle_pubrel(&mut self, pubrel: &proto::PubRel) -> Result<Option<ClientEvent>, Error> {
         self.state.handle_pubrel(pubrel)
     }
 
@@ -86,21 +166,50 @@ impl ConnectedSession {
 
     pub fn publish_to(
         &mut self,
-        publication: proto::Publication,
+        id: PublicationId,
+        publication: Publication,
+        max_qos: proto::QoS,
+        deadline: Option<std::time::Instant>,
     ) -> Result<Option<ClientEvent>, Error> {
-        self.state.publish_to(publication)
+        self.state.publish_to(id, publication, max_qos, deadline)
     }
 
+    pub fn publish_shared(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<std::time::Instant>,
+        filter: &str,
+    ) -> Result<Option<ClientEvent>, Error> {
+        self.state.publish_shared(id, publication, deadline, filter)
+    }
+
+    /// Subscriptions to filters the client isn't authorized for fail.
     pub fn subscribe(
         &mut self,
         subscribe: proto::Subscribe,
+        authorizer: &dyn Authorizer,
     ) -> Result<(proto::SubAck, Vec<Subscription>), Error> {
         let mut subscriptions = Vec::with_capacity(subscribe.subscribe_to.len());
         let mut acks = Vec::with_capacity(subscribe.subscribe_to.len());
         let packet_identifier = subscribe.packet_identifier;
 
         for subscribe_to in subscribe.subscribe_to {
-            let ack_qos = match subscribe_to.topic_filter.parse() {
+            let ack_qos = match subscribe_to.topic_filter.parse::<TopicFilter>() {
+                Ok(filter)
+                    if !authorizer.authorize(
+                        self.client_id(),
+                        self.auth_id(),
+                        Operation::Subscribe(&filter),
+                    ) =>
+                {
+                    warn!(
+                        "client {} is not authorized to subscribe to {}",
+                        self.client_id(),
+                        filter
+                    );
+                    proto::SubAckQos::Failure
+                }
                 Ok(filter) => {
                     let proto::SubscribeTo { topic_filter, qos } = subscribe_to;
 
@@ -166,20 +275,95 @@ impl OfflineSession {
 
     pub fn publish_to(
         &mut self,
-        publication: proto::Publication,
+        id: PublicationId,
+        publication: Publication,
+        max_qos: proto::QoS,
+        deadline: Option<std::time::Instant>,
     ) -> Result<Option<ClientEvent>, Error> {
-        self.state.queue_publish(publication)?;
+        self.state
+            .queue_publish(id, publication, max_qos, deadline)?;
         Ok(None)
     }
 
+    pub fn publish_shared(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<std::time::Instant>,
+        filter: &str,
+    ) -> Result<Option<ClientEvent>, Error> {
+        self.state.queue_shared(id, publication, deadline, filter);
+        Ok(None)
+    }
+
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut SessionState {
+        &mut self.state
+    }
+
+    pub fn into_state(self) -> SessionState {
+        self.state
+    }
+
+    /// Applies a journaled PUBACK for a message sent before a restart.
+    pub fn replay_puback(&mut self, packet_identifier: proto::PacketIdentifier, id: PublicationId) {
+        self.remove_delivered(packet_identifier, id);
+        self.state.packet_identifiers.discard(packet_identifier);
+    }
+
+    /// Applies a journaled PUBREC for a message sent before a restart.
+    ///
+    /// The PUBREL is sent again when the session comes back online.
+    pub fn replay_pubrec(&mut self, packet_identifier: proto::PacketIdentifier, id: PublicationId) {
+        self.remove_delivered(packet_identifier, id);
+        self.state.packet_identifiers.mark(packet_identifier);
+        self.state.waiting_to_be_completed.insert(packet_identifier);
+    }
+
+    /// Applies a journaled PUBCOMP for a message sent before a restart.
+    pub fn replay_pubcomp(&mut self, id: proto::PacketIdentifier) {
+        self.state.waiting_to_be_completed.remove(&id);
+        self.state.packet_identifiers.discard(id);
+    }
+
+    /// Applies a journaled exactly once publish the client sent before a
+    /// restart.
+    pub fn replay_publish_from(&mut self, id: proto::PacketIdentifier) {
+        self.state.waiting_to_be_released.insert(id);
+    }
+
+    /// Applies a journaled PUBREL the client sent before a restart.
+    pub fn replay_pubrel(&mut self, id: proto::PacketIdentifier) {
+        self.state.waiting_to_be_released.remove(&id);
+    }
+
+    /// Removes a message the client received before a restart.
+    ///
+    /// The message is inflight in the restored state if it was sent before
+    /// the snapshot was taken, and still queued otherwise.
+    fn remove_delivered(&mut self, packet_identifier: proto::PacketIdentifier, id: PublicationId) {
+        let waiting_to_be_acked = &mut self.state.waiting_to_be_acked;
+        match waiting_to_be_acked.get(&packet_identifier) {
+            Some((inflight, _)) if *inflight == id => {
+                waiting_to_be_acked.remove(&packet_identifier);
+            }
+            _ => {
+                self.state.waiting_to_be_sent.remove_id(id);
+            }
+        }
+    }
+
     pub fn into_online(self) -> Result<(SessionState, Vec<ClientEvent>), Error> {
-        let mut events = Vec::with_capacity(MAX_INFLIGHT_MESSAGES);
+        let mut events = Vec::with_capacity(self.state.max_inflight_messages);
         let OfflineSession { mut state } = self;
 
         // Handle the outstanding QoS 1 and QoS 2 packets
-        for (id, publish) in &state.waiting_to_be_acked {
+        for (id, (_, publish)) in &state.waiting_to_be_acked {
             let to_publish = match publish {
-                Publish::QoS12(id, p) => {
+                Publish::QoS12(id, p, properties) => {
                     let pidq = match p.packet_identifier_dup_qos {
                         proto::PacketIdentifierDupQoS::AtLeastOnce(id, _) => {
                             proto::PacketIdentifierDupQoS::AtLeastOnce(id, true)
@@ -194,7 +378,7 @@ impl OfflineSession {
 
                     let mut p1 = p.clone();
                     p1.packet_identifier_dup_qos = pidq;
-                    Publish::QoS12(*id, p1)
+                    Publish::QoS12(*id, p1, properties.clone())
                 }
                 _ => publish.clone(),
             };
@@ -219,9 +403,9 @@ impl OfflineSession {
         // Dequeue any queued messages - up to the max inflight count
         while state.allowed_to_send() {
             match state.waiting_to_be_sent.pop_front() {
-                Some(publication) => {
+                Some((id, publication)) => {
                     debug!("dequeueing a message for {}", state.client_id);
-                    let event = state.prepare_to_send(&publication)?;
+                    let event = state.prepare_to_send(id, &publication)?;
                     events.push(event);
                 }
                 None => break,
@@ -235,16 +419,12 @@ impl OfflineSession {
 #[derive(Debug)]
 pub struct DisconnectingSession {
     client_id: ClientId,
-    will: Option<proto::Publication>,
+    will: Option<Will>,
     handle: ConnectionHandle,
 }
 
 impl DisconnectingSession {
-    fn new(
-        client_id: ClientId,
-        will: Option<proto::Publication>,
-        handle: ConnectionHandle,
-    ) -> Self {
+    fn new(client_id: ClientId, will: Option<Will>, handle: ConnectionHandle) -> Self {
         Self {
             client_id,
             will,
@@ -256,7 +436,7 @@ impl DisconnectingSession {
         &self.client_id
     }
 
-    pub fn into_will(self) -> Option<proto::Publication> {
+    pub fn into_will(self) -> Option<Will> {
         self.will
     }
 
@@ -270,24 +450,43 @@ impl DisconnectingSession {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SessionState {
     client_id: ClientId,
     keep_alive: Duration,
+    #[serde(with = "serialize::instant")]
     last_active: Instant,
+    // Set by MQTT 5 clients, otherwise the broker's session expiry applies
+    expiry_interval: Option<Duration>,
     subscriptions: HashMap<String, Subscription>,
     packet_identifiers: PacketIdentifiers,
     packet_identifiers_qos0: PacketIdentifiers,
 
-    waiting_to_be_sent: VecDeque<proto::Publication>,
+    waiting_to_be_sent: PublicationQueue,
 
-    // for incoming messages - QoS2
-    waiting_to_be_released: HashMap<proto::PacketIdentifier, proto::Publish>,
+    // for incoming messages - QoS2, delivered and waiting for their PUBREL
+    #[serde(with = "serialize::packet_identifier_set")]
+    waiting_to_be_released: HashSet<proto::PacketIdentifier>,
 
     // for outgoing messages - all QoS
-    waiting_to_be_acked: HashMap<proto::PacketIdentifier, Publish>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked: HashMap<proto::PacketIdentifier, (PublicationId, Publish)>,
+    #[serde(with = "serialize::packet_identifier_map")]
     waiting_to_be_acked_qos0: HashMap<proto::PacketIdentifier, Publish>,
+    #[serde(with = "serialize::packet_identifier_set")]
     waiting_to_be_completed: HashSet<proto::PacketIdentifier>,
+
+    // Comes from the broker's config rather than the stored state
+    #[serde(skip, default = "config::default_max_inflight_messages")]
+    max_inflight_messages: usize,
+    #[serde(skip)]
+    queue_limits: QueueLimits,
+    // Comes from the client's current connection
+    #[serde(skip)]
+    receive_maximum: Option<u16>,
+    // Counted since the broker started
+    #[serde(skip)]
+    dropped_count: u64,
 }
 
 impl SessionState {
@@ -296,18 +495,96 @@ impl SessionState {
             client_id,
             keep_alive: connreq.connect().keep_alive,
             last_active: Instant::now(),
+            expiry_interval: connreq
+                .properties()
+                .map(|properties| properties.session_expiry_interval),
             subscriptions: HashMap::new(),
             packet_identifiers: PacketIdentifiers::default(),
             packet_identifiers_qos0: PacketIdentifiers::default(),
 
-            waiting_to_be_sent: VecDeque::new(),
+            waiting_to_be_sent: PublicationQueue::new(),
             waiting_to_be_acked: HashMap::new(),
             waiting_to_be_acked_qos0: HashMap::new(),
-            waiting_to_be_released: HashMap::new(),
+            waiting_to_be_released: HashSet::new(),
             waiting_to_be_completed: HashSet::new(),
+
+            max_inflight_messages: config::default_max_inflight_messages(),
+            queue_limits: QueueLimits::default(),
+            receive_maximum: connreq
+                .properties()
+                .and_then(|properties| properties.receive_maximum),
+            dropped_count: 0,
         }
     }
 
+    /// Sets the limit of inflight messages, see
+    /// `BrokerConfig::max_inflight_messages`.
+    pub fn with_max_inflight_messages(mut self, max_inflight_messages: usize) -> Self {
+        self.max_inflight_messages = max_inflight_messages;
+        self
+    }
+
+    /// Changes the limit of inflight messages. Messages already inflight
+    /// stay inflight when the limit is lowered.
+    pub fn set_max_inflight_messages(&mut self, max_inflight_messages: usize) {
+        self.max_inflight_messages = max_inflight_messages;
+    }
+
+    /// Sets the limits of queued messages, see
+    /// `BrokerConfig::max_queued_messages`.
+    pub(crate) fn with_queue_limits(mut self, queue_limits: QueueLimits) -> Self {
+        self.queue_limits = queue_limits;
+        self
+    }
+
+    /// Changes the limits of queued messages. Messages already queued are
+    /// only dropped when the next one is queued.
+    pub(crate) fn set_queue_limits(&mut self, queue_limits: QueueLimits) {
+        self.queue_limits = queue_limits;
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
+    /// When a message was last sent to the client, or when the client went
+    /// offline.
+    pub fn last_active(&self) -> Instant {
+        self.last_active
+    }
+
+    pub(crate) fn mark_active(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// How long the session is kept after the client disconnects, if the
+    /// client asked for it with MQTT 5.
+    pub fn expiry_interval(&self) -> Option<Duration> {
+        self.expiry_interval
+    }
+
+    pub(crate) fn set_expiry_interval(&mut self, expiry_interval: Option<Duration>) {
+        self.expiry_interval = expiry_interval;
+    }
+
+    /// Limits inflight messages to the receive maximum an MQTT 5 client
+    /// connected with, on top of `max_inflight_messages`.
+    pub(crate) fn set_receive_maximum(&mut self, receive_maximum: Option<u16>) {
+        self.receive_maximum = receive_maximum;
+    }
+
+    /// Returns the publication of the QoS 1 or QoS 2 message sent with
+    /// this packet identifier that hasn't been acknowledged yet.
+    pub fn inflight(&self, packet_identifier: proto::PacketIdentifier) -> Option<PublicationId> {
+        self.waiting_to_be_acked
+            .get(&packet_identifier)
+            .map(|(id, _)| *id)
+    }
+
+    pub fn subscriptions(&self) -> impl Iterator<Item = &Subscription> {
+        self.subscriptions.values()
+    }
+
     pub fn update_subscription(
         &mut self,
         topic_filter: String,
@@ -320,63 +597,143 @@ impl SessionState {
         self.subscriptions.remove(topic_filter)
     }
 
-    pub fn queue_publish(&mut self, publication: proto::Publication) -> Result<(), Error> {
-        if let Some(publication) = self.filter(publication) {
-            self.waiting_to_be_sent.push_back(publication);
-        }
+    /// Queues a publication matching the subscriptions of this client.
+    /// `max_qos` is the largest `max_qos` of the matching subscriptions.
+    pub fn queue_publish(
+        &mut self,
+        id: PublicationId,
+        mut publication: Publication,
+        max_qos: proto::QoS,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<(), Error> {
+        publication.qos = cmp::min(max_qos, publication.qos);
+        self.queue(id, publication, deadline);
         Ok(())
     }
 
     /// Takes a publication and returns an optional Publish packet if sending is allowed.
     /// This can return None if the current outstanding messages is at its limit.
+    ///
+    /// Like `queue_publish`, the publication is sent with at most `max_qos`.
+    /// A queued message expires at `deadline`.
     pub fn publish_to(
         &mut self,
-        publication: proto::Publication,
+        id: PublicationId,
+        mut publication: Publication,
+        max_qos: proto::QoS,
+        deadline: Option<std::time::Instant>,
     ) -> Result<Option<ClientEvent>, Error> {
-        if let Some(publication) = self.filter(publication) {
-            if self.allowed_to_send() {
-                let event = self.prepare_to_send(&publication)?;
-                Ok(Some(event))
-            } else {
-                self.waiting_to_be_sent.push_back(publication);
-                Ok(None)
-            }
+        publication.qos = cmp::min(max_qos, publication.qos);
+        self.send_or_queue(id, publication, deadline)
+    }
+
+    /// Like `queue_publish`, for a publication matching the shared
+    /// subscription `filter` that this client was picked to receive.
+    pub fn queue_shared(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<std::time::Instant>,
+        filter: &str,
+    ) {
+        if let Some(publication) = self.filter_shared(publication, filter) {
+            self.queue(id, publication, deadline);
+        }
+    }
+
+    /// Like `publish_to`, for a publication matching the shared
+    /// subscription `filter` that this client was picked to receive.
+    pub fn publish_shared(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<std::time::Instant>,
+        filter: &str,
+    ) -> Result<Option<ClientEvent>, Error> {
+        if let Some(publication) = self.filter_shared(publication, filter) {
+            self.send_or_queue(id, publication, deadline)
         } else {
             Ok(None)
         }
     }
 
+    fn send_or_queue(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<Option<ClientEvent>, Error> {
+        if self.allowed_to_send() {
+            let event = self.prepare_to_send(id, &publication)?;
+            Ok(Some(event))
+        } else {
+            self.queue(id, publication, deadline);
+            Ok(None)
+        }
+    }
+
+    fn queue(
+        &mut self,
+        id: PublicationId,
+        publication: Publication,
+        deadline: Option<std::time::Instant>,
+    ) {
+        let dropped = self
+            .waiting_to_be_sent
+            .push(id, publication, deadline, &self.queue_limits);
+        if dropped > 0 {
+            debug!("dropped {} queued messages for {}", dropped, self.client_id);
+            self.dropped_count += dropped as u64;
+            counter!("mqtt_dropped_messages_total", dropped as u64);
+        }
+    }
+
     pub fn handle_publish(
         &mut self,
         publish: proto::Publish,
-    ) -> Result<(Option<proto::Publication>, Option<ClientEvent>), Error> {
+        properties: PublicationProperties,
+    ) -> Result<(Option<Publication>, Option<ClientEvent>), Error> {
         let result = match publish.packet_identifier_dup_qos {
             proto::PacketIdentifierDupQoS::AtMostOnce => {
-                let publication = proto::Publication {
+                let publication = Publication {
                     topic_name: publish.topic_name,
                     qos: proto::QoS::AtMostOnce,
                     retain: publish.retain,
                     payload: publish.payload,
+                    properties,
                 };
                 (Some(publication), None)
             }
             proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _dup) => {
-                let publication = proto::Publication {
+                let publication = Publication {
                     topic_name: publish.topic_name,
                     qos: proto::QoS::AtLeastOnce,
                     retain: publish.retain,
                     payload: publish.payload,
+                    properties,
                 };
                 let puback = proto::PubAck { packet_identifier };
                 let event = ClientEvent::PubAck(puback);
                 (Some(publication), Some(event))
             }
+            // The publication is delivered now and only its packet
+            // identifier is kept until the PUBREL, so a retransmitted
+            // PUBLISH isn't delivered twice.
             proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _dup) => {
-                self.waiting_to_be_released
-                    .insert(packet_identifier, publish);
+                let publication = if self.waiting_to_be_released.insert(packet_identifier) {
+                    Some(Publication {
+                        topic_name: publish.topic_name,
+                        qos: proto::QoS::ExactlyOnce,
+                        retain: publish.retain,
+                        payload: publish.payload,
+                        properties,
+                    })
+                } else {
+                    None
+                };
                 let pubrec = proto::PubRec { packet_identifier };
                 let event = ClientEvent::PubRec(pubrec);
-                (None, Some(event))
+                (publication, Some(event))
             }
         };
         Ok(result)
@@ -392,20 +749,13 @@ impl SessionState {
         Ok(Some(ClientEvent::PubRel(pubrel)))
     }
 
-    pub fn handle_pubrel(
-        &mut self,
-        pubrel: &proto::PubRel,
-    ) -> Result<Option<proto::Publication>, Error> {
-        let publication = self
-            .waiting_to_be_released
-            .remove(&pubrel.packet_identifier)
-            .map(|publish| proto::Publication {
-                topic_name: publish.topic_name,
-                qos: proto::QoS::ExactlyOnce,
-                retain: publish.retain,
-                payload: publish.payload,
-            });
-        Ok(publication)
+    pub fn handle_pubrel(&mut self, pubrel: &proto::PubRel) -> Result<Option<ClientEvent>, Error> {
+        self.waiting_to_be_released
+            .remove(&pubrel.packet_identifier);
+        let pubcomp = proto::PubComp {
+            packet_identifier: pubrel.packet_identifier,
+        };
+        Ok(Some(ClientEvent::PubComp(pubcomp)))
     }
 
     pub fn handle_pubcomp(
@@ -437,36 +787,63 @@ impl SessionState {
 
     fn try_publish(&mut self) -> Result<Option<ClientEvent>, Error> {
         if self.allowed_to_send() {
-            if let Some(publication) = self.waiting_to_be_sent.pop_front() {
-                let event = self.prepare_to_send(&publication)?;
+            if let Some((id, publication)) = self.waiting_to_be_sent.pop_front() {
+                let event = self.prepare_to_send(id, &publication)?;
                 return Ok(Some(event));
             }
         }
         Ok(None)
     }
 
-    fn allowed_to_send(&self) -> bool {
-        let num_inflight = self.waiting_to_be_acked.len()
+    /// Returns true if another message can be sent without going over the
+    /// limit of inflight messages.
+    pub fn allowed_to_send(&self) -> bool {
+        let max_inflight_messages = self
+            .receive_maximum
+            .map_or(self.max_inflight_messages, |max| {
+                cmp::min(self.max_inflight_messages, usize::from(max))
+            });
+        self.inflight_count() < max_inflight_messages
+    }
+
+    /// The number of messages sent and waiting to be acknowledged.
+    pub fn inflight_count(&self) -> usize {
+        self.waiting_to_be_acked.len()
             + self.waiting_to_be_acked_qos0.len()
-            + self.waiting_to_be_completed.len();
-        num_inflight < MAX_INFLIGHT_MESSAGES
+            + self.waiting_to_be_completed.len()
     }
 
-    fn filter(&self, mut publication: proto::Publication) -> Option<proto::Publication> {
-        self.subscriptions
-            .values()
-            .filter(|sub| sub.filter().matches(&publication.topic_name))
-            .fold(None, |acc, sub| {
-                acc.map(|qos| cmp::max(qos, cmp::min(*sub.max_qos(), publication.qos)))
-                    .or_else(|| Some(cmp::min(*sub.max_qos(), publication.qos)))
-            })
-            .map(move |qos| {
-                publication.qos = qos;
-                publication
-            })
+    /// The number of messages waiting to be sent.
+    pub fn queued_count(&self) -> usize {
+        self.waiting_to_be_sent.len()
     }
 
-    fn prepare_to_send(&mut self, publication: &proto::Publication) -> Result<ClientEvent, Error> {
+    /// The total payload size of the messages waiting to be sent.
+    pub fn queued_bytes(&self) -> usize {
+        self.waiting_to_be_sent.bytes()
+    }
+
+    /// The number of messages dropped since the broker started because the
+    /// queue was full.
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped_count
+    }
+
+    /// Applies the `max_qos` of the shared subscription `filter`, or returns
+    /// `None` if the client is no longer subscribed to it.
+    fn filter_shared(&self, mut publication: Publication, filter: &str) -> Option<Publication> {
+        let subscription = self.subscriptions.get(filter)?;
+        publication.qos = cmp::min(*subscription.max_qos(), publication.qos);
+        Some(publication)
+    }
+
+    /// Assigns a packet identifier to a message and records it as inflight
+    /// along with the id of its publication.
+    fn prepare_to_send(
+        &mut self,
+        id: PublicationId,
+        publication: &Publication,
+    ) -> Result<ClientEvent, Error> {
         let publish = match publication.qos {
             proto::QoS::AtMostOnce => {
                 let id = self.packet_identifiers_qos0.reserve()?;
@@ -476,7 +853,7 @@ impl SessionState {
                     topic_name: publication.topic_name.to_owned(),
                     payload: publication.payload.to_owned(),
                 };
-                Publish::QoS0(id, packet)
+                Publish::QoS0(id, packet, publication.properties.clone())
             }
             proto::QoS::AtLeastOnce => {
                 let id = self.packet_identifiers.reserve()?;
@@ -488,7 +865,7 @@ impl SessionState {
                     topic_name: publication.topic_name.to_owned(),
                     payload: publication.payload.to_owned(),
                 };
-                Publish::QoS12(id, packet)
+                Publish::QoS12(id, packet, publication.properties.clone())
             }
             proto::QoS::ExactlyOnce => {
                 let id = self.packet_identifiers.reserve()?;
@@ -500,26 +877,230 @@ impl SessionState {
                     topic_name: publication.topic_name.to_owned(),
                     payload: publication.payload.to_owned(),
                 };
-                Publish::QoS12(id, packet)
+                Publish::QoS12(id, packet, publication.properties.clone())
             }
         };
 
         let event = match publish {
-            Publish::QoS0(id, publish) => {
+            Publish::QoS0(packet_identifier, ..) => {
                 self.waiting_to_be_acked_qos0
-                    .insert(id, Publish::QoS0(id, publish.clone()));
-                ClientEvent::PublishTo(Publish::QoS0(id, publish))
+                    .insert(packet_identifier, publish.clone());
+                ClientEvent::PublishTo(publish)
             }
-            Publish::QoS12(id, publish) => {
+            Publish::QoS12(packet_identifier, ..) => {
                 self.waiting_to_be_acked
-                    .insert(id, Publish::QoS12(id, publish.clone()));
-                ClientEvent::PublishTo(Publish::QoS12(id, publish))
+                    .insert(packet_identifier, (id, publish.clone()));
+                ClientEvent::PublishTo(publish)
             }
         };
         Ok(event)
     }
 }
 
+/// Session state as stored by state format versions 1 and 2, before queued
+/// and inflight messages kept the id of their publication.
+#[derive(Deserialize)]
+pub(crate) struct SessionStateV2 {
+    client_id: ClientId,
+    keep_alive: Duration,
+    #[serde(with = "serialize::instant")]
+    last_active: Instant,
+    subscriptions: HashMap<String, Subscription>,
+    packet_identifiers: PacketIdentifiers,
+    packet_identifiers_qos0: PacketIdentifiers,
+    waiting_to_be_sent: Vec<PublicationV2>,
+    #[serde(deserialize_with = "serialize::publish_map::deserialize")]
+    waiting_to_be_released: HashMap<proto::PacketIdentifier, proto::Publish>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked: HashMap<proto::PacketIdentifier, PublishV4>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked_qos0: HashMap<proto::PacketIdentifier, PublishV4>,
+    #[serde(with = "serialize::packet_identifier_set")]
+    waiting_to_be_completed: HashSet<proto::PacketIdentifier>,
+}
+
+#[derive(Deserialize)]
+struct PublicationV2(#[serde(with = "serialize::PublicationDef")] proto::Publication);
+
+/// Migrated messages get the default publication id, which the broker never
+/// gives a publication. Journaled acknowledgements are only matched against
+/// the state they were journaled with, so nothing needs to find them by id.
+///
+/// Older states didn't store when queued messages expire, so migrated
+/// messages don't expire.
+impl From<SessionStateV2> for SessionStateV3 {
+    fn from(state: SessionStateV2) -> Self {
+        let id = PublicationId::default();
+        Self {
+            client_id: state.client_id,
+            keep_alive: state.keep_alive,
+            last_active: state.last_active,
+            subscriptions: state.subscriptions,
+            packet_identifiers: state.packet_identifiers,
+            packet_identifiers_qos0: state.packet_identifiers_qos0,
+
+            waiting_to_be_sent: state
+                .waiting_to_be_sent
+                .into_iter()
+                .map(|PublicationV2(publication)| (id, publication.into(), None))
+                .collect(),
+            waiting_to_be_released: state.waiting_to_be_released,
+            waiting_to_be_acked: state
+                .waiting_to_be_acked
+                .into_iter()
+                .map(|(packet_identifier, publish)| (packet_identifier, (id, publish)))
+                .collect(),
+            waiting_to_be_acked_qos0: state.waiting_to_be_acked_qos0,
+            waiting_to_be_completed: state.waiting_to_be_completed,
+        }
+    }
+}
+
+/// Session state as stored by state format version 3, which held exactly
+/// once messages from the client until the client released them.
+#[derive(Deserialize)]
+pub(crate) struct SessionStateV3 {
+    client_id: ClientId,
+    keep_alive: Duration,
+    #[serde(with = "serialize::instant")]
+    last_active: Instant,
+    subscriptions: HashMap<String, Subscription>,
+    packet_identifiers: PacketIdentifiers,
+    packet_identifiers_qos0: PacketIdentifiers,
+    #[serde(deserialize_with = "PublicationQueue::deserialize_v4")]
+    waiting_to_be_sent: PublicationQueue,
+    #[serde(deserialize_with = "serialize::publish_map::deserialize")]
+    waiting_to_be_released: HashMap<proto::PacketIdentifier, proto::Publish>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked: HashMap<proto::PacketIdentifier, (PublicationId, PublishV4)>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked_qos0: HashMap<proto::PacketIdentifier, PublishV4>,
+    #[serde(with = "serialize::packet_identifier_set")]
+    waiting_to_be_completed: HashSet<proto::PacketIdentifier>,
+}
+
+impl SessionStateV3 {
+    /// Migrates the state, handing back the messages the client hadn't
+    /// released yet. Messages are now delivered when they arrive, so
+    /// the broker delivers these as it restores the state. Their packet
+    /// identifiers are kept until the client releases them.
+    pub(crate) fn into_state(self) -> (SessionStateV4, Vec<Publication>) {
+        let mut waiting_to_be_released = HashSet::new();
+        let mut unreleased = Vec::new();
+        for (packet_identifier, publish) in self.waiting_to_be_released {
+            waiting_to_be_released.insert(packet_identifier);
+            unreleased.push(Publication {
+                topic_name: publish.topic_name,
+                qos: proto::QoS::ExactlyOnce,
+                retain: publish.retain,
+                payload: publish.payload,
+                properties: PublicationProperties::default(),
+            });
+        }
+
+        let state = SessionStateV4 {
+            client_id: self.client_id,
+            keep_alive: self.keep_alive,
+            last_active: self.last_active,
+            subscriptions: self.subscriptions,
+            packet_identifiers: self.packet_identifiers,
+            packet_identifiers_qos0: self.packet_identifiers_qos0,
+
+            waiting_to_be_sent: self.waiting_to_be_sent,
+            waiting_to_be_released,
+            waiting_to_be_acked: self.waiting_to_be_acked,
+            waiting_to_be_acked_qos0: self.waiting_to_be_acked_qos0,
+            waiting_to_be_completed: self.waiting_to_be_completed,
+        };
+        (state, unreleased)
+    }
+}
+
+/// Session state as stored by state format version 4, before messages had
+/// MQTT 5 properties and sessions their own expiry interval.
+#[derive(Deserialize)]
+pub(crate) struct SessionStateV4 {
+    client_id: ClientId,
+    keep_alive: Duration,
+    #[serde(with = "serialize::instant")]
+    last_active: Instant,
+    subscriptions: HashMap<String, Subscription>,
+    packet_identifiers: PacketIdentifiers,
+    packet_identifiers_qos0: PacketIdentifiers,
+    #[serde(deserialize_with = "PublicationQueue::deserialize_v4")]
+    waiting_to_be_sent: PublicationQueue,
+    #[serde(with = "serialize::packet_identifier_set")]
+    waiting_to_be_released: HashSet<proto::PacketIdentifier>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked: HashMap<proto::PacketIdentifier, (PublicationId, PublishV4)>,
+    #[serde(with = "serialize::packet_identifier_map")]
+    waiting_to_be_acked_qos0: HashMap<proto::PacketIdentifier, PublishV4>,
+    #[serde(with = "serialize::packet_identifier_set")]
+    waiting_to_be_completed: HashSet<proto::PacketIdentifier>,
+}
+
+/// Migrated sessions expire by the `session_expiry` of the broker's config,
+/// like the sessions of clients connected with an older version of MQTT.
+impl From<SessionStateV4> for SessionState {
+    fn from(state: SessionStateV4) -> Self {
+        Self {
+            client_id: state.client_id,
+            keep_alive: state.keep_alive,
+            last_active: state.last_active,
+            expiry_interval: None,
+            subscriptions: state.subscriptions,
+            packet_identifiers: state.packet_identifiers,
+            packet_identifiers_qos0: state.packet_identifiers_qos0,
+
+            waiting_to_be_sent: state.waiting_to_be_sent,
+            waiting_to_be_released: state.waiting_to_be_released,
+            waiting_to_be_acked: state
+                .waiting_to_be_acked
+                .into_iter()
+                .map(|(packet_identifier, (id, publish))| (packet_identifier, (id, publish.into())))
+                .collect(),
+            waiting_to_be_acked_qos0: state
+                .waiting_to_be_acked_qos0
+                .into_iter()
+                .map(|(packet_identifier, publish)| (packet_identifier, publish.into()))
+                .collect(),
+            waiting_to_be_completed: state.waiting_to_be_completed,
+
+            max_inflight_messages: config::default_max_inflight_messages(),
+            queue_limits: QueueLimits::default(),
+            receive_maximum: None,
+            dropped_count: 0,
+        }
+    }
+}
+
+/// A message sent to a client as stored by state format versions before 5,
+/// without properties.
+#[derive(Deserialize)]
+pub(crate) enum PublishV4 {
+    QoS0(
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        #[serde(with = "serialize::PublishDef")] proto::Publish,
+    ),
+    QoS12(
+        #[serde(with = "serialize::packet_identifier")] proto::PacketIdentifier,
+        #[serde(with = "serialize::PublishDef")] proto::Publish,
+    ),
+}
+
+impl From<PublishV4> for Publish {
+    fn from(publish: PublishV4) -> Self {
+        match publish {
+            PublishV4::QoS0(id, publish) => {
+                Publish::QoS0(id, publish, PublicationProperties::default())
+            }
+            PublishV4::QoS12(id, publish) => {
+                Publish::QoS12(id, publish, PublicationProperties::default())
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Session {
     Transient(ConnectedSession),
@@ -529,16 +1110,37 @@ pub enum Session {
 }
 
 impl Session {
-    pub fn new_transient(connreq: ConnReq) -> Self {
-        let state = SessionState::new(connreq.client_id().clone(), &connreq);
-        let (connect, handle) = connreq.into_parts();
-        let connected = ConnectedSession::new(state, connect.will, handle);
+    pub fn new_transient(
+        connreq: ConnReq,
+        state: SessionState,
+        protocol_version: ProtocolVersion,
+    ) -> Self {
+        let auth_id = connreq.auth().auth_id().cloned();
+        let will = Will::of(&connreq);
+        let connected = ConnectedSession::new(
+            state,
+            will,
+            connreq.into_handle(),
+            auth_id,
+            protocol_version,
+        );
         Session::Transient(connected)
     }
 
-    pub fn new_persistent(connreq: ConnReq, state: SessionState) -> Self {
-        let (connect, handle) = connreq.into_parts();
-        let connected = ConnectedSession::new(state, connect.will, handle);
+    pub fn new_persistent(
+        connreq: ConnReq,
+        state: SessionState,
+        protocol_version: ProtocolVersion,
+    ) -> Self {
+        let auth_id = connreq.auth().auth_id().cloned();
+        let will = Will::of(&connreq);
+        let connected = ConnectedSession::new(
+            state,
+            will,
+            connreq.into_handle(),
+            auth_id,
+            protocol_version,
+        );
         Session::Persistent(connected)
     }
 
@@ -549,7 +1151,7 @@ impl Session {
 
     pub fn new_disconnecting(
         client_id: ClientId,
-        will: Option<proto::Publication>,
+        will: Option<Will>,
         handle: ConnectionHandle,
     ) -> Self {
         let disconnecting = DisconnectingSession::new(client_id, will, handle);
@@ -565,7 +1167,48 @@ impl Session {
         }
     }
 
-    pub fn into_will(self) -> Option<proto::Publication> {
+    /// The identity a connected client authenticated as, if any.
+    pub fn auth_id(&self) -> Option<&AuthId> {
+        match self {
+            Session::Transient(connected) => connected.auth_id(),
+            Session::Persistent(connected) => connected.auth_id(),
+            Session::Offline(_) | Session::Disconnecting(_) => None,
+        }
+    }
+
+    /// The protocol version negotiated with the client, if it is connected.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        match self {
+            Session::Transient(connected) => Some(connected.protocol_version()),
+            Session::Persistent(connected) => Some(connected.protocol_version()),
+            Session::Offline(_) | Session::Disconnecting(_) => None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Session::Transient(_) | Session::Persistent(_))
+    }
+
+    /// The state of a session that isn't being disconnected.
+    pub fn state(&self) -> Option<&SessionState> {
+        match self {
+            Session::Transient(connected) => Some(connected.state()),
+            Session::Persistent(connected) => Some(connected.state()),
+            Session::Offline(offline) => Some(offline.state()),
+            Session::Disconnecting(_) => None,
+        }
+    }
+
+    pub fn state_mut(&mut self) -> Option<&mut SessionState> {
+        match self {
+            Session::Transient(connected) => Some(connected.state_mut()),
+            Session::Persistent(connected) => Some(connected.state_mut()),
+            Session::Offline(offline) => Some(offline.state_mut()),
+            Session::Disconnecting(_) => None,
+        }
+    }
+
+    pub fn into_will(self) -> Option<Will> {
         match self {
             Session::Transient(connected) => connected.into_will(),
             Session::Persistent(connected) => connected.into_will(),
@@ -577,10 +1220,11 @@ impl Session {
     pub fn handle_publish(
         &mut self,
         publish: proto::Publish,
-    ) -> Result<(Option<proto::Publication>, Option<ClientEvent>), Error> {
+        properties: PublicationProperties,
+    ) -> Result<(Option<Publication>, Option<ClientEvent>), Error> {
         match self {
-            Session::Transient(connected) => connected.handle_publish(publish),
-            Session::Persistent(connected) => connected.handle_publish(publish),
+            Session::Transient(connected) => connected.handle_publish(publish, properties),
+            Session::Persistent(connected) => connected.handle_publish(publish, properties),
             Session::Offline(_offline) => Err(Error::from(ErrorKind::SessionOffline)),
             Session::Disconnecting(_) => Err(Error::from(ErrorKind::SessionOffline)),
         }
@@ -616,10 +1260,7 @@ impl Session {
         }
     }
 
-    pub fn handle_pubrel(
-        &mut self,
-        pubrel: &proto::PubRel,
-    ) -> Result<Option<proto::Publication>, Error> {
+    pub fn handle_pubrel(&mut self, pubrel: &proto::PubRel) -> Result<Option<ClientEvent>, Error> {
         match self {
             Session::Transient(connected) => connected.handle_pubrel(pubrel),
             Session::Persistent(connected) => connected.handle_pubrel(pubrel),
@@ -642,12 +1283,42 @@ impl Session {
 
     pub fn publish_to(
         &mut self,
-        publication: &proto::Publication,
+        id: PublicationId,
+        publication: &Publication,
+        max_qos: proto::QoS,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<Option<ClientEvent>, Error> {
+        match self {
+            Session::Transient(connected) => {
+                connected.publish_to(id, publication.to_owned(), max_qos, deadline)
+            }
+            Session::Persistent(connected) => {
+                connected.publish_to(id, publication.to_owned(), max_qos, deadline)
+            }
+            Session::Offline(offline) => {
+                offline.publish_to(id, publication.to_owned(), max_qos, deadline)
+            }
+            Session::Disconnecting(_) => Err(Error::from(ErrorKind::SessionOffline)),
+        }
+    }
+
+    pub fn publish_shared(
+        &mut self,
+        id: PublicationId,
+        publication: &Publication,
+        deadline: Option<std::time::Instant>,
+        filter: &str,
     ) -> Result<Option<ClientEvent>, Error> {
         match self {
-            Session::Transient(connected) => connected.publish_to(publication.to_owned()),
-            Session::Persistent(connected) => connected.publish_to(publication.to_owned()),
-            Session::Offline(offline) => offline.publish_to(publication.to_owned()),
+            Session::Transient(connected) => {
+                connected.publish_shared(id, publication.to_owned(), deadline, filter)
+            }
+            Session::Persistent(connected) => {
+                connected.publish_shared(id, publication.to_owned(), deadline, filter)
+            }
+            Session::Offline(offline) => {
+                offline.publish_shared(id, publication.to_owned(), deadline, filter)
+            }
             Session::Disconnecting(_) => Err(Error::from(ErrorKind::SessionOffline)),
         }
     }
@@ -655,10 +1326,11 @@ impl Session {
     pub fn subscribe(
         &mut self,
         subscribe: proto::Subscribe,
+        authorizer: &dyn Authorizer,
     ) -> Result<(proto::SubAck, Vec<Subscription>), Error> {
         match self {
-            Session::Transient(connected) => connected.subscribe(subscribe),
-            Session::Persistent(connected) => connected.subscribe(subscribe),
+            Session::Transient(connected) => connected.subscribe(subscribe, authorizer),
+            Session::Persistent(connected) => connected.subscribe(subscribe, authorizer),
             Session::Offline(_) => Err(Error::from(ErrorKind::SessionOffline)),
             Session::Disconnecting(_) => Err(Error::from(ErrorKind::SessionOffline)),
         }
@@ -686,6 +1358,7 @@ impl Session {
     }
 }
 
+#[derive(Clone)]
 struct PacketIdentifiers {
     in_use: Box<[usize; PacketIdentifiers::SIZE]>,
     previous: proto::PacketIdentifier,
@@ -723,6 +1396,11 @@ impl PacketIdentifiers {
         *block &= !mask;
     }
 
+    fn mark(&mut self, packet_identifier: proto::PacketIdentifier) {
+        let (block, mask) = self.entry(packet_identifier);
+        *block |= mask;
+    }
+
     fn entry(&mut self, packet_identifier: proto::PacketIdentifier) -> (&mut usize, usize) {
         let packet_identifier = usize::from(packet_identifier.get());
         let (block, offset) = (
@@ -731,6 +1409,64 @@ impl PacketIdentifiers {
         );
         (&mut self.in_use[block], 1 << offset)
     }
+
+    fn in_use(&self) -> impl Iterator<Item = proto::PacketIdentifier> + '_ {
+        const BITS: usize = mem::size_of::<usize>() * 8;
+
+        self.in_use
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| **block != 0)
+            .flat_map(|(i, block)| {
+                (0..BITS)
+                    .filter(move |offset| block & (1 << offset) != 0)
+                    .map(move |offset| i * BITS + offset)
+            })
+            .filter_map(|id| {
+                u16::try_from(id)
+                    .ok()
+                    .and_then(proto::PacketIdentifier::new)
+            })
+    }
+}
+
+/// Packet identifiers are stored as the list of identifiers in use,
+/// rather than the full bitset, which is mostly empty.
+#[derive(Deserialize, Serialize)]
+struct PacketIdentifiersRepr {
+    in_use: Vec<u16>,
+    previous: u16,
+}
+
+impl Serialize for PacketIdentifiers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let repr = PacketIdentifiersRepr {
+            in_use: self.in_use().map(proto::PacketIdentifier::get).collect(),
+            previous: self.previous.get(),
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PacketIdentifiers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let repr = PacketIdentifiersRepr::deserialize(deserializer)?;
+        let mut packet_identifiers = PacketIdentifiers::default();
+        for id in repr.in_use {
+            let id = proto::PacketIdentifier::new(id)
+                .ok_or_else(|| D::Error::custom("packet identifier must be non-zero"))?;
+            packet_identifiers.mark(id);
+        }
+        packet_identifiers.previous = proto::PacketIdentifier::new(repr.previous)
+            .ok_or_else(|| D::Error::custom("packet identifier must be non-zero"))?;
+        Ok(packet_identifiers)
+    }
 }
 
 impl fmt::Debug for PacketIdentifiers {
@@ -757,6 +1493,7 @@ mod tests {
     use tokio::sync::mpsc;
     use uuid::Uuid;
 
+    use crate::auth::AllowAll;
     use crate::ConnectionHandle;
 
     fn connection_handle() -> ConnectionHandle {
@@ -784,7 +1521,8 @@ mod tests {
         let connect1 = transient_connect(id.clone());
         let handle1 = connection_handle();
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
-        let mut session = Session::new_transient(req1);
+        let state = SessionState::new(client_id.clone(), &req1);
+        let mut session = Session::new_transient(req1, state, ProtocolVersion::V311);
 
         let subscribe = proto::Subscribe {
             packet_identifier: proto::PacketIdentifier::new(23).unwrap(),
@@ -793,7 +1531,7 @@ mod tests {
                 qos: proto::QoS::AtMostOnce,
             }],
         };
-        let (suback, subscriptions) = session.subscribe(subscribe).unwrap();
+        let (suback, subscriptions) = session.subscribe(subscribe, &AllowAll).unwrap();
         assert_eq!(
             proto::PacketIdentifier::new(23).unwrap(),
             suback.packet_identifier
@@ -817,7 +1555,7 @@ mod tests {
                 qos: proto::QoS::AtLeastOnce,
             }],
         };
-        session.subscribe(subscribe).unwrap();
+        session.subscribe(subscribe, &AllowAll).unwrap();
 
         match session {
             Session::Transient(ref connected) => {
@@ -838,7 +1576,8 @@ mod tests {
         let connect1 = transient_connect(id.clone());
         let handle1 = connection_handle();
         let req1 = ConnReq::new(client_id.clone(), connect1, handle1);
-        let mut session = Session::new_transient(req1);
+        let state = SessionState::new(client_id.clone(), &req1);
+        let mut session = Session::new_transient(req1, state, ProtocolVersion::V311);
 
         let subscribe = proto::Subscribe {
             packet_identifier: proto::PacketIdentifier::new(1).unwrap(),
@@ -847,7 +1586,7 @@ mod tests {
                 qos: proto::QoS::AtMostOnce,
             }],
         };
-        session.subscribe(subscribe).unwrap();
+        session.subscribe(subscribe, &AllowAll).unwrap();
         match session {
             Session::Transient(ref connected) => {
                 assert_eq!(1, connected.state.subscriptions.len());
@@ -910,7 +1649,7 @@ mod tests {
                 qos: proto::QoS::AtMostOnce,
             }],
         };
-        let err = session.subscribe(subscribe).unwrap_err();
+        let err = session.subscribe(subscribe, &AllowAll).unwrap_err();
         assert_eq!(ErrorKind::SessionOffline, *err.kind());
     }
 
@@ -931,6 +1670,119 @@ mod tests {
         assert_eq!(ErrorKind::SessionOffline, *err.kind());
     }
 
+    #[test]
+    fn test_queue_limits() {
+        let id = "id1".to_string();
+        let client_id = ClientId::from(id.clone());
+        let req = ConnReq::new(
+            client_id.clone(),
+            transient_connect(id),
+            connection_handle(),
+        );
+        let limits = config::BrokerConfig::new()
+            .with_max_queued_messages(2)
+            .queue_limits();
+        let mut state = SessionState::new(client_id, &req)
+            .with_max_inflight_messages(0)
+            .with_queue_limits(limits);
+        let subscription = Subscription::new("topic".parse().unwrap(), proto::QoS::AtLeastOnce);
+        state.update_subscription("topic".to_string(), subscription);
+
+        let publication = Publication {
+            topic_name: "topic".to_string(),
+            qos: proto::QoS::AtLeastOnce,
+            retain: false,
+            payload: "payload".into(),
+            properties: PublicationProperties::default(),
+        };
+
+        // Queued while no more messages can be inflight
+        for _ in 0..3 {
+            let event = state
+                .publish_to(
+                    PublicationId::default(),
+                    publication.clone(),
+                    proto::QoS::AtLeastOnce,
+                    None,
+                )
+                .unwrap();
+            assert!(event.is_none());
+        }
+        assert_eq!(2, state.queued_count());
+        assert_eq!(14, state.queued_bytes());
+        assert_eq!(1, state.dropped_count());
+
+        // The same limits apply while offline
+        let mut session = Session::new_offline(state);
+        session
+            .publish_to(
+                PublicationId::default(),
+                &publication,
+                proto::QoS::AtLeastOnce,
+                None,
+            )
+            .unwrap();
+        let state = session.state().unwrap();
+        assert_eq!(2, state.queued_count());
+        assert_eq!(2, state.dropped_count());
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let id = "id1".to_string();
+        let client_id = ClientId::from(id.clone());
+        let req = ConnReq::new(
+            client_id.clone(),
+            transient_connect(id),
+            connection_handle(),
+        );
+        let rules = config::BrokerConfig::new()
+            .with_message_expiry(Duration::from_millis(10))
+            .with_topic_message_expiry(config::TopicExpiry::new(
+                "keep/#".parse().unwrap(),
+                Duration::from_secs(0),
+            ))
+            .expiry_rules();
+        let mut state = SessionState::new(client_id, &req);
+        let subscription = Subscription::new("#".parse().unwrap(), proto::QoS::AtLeastOnce);
+        state.update_subscription("#".to_string(), subscription);
+
+        let mut session = Session::new_offline(state);
+        for topic_name in &["telemetry", "keep/this"] {
+            let publication = Publication {
+                topic_name: (*topic_name).to_string(),
+                qos: proto::QoS::AtLeastOnce,
+                retain: false,
+                payload: "payload".into(),
+                properties: PublicationProperties::default(),
+            };
+            let deadline = rules.deadline(topic_name);
+            session
+                .publish_to(
+                    PublicationId::default(),
+                    &publication,
+                    proto::QoS::AtLeastOnce,
+                    deadline,
+                )
+                .unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        // Only the message that doesn't expire is sent when the client
+        // comes back
+        let (_, events) = match session {
+            Session::Offline(offline) => offline.into_online().unwrap(),
+            _ => panic!("not offline"),
+        };
+        assert_eq!(1, events.len());
+        match &events[0] {
+            ClientEvent::PublishTo(Publish::QoS12(_, publish, _)) => {
+                assert_eq!("keep/this", publish.topic_name);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
     #[test]
     fn packet_identifiers() {
         #[cfg(target_pointer_width = "32")]
@@ -1014,4 +1866,21 @@ mod tests {
         }
         assert_eq!(packet_identifiers.in_use[..], expected[..]);
     }
+
+    #[test]
+    fn packet_identifiers_serde_roundtrip() {
+        let mut packet_identifiers = PacketIdentifiers::default();
+        for _ in 0..70 {
+            packet_identifiers.reserve().unwrap();
+        }
+        packet_identifiers.discard(crate::proto::PacketIdentifier::new(2).unwrap());
+        packet_identifiers.discard(crate::proto::PacketIdentifier::new(65).unwrap());
+
+        let serialized = bincode::serialize(&packet_identifiers).unwrap();
+        let mut deserialized: PacketIdentifiers = bincode::deserialize(&serialized).unwrap();
+
+        assert_eq!(packet_identifiers.in_use[..], deserialized.in_use[..]);
+        assert_eq!(packet_identifiers.previous, deserialized.previous);
+        assert_eq!(deserialized.reserve().unwrap().get(), 71);
+    }
 }
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use failure::ResultExt;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task;
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+use crate::broker::{BrokerHandle, BrokerState};
+use crate::journal::Compactor;
+use crate::persist::Persist;
+use crate::{Error, ErrorKind, Message, SystemEvent};
+
+/// Number of snapshots that can be waiting to be persisted.
+///
+/// Snapshots taken while the snapshotter is busy are dropped rather than
+/// blocking the broker. The next snapshot supersedes them anyway.
+const SNAPSHOT_BUFFER: usize = 4;
+
+#[derive(Debug)]
+enum Event {
+    State(BrokerState),
+    Shutdown,
+}
+
+/// Persists broker state snapshots in the background.
+///
+/// Writing state to disk is blocking, so it is done on the blocking thread
+/// pool instead of on the broker task. Once a snapshot is persisted, the
+/// journal segments it covers are compacted.
+pub struct Snapshotter<P> {
+    persistor: P,
+    compactor: Option<Compactor>,
+    sender: Sender<Event>,
+    events: Receiver<Event>,
+}
+
+impl<P> Snapshotter<P>
+where
+    P: Persist + Send + 'static,
+{
+    pub fn new(persistor: P) -> Self {
+        let (sender, events) = mpsc::channel(SNAPSHOT_BUFFER);
+        Self {
+            persistor,
+            compactor: None,
+            sender,
+            events,
+        }
+    }
+
+    pub fn with_compactor(mut self, compactor: Compactor) -> Self {
+        self.compactor = Some(compactor);
+        self
+    }
+
+    pub fn snapshot_handle(&self) -> StateSnapshotHandle {
+        StateSnapshotHandle(self.sender.clone())
+    }
+
+    /// Persists snapshots until a shutdown is requested through a handle.
+    ///
+    /// Snapshots that fail to persist are logged and skipped.
+    pub async fn run(self) -> Result<(), Error> {
+        let Snapshotter {
+            mut persistor,
+            compactor,
+            mut events,
+            ..
+        } = self;
+
+        while let Some(event) = events.recv().await {
+            match event {
+                Event::State(state) => {
+                    debug!("persisting state snapshot...");
+                    let compactor = compactor.clone();
+                    let (returned, result) = task::spawn_blocking(move || {
+                        let result = persistor.store(&state).and_then(|_| match compactor {
+                            Some(compactor) => compactor.compact(state.journal_segment()),
+                            None => Ok(()),
+                        });
+                        (persistor, result)
+                    })
+                    .await
+                    .context(ErrorKind::SnapshotJoin)?;
+                    persistor = returned;
+
+                    match result {
+                        Ok(()) => debug!("state snapshot persisted."),
+                        Err(e) => {
+                            warn!(message = "an error occurred persisting a state snapshot", error=%e);
+                        }
+                    }
+                }
+                Event::Shutdown => {
+                    info!("snapshotter is shutdown.");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StateSnapshotHandle(Sender<Event>);
+
+impl StateSnapshotHandle {
+    /// Queues a snapshot without waiting, failing if the snapshotter is busy.
+    pub fn try_send(&mut self, state: BrokerState) -> Result<(), Error> {
+        self.0
+            .try_send(Event::State(state))
+            .map_err(|_| Error::from(ErrorKind::SendSnapshotMessage))
+    }
+
+    pub async fn send(&mut self, state: BrokerState) -> Result<(), Error> {
+        self.0
+            .send(Event::State(state))
+            .await
+            .map_err(|_| Error::from(ErrorKind::SendSnapshotMessage))
+    }
+
+    /// Stops the snapshotter once all previously sent snapshots are persisted.
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        self.0
+            .send(Event::Shutdown)
+            .await
+            .map_err(|_| Error::from(ErrorKind::SendSnapshotMessage))
+    }
+}
+
+/// Asks the broker for a state snapshot every `period`. A zero `period`
+/// turns periodic snapshots off.
+///
+/// Runs until the broker stops accepting messages.
+pub async fn snapshot_timer(
+    period: Duration,
+    mut broker_handle: BrokerHandle,
+    snapshot_handle: StateSnapshotHandle,
+) {
+    if period == Duration::from_secs(0) {
+        info!("periodic state snapshots are turned off");
+        return;
+    }
+
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+
+        let event = SystemEvent::StateSnapshot(snapshot_handle.clone());
+        if let Err(e) = broker_handle.send(Message::System(event)).await {
+            debug!(message = "stopping state snapshot timer", error=%e);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::Broker;
+
+    #[derive(Clone, Default)]
+    struct MemoryPersistor(Arc<Mutex<Vec<BrokerState>>>);
+
+    impl Persist for MemoryPersistor {
+        fn load(&mut self) -> Result<Option<BrokerState>, Error> {
+            Ok(self.0.lock().unwrap().last().cloned())
+        }
+
+        fn store(&mut self, state: &BrokerState) -> Result<(), Error> {
+            self.0.lock().unwrap().push(state.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshotter_persists_before_shutdown() {
+        let persistor = MemoryPersistor::default();
+        let snapshotter = Snapshotter::new(persistor.clone());
+        let mut handle = snapshotter.snapshot_handle();
+        let task = tokio::spawn(snapshotter.run());
+
+        handle.send(BrokerState::default()).await.unwrap();
+        handle.send(BrokerState::default()).await.unwrap();
+        handle.shutdown().await.unwrap();
+        task.await.unwrap().unwrap();
+
+        assert_eq!(2, persistor.0.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_timer_zero_period() {
+        let broker = Broker::default();
+        let snapshotter = Snapshotter::new(MemoryPersistor::default());
+        let timer = snapshot_timer(
+            Duration::from_secs(0),
+            broker.handle(),
+            snapshotter.snapshot_handle(),
+        );
+        time::timeout(Duration::from_secs(1), timer).await.unwrap();
+    }
+}
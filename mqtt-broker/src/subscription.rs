@@ -1,18 +1,26 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::str::FromStr;
 
 use mqtt::proto;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
 
-use crate::{Error, ErrorKind};
+use crate::serialize::QoSDef;
+use crate::{ClientId, Error, ErrorKind};
 
 const NUL_CHAR: char = '\0';
-const TOPIC_SEPARATOR: char = '/';
+pub(crate) const TOPIC_SEPARATOR: char = '/';
 static MULTILEVEL_WILDCARD: &str = "#";
 static SINGLELEVEL_WILDCARD: &str = "+";
+static SHARED_SUBSCRIPTION_PREFIX: &str = "$share/";
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Subscription {
     filter: TopicFilter,
+    #[serde(with = "QoSDef")]
     max_qos: proto::QoS,
 }
 
@@ -30,8 +38,13 @@ impl Subscription {
     }
 }
 
+/// A topic filter, or a shared subscription `$share/<group>/<filter>`.
+///
+/// A shared subscription matches the same topics as its filter, but each
+/// publication is only delivered to one of the clients subscribed to it.
 #[derive(Clone, Debug, PartialEq)]
 pub struct TopicFilter {
+    share: Option<String>,
     segments: Vec<Segment>,
     multilevel: bool,
 }
@@ -41,11 +54,20 @@ impl TopicFilter {
         let len = segments.len();
         let multilevel = len > 0 && segments[len - 1] == Segment::MultiLevelWildcard;
         Self {
+            share: None,
             segments,
             multilevel,
         }
     }
 
+    pub fn is_shared(&self) -> bool {
+        self.share.is_some()
+    }
+
+    pub(crate) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
     pub fn matches(&self, topic_name: &str) -> bool {
         let mut segments = self.segments.iter();
         let mut levels = topic_name.split(TOPIC_SEPARATOR);
@@ -80,10 +102,60 @@ impl TopicFilter {
         }
         true
     }
+
+    /// Returns the filter with each level that isn't a wildcard replaced by
+    /// `substitute`, or `None` if `substitute` returns `None` for a level.
+    pub(crate) fn map_levels<F>(&self, mut substitute: F) -> Option<TopicFilter>
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Level(level) => substitute(level).map(Segment::Level),
+                segment => Some(segment.clone()),
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(TopicFilter {
+            share: self.share.clone(),
+            ..TopicFilter::new(segments)
+        })
+    }
+
+    /// Returns true if every topic matched by `filter` is also matched by
+    /// this filter.
+    pub fn covers(&self, filter: &TopicFilter) -> bool {
+        // A wildcard first level doesn't match `$` topics, so it can't cover
+        // a filter for them
+        let wildcard = match self.segments.first() {
+            Some(Segment::Level(_)) | None => false,
+            Some(_) => true,
+        };
+        if wildcard {
+            if let Some(Segment::Level(level)) = filter.segments.first() {
+                if level.starts_with('$') {
+                    return false;
+                }
+            }
+        }
+
+        let mut other = filter.segments.iter();
+        for segment in &self.segments {
+            match (segment, other.next()) {
+                (Segment::MultiLevelWildcard, _) => return true,
+                (Segment::SingleLevelWildcard, Some(o)) if *o != Segment::MultiLevelWildcard => (),
+                (Segment::Level(s), Some(Segment::Level(o))) if s == o => (),
+                (_, _) => return false,
+            }
+        }
+        other.next().is_none()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
-enum Segment {
+pub(crate) enum Segment {
     Level(String),
     SingleLevelWildcard,
     MultiLevelWildcard,
@@ -101,6 +173,14 @@ impl Segment {
 
 impl fmt::Display for TopicFilter {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(share) = &self.share {
+            write!(
+                f,
+                "{}{}{}",
+                SHARED_SUBSCRIPTION_PREFIX, share, TOPIC_SEPARATOR
+            )?;
+        }
+
         let len = self.segments.len();
         for (i, segment) in self.segments.iter().enumerate() {
             match segment {
@@ -120,27 +200,43 @@ impl FromStr for TopicFilter {
     type Err = Error;
 
     fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::from(ErrorKind::InvalidTopicFilter(string.to_owned()));
+
         // [MQTT-4.7.3-1] - All Topic Names and Topic Filters MUST be at least
         // one character long.
         // [MQTT-4.7.3-2] - Topic Names and Topic Filters MUST NOT include the
         // null character (Unicode U+0000).
         if string.is_empty() || string.contains(NUL_CHAR) {
-            return Err(Error::from(ErrorKind::InvalidTopicFilter(
-                string.to_owned(),
-            )));
+            return Err(invalid());
         }
 
+        // The share name of a shared subscription must be at least one
+        // character long and can't contain `/`, `+` or `#`
+        let (share, filter) = if string.starts_with(SHARED_SUBSCRIPTION_PREFIX) {
+            let mut parts = string[SHARED_SUBSCRIPTION_PREFIX.len()..].splitn(2, TOPIC_SEPARATOR);
+            let share = parts.next().ok_or_else(invalid)?;
+            let filter = parts.next().ok_or_else(invalid)?;
+            if share.is_empty()
+                || share.contains(MULTILEVEL_WILDCARD)
+                || share.contains(SINGLELEVEL_WILDCARD)
+                || filter.is_empty()
+            {
+                return Err(invalid());
+            }
+            (Some(share.to_owned()), filter)
+        } else {
+            (None, string)
+        };
+
         let mut segments = Vec::new();
-        for s in string.split(TOPIC_SEPARATOR) {
+        for s in filter.split(TOPIC_SEPARATOR) {
             let segment = if s == MULTILEVEL_WILDCARD {
                 Segment::MultiLevelWildcard
             } else if s == SINGLELEVEL_WILDCARD {
                 Segment::SingleLevelWildcard
             } else {
                 if s.contains(MULTILEVEL_WILDCARD) || s.contains(SINGLELEVEL_WILDCARD) {
-                    return Err(Error::from(ErrorKind::InvalidTopicFilter(
-                        string.to_owned(),
-                    )));
+                    return Err(invalid());
                 }
                 Segment::Level(s.to_owned())
             };
@@ -154,16 +250,268 @@ impl FromStr for TopicFilter {
         let len = segments.len();
         for (i, segment) in segments.iter().enumerate() {
             if *segment == Segment::MultiLevelWildcard && i != len - 1 {
-                return Err(Error::from(ErrorKind::InvalidTopicFilter(
-                    string.to_owned(),
-                )));
+                return Err(invalid());
             }
         }
-        let filter = TopicFilter::new(segments);
+        let mut filter = TopicFilter::new(segments);
+        filter.share = share;
         Ok(filter)
     }
 }
 
+/// An index of the subscriptions of all sessions for routing publications.
+///
+/// Topic filters are stored in a tree of their segments, so finding the
+/// clients subscribed to a topic only visits the branches that can match it
+/// instead of testing every subscription of every session.
+///
+/// Shared subscriptions are indexed by their full filter, including the
+/// `$share/<group>/` prefix, and keep their members in a `SharedGroup`.
+#[derive(Debug, Default)]
+pub struct SubscriptionIndex {
+    root: Node,
+    groups: HashMap<String, SharedGroup>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    levels: HashMap<String, Node>,
+    single_level: Option<Box<Node>>,
+
+    // Subscribed to a filter ending at this node, and to the same filter
+    // followed by `#`
+    exact: Subscribers,
+    multi_level: Subscribers,
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    clients: HashMap<ClientId, proto::QoS>,
+    groups: HashSet<String>,
+}
+
+/// The subscriptions matching a topic.
+#[derive(Debug, Default)]
+pub struct Matches {
+    clients: HashMap<ClientId, proto::QoS>,
+    groups: HashSet<String>,
+}
+
+impl Matches {
+    /// The clients with a matching subscription, with the largest
+    /// `max_qos` of their matching subscriptions. Shared subscriptions
+    /// are not included.
+    pub fn clients(&self) -> &HashMap<ClientId, proto::QoS> {
+        &self.clients
+    }
+
+    /// The matching shared subscriptions.
+    pub fn groups(&self) -> &HashSet<String> {
+        &self.groups
+    }
+}
+
+/// The members of a shared subscription, which take turns receiving
+/// the publications matching it.
+#[derive(Debug, Default)]
+pub struct SharedGroup {
+    members: Vec<ClientId>,
+    next: usize,
+}
+
+impl SharedGroup {
+    /// Returns the members, starting with the one whose turn it is.
+    pub fn members(&self) -> impl Iterator<Item = &ClientId> {
+        let (before, after) = self.members.split_at(self.next);
+        after.iter().chain(before)
+    }
+
+    /// Gives the turn to the member after `client_id`.
+    pub fn advance(&mut self, client_id: &ClientId) {
+        if let Some(i) = self.members.iter().position(|id| id == client_id) {
+            self.next = (i + 1) % self.members.len();
+        }
+    }
+
+    fn insert(&mut self, client_id: ClientId) {
+        if !self.members.contains(&client_id) {
+            self.members.push(client_id);
+        }
+    }
+
+    fn remove(&mut self, client_id: &ClientId) {
+        if let Some(i) = self.members.iter().position(|id| id == client_id) {
+            self.members.remove(i);
+            if self.next > i {
+                self.next -= 1;
+            }
+            if self.next >= self.members.len() {
+                self.next = 0;
+            }
+        }
+    }
+}
+
+impl SubscriptionIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a subscription of `client_id`, replacing an existing
+    /// subscription to the same filter.
+    pub fn insert(&mut self, client_id: ClientId, subscription: &Subscription) {
+        let filter = &subscription.filter;
+        let subscribers = self.root.subscribers_mut(&filter.segments);
+        if filter.is_shared() {
+            let group = filter.to_string();
+            subscribers.groups.insert(group.clone());
+            self.groups.entry(group).or_default().insert(client_id);
+        } else {
+            subscribers.clients.insert(client_id, subscription.max_qos);
+        }
+    }
+
+    /// Removes the subscription of `client_id` to `filter`, if there is one.
+    pub fn remove(&mut self, client_id: &ClientId, filter: &TopicFilter) {
+        if filter.is_shared() {
+            let group = filter.to_string();
+            if let Some(shared) = self.groups.get_mut(&group) {
+                shared.remove(client_id);
+                if shared.members.is_empty() {
+                    self.groups.remove(&group);
+                    self.root.remove(&filter.segments, &mut |subscribers| {
+                        subscribers.groups.remove(&group);
+                    });
+                }
+            }
+        } else {
+            self.root.remove(&filter.segments, &mut |subscribers| {
+                subscribers.clients.remove(client_id);
+            });
+        }
+    }
+
+    /// Returns the subscriptions matching `topic_name`.
+    pub fn matches(&self, topic_name: &str) -> Matches {
+        let levels = topic_name.split(TOPIC_SEPARATOR).collect::<Vec<_>>();
+
+        // [MQTT-4.7.2-1] - Topic filters starting with a wildcard don't
+        // match topic names starting with `$`
+        let wildcards = levels.first().map_or(true, |l| !l.starts_with('$'));
+
+        let mut matches = Matches::default();
+        self.root.collect(&levels, wildcards, &mut matches);
+        matches
+    }
+
+    pub fn group_mut(&mut self, group: &str) -> Option<&mut SharedGroup> {
+        self.groups.get_mut(group)
+    }
+}
+
+impl Node {
+    fn subscribers_mut(&mut self, segments: &[Segment]) -> &mut Subscribers {
+        let mut node = self;
+        for segment in segments {
+            match segment {
+                Segment::Level(level) => node = node.levels.entry(level.clone()).or_default(),
+                Segment::SingleLevelWildcard => {
+                    node = node.single_level.get_or_insert_with(Default::default);
+                }
+                Segment::MultiLevelWildcard => return &mut node.multi_level,
+            }
+        }
+        &mut node.exact
+    }
+
+    /// Returns true if the node can be dropped.
+    fn remove<F>(&mut self, segments: &[Segment], remove: &mut F) -> bool
+    where
+        F: FnMut(&mut Subscribers),
+    {
+        match segments.split_first() {
+            None => remove(&mut self.exact),
+            Some((Segment::MultiLevelWildcard, _)) => remove(&mut self.multi_level),
+            Some((Segment::Level(level), rest)) => {
+                if let Some(child) = self.levels.get_mut(level) {
+                    if child.remove(rest, remove) {
+                        self.levels.remove(level);
+                    }
+                }
+            }
+            Some((Segment::SingleLevelWildcard, rest)) => {
+                if let Some(child) = self.single_level.as_mut() {
+                    if child.remove(rest, remove) {
+                        self.single_level = None;
+                    }
+                }
+            }
+        }
+        self.is_empty()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+            && self.single_level.is_none()
+            && self.exact.is_empty()
+            && self.multi_level.is_empty()
+    }
+
+    fn collect(&self, levels: &[&str], wildcards: bool, matches: &mut Matches) {
+        // `#` also matches the parent level, so it matches whatever is left
+        if wildcards {
+            self.multi_level.add_to(matches);
+        }
+
+        if let Some((level, rest)) = levels.split_first() {
+            if let Some(child) = self.levels.get(*level) {
+                child.collect(rest, true, matches);
+            }
+            if let (true, Some(child)) = (wildcards, &self.single_level) {
+                child.collect(rest, true, matches);
+            }
+        } else {
+            self.exact.add_to(matches);
+        }
+    }
+}
+
+impl Subscribers {
+    fn is_empty(&self) -> bool {
+        self.clients.is_empty() && self.groups.is_empty()
+    }
+
+    fn add_to(&self, matches: &mut Matches) {
+        for (client_id, qos) in &self.clients {
+            matches
+                .clients
+                .entry(client_id.clone())
+                .and_modify(|max| *max = cmp::max(*max, *qos))
+                .or_insert(*qos);
+        }
+        matches.groups.extend(self.groups.iter().cloned());
+    }
+}
+
+impl Serialize for TopicFilter {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let filter = String::deserialize(deserializer)?;
+        filter.parse().map_err(de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -245,6 +593,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn shared_subscription() {
+        let shared = TopicFilter::from_str("$share/group/sport/+").unwrap();
+        assert!(shared.is_shared());
+        assert_eq!("$share/group/sport/+", shared.to_string());
+        assert!(shared.matches("sport/tennis"));
+        assert!(!shared.matches("$share/group/sport/tennis"));
+        assert_ne!(TopicFilter::from_str("sport/+").unwrap(), shared);
+
+        let cases = [
+            "$share/",
+            "$share/group",
+            "$share//sport",
+            "$share/gr+/sport",
+            "$share/group/",
+        ];
+        for case in &cases {
+            assert!(TopicFilter::from_str(case).is_err(), "{}", case);
+        }
+    }
+
     fn segment_strategy() -> impl Strategy<Value = Segment> {
         prop_oneof![
             "[^+#\0/]+".prop_map(Segment::Level),
@@ -307,4 +676,189 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_covers() {
+        let cases = vec![
+            ("#", "blah/+/blah2", true),
+            ("#", "#", true),
+            ("blah/#", "blah", true),
+            ("blah/#", "blah/blah1/#", true),
+            ("blah/+", "blah/blah1", true),
+            ("blah/+", "blah/+", true),
+            ("blah/+", "blah/#", false),
+            ("blah/+", "blah/blah1/blah2", false),
+            ("blah/blah1", "blah/+", false),
+            ("blah/blah1", "blah/blah1", true),
+            ("blah/blah1", "blah", false),
+            ("#", "$SYS/#", false),
+            ("+/blah", "$SYS/blah", false),
+            ("$SYS/#", "$SYS/blah", true),
+        ];
+
+        for (filter, other, expected) in cases.iter() {
+            let parsed = TopicFilter::from_str(filter).unwrap();
+            let other_parsed = TopicFilter::from_str(other).unwrap();
+            assert_eq!(
+                *expected,
+                parsed.covers(&other_parsed),
+                "filter \"{}\" covers \"{}\"",
+                filter,
+                other
+            );
+        }
+    }
+
+    fn client_id(id: &str) -> ClientId {
+        ClientId::from(id.to_string())
+    }
+
+    fn subscription(filter: &str, max_qos: proto::QoS) -> Subscription {
+        Subscription::new(filter.parse().unwrap(), max_qos)
+    }
+
+    #[test]
+    fn test_index_matches() {
+        let mut index = SubscriptionIndex::new();
+        index.insert(
+            client_id("c1"),
+            &subscription("blah/+", proto::QoS::AtMostOnce),
+        );
+        index.insert(
+            client_id("c1"),
+            &subscription("blah/#", proto::QoS::ExactlyOnce),
+        );
+        index.insert(client_id("c2"), &subscription("#", proto::QoS::AtLeastOnce));
+        index.insert(
+            client_id("c3"),
+            &subscription("$SYS/#", proto::QoS::AtMostOnce),
+        );
+
+        let matched = index.matches("blah/blah1").clients;
+        assert_eq!(2, matched.len());
+        assert_eq!(proto::QoS::ExactlyOnce, matched[&client_id("c1")]);
+        assert_eq!(proto::QoS::AtLeastOnce, matched[&client_id("c2")]);
+
+        let matched = index.matches("blah").clients;
+        assert_eq!(2, matched.len());
+
+        let matched = index.matches("$SYS/blah").clients;
+        assert_eq!(1, matched.len());
+        assert!(matched.contains_key(&client_id("c3")));
+    }
+
+    #[test]
+    fn test_index_remove() {
+        let mut index = SubscriptionIndex::new();
+        index.insert(
+            client_id("c1"),
+            &subscription("blah/+", proto::QoS::AtMostOnce),
+        );
+        index.insert(
+            client_id("c1"),
+            &subscription("blah/#", proto::QoS::AtMostOnce),
+        );
+        index.insert(
+            client_id("c2"),
+            &subscription("blah/+", proto::QoS::AtMostOnce),
+        );
+
+        index.remove(&client_id("c1"), &"blah/+".parse().unwrap());
+        let matched = index.matches("blah/blah1").clients;
+        assert_eq!(2, matched.len());
+
+        index.remove(&client_id("c1"), &"blah/#".parse().unwrap());
+        index.remove(&client_id("c2"), &"blah/+".parse().unwrap());
+        assert!(index.matches("blah/blah1").clients.is_empty());
+        assert!(index.root.is_empty());
+    }
+
+    #[test]
+    fn test_index_shared() {
+        let mut index = SubscriptionIndex::new();
+        for id in &["c1", "c2", "c3"] {
+            index.insert(
+                client_id(id),
+                &subscription("$share/g/blah/#", proto::QoS::AtMostOnce),
+            );
+        }
+        index.insert(
+            client_id("c1"),
+            &subscription("blah/+", proto::QoS::AtMostOnce),
+        );
+
+        let matched = index.matches("blah/blah1");
+        assert_eq!(1, matched.clients().len());
+        assert_eq!(1, matched.groups().len());
+        assert!(matched.groups().contains("$share/g/blah/#"));
+
+        let group = index.group_mut("$share/g/blah/#").unwrap();
+        group.advance(&client_id("c1"));
+        let members = group.members().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            vec![client_id("c2"), client_id("c3"), client_id("c1")],
+            members
+        );
+
+        // The turn stays with the next member when a member leaves
+        let filter = "$share/g/blah/#".parse().unwrap();
+        index.remove(&client_id("c2"), &filter);
+        let group = index.group_mut("$share/g/blah/#").unwrap();
+        assert_eq!(Some(&client_id("c3")), group.members().next());
+
+        index.remove(&client_id("c1"), &filter);
+        index.remove(&client_id("c3"), &filter);
+        assert!(index.group_mut("$share/g/blah/#").is_none());
+        assert!(index.matches("blah/blah1").groups().is_empty());
+    }
+
+    fn short_segment_strategy() -> impl Strategy<Value = Segment> {
+        prop_oneof![
+            "[$ab]?".prop_map(Segment::Level),
+            Just(Segment::SingleLevelWildcard),
+            Just(Segment::MultiLevelWildcard),
+        ]
+    }
+
+    prop_compose! {
+        fn short_topic_filter()(segments in vec(short_segment_strategy(), 1..4)) -> TopicFilter {
+            let len = segments.len();
+            let segments = segments
+                .into_iter()
+                .enumerate()
+                .filter(|(i, s)| *s != Segment::MultiLevelWildcard || *i == len - 1)
+                .map(|(_, s)| s)
+                .collect::<Vec<_>>();
+            if segments.is_empty() {
+                TopicFilter::new(vec![Segment::MultiLevelWildcard])
+            } else {
+                TopicFilter::new(segments)
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn index_matches_filters(
+            filters in vec(short_topic_filter(), 1..10),
+            topic in "[$ab]?(/[$ab]?){0,3}",
+        ) {
+            let mut index = SubscriptionIndex::new();
+            for (i, filter) in filters.iter().enumerate() {
+                let subscription = Subscription::new(filter.clone(), proto::QoS::AtMostOnce);
+                index.insert(client_id(&i.to_string()), &subscription);
+            }
+
+            let matched = index.matches(&topic).clients;
+            for (i, filter) in filters.iter().enumerate() {
+                prop_assert_eq!(
+                    filter.matches(&topic),
+                    matched.contains_key(&client_id(&i.to_string())),
+                    "filter \"{}\" matches \"{}\"",
+                    filter,
+                    topic
+                );
+            }
+        }
+    }
 }
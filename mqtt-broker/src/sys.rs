@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+use mqtt::proto;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::debug;
+
+use crate::{
+    BrokerConfig, BrokerHandle, Message, Publication, PublicationProperties, Publish, SystemEvent,
+};
+
+static SYS_TOPIC: &str = "$SYS";
+static VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Whether `topic_name` is under `$SYS`, which only the broker publishes to.
+pub(crate) fn is_sys_topic(topic_name: &str) -> bool {
+    topic_name.split('/').next() == Some(SYS_TOPIC)
+}
+
+/// Counters of the publications going through the broker.
+///
+/// Bytes are counted by the size of the payloads.
+#[derive(Debug)]
+pub(crate) struct BrokerStats {
+    started: Instant,
+    messages_received: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+/// Values the broker reads from its sessions and stores when it publishes
+/// its `$SYS` topics and updates its metrics.
+#[derive(Debug, Default)]
+pub(crate) struct Gauges {
+    pub clients_connected: usize,
+    pub clients_total: usize,
+    pub sessions_transient: usize,
+    pub sessions_persistent: usize,
+    pub sessions_offline: usize,
+    pub sessions_disconnecting: usize,
+    pub queued: usize,
+    pub inflight: usize,
+    pub retained: usize,
+    pub subscriptions: usize,
+}
+
+impl BrokerStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            messages_received: 0,
+            messages_sent: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        }
+    }
+
+    /// Counts a PUBLISH received from a client.
+    pub(crate) fn received(&mut self, publish: &proto::Publish) {
+        self.messages_received += 1;
+        self.bytes_received += publish.payload.len() as u64;
+    }
+
+    /// Counts a PUBLISH sent to a client.
+    pub(crate) fn sent(&mut self, publish: &Publish) {
+        let publish = match publish {
+            Publish::QoS0(_, publish, _) => publish,
+            Publish::QoS12(_, publish, _) => publish,
+        };
+        self.messages_sent += 1;
+        self.bytes_sent += publish.payload.len() as u64;
+    }
+
+    /// The retained publications of the `$SYS/broker` topics.
+    pub(crate) fn publications(&self, gauges: &Gauges) -> Vec<Publication> {
+        let uptime = self.started.elapsed().as_secs();
+        let values = vec![
+            ("version", VERSION.to_string()),
+            ("uptime", uptime.to_string()),
+            ("clients/connected", gauges.clients_connected.to_string()),
+            ("clients/total", gauges.clients_total.to_string()),
+            ("messages/received", self.messages_received.to_string()),
+            ("messages/sent", self.messages_sent.to_string()),
+            ("bytes/received", self.bytes_received.to_string()),
+            ("bytes/sent", self.bytes_sent.to_string()),
+            ("retained/count", gauges.retained.to_string()),
+            ("subscriptions/count", gauges.subscriptions.to_string()),
+        ];
+
+        values
+            .into_iter()
+            .map(|(topic, value)| Publication {
+                topic_name: format!("{}/broker/{}", SYS_TOPIC, topic),
+                qos: proto::QoS::AtMostOnce,
+                retain: true,
+                payload: value.into(),
+                properties: PublicationProperties::default(),
+            })
+            .collect()
+    }
+}
+
+/// Asks the broker to publish its `$SYS` topics every `sys_interval` of
+/// its config, following config updates.
+///
+/// Runs until the broker stops accepting messages.
+pub(crate) async fn sys_timer(
+    mut config: watch::Receiver<BrokerConfig>,
+    mut broker_handle: BrokerHandle,
+) {
+    loop {
+        let period = config.borrow().sys_interval();
+        if period == Duration::from_secs(0) {
+            // Turned off until the config changes
+            if config.recv().await.is_none() {
+                break;
+            }
+            continue;
+        }
+
+        time::delay_for(period).await;
+
+        let message = Message::System(SystemEvent::PublishStats);
+        if let Err(e) = broker_handle.send(message).await {
+            debug!(message = "stopping $SYS timer", error=%e);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sys_topic() {
+        assert!(is_sys_topic("$SYS"));
+        assert!(is_sys_topic("$SYS/broker/uptime"));
+        assert!(!is_sys_topic("$SYSTEM/broker"));
+        assert!(!is_sys_topic("SYS/broker"));
+        assert!(!is_sys_topic("a/$SYS"));
+    }
+
+    #[test]
+    fn test_publications() {
+        let mut stats = BrokerStats::new();
+        let publish = proto::Publish {
+            packet_identifier_dup_qos: proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "topic".to_string(),
+            payload: "payload".into(),
+        };
+        stats.received(&publish);
+        stats.received(&publish);
+
+        let gauges = Gauges {
+            clients_connected: 1,
+            clients_total: 2,
+            ..Gauges::default()
+        };
+        let publications = stats.publications(&gauges);
+        let value = |topic: &str| {
+            publications
+                .iter()
+                .find(|publication| publication.topic_name == topic)
+                .map(|publication| publication.payload.clone())
+                .unwrap()
+        };
+
+        assert!(publications.iter().all(|publication| publication.retain));
+        assert_eq!("1", value("$SYS/broker/clients/connected"));
+        assert_eq!("2", value("$SYS/broker/clients/total"));
+        assert_eq!("2", value("$SYS/broker/messages/received"));
+        assert_eq!("14", value("$SYS/broker/bytes/received"));
+        assert_eq!("0", value("$SYS/broker/messages/sent"));
+    }
+}
@@ -0,0 +1,148 @@
+//! Metrics recorded through the `metrics` facade.
+//!
+//! The broker doesn't export metrics itself. An application installs a
+//! recorder of its choice with `metrics::set_recorder`, otherwise the
+//! metrics are discarded.
+
+use metrics::{gauge, register_counter, register_gauge};
+use mqtt::proto;
+
+use crate::sys::Gauges;
+use crate::ClientEvent;
+
+/// Describes the broker's metrics to the installed recorder.
+///
+/// Recording works without this, but recorders such as a Prometheus
+/// exporter use the descriptions as help text. The metrics are
+///
+/// - `mqtt_connections` and `mqtt_connections_total`, the open and all
+///   accepted connections
+/// - `mqtt_sessions`, the sessions by `state` (`transient`, `persistent`,
+///   `offline` or `disconnecting`)
+/// - `mqtt_messages_received_total` and `mqtt_messages_sent_total`, the
+///   PUBLISH packets received from and sent to clients by `qos`
+/// - `mqtt_queued_messages` and `mqtt_inflight_messages`, the messages
+///   waiting to be sent and waiting to be acknowledged in all sessions
+/// - `mqtt_dropped_messages_total`, the messages dropped because the queue
+///   of a session was full
+/// - `mqtt_expired_sessions_total`, the offline sessions removed after
+///   `session_expiry`
+/// - `mqtt_expired_messages_total`, the queued and retained messages
+///   dropped after their `message_expiry`
+/// - `mqtt_retained_messages` and `mqtt_subscriptions`
+/// - `mqtt_broker_mailbox_messages`, the messages waiting to be processed
+///   by the broker
+/// - `mqtt_broker_event_duration_seconds`, how long the broker takes to
+///   process client events by `event`
+///
+/// The gauges of sessions, queues, retained messages and subscriptions are
+/// updated every `sys_interval` of the broker's config.
+pub fn register_metrics() {
+    register_gauge!("mqtt_connections", "The number of open connections");
+    register_counter!(
+        "mqtt_connections_total",
+        "The number of accepted connections"
+    );
+    for state in &["transient", "persistent", "offline", "disconnecting"] {
+        register_gauge!("mqtt_sessions", "The number of sessions by state", "state" => *state);
+    }
+    for qos in &["0", "1", "2"] {
+        register_counter!(
+            "mqtt_messages_received_total",
+            "The number of PUBLISH packets received from clients",
+            "qos" => *qos
+        );
+        register_counter!(
+            "mqtt_messages_sent_total",
+            "The number of PUBLISH packets sent to clients",
+            "qos" => *qos
+        );
+    }
+    register_gauge!(
+        "mqtt_queued_messages",
+        "The number of messages waiting to be sent to clients"
+    );
+    register_gauge!(
+        "mqtt_inflight_messages",
+        "The number of messages sent to clients and waiting to be acknowledged"
+    );
+    register_counter!(
+        "mqtt_dropped_messages_total",
+        "The number of messages dropped because the queue of a session was full"
+    );
+    register_counter!(
+        "mqtt_expired_sessions_total",
+        "The number of offline sessions removed because they expired"
+    );
+    register_counter!(
+        "mqtt_expired_messages_total",
+        "The number of queued and retained messages dropped because they expired"
+    );
+    register_gauge!("mqtt_retained_messages", "The number of retained messages");
+    register_gauge!("mqtt_subscriptions", "The number of subscriptions");
+    register_gauge!(
+        "mqtt_broker_mailbox_messages",
+        "The number of messages waiting to be processed by the broker"
+    );
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn record_gauges(gauges: &Gauges) {
+    gauge!(
+        "mqtt_sessions",
+        gauges.sessions_transient as f64,
+        "state" => "transient"
+    );
+    gauge!(
+        "mqtt_sessions",
+        gauges.sessions_persistent as f64,
+        "state" => "persistent"
+    );
+    gauge!(
+        "mqtt_sessions",
+        gauges.sessions_offline as f64,
+        "state" => "offline"
+    );
+    gauge!(
+        "mqtt_sessions",
+        gauges.sessions_disconnecting as f64,
+        "state" => "disconnecting"
+    );
+    gauge!("mqtt_queued_messages", gauges.queued as f64);
+    gauge!("mqtt_inflight_messages", gauges.inflight as f64);
+    gauge!("mqtt_retained_messages", gauges.retained as f64);
+    gauge!("mqtt_subscriptions", gauges.subscriptions as f64);
+}
+
+pub(crate) fn qos_label(qos: proto::PacketIdentifierDupQoS) -> &'static str {
+    match qos {
+        proto::PacketIdentifierDupQoS::AtMostOnce => "0",
+        proto::PacketIdentifierDupQoS::AtLeastOnce(..) => "1",
+        proto::PacketIdentifierDupQoS::ExactlyOnce(..) => "2",
+    }
+}
+
+pub(crate) fn event_label(event: &ClientEvent) -> &'static str {
+    match event {
+        ClientEvent::ConnReq(_) => "connreq",
+        ClientEvent::ConnAck(_) => "connack",
+        ClientEvent::Disconnect(_) => "disconnect",
+        ClientEvent::DropConnection => "drop_connection",
+        ClientEvent::ServerDisconnect(_) => "server_disconnect",
+        ClientEvent::CloseSession => "close_session",
+        ClientEvent::PingReq(_) => "pingreq",
+        ClientEvent::PingResp(_) => "pingresp",
+        ClientEvent::Subscribe(_) => "subscribe",
+        ClientEvent::SubAck(_) => "suback",
+        ClientEvent::Unsubscribe(_) => "unsubscribe",
+        ClientEvent::UnsubAck(_) => "unsuback",
+        ClientEvent::PublishFrom(..) => "publish_from",
+        ClientEvent::PublishTo(_) => "publish_to",
+        ClientEvent::PubAck0(_) => "puback0",
+        ClientEvent::PublishNotAuthorized(..) => "publish_not_authorized",
+        ClientEvent::PubAck(_) => "puback",
+        ClientEvent::PubRec(_) => "pubrec",
+        ClientEvent::PubRel(_) => "pubrel",
+        ClientEvent::PubComp(_) => "pubcomp",
+    }
+}
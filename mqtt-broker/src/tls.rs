@@ -0,0 +1,391 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use failure::ResultExt;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig, ServerSession, Session,
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+use crate::{Error, ErrorKind};
+
+/// Whether clients of a TLS listener have to present a certificate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuth {
+    /// Clients may connect without a certificate. A certificate that is
+    /// presented must still be signed by a trusted CA.
+    Optional,
+
+    /// Clients without a certificate signed by a trusted CA are refused
+    /// during the handshake.
+    Required,
+}
+
+/// Server certificate chain and private key for a TLS listener, and
+/// optionally the CAs client certificates are verified against.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca: Option<(PathBuf, ClientAuth)>,
+    match_client_id: bool,
+}
+
+impl TlsConfig {
+    /// `cert_path` is a PEM file holding the server certificate followed
+    /// by any intermediate certificates. `key_path` is a PEM file holding
+    /// a PKCS#8 or RSA private key.
+    pub fn new<C, K>(cert_path: C, key_path: K) -> Self
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca: None,
+            match_client_id: false,
+        }
+    }
+
+    /// Verifies client certificates against the CAs in the PEM file at
+    /// `ca_path`.
+    pub fn with_client_auth<P: Into<PathBuf>>(mut self, ca_path: P, auth: ClientAuth) -> Self {
+        self.client_ca = Some((ca_path.into(), auth));
+        self
+    }
+
+    /// Requires the client id in CONNECT to match the common name or one of
+    /// the subject alternative names of the client certificate.
+    ///
+    /// Clients connecting without a certificate are refused.
+    pub fn with_client_id_match(mut self, match_client_id: bool) -> Self {
+        self.match_client_id = match_client_id;
+        self
+    }
+
+    pub fn match_client_id(&self) -> bool {
+        self.match_client_id
+    }
+
+    pub fn acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let certs = load_certs(&self.cert_path)?;
+        let key = load_key(&self.key_path)?;
+
+        let verifier = match &self.client_ca {
+            None => NoClientAuth::new(),
+            Some((path, auth)) => {
+                let roots = load_roots(path)?;
+                match auth {
+                    ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+                    ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+                }
+            }
+        };
+
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(certs, key)
+            .context(ErrorKind::TlsConfiguration)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Identity of a client taken from its verified certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificateIdentity {
+    common_name: Option<String>,
+    subject_alt_names: Vec<String>,
+}
+
+impl CertificateIdentity {
+    /// Reads the identity from a DER encoded certificate.
+    ///
+    /// Only DNS, email and URI subject alternative names are kept.
+    pub fn from_der(der: &[u8]) -> Result<Self, Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|_| Error::from(ErrorKind::ClientCertificate))?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .map(|cn| cn.as_str().map(str::to_string))
+            .transpose()
+            .map_err(|_| Error::from(ErrorKind::ClientCertificate))?;
+
+        let subject_alt_names = cert
+            .subject_alternative_name()
+            .map_err(|_| Error::from(ErrorKind::ClientCertificate))?
+            .map(|ext| {
+                ext.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name)
+                        | GeneralName::RFC822Name(name)
+                        | GeneralName::URI(name) => Some((*name).to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            common_name,
+            subject_alt_names,
+        })
+    }
+
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    pub fn subject_alt_names(&self) -> &[String] {
+        &self.subject_alt_names
+    }
+
+    /// Whether `name` is the common name or one of the subject alternative
+    /// names.
+    pub fn matches(&self, name: &str) -> bool {
+        self.common_name() == Some(name) || self.subject_alt_names.iter().any(|san| san == name)
+    }
+}
+
+/// Returns the identity of the client certificate presented during the
+/// handshake, if any.
+pub(crate) fn peer_identity(session: &ServerSession) -> Result<Option<CertificateIdentity>, Error> {
+    session
+        .get_peer_certificates()
+        .and_then(|certs| certs.into_iter().next())
+        .map(|cert| CertificateIdentity::from_der(&cert.0))
+        .transpose()
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, Error> {
+    let file = File::open(path).context(ErrorKind::LoadTlsClientCa)?;
+    let certs = pemfile::certs(&mut BufReader::new(file))
+        .map_err(|_| Error::from(ErrorKind::LoadTlsClientCa))?;
+    if certs.is_empty() {
+        return Err(Error::from(ErrorKind::LoadTlsClientCa));
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in &certs {
+        roots
+            .add(cert)
+            .map_err(|_| Error::from(ErrorKind::LoadTlsClientCa))?;
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let file = File::open(path).context(ErrorKind::LoadTlsCertificate)?;
+    let certs = pemfile::certs(&mut BufReader::new(file))
+        .map_err(|_| Error::from(ErrorKind::LoadTlsCertificate))?;
+    if certs.is_empty() {
+        return Err(Error::from(ErrorKind::LoadTlsCertificate));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, Error> {
+    let file = File::open(path).context(ErrorKind::LoadTlsPrivateKey)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(file))
+        .map_err(|_| Error::from(ErrorKind::LoadTlsPrivateKey))?;
+
+    if keys.is_empty() {
+        let file = File::open(path).context(ErrorKind::LoadTlsPrivateKey)?;
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(file))
+            .map_err(|_| Error::from(ErrorKind::LoadTlsPrivateKey))?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| Error::from(ErrorKind::LoadTlsPrivateKey))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::net::SocketAddr;
+
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    fn write_cert(dir: &TempDir) -> (TlsConfig, Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let der = Certificate(cert.serialize_der().unwrap());
+        (TlsConfig::new(cert_path, key_path), der)
+    }
+
+    /// Writes a CA to `dir` and returns it with a client certificate and
+    /// key it signed.
+    fn write_client_ca(dir: &TempDir) -> (PathBuf, Certificate, PrivateKey) {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let ca_path = dir.path().join("ca.pem");
+        fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+        let mut params = CertificateParams::new(vec!["device-1.example".to_string()]);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "device-1");
+        let client = rcgen::Certificate::from_params(params).unwrap();
+        let cert = Certificate(client.serialize_der_with_signer(&ca).unwrap());
+        let key = PrivateKey(client.serialize_private_key_der());
+        (ca_path, cert, key)
+    }
+
+    async fn connect(
+        acceptor: TlsAcceptor,
+        mut client_config: ClientConfig,
+        server_cert: &Certificate,
+    ) -> Result<Option<CertificateIdentity>, Error> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor
+                .accept(stream)
+                .await
+                .context(ErrorKind::TlsHandshake)?;
+            peer_identity(stream.get_ref().1)
+        });
+
+        client_config.root_store.add(server_cert).unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let _ = connector.connect(domain, stream).await;
+
+        server.await.unwrap()
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let dir = TempDir::new().unwrap();
+        let (config, _) = write_cert(&dir);
+        let config = TlsConfig::new(config.cert_path, dir.path().join("missing.pem"));
+
+        let err = config.acceptor().err().unwrap();
+        assert_eq!(ErrorKind::LoadTlsPrivateKey, *err.kind());
+    }
+
+    #[test]
+    fn test_key_as_cert() {
+        let dir = TempDir::new().unwrap();
+        let (config, _) = write_cert(&dir);
+        let config = TlsConfig::new(config.key_path.clone(), config.key_path);
+
+        let err = config.acceptor().err().unwrap();
+        assert_eq!(ErrorKind::LoadTlsCertificate, *err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let dir = TempDir::new().unwrap();
+        let (config, cert) = write_cert(&dir);
+        let acceptor = config.acceptor().unwrap();
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add(&cert).unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = connector.connect(domain, stream).await.unwrap();
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"hello", &buf);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_identity() {
+        let dir = TempDir::new().unwrap();
+        let (config, server_cert) = write_cert(&dir);
+        let (ca_path, cert, key) = write_client_ca(&dir);
+        let acceptor = config
+            .with_client_auth(ca_path, ClientAuth::Required)
+            .acceptor()
+            .unwrap();
+
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set_single_client_cert(vec![cert], key)
+            .unwrap();
+        let identity = connect(acceptor, client_config, &server_cert)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(Some("device-1"), identity.common_name());
+        assert_eq!(
+            &["device-1.example".to_string()],
+            identity.subject_alt_names()
+        );
+        assert!(identity.matches("device-1"));
+        assert!(identity.matches("device-1.example"));
+        assert!(!identity.matches("device-2"));
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_optional() {
+        let dir = TempDir::new().unwrap();
+        let (config, server_cert) = write_cert(&dir);
+        let (ca_path, _, _) = write_client_ca(&dir);
+        let acceptor = config
+            .with_client_auth(ca_path, ClientAuth::Optional)
+            .acceptor()
+            .unwrap();
+
+        let identity = connect(acceptor, ClientConfig::new(), &server_cert)
+            .await
+            .unwrap();
+        assert_eq!(None, identity);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_required() {
+        let dir = TempDir::new().unwrap();
+        let (config, server_cert) = write_cert(&dir);
+        let (ca_path, _, _) = write_client_ca(&dir);
+        let acceptor = config
+            .with_client_auth(ca_path, ClientAuth::Required)
+            .acceptor()
+            .unwrap();
+
+        let err = connect(acceptor, ClientConfig::new(), &server_cert)
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorKind::TlsHandshake, *err.kind());
+    }
+}
@@ -0,0 +1,257 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures_util::ready;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
+
+/// WebSocket subprotocols a client can use, as offered by MQTT 3.1.1 and
+/// MQTT 3.1 clients.
+const SUBPROTOCOLS: [&str; 2] = ["mqtt", "mqttv3.1"];
+
+/// Accepts a WebSocket connection for `path`.
+///
+/// The handshake is refused unless the request is for `path` and the client
+/// offers an MQTT subprotocol.
+pub(crate) async fn accept<S>(stream: S, path: &str) -> Result<WsStream<S>, WsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let callback = |request: &Request, response: Response| handshake(request, response, path);
+    let inner = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+    Ok(WsStream::new(inner))
+}
+
+fn handshake(
+    request: &Request,
+    mut response: Response,
+    path: &str,
+) -> Result<Response, ErrorResponse> {
+    if request.uri().path() != path {
+        debug!(
+            "WebSocket request for unknown path {}",
+            request.uri().path()
+        );
+        return Err(error_response(StatusCode::NOT_FOUND));
+    }
+
+    let subprotocol = request
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .find_map(|offered| SUBPROTOCOLS.iter().find(|protocol| **protocol == offered));
+
+    if let Some(subprotocol) = subprotocol {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(subprotocol),
+        );
+        Ok(response)
+    } else {
+        debug!("WebSocket client did not offer the mqtt subprotocol");
+        Err(error_response(StatusCode::BAD_REQUEST))
+    }
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(None);
+    *response.status_mut() = status;
+    response
+}
+
+/// A WebSocket connection read and written as a byte stream.
+///
+/// MQTT packets are carried in binary frames. A packet may span several
+/// frames and a frame may hold several packets, so frames are only used as
+/// chunks of the underlying byte stream. Every write is sent as one frame.
+pub(crate) struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Bytes,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let len = buf.len().min(self.read_buf.len());
+                buf[..len].copy_from_slice(&self.read_buf[..len]);
+                self.read_buf.advance(len);
+                return Poll::Ready(Ok(len));
+            }
+
+            let message = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(WsError::ConnectionClosed)) | None => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(into_io_error(e))),
+            };
+
+            match message {
+                WsMessage::Binary(data) => self.read_buf = Bytes::from(data),
+                WsMessage::Text(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "MQTT packets must be sent in binary frames",
+                    )))
+                }
+                // Pings are answered by the WebSocket stream itself
+                WsMessage::Ping(_) | WsMessage::Pong(_) => (),
+                WsMessage::Close(_) => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(WsMessage::Binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
+
+fn into_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::sink::SinkExt;
+    use futures_util::stream::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    async fn connect(
+        protocol: Option<&str>,
+        path: &str,
+    ) -> (
+        Result<WebSocketStream<TcpStream>, WsError>,
+        Result<WsStream<TcpStream>, WsError>,
+    ) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut request = format!("ws://{}{}", addr, path)
+            .into_client_request()
+            .unwrap();
+        if let Some(protocol) = protocol {
+            request.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_str(protocol).unwrap(),
+            );
+        }
+
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            tokio_tungstenite::client_async(request, stream)
+                .await
+                .map(|(stream, _)| stream)
+        };
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept(stream, "/mqtt").await
+        };
+        futures_util::future::join(client, server).await
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, server) = connect(Some("mqtt"), "/mqtt").await;
+        assert!(client.is_ok());
+        assert!(server.is_ok());
+
+        let (client, server) = connect(Some("chat, mqttv3.1"), "/mqtt").await;
+        assert!(client.is_ok());
+        assert!(server.is_ok());
+
+        let (client, server) = connect(Some("mqtt"), "/other").await;
+        assert!(client.is_err());
+        assert!(server.is_err());
+
+        let (client, server) = connect(None, "/mqtt").await;
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let (client, server) = connect(Some("mqtt"), "/mqtt").await;
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+
+        // Frames are read as one stream of bytes
+        client.send(WsMessage::Binary(vec![1, 2])).await.unwrap();
+        client.send(WsMessage::Ping(vec![])).await.unwrap();
+        client.send(WsMessage::Binary(vec![3])).await.unwrap();
+        let mut buf = [0; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!([1, 2, 3], buf);
+
+        server.write_all(&[4, 5]).await.unwrap();
+        server.flush().await.unwrap();
+        let message = client.next().await.unwrap().unwrap();
+        assert_eq!(WsMessage::Pong(vec![]), message);
+        let message = client.next().await.unwrap().unwrap();
+        assert_eq!(WsMessage::Binary(vec![4, 5]), message);
+
+        client.send(WsMessage::Text("text".into())).await.unwrap();
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        client.close(None).await.unwrap();
+        assert_eq!(0, server.read(&mut buf).await.unwrap());
+    }
+}
@@ -0,0 +1,258 @@
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::time::Instant;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use mqtt::proto;
+use mqtt_broker::{
+    AdminHandle, ClientId, Error, ErrorKind, Publication, PublicationProperties, SessionInfo,
+    SessionStatus,
+};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+static DISCONNECT: &str = "/disconnect";
+
+/// Serves the admin API.
+///
+/// - `GET /sessions` lists the sessions
+/// - `POST /sessions/<client id>/disconnect` drops a client's connection
+/// - `DELETE /sessions/<client id>` deletes an offline session
+/// - `GET /retained` lists the retained messages
+/// - `DELETE /retained/<topic>` deletes the retained message of a topic
+/// - `POST /publish` publishes a message as the broker, given as
+///   `{"topic": .., "payload": .., "qos": .., "retain": ..}`
+///
+/// Client ids and topics are percent-decoded. The API doesn't authenticate
+/// its callers, so it should only listen on a local address.
+pub async fn serve(listener: TcpListener, admin: AdminHandle) {
+    let make_service = make_service_fn(move |_| {
+        let admin = admin.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let admin = admin.clone();
+                async move { Ok::<_, Infallible>(respond(request, admin).await) }
+            }))
+        }
+    });
+
+    let server = match Server::from_tcp(listener) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            warn!(message = "failed to serve the admin API", error=%e);
+            return;
+        }
+    };
+
+    info!("serving the admin API on {}", server.local_addr());
+    if let Err(e) = server.await {
+        warn!(message = "an error occurred serving the admin API", error=%e);
+    }
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    client_id: String,
+    state: &'static str,
+    subscriptions: Vec<SubscriptionResponse>,
+    queued: usize,
+    queued_bytes: usize,
+    dropped: u64,
+    inflight: usize,
+    idle_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SubscriptionResponse {
+    filter: String,
+    qos: u8,
+}
+
+#[derive(Serialize)]
+struct RetainedResponse {
+    topic: String,
+    qos: u8,
+    payload: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PublishRequest {
+    topic: String,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+}
+
+async fn respond(request: Request<Body>, mut admin: AdminHandle) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let segments = path
+        .trim_start_matches('/')
+        .splitn(2, '/')
+        .collect::<Vec<_>>();
+
+    let result = match (&method, segments.as_slice()) {
+        (&Method::GET, ["sessions"]) => admin.sessions().await.map(|sessions| {
+            let now = Instant::now();
+            let sessions = sessions
+                .iter()
+                .map(|session| session_response(session, now))
+                .collect::<Vec<_>>();
+            json(&sessions)
+        }),
+        (&Method::POST, ["sessions", rest]) if rest.ends_with(DISCONNECT) => {
+            match decode(&rest[..rest.len() - DISCONNECT.len()]) {
+                Some(client_id) => admin
+                    .disconnect(ClientId::from(client_id))
+                    .await
+                    .map(|_| status(StatusCode::NO_CONTENT)),
+                None => Ok(status(StatusCode::BAD_REQUEST)),
+            }
+        }
+        (&Method::DELETE, ["sessions", client_id]) => match decode(client_id) {
+            Some(client_id) => admin
+                .delete_session(ClientId::from(client_id))
+                .await
+                .map(|_| status(StatusCode::NO_CONTENT)),
+            None => Ok(status(StatusCode::BAD_REQUEST)),
+        },
+        (&Method::GET, ["retained"]) => admin.retained().await.map(|retained| {
+            let retained = retained
+                .into_iter()
+                .map(|publication| RetainedResponse {
+                    topic: publication.topic_name,
+                    qos: qos_to_u8(publication.qos),
+                    payload: String::from_utf8_lossy(&publication.payload).into_owned(),
+                })
+                .collect::<Vec<_>>();
+            json(&retained)
+        }),
+        (&Method::DELETE, ["retained", topic]) => match decode(topic) {
+            Some(topic) => admin.delete_retained(topic).await.map(|removed| {
+                if removed {
+                    status(StatusCode::NO_CONTENT)
+                } else {
+                    status(StatusCode::NOT_FOUND)
+                }
+            }),
+            None => Ok(status(StatusCode::BAD_REQUEST)),
+        },
+        (&Method::POST, ["publish"]) => match publication(request).await {
+            Some(publication) => admin
+                .publish(publication)
+                .await
+                .map(|_| status(StatusCode::NO_CONTENT)),
+            None => Ok(status(StatusCode::BAD_REQUEST)),
+        },
+        _ => Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    result.unwrap_or_else(|e| error_response(&e))
+}
+
+fn session_response(session: &SessionInfo, now: Instant) -> SessionResponse {
+    let state = match session.status() {
+        SessionStatus::Transient => "transient",
+        SessionStatus::Persistent => "persistent",
+        SessionStatus::Offline => "offline",
+        SessionStatus::Disconnecting => "disconnecting",
+    };
+    let subscriptions = session
+        .subscriptions()
+        .iter()
+        .map(|subscription| SubscriptionResponse {
+            filter: subscription.filter().to_string(),
+            qos: qos_to_u8(*subscription.max_qos()),
+        })
+        .collect();
+
+    SessionResponse {
+        client_id: session.client_id().to_string(),
+        state,
+        subscriptions,
+        queued: session.queued(),
+        queued_bytes: session.queued_bytes(),
+        dropped: session.dropped(),
+        inflight: session.inflight(),
+        idle_seconds: session
+            .last_active()
+            .map(|last_active| now.duration_since(last_active).as_secs()),
+    }
+}
+
+async fn publication(request: Request<Body>) -> Option<Publication> {
+    let body = hyper::body::to_bytes(request.into_body()).await.ok()?;
+    let publish: PublishRequest = serde_json::from_slice(&body).ok()?;
+    let qos = match publish.qos {
+        0 => proto::QoS::AtMostOnce,
+        1 => proto::QoS::AtLeastOnce,
+        2 => proto::QoS::ExactlyOnce,
+        _ => return None,
+    };
+    if publish.topic.is_empty() || publish.topic.contains(&['+', '#'][..]) {
+        return None;
+    }
+
+    Some(Publication {
+        topic_name: publish.topic,
+        qos,
+        retain: publish.retain,
+        payload: publish.payload.into(),
+        properties: PublicationProperties::default(),
+    })
+}
+
+fn decode(segment: &str) -> Option<String> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|decoded| decoded.into_owned())
+}
+
+fn qos_to_u8(qos: proto::QoS) -> u8 {
+    match qos {
+        proto::QoS::AtMostOnce => 0,
+        proto::QoS::AtLeastOnce => 1,
+        proto::QoS::ExactlyOnce => 2,
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut response = Response::new(Body::from(body));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(e) => {
+            warn!(message = "failed to serialize an admin API response", error=%e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::default();
+    *response.status_mut() = status;
+    response
+}
+
+fn error_response(error: &Error) -> Response<Body> {
+    let status_code = match error.kind() {
+        ErrorKind::NoSession => StatusCode::NOT_FOUND,
+        ErrorKind::SessionOffline | ErrorKind::SessionConnected => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let mut response = Response::new(Body::from(error.to_string()));
+    *response.status_mut() = status_code;
+    response
+}
@@ -0,0 +1,539 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use failure::ResultExt;
+use mqtt_broker::{BrokerConfig, ClientAuth, Error, ErrorKind, Listener, TlsConfig};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+static DEFAULT_ADDR: &str = "0.0.0.0:1883";
+static DEFAULT_TLS_ADDR: &str = "0.0.0.0:8883";
+static DEFAULT_WS_PATH: &str = "/mqtt";
+static DEFAULT_STATE_DIR: &str = "/var/lib/mqttd";
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The command line arguments `Config::load` understands.
+pub static USAGE: &str = "\
+usage: mqttd [options] [<addr>]
+
+    <addr>                  serve plain MQTT on this address only
+    -c, --config <file>     read settings from a TOML or YAML file
+    --state-dir <dir>       keep the broker state in this directory
+    --log-level <filter>    log at a level like `debug`, or with a filter
+                            like `info,mqtt_broker=debug`
+    -h, --help              print this message";
+
+/// Settings of `mqttd`.
+///
+/// Settings are read from a TOML file, or a YAML file if its name ends in
+/// `.yaml` or `.yml`. The `MQTTD_*` environment variables are applied on
+/// top of the file, and the command line on top of those.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub broker: BrokerConfig,
+    pub persistence: PersistenceConfig,
+    pub auth: AuthConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ListenerConfig {
+    Tcp {
+        addr: String,
+    },
+    Tls {
+        addr: String,
+        cert: PathBuf,
+        key: PathBuf,
+        #[serde(default)]
+        client_ca: Option<PathBuf>,
+        #[serde(default)]
+        client_auth: ClientAuthConfig,
+        #[serde(default)]
+        match_client_id: bool,
+    },
+    WebSocket {
+        addr: String,
+        #[serde(default = "default_ws_path")]
+        path: String,
+    },
+    Unix {
+        path: PathBuf,
+        #[serde(default)]
+        mode: Option<u32>,
+    },
+}
+
+impl ListenerConfig {
+    pub fn listener(&self) -> Result<Listener, Error> {
+        match self {
+            ListenerConfig::Tcp { addr } => Ok(Listener::tcp(addr)),
+            ListenerConfig::Tls {
+                addr,
+                cert,
+                key,
+                client_ca,
+                client_auth,
+                match_client_id,
+            } => {
+                let mut tls = TlsConfig::new(cert, key);
+                if let Some(ca) = client_ca {
+                    tls = tls
+                        .with_client_auth(ca, client_auth.into())
+                        .with_client_id_match(*match_client_id);
+                }
+                Listener::tls(addr, &tls)
+            }
+            ListenerConfig::WebSocket { addr, path } => Ok(Listener::websocket(addr, path)),
+            #[cfg(unix)]
+            ListenerConfig::Unix { path, mode } => Ok(Listener::unix(path, *mode)),
+            #[cfg(not(unix))]
+            ListenerConfig::Unix { .. } => Err(Error::from(ErrorKind::InvalidConfig)),
+        }
+    }
+}
+
+/// Whether clients of a TLS listener with a client CA have to present a
+/// certificate.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthConfig {
+    Optional,
+    Required,
+}
+
+impl Default for ClientAuthConfig {
+    fn default() -> Self {
+        ClientAuthConfig::Optional
+    }
+}
+
+impl From<&ClientAuthConfig> for ClientAuth {
+    fn from(auth: &ClientAuthConfig) -> Self {
+        match auth {
+            ClientAuthConfig::Optional => ClientAuth::Optional,
+            ClientAuthConfig::Required => ClientAuth::Required,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Where the broker state and journal are kept. It has to survive
+    /// reboots, so it defaults to `/var/lib/mqttd` rather than a
+    /// temporary directory when mqttd runs as root. Other users can't
+    /// create that, so they default to `$XDG_STATE_HOME/mqttd`,
+    /// `~/.local/state/mqttd` or `./state`, whichever is first available.
+    pub state_dir: PathBuf,
+    /// How often the broker state is snapshotted. Snapshots let the journal
+    /// be compacted, so they can't be turned off.
+    #[serde(deserialize_with = "nonzero_seconds")]
+    pub snapshot_interval: Duration,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            state_dir: default_state_dir(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Clients need a username and password from this file to connect
+    pub password_file: Option<PathBuf>,
+
+    /// Restricts the topics clients can use
+    pub acl_file: Option<PathBuf>,
+
+    pub allow_mqtt31: bool,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// A filter in the format of `RUST_LOG`, e.g. `info,mqtt_broker=debug`
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serves Prometheus metrics on `/metrics` at this address, e.g.
+    /// `127.0.0.1:9090`
+    pub addr: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Serves the admin API at this address, e.g. `127.0.0.1:8081`. The API
+    /// doesn't authenticate its callers, so the address should be local.
+    pub addr: Option<String>,
+}
+
+impl Config {
+    /// Loads the config for the command line arguments, which are
+    /// described by `USAGE`.
+    ///
+    /// `MQTTD_CONFIG` can name the file instead of `--config`. Giving an
+    /// address replaces the plain TCP listeners with one listening on that
+    /// address. Plain MQTT is served on `0.0.0.0:1883` when the file has no
+    /// listeners. Unknown options and a second address are invalid
+    /// arguments.
+    pub fn load<I: IntoIterator<Item = OsString>>(args: I) -> Result<Self, Error> {
+        let mut path = env::var_os("MQTTD_CONFIG").map(PathBuf::from);
+        let mut addr = None;
+        let mut state_dir = None;
+        let mut log_level = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let arg = arg.into_string().map_err(|_| ErrorKind::InvalidArguments)?;
+            match arg.as_str() {
+                "-c" | "--config" => {
+                    let value = args.next().ok_or(ErrorKind::InvalidArguments)?;
+                    path = Some(PathBuf::from(value));
+                }
+                "--state-dir" => {
+                    let value = args.next().ok_or(ErrorKind::InvalidArguments)?;
+                    state_dir = Some(PathBuf::from(value));
+                }
+                "--log-level" => {
+                    let value = args.next().ok_or(ErrorKind::InvalidArguments)?;
+                    let value = value
+                        .into_string()
+                        .map_err(|_| ErrorKind::InvalidArguments)?;
+                    log_level = Some(value);
+                }
+                _ if arg.starts_with('-') || addr.is_some() => {
+                    return Err(Error::from(ErrorKind::InvalidArguments));
+                }
+                _ => addr = Some(arg),
+            }
+        }
+
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        if config.listeners.is_empty() {
+            config.listeners.push(ListenerConfig::Tcp {
+                addr: DEFAULT_ADDR.to_string(),
+            });
+        }
+        config.apply_env();
+
+        if let Some(state_dir) = state_dir {
+            config.persistence.state_dir = state_dir;
+        }
+        if let Some(filter) = log_level {
+            config.logging.filter = Some(filter);
+        }
+        if let Some(addr) = addr {
+            config
+                .listeners
+                .retain(|listener| !matches!(listener, ListenerConfig::Tcp { .. }));
+            config.listeners.insert(0, ListenerConfig::Tcp { addr });
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).context(ErrorKind::LoadConfig)?;
+        let yaml = path
+            .extension()
+            .map_or(false, |extension| extension == "yaml" || extension == "yml");
+        let config = if yaml {
+            serde_yaml::from_str(&contents).context(ErrorKind::InvalidConfig)?
+        } else {
+            toml::from_str(&contents).context(ErrorKind::InvalidConfig)?
+        };
+        Ok(config)
+    }
+
+    /// Applies the `MQTTD_*` environment variables mqttd was configured
+    /// with before it had a config file.
+    fn apply_env(&mut self) {
+        // Also serve over TLS when a certificate and key are configured.
+        // MQTTD_TLS_CLIENT_AUTH is `optional` (the default) or `required`.
+        if let (Some(cert), Some(key)) =
+            (env::var_os("MQTTD_TLS_CERT"), env::var_os("MQTTD_TLS_KEY"))
+        {
+            let client_auth = match env::var("MQTTD_TLS_CLIENT_AUTH")
+                .as_ref()
+                .map(String::as_str)
+            {
+                Ok("required") => ClientAuthConfig::Required,
+                _ => ClientAuthConfig::Optional,
+            };
+            self.listeners.push(ListenerConfig::Tls {
+                addr: env::var("MQTTD_TLS_ADDR").unwrap_or_else(|_| DEFAULT_TLS_ADDR.to_string()),
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: env::var_os("MQTTD_TLS_CLIENT_CA").map(PathBuf::from),
+                client_auth,
+                match_client_id: env::var_os("MQTTD_TLS_MATCH_CLIENT_ID").is_some(),
+            });
+        }
+
+        if let Ok(addr) = env::var("MQTTD_WS_ADDR") {
+            let path = env::var("MQTTD_WS_PATH").unwrap_or_else(|_| default_ws_path());
+            self.listeners
+                .push(ListenerConfig::WebSocket { addr, path });
+        }
+
+        // MQTTD_UNIX_SOCKET_MODE sets the socket's permissions in octal, e.g. 660
+        if let Some(path) = env::var_os("MQTTD_UNIX_SOCKET") {
+            let mode = env::var("MQTTD_UNIX_SOCKET_MODE")
+                .ok()
+                .and_then(|mode| u32::from_str_radix(&mode, 8).ok());
+            self.listeners.push(ListenerConfig::Unix {
+                path: PathBuf::from(path),
+                mode,
+            });
+        }
+
+        if let Some(state_dir) = env::var_os("MQTTD_STATE_DIR") {
+            self.persistence.state_dir = PathBuf::from(state_dir);
+        }
+        if let Some(path) = env::var_os("MQTTD_PASSWORD_FILE") {
+            self.auth.password_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env::var_os("MQTTD_ACL_FILE") {
+            self.auth.acl_file = Some(PathBuf::from(path));
+        }
+        if env::var_os("MQTTD_ALLOW_MQTT31").is_some() {
+            self.auth.allow_mqtt31 = true;
+        }
+        if let Ok(filter) = env::var("RUST_LOG") {
+            self.logging.filter = Some(filter);
+        }
+    }
+}
+
+fn default_ws_path() -> String {
+    DEFAULT_WS_PATH.to_string()
+}
+
+fn default_state_dir() -> PathBuf {
+    if is_root() {
+        return PathBuf::from(DEFAULT_STATE_DIR);
+    }
+
+    // Relative paths in XDG_STATE_HOME are invalid and ignored
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .map_or_else(|| PathBuf::from("state"), |dir| dir.join("mqttd"))
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // geteuid always succeeds
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+fn nonzero_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(D::Error::custom("expected at least 1 second")),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mqtt_broker::{OverflowPolicy, TopicExpiry};
+
+    static TOML: &str = r#"
+        [[listeners]]
+        type = "tcp"
+        addr = "127.0.0.1:1883"
+
+        [[listeners]]
+        type = "websocket"
+        addr = "127.0.0.1:8080"
+
+        [[listeners]]
+        type = "unix"
+        path = "/run/mqttd.sock"
+        mode = 0o660
+
+        [broker]
+        max_inflight_messages = 32
+        connection_timeout = 10
+        max_queued_bytes = 65536
+        queue_overflow = "drop_oldest"
+        session_expiry = 86400
+        message_expiry = 300
+
+        [[broker.topic_message_expiry]]
+        filter = "alerts/#"
+        expiry = 0
+
+        [persistence]
+        state_dir = "/var/lib/mqttd"
+
+        [auth]
+        allow_mqtt31 = true
+
+        [metrics]
+        addr = "127.0.0.1:9090"
+
+        [admin]
+        addr = "127.0.0.1:8081"
+    "#;
+
+    static YAML: &str = r#"
+        listeners:
+          - type: tcp
+            addr: "127.0.0.1:1883"
+          - type: websocket
+            addr: "127.0.0.1:8080"
+          - type: unix
+            path: /run/mqttd.sock
+            mode: 0o660
+        broker:
+          max_inflight_messages: 32
+          connection_timeout: 10
+          max_queued_bytes: 65536
+          queue_overflow: drop_oldest
+          session_expiry: 86400
+          message_expiry: 300
+          topic_message_expiry:
+            - filter: "alerts/#"
+              expiry: 0
+        persistence:
+          state_dir: /var/lib/mqttd
+        auth:
+          allow_mqtt31: true
+        metrics:
+          addr: "127.0.0.1:9090"
+        admin:
+          addr: "127.0.0.1:8081"
+    "#;
+
+    fn check(config: &Config) {
+        assert_eq!(
+            vec![
+                ListenerConfig::Tcp {
+                    addr: "127.0.0.1:1883".to_string()
+                },
+                ListenerConfig::WebSocket {
+                    addr: "127.0.0.1:8080".to_string(),
+                    path: "/mqtt".to_string(),
+                },
+                ListenerConfig::Unix {
+                    path: PathBuf::from("/run/mqttd.sock"),
+                    mode: Some(0o660),
+                },
+            ],
+            config.listeners
+        );
+        assert_eq!(
+            BrokerConfig::new()
+                .with_max_inflight_messages(32)
+                .with_connection_timeout(Duration::from_secs(10))
+                .with_max_queued_bytes(65536)
+                .with_queue_overflow(OverflowPolicy::DropOldest)
+                .with_session_expiry(Duration::from_secs(86400))
+                .with_message_expiry(Duration::from_secs(300))
+                .with_topic_message_expiry(TopicExpiry::new(
+                    "alerts/#".parse().unwrap(),
+                    Duration::from_secs(0)
+                )),
+            config.broker
+        );
+        assert_eq!(
+            PathBuf::from("/var/lib/mqttd"),
+            config.persistence.state_dir
+        );
+        assert_eq!(
+            DEFAULT_SNAPSHOT_INTERVAL,
+            config.persistence.snapshot_interval
+        );
+        assert!(config.auth.allow_mqtt31);
+        assert_eq!(None, config.auth.password_file);
+        assert_eq!(Some("127.0.0.1:9090"), config.metrics.addr.as_deref());
+        assert_eq!(Some("127.0.0.1:8081"), config.admin.addr.as_deref());
+    }
+
+    #[test]
+    fn test_toml() {
+        check(&toml::from_str(TOML).unwrap());
+    }
+
+    #[test]
+    fn test_yaml() {
+        check(&serde_yaml::from_str(YAML).unwrap());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(toml::from_str::<Config>("[broker]\nmax_inflight = 32\n").is_err());
+    }
+
+    #[test]
+    fn test_args() {
+        let args = |args: &[&str]| Config::load(args.iter().map(OsString::from));
+
+        let config = args(&[
+            "--state-dir",
+            "/tmp/mqttd",
+            "--log-level",
+            "debug",
+            "127.0.0.1:1884",
+        ])
+        .unwrap();
+        assert_eq!(PathBuf::from("/tmp/mqttd"), config.persistence.state_dir);
+        assert_eq!(Some("debug"), config.logging.filter.as_deref());
+        assert_eq!(
+            vec![ListenerConfig::Tcp {
+                addr: "127.0.0.1:1884".to_string()
+            }],
+            config.listeners
+        );
+
+        for invalid in &[
+            &["--conifg", "mqttd.toml"][..],
+            &["--help"],
+            &["--state-dir"],
+            &["127.0.0.1:1884", "127.0.0.1:1885"],
+        ] {
+            let e = args(invalid).unwrap_err();
+            assert_eq!(ErrorKind::InvalidArguments, *e.kind());
+        }
+    }
+
+    #[test]
+    fn test_zero_snapshot_interval() {
+        let config = "[persistence]\nsnapshot_interval = 0\n";
+        assert!(toml::from_str::<Config>(config).is_err());
+
+        let config = "[persistence]\nsnapshot_interval = 5\n";
+        let config = toml::from_str::<Config>(config).unwrap();
+        assert_eq!(Duration::from_secs(5), config.persistence.snapshot_interval);
+    }
+}
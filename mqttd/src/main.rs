@@ -1,31 +1,124 @@
-use std::{env, io};
+use std::{env, fs, io};
 
+use failure::ResultExt;
 use futures_util::pin_mut;
-use mqtt_broker::{Error, Server};
+use mqtt_broker::{
+    snapshot_timer, AclAuthorizer, AdminHandle, Broker, Error, ErrorKind, FilePersistor, Journal,
+    PasswordFileAuthenticator, Persist, Server, Snapshotter,
+};
 use tracing::Level;
 use tracing_subscriber::{fmt, EnvFilter};
 
+use crate::config::{Config, ListenerConfig};
+use crate::reload::Reloader;
+
+mod admin;
+mod config;
+mod prometheus;
+mod reload;
 mod shutdown;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let subscriber = fmt::Subscriber::builder()
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", config::USAGE);
+        return Ok(());
+    }
+    let config = match Config::load(args.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            if *e.kind() == ErrorKind::InvalidArguments {
+                eprintln!("{}", config::USAGE);
+            }
+            return Err(e);
+        }
+    };
+
+    let filter = config
+        .logging
+        .filter
+        .as_ref()
+        .map_or_else(EnvFilter::from_default_env, EnvFilter::new);
+    let builder = fmt::Subscriber::builder()
         .with_ansi(atty::is(atty::Stream::Stderr))
         .with_max_level(Level::TRACE)
         .with_writer(io::stderr)
-        .with_env_filter(EnvFilter::from_default_env())
-        .finish();
-    let _ = tracing::subscriber::set_global_default(subscriber);
+        .with_env_filter(filter)
+        .with_filter_reloading();
+    let filter_handle = builder.reload_handle();
+    let _ = tracing::subscriber::set_global_default(builder.finish());
+    let set_log_filter = move |filter| filter_handle.reload(filter).map_err(|e| e.to_string());
+
+    if let Some(addr) = &config.metrics.addr {
+        let handle = prometheus::install()?;
+        let listener = std::net::TcpListener::bind(addr).context(ErrorKind::BindServer)?;
+        tokio::spawn(prometheus::serve(listener, handle));
+    }
+
+    let listeners = config
+        .listeners
+        .iter()
+        .map(ListenerConfig::listener)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let state_dir = &config.persistence.state_dir;
+    fs::create_dir_all(state_dir)
+        .context(ErrorKind::CreateStateDir(state_dir.display().to_string()))?;
+    let mut persistor = FilePersistor::new(state_dir);
+    let state = persistor.load()?.unwrap_or_default();
+
+    let journal = Journal::open(state_dir.join("journal"), state.journal_segment())?;
+    let compactor = journal.compactor();
+    let mut broker = Broker::from_state(config.broker.clone(), state)
+        .with_journal(journal)?
+        .with_mqtt31(config.auth.allow_mqtt31);
 
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "0.0.0.0:1883".to_string());
+    if let Some(path) = &config.auth.acl_file {
+        broker = broker.with_authorizer(AclAuthorizer::from_file(path)?);
+    }
+
+    let snapshotter = Snapshotter::new(persistor).with_compactor(compactor);
+    let mut snapshot_handle = snapshotter.snapshot_handle();
+    let snapshotter_task = tokio::spawn(snapshotter.run());
+    tokio::spawn(snapshot_timer(
+        config.persistence.snapshot_interval,
+        broker.handle(),
+        snapshot_handle.clone(),
+    ));
+
+    let handle = broker.handle();
+    if let Some(addr) = &config.admin.addr {
+        let listener = std::net::TcpListener::bind(addr).context(ErrorKind::BindServer)?;
+        tokio::spawn(admin::serve(listener, AdminHandle::new(handle.clone())));
+    }
 
     let shutdown = shutdown::shutdown();
     pin_mut!(shutdown);
 
-    let _state = Server::new().serve(addr, shutdown).await?;
+    let mut server = listeners
+        .into_iter()
+        .fold(Server::from_broker(broker), Server::with_listener);
+
+    let mut authenticator = None;
+    if let Some(path) = &config.auth.password_file {
+        let password_file = PasswordFileAuthenticator::open(path)?;
+        server = server.with_authenticator(password_file.clone());
+        authenticator = Some(password_file);
+    }
+
+    // The config is reloaded on SIGHUP
+    let reloader = Reloader::new(args, config, handle, authenticator, set_log_filter);
+    tokio::spawn(reload::reload_on_hangup(reloader));
+
+    let state = server.run(shutdown).await?;
 
-    // TODO - sync state to disk
+    // The final state goes through the snapshotter so it can't be
+    // overwritten by a periodic snapshot that is still being written.
+    snapshot_handle.send(state).await?;
+    snapshot_handle.shutdown().await?;
+    snapshotter_task
+        .await
+        .map_err(|_| Error::from(ErrorKind::SnapshotJoin))??;
     Ok(())
 }
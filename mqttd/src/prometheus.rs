@@ -0,0 +1,62 @@
+use std::convert::Infallible;
+use std::net::TcpListener;
+
+use failure::ResultExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use mqtt_broker::{Error, ErrorKind};
+use tracing::{info, warn};
+
+static CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Records the broker's metrics for Prometheus.
+pub fn install() -> Result<PrometheusHandle, Error> {
+    let recorder = PrometheusBuilder::new().build();
+    let handle = recorder.handle();
+    metrics::set_boxed_recorder(Box::new(recorder)).context(ErrorKind::InstallMetricsRecorder)?;
+    mqtt_broker::register_metrics();
+    Ok(handle)
+}
+
+/// Serves the metrics in the Prometheus text format on `GET /metrics`.
+pub async fn serve(listener: TcpListener, handle: PrometheusHandle) {
+    let make_service = make_service_fn(move |_| {
+        let handle = handle.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&request, &handle);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = match Server::from_tcp(listener) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            warn!(message = "failed to serve metrics", error=%e);
+            return;
+        }
+    };
+
+    info!("serving metrics on {}", server.local_addr());
+    if let Err(e) = server.await {
+        warn!(message = "an error occurred serving metrics", error=%e);
+    }
+}
+
+fn respond(request: &Request<Body>, handle: &PrometheusHandle) -> Response<Body> {
+    let mut response = Response::default();
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    } else if request.method() != Method::GET {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(CONTENT_TYPE),
+        );
+        *response.body_mut() = Body::from(handle.render());
+    }
+    response
+}
@@ -0,0 +1,140 @@
+use std::ffi::OsString;
+
+use mqtt_broker::{
+    AclAuthorizer, AllowAll, BrokerHandle, ConfigUpdate, Error, Message, PasswordFileAuthenticator,
+    SystemEvent,
+};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+
+/// Applies changes to the config file while mqttd is running.
+///
+/// Broker limits and timeouts, the ACL file, MQTT 3.1 support and the log
+/// filter are updated in place, and the password file is re-read.
+/// Connections and sessions are kept. Changes to listeners, persistence,
+/// the metrics and admin API addresses and the password file path are only
+/// reported, as they need a restart.
+pub struct Reloader<F> {
+    args: Vec<OsString>,
+    config: Config,
+    broker_handle: BrokerHandle,
+    authenticator: Option<PasswordFileAuthenticator>,
+    set_log_filter: F,
+}
+
+impl<F> Reloader<F>
+where
+    F: Fn(EnvFilter) -> Result<(), String>,
+{
+    /// `args` and `config` are the command line arguments mqttd was started
+    /// with and the config it loaded from them.
+    pub fn new(
+        args: Vec<OsString>,
+        config: Config,
+        broker_handle: BrokerHandle,
+        authenticator: Option<PasswordFileAuthenticator>,
+        set_log_filter: F,
+    ) -> Self {
+        Self {
+            args,
+            config,
+            broker_handle,
+            authenticator,
+            set_log_filter,
+        }
+    }
+
+    /// Re-reads the config and applies it. A config that can't be loaded
+    /// is skipped, an error means the broker is gone.
+    pub async fn reload(&mut self) -> Result<(), Error> {
+        let config = match Config::load(self.args.clone()) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(message = "failed to reload config. keeping the current config", error=%e);
+                return Ok(());
+            }
+        };
+
+        if config.listeners != self.config.listeners {
+            warn!("listeners can't be changed while mqttd is running. restart to apply them");
+        }
+        if config.persistence != self.config.persistence {
+            warn!("persistence settings can't be changed while mqttd is running. restart to apply them");
+        }
+        if config.metrics != self.config.metrics {
+            warn!(
+                "the metrics address can't be changed while mqttd is running. restart to apply it"
+            );
+        }
+        if config.admin != self.config.admin {
+            warn!("the admin API address can't be changed while mqttd is running. restart to apply it");
+        }
+        if config.auth.password_file != self.config.auth.password_file {
+            warn!("the password file can't be changed while mqttd is running. restart to apply it");
+        }
+
+        let filter = config
+            .logging
+            .filter
+            .as_ref()
+            .map_or_else(EnvFilter::from_default_env, EnvFilter::new);
+        if let Err(e) = (self.set_log_filter)(filter) {
+            warn!("failed to update the log filter - {}", e);
+        }
+
+        if let Some(authenticator) = &self.authenticator {
+            if let Err(e) = authenticator.reload() {
+                warn!(message = "failed to reload password file. keeping previous credentials", error=%e);
+            }
+        }
+
+        let mut update = ConfigUpdate::new(config.broker).with_mqtt31(config.auth.allow_mqtt31);
+        match &config.auth.acl_file {
+            Some(path) => match AclAuthorizer::from_file(path) {
+                Ok(acl) => update = update.with_authorizer(acl),
+                Err(e) => {
+                    warn!(message = "failed to reload ACL file. keeping previous rules", error=%e);
+                }
+            },
+            None => update = update.with_authorizer(AllowAll),
+        }
+
+        self.broker_handle
+            .send(Message::System(SystemEvent::ConfigUpdate(update)))
+            .await
+    }
+}
+
+/// Reloads the config every time SIGHUP is received.
+pub async fn reload_on_hangup<F>(reloader: Reloader<F>)
+where
+    F: Fn(EnvFilter) -> Result<(), String>,
+{
+    imp::reload_on_hangup(reloader).await
+}
+
+#[cfg(unix)]
+mod imp {
+    use futures_util::stream::StreamExt;
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::{info, warn};
+    use tracing_subscriber::EnvFilter;
+
+    use super::Reloader;
+
+    pub(super) async fn reload_on_hangup<F>(mut reloader: Reloader<F>)
+    where
+        F: Fn(EnvFilter) -> Result<(), String>,
+    {
+        let mut hangup = signal(SignalKind::hangup()).expect("signal handling failed");
+        while hangup.next().await.is_some() {
+            info!("SIGHUP received. reloading config...");
+            if let Err(e) = reloader.reload().await {
+                warn!(message = "failed to update the broker config", error=%e);
+                break;
+            }
+        }
+    }
+}